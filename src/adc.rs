@@ -0,0 +1,37 @@
+//! Sampling an ADC channel over and over, for watching a sensor while calibrating it
+use std::time::Duration;
+
+use crate::script::literal;
+
+/// Code that reads the ADC on `pin` every `interval`, `count` times or until interrupted, and
+/// prints each reading as a 16 bit value along with the milliseconds since the first one. With
+/// `csv` the readings are printed as CSV, after a header.
+pub fn monitor_script(pin: &str, interval: Duration, count: Option<u64>, csv: bool) -> String {
+    let (header, row) = if csv {
+        ("print('time_ms,value')", "'{},{}'")
+    } else {
+        ("", "'{:>8} ms {:>6}'")
+    };
+    format!(
+        "import machine, time
+adc = machine.ADC(machine.Pin({}))
+count = {}
+{}
+start = time.ticks_ms()
+n = 0
+try:
+    while count is None or n < count:
+        value = adc.read_u16()
+        print({}.format(time.ticks_diff(time.ticks_ms(), start), value))
+        n += 1
+        time.sleep_ms({})
+except KeyboardInterrupt:
+    pass
+",
+        literal(pin),
+        count.map_or(String::from("None"), |count| count.to_string()),
+        header,
+        row,
+        interval.as_millis()
+    )
+}
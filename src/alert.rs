@@ -0,0 +1,115 @@
+//! Running host commands when the device prints a line matching a pattern, to be alerted from a
+//! long monitoring session, such as when `ERROR` or `battery low` appears
+use anyhow::{bail, Result};
+use std::process::{self, Child};
+
+use crate::regex::Regex;
+
+/// A command to run whenever a line matches a pattern
+#[derive(Debug, Clone)]
+pub struct Trigger {
+    pub regex: Regex,
+    pub command: String,
+}
+
+impl Trigger {
+    /// Parse `REGEX:COMMAND`, split at the first `:` of the regex that isn't escaped as `\:`
+    pub fn parse(value: &str) -> Result<Trigger> {
+        let mut escaped = false;
+        let split = value.char_indices().find(|&(_, c)| {
+            let found = c == ':' && !escaped;
+            escaped = c == '\\' && !escaped;
+            found
+        });
+        match split {
+            Some((index, _)) if index > 0 && index + 1 < value.len() => Ok(Trigger {
+                regex: Regex::new(&value[..index])?,
+                command: value[index + 1..].to_string(),
+            }),
+            _ => bail!("Expected REGEX:COMMAND, got {:?}", value),
+        }
+    }
+}
+
+/// Watches the output a chunk at a time, starting each trigger's command for the lines it
+/// matches. The commands run in the background, so the output keeps flowing while they do, and a
+/// trigger whose command is still running doesn't start it again, so a burst of matching lines
+/// raises one alert rather than one each.
+pub struct Alerts {
+    triggers: Vec<Trigger>,
+    /// The command each trigger last started, until it's seen to have finished
+    running: Vec<Option<Child>>,
+    /// A line that hasn't ended yet
+    line: Vec<u8>,
+}
+
+impl Alerts {
+    pub fn new(triggers: Vec<Trigger>) -> Alerts {
+        Alerts {
+            running: triggers.iter().map(|_| None).collect(),
+            triggers,
+            line: Vec::new(),
+        }
+    }
+
+    /// Match the lines completed by `bytes`
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if self.triggers.is_empty() {
+            return;
+        }
+        for &byte in bytes {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+            let line = String::from_utf8_lossy(&self.line).trim_end().to_string();
+            self.line.clear();
+            self.matched(&line);
+        }
+    }
+
+    fn matched(&mut self, line: &str) {
+        for (trigger, running) in self.triggers.iter().zip(self.running.iter_mut()) {
+            if !trigger.regex.is_match(line) {
+                continue;
+            }
+            if let Some(child) = running {
+                match child.try_wait() {
+                    Ok(None) => continue,
+                    Ok(Some(status)) if !status.success() => {
+                        eprintln!("Alert `{}` failed with {}", trigger.command, status)
+                    }
+                    _ => {}
+                }
+            }
+            *running = match spawn(&trigger.command, line) {
+                Ok(child) => Some(child),
+                Err(e) => {
+                    eprintln!("Couldn't run alert `{}`: {}", trigger.command, e);
+                    None
+                }
+            };
+        }
+    }
+
+    /// Wait for the commands still running, so that none is cut short by serpico exiting
+    pub fn finish(&mut self) {
+        for child in self.running.iter_mut().flatten() {
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Start `command` in the shell, with the line that matched in `$SERPICO_LINE`
+fn spawn(command: &str, line: &str) -> std::io::Result<Child> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command).env("SERPICO_LINE", line).spawn()
+}
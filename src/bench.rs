@@ -0,0 +1,124 @@
+//! Measuring the throughput of the link to the device with synthetic payloads
+use anyhow::{bail, Result};
+use std::time::Duration;
+
+use crate::device::Device;
+use crate::serial::{execute, ExecOptions, Timings};
+
+/// Which way a payload travelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// A script of the given size sent to the device
+    Upload,
+    /// Output of the given size printed by the device
+    Download,
+}
+
+/// The result of sending a single payload
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub direction: Direction,
+    pub size: usize,
+    pub timings: Timings,
+}
+
+impl Measurement {
+    /// The time it took to move the payload
+    pub fn duration(&self) -> Duration {
+        match self.direction {
+            Direction::Upload => self.timings.upload,
+            Direction::Download => self.timings.execution + self.timings.output,
+        }
+    }
+
+    /// Effective throughput in bytes per second
+    pub fn bytes_per_sec(&self) -> f64 {
+        let secs = self.duration().as_secs_f64();
+        if secs > 0.0 {
+            self.size as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Length of each line of the synthetic payloads, including the newline
+const LINE_LENGTH: usize = 64;
+
+/// A script made up of comment lines, `size` bytes long
+fn upload_payload(size: usize) -> String {
+    let line = format!("#{}\n", "x".repeat(LINE_LENGTH - 2));
+    let mut script = line.repeat(size / LINE_LENGTH);
+    script.push_str(&"#".repeat(size % LINE_LENGTH));
+    script
+}
+
+/// A script that prints `size` bytes
+fn download_payload(size: usize) -> String {
+    format!(
+        "_l = 'x' * {} + '\\n'\nfor _ in range({}):\n    print(_l, end='')\nprint('x' * {}, end='')\n",
+        LINE_LENGTH - 1,
+        size / LINE_LENGTH,
+        size % LINE_LENGTH
+    )
+}
+
+/// Upload and download payloads of each of the given sizes, measuring how long each takes. The
+/// device is only soft rebooted before the first payload.
+pub fn run(
+    device: &mut Device,
+    sizes: &[usize],
+    options: &ExecOptions,
+) -> Result<Vec<Measurement>> {
+    let mut options = ExecOptions {
+        echo: false,
+        log: false,
+        ..options.clone()
+    };
+    let mut measurements = Vec::new();
+
+    for &size in sizes {
+        let result = execute(device, upload_payload(size), &options)?;
+        options.soft_reset = false;
+        measurements.push(Measurement {
+            direction: Direction::Upload,
+            size,
+            timings: result.timings,
+        });
+    }
+
+    for &size in sizes {
+        let result = execute(device, download_payload(size), &options)?;
+        options.soft_reset = false;
+        if result.stdout.len() != size {
+            bail!(
+                "Expected {} bytes of output from device, got {}",
+                size,
+                result.stdout.len()
+            );
+        }
+        measurements.push(Measurement {
+            direction: Direction::Download,
+            size,
+            timings: result.timings,
+        });
+    }
+
+    Ok(measurements)
+}
+
+/// Parse a size such as `512`, `8k` or `1m`
+pub fn parse_size(value: &str) -> Result<usize> {
+    let lower = value.trim().to_ascii_lowercase();
+    let (number, multiplier) = match lower.strip_suffix('k') {
+        Some(number) => (number, 1024),
+        None => match lower.strip_suffix('m') {
+            Some(number) => (number, 1024 * 1024),
+            None => (lower.as_str(), 1),
+        },
+    };
+    match number.parse::<usize>() {
+        Ok(number) => Ok(number * multiplier),
+        Err(_) => bail!("Invalid size {:?}", value),
+    }
+}
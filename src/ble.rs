@@ -0,0 +1,729 @@
+//! Scripting boards over Bluetooth Low Energy at a device such as `ble://AA:BB:CC:DD:EE:FF`, for
+//! boards like the Pico W or nRF boards that serve their REPL over the Nordic UART Service, as
+//! MicroPython's `ble_uart_repl.py` example does. Only on Linux, with serpico built with
+//! `--features ble`.
+//!
+//! The board is spoken to over the kernel's L2CAP socket for the ATT channel, so nothing is needed
+//! but the kernel's Bluetooth stack. What the host writes goes to the service's RX characteristic
+//! as write commands of up to the negotiated MTU, and what the board prints arrives as
+//! notifications of its TX characteristic.
+//!
+//! The address is taken to be a random static one, as MicroPython's own stack uses, if its top
+//! two bits are set, and a public one otherwise. `?random` or `?public` after it says which.
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::socket::{Socket, SocketPort};
+
+const SCHEME: &str = "ble://";
+
+/// How long connecting to the board may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the board may take to answer a request while the service is discovered
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+const BTPROTO_L2CAP: libc::c_int = 0;
+/// The fixed L2CAP channel of the attribute protocol
+const ATT_CID: u16 = 4;
+const BDADDR_LE_PUBLIC: u8 = 1;
+const BDADDR_LE_RANDOM: u8 = 2;
+
+/// The MTU every board supports, and the largest one asked for
+const DEFAULT_MTU: usize = 23;
+const MAX_MTU: usize = 517;
+
+const ERROR_RESPONSE: u8 = 0x01;
+const EXCHANGE_MTU_REQUEST: u8 = 0x02;
+const EXCHANGE_MTU_RESPONSE: u8 = 0x03;
+const FIND_INFORMATION_REQUEST: u8 = 0x04;
+const READ_BY_TYPE_REQUEST: u8 = 0x08;
+const READ_BY_GROUP_TYPE_REQUEST: u8 = 0x10;
+const WRITE_REQUEST: u8 = 0x12;
+const NOTIFICATION: u8 = 0x1b;
+const INDICATION: u8 = 0x1d;
+const CONFIRMATION: u8 = 0x1e;
+const WRITE_COMMAND: u8 = 0x52;
+
+/// The requests a client may be sent, which are answered with an error but for the MTU's
+const REQUESTS: &[u8] = &[
+    0x02, 0x04, 0x06, 0x08, 0x0a, 0x0c, 0x0e, 0x10, 0x12, 0x16, 0x18, 0x20,
+];
+
+const ATTRIBUTE_NOT_FOUND: u8 = 0x0a;
+const REQUEST_NOT_SUPPORTED: u8 = 0x06;
+
+const PRIMARY_SERVICE: u16 = 0x2800;
+const CHARACTERISTIC: u16 = 0x2803;
+const CLIENT_CHARACTERISTIC_CONFIGURATION: u16 = 0x2902;
+
+/// The Nordic UART Service's UUIDs, 6E40000x-B5A3-F393-E0A9-E50E24DCCA9E, in the little-endian
+/// order they're sent in
+const NUS_SERVICE: [u8; 16] = nus_uuid(0x01);
+const NUS_RX: [u8; 16] = nus_uuid(0x02);
+const NUS_TX: [u8; 16] = nus_uuid(0x03);
+
+const fn nus_uuid(short: u8) -> [u8; 16] {
+    let big_endian = [
+        0x6e, 0x40, 0x00, short, 0xb5, 0xa3, 0xf3, 0x93, 0xe0, 0xa9, 0xe5, 0x0e, 0x24, 0xdc, 0xca,
+        0x9e,
+    ];
+    let mut uuid = [0; 16];
+    let mut index = 0;
+    while index < 16 {
+        uuid[index] = big_endian[15 - index];
+        index += 1;
+    }
+    uuid
+}
+
+/// A board's Bluetooth address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address {
+    /// The address as it's written, most significant byte first
+    pub bytes: [u8; 6],
+    pub random: bool,
+}
+
+impl Address {
+    /// Parse a `ble://AA:BB:CC:DD:EE:FF` URL, optionally followed by `?random` or `?public`
+    pub fn parse(url: &str) -> Result<Address> {
+        let rest = match url.strip_prefix(SCHEME) {
+            Some(rest) => rest.trim_end_matches('/'),
+            None => bail!("Expected a ble:// URL, got {:?}", url),
+        };
+        let (address, kind) = match rest.split_once('?') {
+            Some((address, kind)) => (address, Some(kind)),
+            None => (rest, None),
+        };
+        let parts: Vec<&str> = address.split(':').collect();
+        let mut bytes = [0; 6];
+        if parts.len() != bytes.len() {
+            bail!(
+                "Expected an address such as ble://AA:BB:CC:DD:EE:FF, got {:?}",
+                url
+            );
+        }
+        for (byte, part) in bytes.iter_mut().zip(parts) {
+            *byte = match u8::from_str_radix(part, 16) {
+                Ok(byte) if part.len() == 2 => byte,
+                _ => bail!("Invalid Bluetooth address {:?}", address),
+            };
+        }
+        let random = match kind {
+            Some("random") => true,
+            Some("public") => false,
+            Some(kind) => bail!("Unknown address type {:?}, use random or public", kind),
+            // The top two bits of a random static address are set
+            None => bytes[0] & 0xc0 == 0xc0,
+        };
+        Ok(Address { bytes, random })
+    }
+}
+
+/// The kernel's `struct sockaddr_l2`
+#[repr(C)]
+struct SockaddrL2 {
+    family: libc::sa_family_t,
+    psm: u16,
+    bdaddr: [u8; 6],
+    cid: u16,
+    bdaddr_type: u8,
+}
+
+impl SockaddrL2 {
+    fn att(bdaddr: [u8; 6], bdaddr_type: u8) -> SockaddrL2 {
+        SockaddrL2 {
+            family: libc::AF_BLUETOOTH as libc::sa_family_t,
+            psm: 0,
+            bdaddr,
+            cid: ATT_CID.to_le(),
+            bdaddr_type,
+        }
+    }
+}
+
+/// Connect to the REPL the board at the `ble://` URL serves over the Nordic UART Service
+pub fn connect(url: &str) -> Result<SocketPort<Link>> {
+    let address = Address::parse(url)?;
+    let fd = match open_socket(&address) {
+        Ok(fd) => fd,
+        Err(e) if e.raw_os_error() == Some(libc::EAFNOSUPPORT) => {
+            bail!("This system has no Bluetooth support")
+        }
+        Err(e) => bail!("Couldn't connect to {}: {}", url, e),
+    };
+    let link = Link::discover(fd)?;
+    Ok(SocketPort::new(link, url, Duration::from_millis(10))?)
+}
+
+fn open_socket(address: &Address) -> io::Result<OwnedFd> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_BLUETOOTH,
+            libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+            BTPROTO_L2CAP,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let size = std::mem::size_of::<SockaddrL2>() as libc::socklen_t;
+    let local = SockaddrL2::att([0; 6], BDADDR_LE_PUBLIC);
+    let bound = unsafe { libc::bind(fd.as_raw_fd(), &local as *const _ as *const _, size) };
+    if bound < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The kernel bounds connecting by the send timeout
+    set_timeout(fd.as_raw_fd(), libc::SO_SNDTIMEO, Some(CONNECT_TIMEOUT))?;
+    let mut bdaddr = address.bytes;
+    bdaddr.reverse();
+    let kind = match address.random {
+        true => BDADDR_LE_RANDOM,
+        false => BDADDR_LE_PUBLIC,
+    };
+    let remote = SockaddrL2::att(bdaddr, kind);
+    let connected = unsafe { libc::connect(fd.as_raw_fd(), &remote as *const _ as *const _, size) };
+    if connected < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    set_timeout(fd.as_raw_fd(), libc::SO_SNDTIMEO, None)?;
+    Ok(fd)
+}
+
+fn set_timeout(fd: RawFd, option: libc::c_int, timeout: Option<Duration>) -> io::Result<()> {
+    // A zero timeval waits forever
+    let timeout = timeout.map(|timeout| timeout.max(Duration::from_millis(1)));
+    let value = libc::timeval {
+        tv_sec: timeout.map_or(0, |timeout| timeout.as_secs()) as libc::time_t,
+        tv_usec: timeout.map_or(0, |timeout| timeout.subsec_micros()) as libc::suseconds_t,
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            option,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// The ATT channel to a board's Nordic UART Service, read and written as a stream of bytes. Its
+/// clones share the channel and what has been received on it.
+pub struct Link {
+    fd: Arc<OwnedFd>,
+    /// The value handle of the RX characteristic, written to
+    rx: u16,
+    /// The value handle of the TX characteristic, notified
+    tx: u16,
+    mtu: usize,
+    /// What has been notified but not yet read
+    received: Arc<Mutex<VecDeque<u8>>>,
+}
+
+impl Link {
+    /// Find the Nordic UART Service on the ATT channel `fd` and turn on its notifications
+    pub fn discover(fd: OwnedFd) -> Result<Link> {
+        let mut link = Link {
+            fd: Arc::new(fd),
+            rx: 0,
+            tx: 0,
+            mtu: DEFAULT_MTU,
+            received: Arc::default(),
+        };
+        link.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+        let mut request = vec![EXCHANGE_MTU_REQUEST];
+        request.extend((MAX_MTU as u16).to_le_bytes());
+        if let Ok(response) = link.request(&request)? {
+            if response.len() >= 3 {
+                link.mtu = (u16_at(&response, 1) as usize).clamp(DEFAULT_MTU, MAX_MTU);
+            }
+        }
+
+        let (start, end) = link.find_service()?;
+        let (rx, tx) = link.find_characteristics(start, end)?;
+        link.rx = rx;
+        link.tx = tx;
+        let configuration = link.find_configuration(tx, end)?;
+        let mut request = vec![WRITE_REQUEST];
+        request.extend(configuration.to_le_bytes());
+        request.extend(1u16.to_le_bytes());
+        if let Err(code) = link.request(&request)? {
+            bail!(
+                "The board refused to send its output, with ATT error {:#04x}",
+                code
+            );
+        }
+        Ok(link)
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// The handles of the Nordic UART Service
+    fn find_service(&self) -> Result<(u16, u16)> {
+        let mut start = 1u16;
+        loop {
+            let mut request = vec![READ_BY_GROUP_TYPE_REQUEST];
+            request.extend(start.to_le_bytes());
+            request.extend(0xffffu16.to_le_bytes());
+            request.extend(PRIMARY_SERVICE.to_le_bytes());
+            let response = match self.request(&request)? {
+                Ok(response) => response,
+                Err(ATTRIBUTE_NOT_FOUND) => {
+                    bail!("The board has no Nordic UART Service to serve its REPL over")
+                }
+                Err(code) => bail!(
+                    "The board's services couldn't be listed, ATT error {:#04x}",
+                    code
+                ),
+            };
+            let mut last = None;
+            for entry in entries(&response, 4)? {
+                let (handle, end) = (u16_at(entry, 0), u16_at(entry, 2));
+                if entry[4..] == NUS_SERVICE {
+                    return Ok((handle, end));
+                }
+                last = Some(end);
+            }
+            match last {
+                Some(end) if end < 0xffff => start = end + 1,
+                _ => bail!("The board has no Nordic UART Service to serve its REPL over"),
+            }
+        }
+    }
+
+    /// The value handles of the service's RX and TX characteristics
+    fn find_characteristics(&self, start: u16, end: u16) -> Result<(u16, u16)> {
+        let (mut rx, mut tx) = (None, None);
+        let mut handle = start;
+        while handle <= end {
+            let mut request = vec![READ_BY_TYPE_REQUEST];
+            request.extend(handle.to_le_bytes());
+            request.extend(end.to_le_bytes());
+            request.extend(CHARACTERISTIC.to_le_bytes());
+            let response = match self.request(&request)? {
+                Ok(response) => response,
+                Err(ATTRIBUTE_NOT_FOUND) => break,
+                Err(code) => bail!(
+                    "The board's characteristics couldn't be listed, ATT error {:#04x}",
+                    code
+                ),
+            };
+            let mut last = handle;
+            for entry in entries(&response, 5)? {
+                let value = u16_at(entry, 3);
+                match &entry[5..] {
+                    uuid if uuid == NUS_RX => rx = Some(value),
+                    uuid if uuid == NUS_TX => tx = Some(value),
+                    _ => {}
+                }
+                last = u16_at(entry, 0);
+            }
+            match last.checked_add(1) {
+                Some(next) if next > handle => handle = next,
+                _ => break,
+            }
+        }
+        match (rx, tx) {
+            (Some(rx), Some(tx)) => Ok((rx, tx)),
+            _ => bail!("The board's Nordic UART Service lacks its RX or TX characteristic"),
+        }
+    }
+
+    /// The handle of the descriptor that turns on the TX characteristic's notifications, which
+    /// mostly follows its value
+    fn find_configuration(&self, tx: u16, end: u16) -> Result<u16> {
+        let fallback = tx.saturating_add(1);
+        let mut request = vec![FIND_INFORMATION_REQUEST];
+        request.extend(fallback.to_le_bytes());
+        request.extend(end.max(fallback).to_le_bytes());
+        let response = match self.request(&request)? {
+            Ok(response) => response,
+            Err(_) => return Ok(fallback),
+        };
+        // Only descriptors with 16-bit UUIDs are listed in the format of 1
+        if response.get(1) == Some(&1) {
+            for entry in response[2..].chunks_exact(4) {
+                if u16_at(entry, 2) == CLIENT_CHARACTERISTIC_CONFIGURATION {
+                    return Ok(u16_at(entry, 0));
+                }
+            }
+        }
+        Ok(fallback)
+    }
+
+    /// Send the request `pdu` and wait for its response. The response is `Err` with the ATT
+    /// error code if the board answered with an error.
+    fn request(&self, pdu: &[u8]) -> Result<std::result::Result<Vec<u8>, u8>> {
+        self.send(pdu)?;
+        loop {
+            let packet = match self.receive() {
+                Ok(packet) if packet.is_empty() => bail!("The board disconnected"),
+                Ok(packet) => packet,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    bail!("The board didn't answer over Bluetooth")
+                }
+                Err(e) => bail!("Couldn't read from the board: {}", e),
+            };
+            match packet[0] {
+                ERROR_RESPONSE if packet.len() >= 5 && packet[1] == pdu[0] => {
+                    return Ok(Err(packet[4]))
+                }
+                opcode if opcode == pdu[0] + 1 => return Ok(Ok(packet)),
+                _ => self.handle(&packet)?,
+            }
+        }
+    }
+
+    /// Take in a packet the board sent of its own accord
+    fn handle(&self, packet: &[u8]) -> io::Result<()> {
+        match packet[0] {
+            NOTIFICATION | INDICATION if packet.len() >= 3 => {
+                if u16_at(packet, 1) == self.tx {
+                    self.received.lock().unwrap().extend(&packet[3..]);
+                }
+                if packet[0] == INDICATION {
+                    self.send(&[CONFIRMATION])?;
+                }
+            }
+            EXCHANGE_MTU_REQUEST => {
+                let mut response = vec![EXCHANGE_MTU_RESPONSE];
+                response.extend((MAX_MTU as u16).to_le_bytes());
+                self.send(&response)?;
+            }
+            opcode if REQUESTS.contains(&opcode) => {
+                self.send(&[ERROR_RESPONSE, opcode, 0, 0, REQUEST_NOT_SUPPORTED])?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn send(&self, pdu: &[u8]) -> io::Result<()> {
+        let sent = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                pdu.as_ptr() as *const libc::c_void,
+                pdu.len(),
+                libc::MSG_NOSIGNAL,
+            )
+        };
+        match sent {
+            sent if sent < 0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The next packet, or nothing once the channel is closed
+    fn receive(&self) -> io::Result<Vec<u8>> {
+        let mut packet = vec![0; MAX_MTU];
+        let size = unsafe {
+            libc::recv(
+                self.fd.as_raw_fd(),
+                packet.as_mut_ptr() as *mut libc::c_void,
+                packet.len(),
+                0,
+            )
+        };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        packet.truncate(size as usize);
+        Ok(packet)
+    }
+
+    /// The size of the next packet, if one has arrived
+    fn queued(&self) -> io::Result<usize> {
+        let mut count: libc::c_int = 0;
+        if unsafe { libc::ioctl(self.fd.as_raw_fd(), libc::FIONREAD, &mut count) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(count as usize)
+    }
+}
+
+/// The entries of a read by type or read by group type response, of the length its second byte
+/// gives, which has to be longer than `fixed`
+fn entries(response: &[u8], fixed: usize) -> Result<std::slice::ChunksExact<'_, u8>> {
+    match response.get(1) {
+        Some(&length) if length as usize > fixed => Ok(response[2..].chunks_exact(length as usize)),
+        _ => bail!("The board sent a malformed ATT response"),
+    }
+}
+
+fn u16_at(bytes: &[u8], index: usize) -> u16 {
+    u16::from_le_bytes([bytes[index], bytes[index + 1]])
+}
+
+impl AsRawFd for Link {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Read for Link {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            {
+                let mut received = self.received.lock().unwrap();
+                if !received.is_empty() {
+                    let count = buf.len().min(received.len());
+                    for (slot, byte) in buf.iter_mut().zip(received.drain(..count)) {
+                        *slot = byte;
+                    }
+                    return Ok(count);
+                }
+            }
+            let packet = self.receive()?;
+            if packet.is_empty() {
+                return Ok(0);
+            }
+            self.handle(&packet)?;
+        }
+    }
+}
+
+impl Write for Link {
+    /// Write as much of `buf` as fits in one write command
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = buf.len().min(self.mtu - 3);
+        let mut pdu = Vec::with_capacity(count + 3);
+        pdu.push(WRITE_COMMAND);
+        pdu.extend(self.rx.to_le_bytes());
+        pdu.extend(&buf[..count]);
+        self.send(&pdu)?;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Socket for Link {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        set_timeout(self.fd.as_raw_fd(), libc::SO_RCVTIMEO, timeout)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Link {
+            fd: self.fd.clone(),
+            rx: self.rx,
+            tx: self.tx,
+            mtu: self.mtu,
+            received: self.received.clone(),
+        })
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        match unsafe { libc::shutdown(self.fd.as_raw_fd(), libc::SHUT_RDWR) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// What has been notified, taking in the packets that have arrived
+    fn bytes_available(&self) -> io::Result<usize> {
+        while self.queued()? > 0 {
+            let packet = self.receive()?;
+            if packet.is_empty() {
+                break;
+            }
+            self.handle(&packet)?;
+        }
+        Ok(self.received.lock().unwrap().len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const RX: u16 = 0x000c;
+    const TX: u16 = 0x000e;
+    const CCCD: u16 = 0x000f;
+
+    fn pair() -> (OwnedFd, OwnedFd) {
+        let mut fds = [0; 2];
+        let result =
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) };
+        assert_eq!(result, 0);
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }
+    }
+
+    fn send(fd: &OwnedFd, pdu: &[u8]) {
+        let sent = unsafe { libc::send(fd.as_raw_fd(), pdu.as_ptr() as *const _, pdu.len(), 0) };
+        assert_eq!(sent, pdu.len() as isize);
+    }
+
+    fn receive(fd: &OwnedFd) -> Vec<u8> {
+        let mut packet = vec![0; 1024];
+        let size = unsafe { libc::recv(fd.as_raw_fd(), packet.as_mut_ptr() as *mut _, 1024, 0) };
+        packet.truncate(size.max(0) as usize);
+        packet
+    }
+
+    /// A board with a GAP service ahead of the Nordic UART Service, which echoes what's written
+    /// to RX back on TX once its notifications are on
+    fn board(fd: OwnedFd) -> thread::JoinHandle<Vec<usize>> {
+        thread::spawn(move || {
+            let mut writes = Vec::new();
+            loop {
+                let packet = receive(&fd);
+                if packet.is_empty() {
+                    return writes;
+                }
+                let start = || u16_at(&packet, 1);
+                match packet[0] {
+                    EXCHANGE_MTU_REQUEST => send(&fd, &[EXCHANGE_MTU_RESPONSE, 247, 0]),
+                    READ_BY_GROUP_TYPE_REQUEST if start() < 0x000a => {
+                        send(&fd, &[0x11, 6, 0x01, 0, 0x09, 0, 0x00, 0x18])
+                    }
+                    READ_BY_GROUP_TYPE_REQUEST => {
+                        let mut response = vec![0x11, 20, 0x0a, 0, 0x10, 0];
+                        response.extend(NUS_SERVICE);
+                        send(&fd, &response);
+                    }
+                    READ_BY_TYPE_REQUEST if start() <= 0x000d => {
+                        let mut response = vec![0x09, 21];
+                        response.extend([0x0b, 0, 0x0c, 0x0c, 0]);
+                        response.extend(NUS_RX);
+                        response.extend([0x0d, 0, 0x10, 0x0e, 0]);
+                        response.extend(NUS_TX);
+                        send(&fd, &response);
+                    }
+                    READ_BY_TYPE_REQUEST => send(
+                        &fd,
+                        &[
+                            ERROR_RESPONSE,
+                            READ_BY_TYPE_REQUEST,
+                            0x0e,
+                            0,
+                            ATTRIBUTE_NOT_FOUND,
+                        ],
+                    ),
+                    FIND_INFORMATION_REQUEST => send(&fd, &[0x05, 1, 0x0f, 0, 0x02, 0x29]),
+                    WRITE_REQUEST => {
+                        assert_eq!(u16_at(&packet, 1), CCCD);
+                        assert_eq!(&packet[3..], &[1, 0]);
+                        send(&fd, &[0x13]);
+                        // An MTU request of the board's own, and output of another characteristic
+                        send(&fd, &[EXCHANGE_MTU_REQUEST, 0x17, 0]);
+                        send(&fd, &[NOTIFICATION, 0x20, 0, b'x']);
+                        send(&fd, &[INDICATION, TX as u8, 0, b'>', b'>', b'>']);
+                    }
+                    CONFIRMATION | EXCHANGE_MTU_RESPONSE => {}
+                    WRITE_COMMAND => {
+                        assert_eq!(u16_at(&packet, 1), RX);
+                        writes.push(packet.len() - 3);
+                        let mut notification = vec![NOTIFICATION, TX as u8, 0];
+                        notification.extend(&packet[3..]);
+                        send(&fd, &notification);
+                    }
+                    opcode => panic!("Unexpected opcode {:#04x}", opcode),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn discovers_the_service_and_echoes() {
+        let (host, peer) = pair();
+        let board = board(peer);
+        let mut link = Link::discover(host).unwrap();
+        assert_eq!((link.rx, link.tx, link.mtu()), (RX, TX, 247));
+
+        let mut prompt = [0; 3];
+        link.read_exact(&mut prompt).unwrap();
+        assert_eq!(&prompt, b">>>");
+
+        let script: Vec<u8> = (0..600).map(|i| (i % 251) as u8).collect();
+        link.write_all(&script).unwrap();
+        let mut echoed = vec![0; script.len()];
+        link.read_exact(&mut echoed).unwrap();
+        assert_eq!(echoed, script);
+
+        link.shutdown().unwrap();
+        drop(link);
+        assert_eq!(board.join().unwrap(), vec![244, 244, 112]);
+    }
+
+    #[test]
+    fn clones_share_what_was_received() {
+        let (host, peer) = pair();
+        let board = board(peer);
+        let link = Link::discover(host).unwrap();
+        let mut writer = link.try_clone().unwrap();
+        writer.write_all(b"print(1)").unwrap();
+        let mut reader = link;
+        let mut output = [0; 11];
+        reader.read_exact(&mut output).unwrap();
+        assert_eq!(&output, b">>>print(1)");
+        reader.shutdown().unwrap();
+        drop((reader, writer));
+        board.join().unwrap();
+    }
+
+    #[test]
+    fn missing_service() {
+        let (host, peer) = pair();
+        let board = thread::spawn(move || {
+            let packet = receive(&peer);
+            assert_eq!(packet[0], EXCHANGE_MTU_REQUEST);
+            send(
+                &peer,
+                &[
+                    ERROR_RESPONSE,
+                    EXCHANGE_MTU_REQUEST,
+                    0,
+                    0,
+                    REQUEST_NOT_SUPPORTED,
+                ],
+            );
+            let packet = receive(&peer);
+            assert_eq!(packet[0], READ_BY_GROUP_TYPE_REQUEST);
+            send(&peer, &[0x11, 6, 0x01, 0, 0xff, 0xff, 0x00, 0x18]);
+        });
+        let error = Link::discover(host).err().unwrap();
+        assert!(error.to_string().contains("no Nordic UART Service"));
+        board.join().unwrap();
+    }
+
+    #[test]
+    fn parses_addresses() {
+        let address = Address::parse("ble://AA:BB:CC:DD:EE:FF").unwrap();
+        assert_eq!(address.bytes, [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]);
+        assert!(!address.random);
+        assert!(Address::parse("ble://d4:01:02:03:04:05").unwrap().random);
+        assert!(
+            !Address::parse("ble://d4:01:02:03:04:05?public")
+                .unwrap()
+                .random
+        );
+        assert!(
+            Address::parse("ble://00:01:02:03:04:05?random")
+                .unwrap()
+                .random
+        );
+        assert!(Address::parse("ble://00:01:02:03:04").is_err());
+        assert!(Address::parse("ble://00:01:02:03:04:5").is_err());
+        assert!(Address::parse("ble://00:01:02:03:04:05?other").is_err());
+    }
+}
@@ -0,0 +1,223 @@
+//! What sets the boards of each family apart, so that serpico treats each the way it needs: the
+//! state to leave its reset circuit in, how long its soft reboot may take, whether its USB port
+//! goes away while it resets and the pins its helper commands default to.
+//!
+//! The family is told by the USB IDs of the port before connecting, and by the platform the
+//! firmware reports after. The firmware's machine tells the board itself, for those whose pinout
+//! is known.
+use serialport::SerialPortType;
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::pins::{Board, Pinout};
+use crate::reset::{ResetStep, ResetStrategy};
+use crate::version::Firmware;
+
+/// The USB vendor IDs of the bridge chips ESP boards are mostly built with, Silicon Labs' CP210x
+/// and WCH's CH340 and CH9102
+const ESP_BRIDGE_VENDORS: &[u16] = &[0x10c4, 0x1a86];
+
+/// The product IDs of STMicroelectronics' ST-LINK, which bridges the serial port of Nucleo boards
+const ST_LINK_PRODUCTS: &[u16] = &[0x3748, 0x374b, 0x374e, 0x374f, 0x3752, 0x3753];
+
+/// The kinds of board serpico knows the quirks of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    Esp32,
+    Esp8266,
+    /// The RP2040 and RP2350, which MicroPython's `rp2` port runs on
+    Rp2040,
+    /// The pyboard and the other boards of MicroPython's `stm32` port
+    Stm32,
+}
+
+impl Family {
+    /// The family of boards whose firmware reports `platform` as `sys.platform`
+    pub fn from_platform(platform: &str) -> Option<Family> {
+        match platform {
+            "esp32" => Some(Family::Esp32),
+            "esp8266" => Some(Family::Esp8266),
+            "rp2" => Some(Family::Rp2040),
+            "pyboard" | "stm32" => Some(Family::Stm32),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Family::Esp32 => write!(f, "ESP32"),
+            Family::Esp8266 => write!(f, "ESP8266"),
+            Family::Rp2040 => write!(f, "RP2040"),
+            Family::Stm32 => write!(f, "STM32"),
+        }
+    }
+}
+
+/// How the board's serial port reaches the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Usb {
+    /// The board's own USB, which goes away while the board hard resets and comes back after
+    Native,
+    /// A CP210x or CH340 bridge chip, which ESP boards wire to the EN and IO0 pins through their
+    /// auto-reset circuit
+    EspBridge,
+    /// Any other bridge chip, which stays connected while the board resets
+    Bridge,
+}
+
+impl fmt::Display for Usb {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Usb::Native => write!(f, "native USB"),
+            Usb::EspBridge | Usb::Bridge => write!(f, "USB serial bridge"),
+        }
+    }
+}
+
+/// What's known of a board, and how that changes how it's treated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quirks {
+    pub family: Option<Family>,
+    /// How the port is connected, if it's a USB port
+    pub usb: Option<Usb>,
+    /// The board, if its firmware's machine is one whose pinout is known
+    pub board: Option<Board>,
+}
+
+impl Quirks {
+    /// The quirks of the board with the USB vendor and product IDs `vid` and `pid`
+    pub fn from_usb(vid: u16, pid: u16) -> Quirks {
+        let (family, usb) = match vid {
+            // Espressif's own, for the native USB of the ESP32-S2, S3, C3 and later
+            0x303a => (Some(Family::Esp32), Usb::Native),
+            // Raspberry Pi's
+            0x2e8a => (Some(Family::Rp2040), Usb::Native),
+            // MicroPython's own, used by the pyboard
+            0xf055 => (Some(Family::Stm32), Usb::Native),
+            0x0483 if ST_LINK_PRODUCTS.contains(&pid) => (Some(Family::Stm32), Usb::Bridge),
+            0x0483 => (Some(Family::Stm32), Usb::Native),
+            // Whether it's an ESP32 or an ESP8266 behind the bridge is only told by its firmware
+            vid if ESP_BRIDGE_VENDORS.contains(&vid) => (None, Usb::EspBridge),
+            _ => (None, Usb::Bridge),
+        };
+        Quirks {
+            family,
+            usb: Some(usb),
+            board: None,
+        }
+    }
+
+    /// The quirks of the board on the serial port at `path`, as far as its USB IDs tell. Nothing
+    /// is known of ports that aren't USB, nor of WebREPL and TCP connections.
+    pub fn for_port(path: &Path) -> Quirks {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let ports = serialport::available_ports().unwrap_or_default();
+        let info = ports.into_iter().find_map(|port| {
+            let name = Path::new(&port.port_name);
+            let name = name.canonicalize().unwrap_or_else(|_| name.to_path_buf());
+            match port.port_type {
+                SerialPortType::UsbPort(info) if name == path => Some(info),
+                _ => None,
+            }
+        });
+        match info {
+            Some(info) => Quirks::from_usb(info.vid, info.pid),
+            None => Quirks::default(),
+        }
+    }
+
+    /// The quirks with the family and board told by the firmware, which knows better than the
+    /// USB IDs
+    pub fn detected(self, firmware: &Firmware) -> Quirks {
+        Quirks {
+            family: Family::from_platform(&firmware.platform).or(self.family),
+            board: firmware
+                .machine
+                .as_deref()
+                .and_then(Board::from_machine)
+                .or(self.board),
+            ..self
+        }
+    }
+
+    /// The pinout of the board, if it's known
+    pub fn pinout(&self) -> Option<&'static Pinout> {
+        self.board.map(|board| board.pinout())
+    }
+
+    fn esp(&self) -> bool {
+        matches!(self.family, Some(Family::Esp32 | Family::Esp8266))
+    }
+
+    /// The reset strategy to apply after connecting, unless one is configured. The auto-reset
+    /// circuit of ESP boards holds the chip in reset, or in its bootloader at the next reset, for
+    /// as long as the host leaves RTS or DTR asserted, so both are released. RTS goes first, as
+    /// releasing DTR alone would reset the chip.
+    pub fn reset(&self) -> Option<ResetStrategy> {
+        match self.usb {
+            Some(Usb::EspBridge) => {}
+            Some(Usb::Bridge) if self.esp() => {}
+            _ => return None,
+        }
+        Some(ResetStrategy {
+            steps: vec![ResetStep::Rts(false), ResetStep::Dtr(false)],
+        })
+    }
+
+    /// The longest a soft reboot may take before the board is given up on. An ESP32 mounting a
+    /// large filesystem can take many seconds, where the others take a fraction of one, so
+    /// they're given up on sooner. `None` leaves it to the default.
+    pub fn reboot_limit(&self) -> Option<Duration> {
+        match self.family {
+            Some(Family::Rp2040) | Some(Family::Stm32) => Some(Duration::from_secs(10)),
+            _ => None,
+        }
+    }
+
+    /// Whether the board's port goes away while it hard resets, to come back once it has booted,
+    /// possibly at another path
+    pub fn reenumerates(&self) -> bool {
+        self.usb == Some(Usb::Native)
+    }
+
+    /// The pin of the board's LED, from its pinout or on the boards of the family that mostly
+    /// have one on the same pin. The firmware of others may name it `LED` itself.
+    pub fn led_pin(&self) -> Option<&'static str> {
+        if let Some(pin) = self.pinout().and_then(|pinout| pinout.resolve("LED")) {
+            return Some(pin);
+        }
+        match self.family {
+            Some(Family::Esp32) | Some(Family::Esp8266) => Some("2"),
+            _ => None,
+        }
+    }
+
+    /// The SDA and SCL pins to scan I2C on, where the firmware has no default. The ESP8266 has
+    /// no hardware I2C, and most of its boards label GPIO4 and GPIO5 SDA and SCL.
+    pub fn i2c_pins(&self) -> Option<(u32, u32)> {
+        if let Some(pins) = self.pinout().and_then(|pinout| pinout.i2c) {
+            return Some(pins);
+        }
+        match self.family {
+            Some(Family::Esp8266) => Some((4, 5)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Quirks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(board) = self.board {
+            write!(f, "{}, ", board)?;
+        }
+        match (self.family, self.usb) {
+            (Some(family), Some(usb)) => write!(f, "{} on {}", family, usb),
+            (Some(family), None) => write!(f, "{}", family),
+            (None, Some(usb)) => write!(f, "unknown on {}", usb),
+            (None, None) => write!(f, "unknown"),
+        }
+    }
+}
@@ -0,0 +1,37 @@
+//! Sharing a board attached to this machine over TCP, so it can be used from other machines with
+//! a device of `tcp://host:port`. The port stays open between clients, which are served one at a
+//! time and get the board's serial stream as it is.
+use anyhow::{bail, Result};
+use std::net::TcpListener;
+
+use crate::daemon::proxy;
+use crate::device::Device;
+
+/// Listen on `address`, such as `0.0.0.0:5555`, passing bytes between each client and the device.
+/// `on_client` is told when a client connects and disconnects.
+pub fn serve(
+    address: &str,
+    device: &mut Device,
+    mut on_client: impl FnMut(&str, bool),
+) -> Result<()> {
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(e) => bail!("Couldn't listen on {}: {}", address, e),
+    };
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let client = match stream.peer_addr() {
+            Ok(peer) => peer.to_string(),
+            Err(_) => String::from("?"),
+        };
+        stream.set_nodelay(true)?;
+        on_client(&client, true);
+        let result = proxy(stream, device);
+        on_client(&client, false);
+        result?;
+    }
+    Ok(())
+}
@@ -0,0 +1,122 @@
+//! Calling a function on the device with arguments given as JSON and getting back what it
+//! returns as JSON, a lightweight RPC for test harnesses driving a board from the host
+use anyhow::{bail, Result};
+use std::fmt;
+use std::time::Duration;
+
+use crate::device::Device;
+use crate::json::{self, Value};
+use crate::script::quote;
+use crate::serial::{execute, ExecError, ExecOptions};
+
+/// Starts the line the result is printed on, telling it from whatever the function prints
+const RESULT_MARKER: &str = "\x1eserpico-result:";
+
+/// What a function called on the device returned, along with anything it printed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Returned {
+    pub value: Value,
+    pub printed: Vec<u8>,
+}
+
+/// The function raised an exception, or couldn't be imported or called
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallFailed {
+    pub traceback: String,
+}
+
+impl fmt::Display for CallFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The call failed on the device:\n{}",
+            self.traceback.trim_end()
+        )
+    }
+}
+
+impl std::error::Error for CallFailed {}
+
+/// Code that imports `function`, such as `sensors.read`, calls it with `args` and prints the
+/// result as JSON after [`RESULT_MARKER`]. An array of arguments is passed positionally and an
+/// object's as keyword arguments. A function without a module is looked up in the globals and
+/// builtins, such as `len`.
+pub fn script(function: &str, args: &Value) -> Result<String> {
+    let is_identifier = |name: &str| {
+        !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+    };
+    if !function.split('.').all(is_identifier) {
+        bail!(
+            "Expected a function such as module.func, got {:?}",
+            function
+        );
+    }
+    let lookup = match function.rsplit_once('.') {
+        Some((module, name)) => format!("from {} import {} as _function", module, name),
+        None => format!("_function = {}", function),
+    };
+    let call = match args {
+        Value::Array(_) => "_function(*_args)",
+        Value::Object(_) => "_function(**_args)",
+        Value::Null => "_function()",
+        _ => bail!(
+            "Expected the arguments as a JSON array or object, got {}",
+            args
+        ),
+    };
+    Ok(format!(
+        "import json as _json
+_args = _json.loads({})
+try:
+    {}
+    print({} + _json.dumps({}))
+finally:
+    del _json, _args, _function
+",
+        quote(&args.to_string()),
+        lookup,
+        quote(&format!("\n{}", RESULT_MARKER)),
+        call
+    ))
+}
+
+/// Call `function` on the device with `args`, as [`script`] does, failing with [`CallFailed`] if
+/// it raises an exception, which includes returning something that can't be written as JSON
+pub fn call(
+    device: &mut Device,
+    function: &str,
+    args: &Value,
+    timeout: Option<Duration>,
+) -> Result<Returned> {
+    let options = ExecOptions {
+        timeout,
+        soft_reset: false,
+        echo: false,
+        log: false,
+        ..ExecOptions::default()
+    };
+    let result = execute(device, script(function, args)?, &options)?;
+    match result.error() {
+        Some(ExecError::Runtime { traceback }) => bail!(CallFailed { traceback }),
+        Some(ExecError::Syntax { msg, .. }) => bail!("Couldn't compile the call: {}", msg),
+        None => {}
+    }
+
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    let (printed, value) = match stdout.rsplit_once(RESULT_MARKER) {
+        // The line ending printed ahead of the marker, in case the function's output had none
+        Some((printed, value)) => (printed.strip_suffix('\n').unwrap_or(printed), value),
+        None => bail!("The device didn't print the result: {:?}", stdout),
+    };
+    let printed = printed.strip_suffix('\r').unwrap_or(printed);
+    let value = match json::parse(value.trim_end()) {
+        Ok(value) => value,
+        Err(e) => bail!("Couldn't parse the result {:?}: {}", value.trim_end(), e),
+    };
+    Ok(Returned {
+        value,
+        printed: printed.as_bytes().to_vec(),
+    })
+}
@@ -0,0 +1,102 @@
+//! Telling the chatter of the REPL, the banners and prompts a board prints as serpico enters and
+//! leaves its REPLs and as it soft reboots, from what programs print.
+//!
+//! Scripts run with [`crate::serial::execute`] never pass it on: what comes before the script
+//! starts is consumed by the handshake, and the output is framed. Output that isn't framed, such
+//! as what's followed once the script has finished, has the friendly REPL's banner and prompt in
+//! it, which [`EchoPolicy::Program`] leaves out.
+
+/// Which of what the device prints is passed on where its output isn't framed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EchoPolicy {
+    /// Everything, as a terminal would show it
+    #[default]
+    All,
+    /// Only what programs print, leaving out the lines of [`CHATTER`] and the prompts of
+    /// [`PROMPTS`]
+    Program,
+}
+
+/// Lines that are left out whole
+const CHATTER: &[&str] = &[
+    "raw REPL; CTRL-B to exit",
+    "soft reboot",
+    "MPY: soft reboot",
+    "Type \"help()\" for more information.",
+];
+
+/// The starts of the banners, which go on with the version and board
+const BANNERS: &[&str] = &["MicroPython v", "Adafruit CircuitPython "];
+
+/// Prompts, which aren't followed by a line ending
+const PROMPTS: &[&str] = &[">>> "];
+
+/// Filters what the device prints a chunk at a time. A line that may turn out to be chatter is
+/// held back until it's known whether it is.
+#[derive(Debug, Clone, Default)]
+pub struct Chatter {
+    policy: EchoPolicy,
+    /// The start of a line that may be chatter
+    line: Vec<u8>,
+    /// Whether the rest of the current line is passed on, it being known not to be chatter
+    passing: bool,
+}
+
+impl Chatter {
+    pub fn new(policy: EchoPolicy) -> Chatter {
+        Chatter {
+            policy,
+            ..Chatter::default()
+        }
+    }
+
+    /// What of `bytes` is passed on
+    pub fn filter(&mut self, bytes: &[u8]) -> Vec<u8> {
+        if self.policy == EchoPolicy::All {
+            return bytes.to_vec();
+        }
+        let mut passed = Vec::with_capacity(bytes.len());
+        for &byte in bytes {
+            if self.passing {
+                passed.push(byte);
+                self.passing = byte != b'\n';
+                continue;
+            }
+            self.line.push(byte);
+            let text = String::from_utf8_lossy(&self.line);
+            let text = text.trim_matches('\r');
+            if byte == b'\n' {
+                let line = text.trim_end();
+                let chatter = CHATTER.contains(&line)
+                    || BANNERS.iter().any(|banner| line.starts_with(banner));
+                if !chatter {
+                    passed.extend_from_slice(&self.line);
+                }
+                self.line.clear();
+            } else if PROMPTS.contains(&text) {
+                self.line.clear();
+            } else if !may_be_chatter(text) {
+                passed.extend_from_slice(&self.line);
+                self.line.clear();
+                self.passing = true;
+            }
+        }
+        passed
+    }
+
+    /// What's still held back, once nothing more follows
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.passing = false;
+        std::mem::take(&mut self.line)
+    }
+}
+
+/// Whether a line that starts with `text` may be chatter
+fn may_be_chatter(text: &str) -> bool {
+    CHATTER
+        .iter()
+        .chain(BANNERS)
+        .chain(PROMPTS)
+        .any(|chatter| chatter.starts_with(text))
+        || BANNERS.iter().any(|banner| text.starts_with(banner))
+}
@@ -0,0 +1,75 @@
+//! Support for CircuitPython, Adafruit's fork of MicroPython. Its raw REPL frames output the same
+//! way, but it has no raw-paste mode, runs `code.py` after a soft reboot instead of returning to
+//! the raw REPL, reloads when files change on its USB drive, and writes a status bar into the
+//! terminal's title with escape sequences in the middle of the output.
+
+/// Prints the name of the firmware, `circuitpython` or `micropython`
+pub const PROBE: &str = "import sys\nprint(sys.implementation.name)\n";
+
+/// Turns off reloading when files change, so that saving a file doesn't restart the board in the
+/// middle of a script. Versions before 8 only have the function.
+pub const DISABLE_AUTORELOAD: &str = "import supervisor
+try:
+    supervisor.runtime.autoreload = False
+except AttributeError:
+    supervisor.disable_autoreload()
+";
+
+/// Whether the output of [`PROBE`] is CircuitPython's, which can have the status bar in it
+pub fn probed(output: &[u8]) -> bool {
+    let mut stripped = Vec::new();
+    StatusBar::new().strip(output, &mut stripped);
+    String::from_utf8_lossy(&stripped).contains("circuitpython")
+}
+
+/// Strips the status bar from output as it's read. The status bar is an OSC sequence, `ESC ]`
+/// up to a BEL or `ESC \`, which can be split across reads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatusBar {
+    state: State,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum State {
+    #[default]
+    Text,
+    /// An ESC that may start a sequence
+    Escape,
+    Sequence,
+    /// An ESC in a sequence that may end it
+    SequenceEscape,
+}
+
+impl StatusBar {
+    pub fn new() -> StatusBar {
+        StatusBar::default()
+    }
+
+    /// Add `bytes` to `output` without the status bar
+    pub fn strip(&mut self, bytes: &[u8], output: &mut Vec<u8>) {
+        for &byte in bytes {
+            self.state = match (self.state, byte) {
+                (State::Text, 0x1b) => State::Escape,
+                (State::Text, _) => {
+                    output.push(byte);
+                    State::Text
+                }
+                (State::Escape, b']') => State::Sequence,
+                (State::Escape, 0x1b) => {
+                    output.push(0x1b);
+                    State::Escape
+                }
+                (State::Escape, _) => {
+                    output.extend_from_slice(&[0x1b, byte]);
+                    State::Text
+                }
+                (State::Sequence, 0x07) => State::Text,
+                (State::Sequence, 0x1b) => State::SequenceEscape,
+                (State::Sequence, _) => State::Sequence,
+                (State::SequenceEscape, b'\\') => State::Text,
+                (State::SequenceEscape, 0x1b) => State::SequenceEscape,
+                (State::SequenceEscape, _) => State::Sequence,
+            };
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Compiling scripts to MicroPython bytecode with mpy-cross before sending them
+use anyhow::{bail, Result};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+use crate::device::Device;
+use crate::script::quote;
+use crate::serial::eval;
+
+/// Name of the module the compiled script is imported as on the device
+pub const MODULE: &str = "__serpico__";
+
+/// Where the compiled script is written on the device, relative to the working directory
+pub const FILE: &str = "__serpico__.mpy";
+
+/// Architectures in the order of the `arch` bits of `sys.implementation._mpy`
+const ARCHITECTURES: [Option<&str>; 12] = [
+    None,
+    Some("x86"),
+    Some("x64"),
+    Some("armv6"),
+    Some("armv6m"),
+    Some("armv7m"),
+    Some("armv7em"),
+    Some("armv7emsp"),
+    Some("armv7emdp"),
+    Some("xtensa"),
+    Some("xtensawin"),
+    Some("rv32imc"),
+];
+
+const TARGET_QUERY: &str = "import sys\nprint(getattr(sys.implementation, '_mpy', 0))\n";
+
+/// The bytecode a device is able to import
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Target {
+    pub version: u8,
+    pub sub_version: u8,
+    /// Native code architecture, if the firmware supports native code
+    pub arch: Option<&'static str>,
+}
+
+/// Ask the device which bytecode version and architecture it supports
+pub fn detect_target(device: &mut Device, timeout: Option<Duration>) -> Result<Target> {
+    let output = eval(device, TARGET_QUERY, timeout)?;
+    let output = String::from_utf8_lossy(&output);
+    let value: usize = match output.trim().parse() {
+        Ok(value) => value,
+        Err(_) => bail!(
+            "Unexpected bytecode version from device: {:?}",
+            output.trim()
+        ),
+    };
+    if value == 0 {
+        bail!("The device doesn't report a bytecode version, it's unable to import .mpy files");
+    }
+
+    Ok(Target {
+        version: (value & 0xff) as u8,
+        sub_version: ((value >> 8) & 0x3) as u8,
+        arch: ARCHITECTURES.get(value >> 10).copied().flatten(),
+    })
+}
+
+/// The mpy-cross binary to use, `$MPY_CROSS` or `mpy-cross` from the path
+fn mpy_cross() -> PathBuf {
+    env::var_os("MPY_CROSS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("mpy-cross"))
+}
+
+/// Compile `source` to a `.mpy` for the target. `name` is the file name that tracebacks refer to.
+pub fn compile(source: &str, name: &str, target: &Target) -> Result<Vec<u8>> {
+    let dir = env::temp_dir().join(format!("serpico-{}", process::id()));
+    fs::create_dir_all(&dir)?;
+    let input = dir.join(format!("{}.py", MODULE));
+    let output = dir.join(FILE);
+    fs::write(&input, source)?;
+
+    let mut command = process::Command::new(mpy_cross());
+    command.arg("-s").arg(name).arg("-o").arg(&output);
+    if let Some(arch) = target.arch {
+        command.arg(format!("-march={}", arch));
+    }
+    command.arg(&input);
+    let result = command.output();
+    let compiled = fs::read(&output);
+    fs::remove_dir_all(&dir)?;
+
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => bail!("Couldn't run {}: {}", mpy_cross().display(), e),
+    };
+    if !result.status.success() {
+        bail!(
+            "mpy-cross failed: {}",
+            String::from_utf8_lossy(&result.stderr).trim()
+        );
+    }
+    let compiled = compiled?;
+
+    match compiled.get(..3) {
+        Some([b'M', version, flags])
+            if *version == target.version && flags & 0x3 == target.sub_version => {}
+        Some([b'M', version, flags]) => bail!(
+            "mpy-cross emits bytecode v{}.{} but the device expects v{}.{}",
+            version,
+            flags & 0x3,
+            target.version,
+            target.sub_version
+        ),
+        _ => bail!("mpy-cross produced an invalid .mpy file"),
+    }
+    Ok(compiled)
+}
+
+/// A script that imports the compiled module, removing it from the device afterwards
+pub fn runner() -> String {
+    format!(
+        "\
+import sys
+try:
+    import {module}
+finally:
+    sys.modules.pop({name}, None)
+    import os
+    os.remove({path})
+",
+        module = MODULE,
+        name = quote(MODULE),
+        path = quote(FILE),
+    )
+}
@@ -0,0 +1,520 @@
+//! Project defaults read from a `serpico.toml` in the current directory or one of its parents.
+//! Command line flags take precedence over the file.
+//!
+//! ```toml
+//! device = "/dev/ttyACM0"   # or serial = "E660583883375E2F" to pick the device by serial number
+//! baud = 115200
+//! timeout = "10s"
+//!
+//! [sync]
+//! include = ["*.py", "lib"]
+//! exclude = ["tests", "*.pyc"]
+//!
+//! [hooks]
+//! before = ["ruff check ."]
+//! after = ["echo deployed"]
+//!
+//! # Python run on the device before and after the script, in the same session
+//! [run]
+//! before = "import machine; machine.WDT(timeout=60000)"
+//! after = "print('done')"
+//!
+//! # An SD card on SPI(1) with its chip select on pin 15, which put and sync copy onto
+//! [sd]
+//! spi = 1
+//! cs = 15
+//! mount = "/sd"
+//!
+//! [snippets]
+//! blink = "import machine; machine.Pin('LED', machine.Pin.OUT).toggle()"
+//!
+//! # Selected with --profile, the keys of a profile replace the ones above
+//! [profile.esp32]
+//! product = "ESP32"
+//! baud = 460800
+//! reset = "esp32"
+//! reboot_banner = ""   # the board prints nothing when it soft reboots
+//! sync.include = ["main.py", "esp32"]
+//! ```
+use anyhow::{bail, Result};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+use crate::duration;
+use crate::reset::ResetStrategy;
+use crate::sdcard::{self, SdCard};
+use crate::toml::{self, quote, Value};
+
+/// The name of the configuration file
+pub const FILE_NAME: &str = "serpico.toml";
+
+/// The configuration of a project, everything is optional
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    /// Where the configuration was read from, if a file was found
+    pub path: Option<PathBuf>,
+    pub device: Option<PathBuf>,
+    /// Serial number of the device to use when no device is given
+    pub serial: Option<String>,
+    /// Part of the USB product name of the device to use when no device is given
+    pub product: Option<String>,
+    pub baud: Option<u32>,
+    pub reset: Option<ResetStrategy>,
+    /// Timeout for running scripts and transferring files
+    pub timeout: Option<Duration>,
+    /// What the board prints when it soft reboots, empty if it prints nothing
+    pub reboot_banner: Option<String>,
+    pub sync: SyncFiles,
+    pub hooks: Hooks,
+    pub run: RunHooks,
+    /// An SD card to mount, and to copy files onto instead of the root
+    pub sd: Option<SdCard>,
+    /// Python run with `serpico snippet NAME`, by name
+    pub snippets: Vec<(String, String)>,
+    /// The named profiles, each replacing the keys it sets
+    pub profiles: Vec<(String, Config)>,
+    /// The profile that has been applied, if any
+    pub profile: Option<String>,
+}
+
+impl Config {
+    /// The configuration with the profile `name` applied
+    pub fn with_profile(&self, name: &str) -> Result<Config> {
+        let profile = match self.profiles.iter().find(|(profile, _)| profile == name) {
+            Some((_, profile)) => profile,
+            None if self.profiles.is_empty() => bail!("No profiles configured, so no {:?}", name),
+            None => {
+                let names: Vec<&str> = self
+                    .profiles
+                    .iter()
+                    .map(|(name, _)| name.as_str())
+                    .collect();
+                bail!(
+                    "No profile {:?}, the profiles are {}",
+                    name,
+                    names.join(", ")
+                )
+            }
+        };
+        let list = |profile: &Vec<String>, base: &Vec<String>| {
+            if profile.is_empty() {
+                base.clone()
+            } else {
+                profile.clone()
+            }
+        };
+        Ok(Config {
+            path: self.path.clone(),
+            device: profile.device.clone().or_else(|| self.device.clone()),
+            serial: profile.serial.clone().or_else(|| self.serial.clone()),
+            product: profile.product.clone().or_else(|| self.product.clone()),
+            baud: profile.baud.or(self.baud),
+            reset: profile.reset.clone().or_else(|| self.reset.clone()),
+            timeout: profile.timeout.or(self.timeout),
+            reboot_banner: profile
+                .reboot_banner
+                .clone()
+                .or_else(|| self.reboot_banner.clone()),
+            sync: SyncFiles {
+                include: list(&profile.sync.include, &self.sync.include),
+                exclude: list(&profile.sync.exclude, &self.sync.exclude),
+            },
+            hooks: Hooks {
+                before: list(&profile.hooks.before, &self.hooks.before),
+                after: list(&profile.hooks.after, &self.hooks.after),
+            },
+            run: RunHooks {
+                before: profile
+                    .run
+                    .before
+                    .clone()
+                    .or_else(|| self.run.before.clone()),
+                after: profile.run.after.clone().or_else(|| self.run.after.clone()),
+            },
+            sd: profile.sd.clone().or_else(|| self.sd.clone()),
+            snippets: profile
+                .snippets
+                .iter()
+                .chain(self.snippets.iter().filter(|(name, _)| {
+                    !profile
+                        .snippets
+                        .iter()
+                        .any(|(overridden, _)| overridden == name)
+                }))
+                .cloned()
+                .collect(),
+            profiles: self.profiles.clone(),
+            profile: Some(name.to_string()),
+        })
+    }
+
+    /// Whether a discovered device matches the serial number and product configured
+    pub fn matches(&self, serial_number: Option<&str>, product: Option<&str>) -> bool {
+        let serial = match &self.serial {
+            Some(serial) => serial_number == Some(serial.as_str()),
+            None => true,
+        };
+        let product = match &self.product {
+            Some(name) => product.is_some_and(|product| product.contains(name.as_str())),
+            None => true,
+        };
+        serial && product
+    }
+}
+
+/// Which local files `sync` and `dev` copy to the device
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncFiles {
+    /// Patterns of files to copy, all files if empty
+    pub include: Vec<String>,
+    /// Patterns of files not to copy, even if included
+    pub exclude: Vec<String>,
+}
+
+/// Shell commands to run on the host around the commands that run or copy code
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hooks {
+    /// Run before executing or copying anything, a failing command stops serpico
+    pub before: Vec<String>,
+    /// Run once the command has succeeded
+    pub after: Vec<String>,
+}
+
+/// Python that `run` executes on the device around the script, without a soft reset in between
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunHooks {
+    /// Run ahead of the script, as part of it
+    pub before: Option<String>,
+    /// Run once the script has finished, whether or not it succeeded
+    pub after: Option<String>,
+}
+
+impl SyncFiles {
+    /// Whether the file at `relative`, with `/` separators, is synced. A pattern without a `/`
+    /// matches any file or directory of that name, others match paths relative to the synced
+    /// directory. Patterns matching a directory match everything in it. `*` and `?` match within
+    /// a path component, `**` matches any number of components.
+    pub fn includes(&self, relative: &str) -> bool {
+        let matches = |pattern: &String| matches_path(pattern, relative);
+        (self.include.is_empty() || self.include.iter().any(matches))
+            && !self.exclude.iter().any(matches)
+    }
+}
+
+impl Hooks {
+    pub fn run_before(&self) -> Result<()> {
+        run_hooks(&self.before)
+    }
+
+    pub fn run_after(&self) -> Result<()> {
+        run_hooks(&self.after)
+    }
+}
+
+fn run_hooks(commands: &[String]) -> Result<()> {
+    for command in commands {
+        let status = if cfg!(windows) {
+            process::Command::new("cmd").arg("/C").arg(command).status()
+        } else {
+            process::Command::new("sh").arg("-c").arg(command).status()
+        };
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => bail!("Hook `{}` failed with {}", command, status),
+            Err(e) => bail!("Couldn't run hook `{}`: {}", command, e),
+        }
+    }
+    Ok(())
+}
+
+/// Find `serpico.toml` in `dir` or the closest of its parents that has one
+pub fn find(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(FILE_NAME))
+        .find(|path| path.is_file())
+}
+
+/// The configuration for the current directory, the defaults if there's no file
+pub fn discover() -> Result<Config> {
+    match find(&env::current_dir()?) {
+        Some(path) => load(&path),
+        None => Ok(Config::default()),
+    }
+}
+
+/// Read the configuration file at `path`
+pub fn load(path: &Path) -> Result<Config> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => bail!("Couldn't read {}: {}", path.display(), e),
+    };
+    match parse(&text) {
+        Ok(config) => Ok(Config {
+            path: Some(path.to_path_buf()),
+            ..config
+        }),
+        Err(e) => bail!("Invalid {}: {}", path.display(), e),
+    }
+}
+
+fn parse(text: &str) -> Result<Config> {
+    let root = toml::parse(text)?;
+    let mut config = parse_table(entries(&root), "")?;
+    if let Some(profiles) = root.get("profile") {
+        for (name, profile) in table("profile", profiles)? {
+            let prefix = format!("profile.{}.", name);
+            let profile = parse_table(table(&prefix[..prefix.len() - 1], profile)?, &prefix)?;
+            config.profiles.push((name.clone(), profile));
+        }
+    }
+    Ok(config)
+}
+
+/// The keys of the top level or of a profile, whose keys start with `prefix` in errors
+fn parse_table(entries: &[(String, Value)], prefix: &str) -> Result<Config> {
+    let mut config = Config::default();
+    for (key, value) in entries {
+        match key.as_str() {
+            "device" => config.device = Some(PathBuf::from(string(key, value)?)),
+            "serial" => config.serial = Some(string(key, value)?),
+            "product" => config.product = Some(string(key, value)?),
+            "baud" => config.baud = Some(number(&format!("{}baud", prefix), value)?),
+            "reset" => config.reset = Some(ResetStrategy::parse(&string(key, value)?)?),
+            "timeout" => config.timeout = Some(duration::parse(&string(key, value)?)?),
+            "reboot_banner" => config.reboot_banner = Some(string(key, value)?),
+            "sync" => {
+                for (key, value) in table(key, value)? {
+                    match key.as_str() {
+                        "include" => config.sync.include = strings(key, value)?,
+                        "exclude" => config.sync.exclude = strings(key, value)?,
+                        _ => bail!("Unknown key {}sync.{}", prefix, key),
+                    }
+                }
+            }
+            "hooks" => {
+                for (key, value) in table(key, value)? {
+                    match key.as_str() {
+                        "before" => config.hooks.before = strings(key, value)?,
+                        "after" => config.hooks.after = strings(key, value)?,
+                        _ => bail!("Unknown key {}hooks.{}", prefix, key),
+                    }
+                }
+            }
+            "run" => {
+                for (key, value) in table(key, value)? {
+                    match key.as_str() {
+                        "before" => config.run.before = Some(string(key, value)?),
+                        "after" => config.run.after = Some(string(key, value)?),
+                        _ => bail!("Unknown key {}run.{}", prefix, key),
+                    }
+                }
+            }
+            "sd" => {
+                let (mut spi, mut cs, mut mount_point) = (None, None, None);
+                for (key, value) in table(key, value)? {
+                    match key.as_str() {
+                        "spi" => spi = Some(number(&format!("{}sd.spi", prefix), value)?),
+                        "cs" => cs = Some(number(&format!("{}sd.cs", prefix), value)?),
+                        "mount" => mount_point = Some(string(key, value)?),
+                        _ => bail!("Unknown key {}sd.{}", prefix, key),
+                    }
+                }
+                match (spi, cs) {
+                    (Some(spi), Some(cs)) => {
+                        config.sd = Some(SdCard {
+                            spi,
+                            cs,
+                            mount_point: mount_point
+                                .unwrap_or_else(|| sdcard::DEFAULT_MOUNT_POINT.to_string()),
+                        })
+                    }
+                    _ => bail!("{}sd needs both spi and cs", prefix),
+                }
+            }
+            "snippets" => {
+                for (name, source) in table(key, value)? {
+                    config.snippets.push((name.clone(), string(name, source)?));
+                }
+            }
+            "profile" if prefix.is_empty() => {}
+            _ => bail!("Unknown key {}{}", prefix, key),
+        }
+    }
+    Ok(config)
+}
+
+fn entries(table: &Value) -> &[(String, Value)] {
+    match table {
+        Value::Table(entries) => entries,
+        _ => &[],
+    }
+}
+
+fn table<'a>(key: &str, value: &'a Value) -> Result<&'a [(String, Value)]> {
+    match value {
+        Value::Table(entries) => Ok(entries),
+        _ => bail!("{} must be a table, not {}", key, value.kind()),
+    }
+}
+
+fn string(key: &str, value: &Value) -> Result<String> {
+    match value {
+        Value::String(value) => Ok(value.clone()),
+        _ => bail!("{} must be a string, not {}", key, value.kind()),
+    }
+}
+
+fn number(key: &str, value: &Value) -> Result<u32> {
+    match value {
+        Value::Integer(number) if u32::try_from(*number).is_ok() => Ok(*number as u32),
+        _ => bail!("{} must be a positive integer", key),
+    }
+}
+
+/// A list of strings, which can also be given as a single string
+fn strings(key: &str, value: &Value) -> Result<Vec<String>> {
+    match value {
+        Value::String(value) => Ok(vec![value.clone()]),
+        Value::Array(values) => values.iter().map(|value| string(key, value)).collect(),
+        _ => bail!("{} must be a list of strings, not {}", key, value.kind()),
+    }
+}
+
+/// Whether `pattern` matches `path` or one of the directories leading to it
+fn matches_path(pattern: &str, path: &str) -> bool {
+    let components: Vec<&str> = path.split('/').collect();
+    if !pattern.contains('/') {
+        return components
+            .iter()
+            .any(|component| matches_component(pattern, component));
+    }
+    let pattern: Vec<&str> = pattern.trim_matches('/').split('/').collect();
+    (1..=components.len()).any(|end| matches_components(&pattern, &components[..end]))
+}
+
+/// Whether the components of a pattern match all of `components`, with `**` for any number of
+/// them
+pub fn matches_components(pattern: &[&str], components: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => components.is_empty(),
+        Some((&"**", rest)) => {
+            (0..=components.len()).any(|skip| matches_components(rest, &components[skip..]))
+        }
+        Some((first, rest)) => match components.split_first() {
+            Some((component, components)) => {
+                matches_component(first, component) && matches_components(rest, components)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Whether the glob `pattern` matches all of `text`, with `*` for any run of characters and `?`
+/// for any one character
+pub fn matches_component(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // Where to resume after the last `*` if the rest fails to match
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+impl fmt::Display for Config {
+    /// The configuration in the format of the file
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |values: &[String]| {
+            let quoted: Vec<String> = values.iter().map(|value| quote(value)).collect();
+            format!("[{}]", quoted.join(", "))
+        };
+        match &self.path {
+            Some(path) => writeln!(f, "# From {}", path.display())?,
+            None => writeln!(f, "# No {} found", FILE_NAME)?,
+        }
+        if let Some(profile) = &self.profile {
+            writeln!(f, "# Profile {}", profile)?;
+        } else if !self.profiles.is_empty() {
+            let names: Vec<&str> = self
+                .profiles
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect();
+            writeln!(
+                f,
+                "# Profiles {}, select one with --profile",
+                names.join(", ")
+            )?;
+        }
+        if let Some(device) = &self.device {
+            writeln!(f, "device = {}", quote(&device.display().to_string()))?;
+        }
+        if let Some(serial) = &self.serial {
+            writeln!(f, "serial = {}", quote(serial))?;
+        }
+        if let Some(product) = &self.product {
+            writeln!(f, "product = {}", quote(product))?;
+        }
+        if let Some(baud) = self.baud {
+            writeln!(f, "baud = {}", baud)?;
+        }
+        if let Some(reset) = &self.reset {
+            writeln!(f, "reset = {}", quote(&reset.to_string()))?;
+        }
+        if let Some(timeout) = self.timeout {
+            writeln!(f, "timeout = \"{}s\"", timeout.as_secs_f64())?;
+        }
+        if let Some(reboot_banner) = &self.reboot_banner {
+            writeln!(f, "reboot_banner = {}", quote(reboot_banner))?;
+        }
+        writeln!(f, "\n[sync]")?;
+        writeln!(f, "include = {}", list(&self.sync.include))?;
+        writeln!(f, "exclude = {}", list(&self.sync.exclude))?;
+        writeln!(f, "\n[hooks]")?;
+        writeln!(f, "before = {}", list(&self.hooks.before))?;
+        write!(f, "after = {}", list(&self.hooks.after))?;
+        if self.run != RunHooks::default() {
+            write!(f, "\n\n[run]")?;
+            if let Some(before) = &self.run.before {
+                write!(f, "\nbefore = {}", quote(before))?;
+            }
+            if let Some(after) = &self.run.after {
+                write!(f, "\nafter = {}", quote(after))?;
+            }
+        }
+        if let Some(sd) = &self.sd {
+            write!(f, "\n\n[sd]")?;
+            write!(f, "\nspi = {}", sd.spi)?;
+            write!(f, "\ncs = {}", sd.cs)?;
+            write!(f, "\nmount = {}", quote(&sd.mount_point))?;
+        }
+        if !self.snippets.is_empty() {
+            writeln!(f, "\n\n[snippets]")?;
+            for (index, (name, source)) in self.snippets.iter().enumerate() {
+                if index > 0 {
+                    writeln!(f)?;
+                }
+                write!(f, "{} = {}", name, quote(source))?;
+            }
+        }
+        Ok(())
+    }
+}
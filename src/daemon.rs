@@ -0,0 +1,353 @@
+//! A background process that keeps device connections open between runs, so each run skips
+//! opening the port and resetting the board. Clients connect over a unix socket, name the device
+//! they want, and the daemon then passes bytes between the client and the device's port until the
+//! client disconnects. Clients are served one at a time.
+//!
+//! Whoever can connect to the socket can drive the devices, so it's kept in a directory only the
+//! user has access to, and the daemon refuses to listen or be connected to anywhere else.
+use anyhow::{bail, Result};
+use serialport::SerialPort;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::device::Device;
+use crate::socket::{Socket, SocketPort};
+use crate::tap::TapPort;
+use crate::trace::TraceLog;
+
+/// Sent by clients ahead of the device path
+const GREETING: &str = "serpico 1";
+
+/// How often the proxy checks whether the client has gone away
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a client has to send its greeting before it's hung up on, so that one that connects
+/// and says nothing doesn't keep the others waiting
+const GREETING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest line either side sends, well over a greeting with the path of a device
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// Where the daemon listens unless another socket is given, in `$XDG_RUNTIME_DIR` if it's set or
+/// otherwise in a directory of the user's own in the temp directory
+pub fn default_socket_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("serpico.sock"),
+        _ => env::temp_dir()
+            .join(format!("serpico-{}", unsafe { libc::getuid() }))
+            .join("serpico.sock"),
+    }
+}
+
+/// The directory of `socket`, failing unless it's owned by the user and closed to everyone else
+fn private_dir(socket: &Path) -> Result<&Path> {
+    let dir = match socket.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let metadata = match fs::symlink_metadata(dir) {
+        Ok(metadata) => metadata,
+        Err(e) => bail!(
+            "Couldn't check the daemon's directory {}: {}",
+            dir.display(),
+            e
+        ),
+    };
+    if !metadata.is_dir() {
+        bail!("The daemon's directory {} isn't a directory", dir.display());
+    }
+    if metadata.uid() != unsafe { libc::getuid() } {
+        bail!(
+            "The daemon's directory {} belongs to another user",
+            dir.display()
+        );
+    }
+    if metadata.mode() & 0o077 != 0 {
+        bail!(
+            "The daemon's directory {} is open to other users (mode {:o}), it has to be 0700",
+            dir.display(),
+            metadata.mode() & 0o777
+        );
+    }
+    Ok(dir)
+}
+
+/// Connect to the daemon listening on `socket`, asking for the device at `device`. The connection
+/// is traced to a file at `trace` if given.
+pub fn connect(
+    socket: &Path,
+    device: &Path,
+    buffer_size: usize,
+    trace: Option<&Path>,
+) -> Result<Device> {
+    private_dir(socket)?;
+    let mut stream = match UnixStream::connect(socket) {
+        Ok(stream) => stream,
+        Err(e) => bail!(
+            "Couldn't connect to the daemon at {}, is `serpico daemon` running? {}",
+            socket.display(),
+            e
+        ),
+    };
+    writeln!(stream, "{} {}", GREETING, device.display())?;
+
+    let response = read_line(&mut stream, None)?;
+    if let Some(message) = response.strip_prefix("error ") {
+        bail!("Daemon couldn't open {}: {}", device.display(), message);
+    }
+    if response != "ok" {
+        bail!("Unexpected response from the daemon: {:?}", response);
+    }
+
+    let name = format!("{} via daemon", device.display());
+    let mut port: Box<dyn SerialPort> =
+        Box::new(SocketPort::new(stream, name, Duration::from_millis(10))?);
+    if let Some(trace) = trace {
+        let name = port.name().unwrap_or_default();
+        port = Box::new(TapPort::new(port, TraceLog::create(trace, &name)?));
+    }
+    Ok(Device::new(port, buffer_size))
+}
+
+/// Listen on `socket`, opening devices with `open` the first time a client asks for them and
+/// keeping them open afterwards. The socket's directory is created if it doesn't exist.
+pub fn serve(socket: &Path, mut open: impl FnMut(&Path) -> Result<Device>) -> Result<()> {
+    if let Some(dir) = socket.parent().filter(|dir| !dir.exists()) {
+        if let Err(e) = DirBuilder::new().mode(0o700).create(dir) {
+            bail!(
+                "Couldn't create the daemon's directory {}: {}",
+                dir.display(),
+                e
+            );
+        }
+    }
+    private_dir(socket)?;
+    if UnixStream::connect(socket).is_ok() {
+        bail!("A daemon is already listening at {}", socket.display());
+    }
+    // A socket that can't be connected to was left behind by a daemon that's gone
+    match fs::symlink_metadata(socket) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            bail!("{} exists and isn't a socket", socket.display())
+        }
+        Ok(_) => fs::remove_file(socket)?,
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(socket)?;
+
+    let mut devices: HashMap<PathBuf, Device> = HashMap::new();
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+        let request = match read_line(&mut stream, Some(Instant::now() + GREETING_TIMEOUT)) {
+            Ok(request) => request,
+            Err(_) => continue,
+        };
+        if stream.set_read_timeout(None).is_err() {
+            continue;
+        }
+        let path = match request.strip_prefix(GREETING) {
+            Some(path) => PathBuf::from(path.trim_start()),
+            None => {
+                let _ = writeln!(stream, "error unknown request {:?}", request);
+                continue;
+            }
+        };
+
+        if !devices.contains_key(&path) {
+            match open(&path) {
+                Ok(device) => {
+                    devices.insert(path.clone(), device);
+                }
+                Err(e) => {
+                    let _ = writeln!(stream, "error {}", e);
+                    continue;
+                }
+            }
+        }
+        if writeln!(stream, "ok").is_err() {
+            continue;
+        }
+
+        let device = devices.get_mut(&path).unwrap();
+        if let Err(e) = proxy(stream, device) {
+            // The device is reopened for the next client that asks for it
+            eprintln!("Lost {}: {}", path.display(), e);
+            devices.remove(&path);
+        }
+    }
+    Ok(())
+}
+
+/// Pass bytes between the client and the device until the client disconnects. Only errors from
+/// the device are returned.
+pub fn proxy<S: Socket>(mut stream: S, device: &mut Device) -> Result<()> {
+    let port = device.port();
+    let port_timeout = port.timeout();
+    port.set_timeout(POLL_INTERVAL)?;
+
+    let done = Arc::new(AtomicBool::new(false));
+    let mut client = stream.try_clone()?;
+    let mut port_writer = port.try_clone()?;
+    let writer_done = done.clone();
+    let writer = thread::spawn(move || {
+        let mut buf = [0; 1024];
+        loop {
+            match client.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(count) => {
+                    if port_writer.write_all(&buf[..count]).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        writer_done.store(true, Ordering::SeqCst);
+    });
+
+    let mut buf = vec![0; 1024];
+    let result = loop {
+        if done.load(Ordering::SeqCst) {
+            break Ok(());
+        }
+        match port.read(&mut buf) {
+            Ok(count) => {
+                if stream.write_all(&buf[..count]).is_err() {
+                    break Ok(());
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => break Err(e.into()),
+        }
+    };
+
+    let _ = stream.shutdown();
+    let _ = writer.join();
+    port.set_timeout(port_timeout)?;
+    result
+}
+
+/// Read a line, giving up once `deadline` has passed however slowly its bytes arrive
+fn read_line(stream: &mut UnixStream, deadline: Option<Instant>) -> Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0; 1];
+    loop {
+        if let Some(deadline) = deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                bail!("Timed out reading a line");
+            }
+            stream.set_read_timeout(Some(left))?;
+        }
+        if stream.read(&mut byte)? == 0 {
+            bail!("Connection closed");
+        }
+        if byte[0] == b'\n' {
+            break;
+        }
+        if line.len() == MAX_LINE_LENGTH {
+            bail!("Line longer than {} bytes", MAX_LINE_LENGTH);
+        }
+        line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn dir(name: &str, mode: u32) -> PathBuf {
+        let dir = env::temp_dir().join(format!("serpico-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(mode)).unwrap();
+        dir
+    }
+
+    fn refused(socket: &Path) -> String {
+        let error = serve(socket, |_| bail!("No devices")).unwrap_err();
+        error.to_string()
+    }
+
+    #[test]
+    fn private_directory() {
+        let dir = dir("private", 0o700);
+        assert_eq!(private_dir(&dir.join("serpico.sock")).unwrap(), dir);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn open_directory_is_refused() {
+        let dir = dir("open", 0o755);
+        assert!(refused(&dir.join("serpico.sock")).contains("open to other users"));
+        assert!(connect(&dir.join("serpico.sock"), Path::new("/dev/null"), 64, None).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn other_files_are_not_replaced() {
+        let dir = dir("file", 0o700);
+        let socket = dir.join("serpico.sock");
+        fs::write(&socket, "data").unwrap();
+        assert!(refused(&socket).contains("isn't a socket"));
+        assert_eq!(fs::read(&socket).unwrap(), b"data");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_directory_is_created_private() {
+        let parent = dir("missing", 0o700);
+        let socket = parent.join("serpico").join("serpico.sock");
+        let listening = socket.clone();
+        // The daemon keeps listening until the tests end
+        thread::spawn(move || serve(&listening, |_| bail!("No devices")));
+        while UnixStream::connect(&socket).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let mode = fs::metadata(socket.parent().unwrap()).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        let error = connect(&socket, Path::new("/dev/missing"), 64, None)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("No devices"));
+        fs::remove_dir_all(&parent).unwrap();
+    }
+
+    #[test]
+    fn trickled_line_times_out() {
+        let (mut client, mut daemon) = UnixStream::pair().unwrap();
+        let trickle = thread::spawn(move || {
+            for _ in 0..20 {
+                if client.write_all(b"s").is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+        let start = Instant::now();
+        let deadline = Some(start + Duration::from_millis(100));
+        assert!(read_line(&mut daemon, deadline).is_err());
+        assert!(start.elapsed() < Duration::from_millis(300));
+        drop(daemon);
+        trickle.join().unwrap();
+    }
+
+    #[test]
+    fn long_line_is_refused() {
+        let (mut client, mut daemon) = UnixStream::pair().unwrap();
+        client.write_all(&[b's'; MAX_LINE_LENGTH + 1]).unwrap();
+        let error = read_line(&mut daemon, None).unwrap_err();
+        assert!(error.to_string().contains("longer"));
+    }
+}
@@ -0,0 +1,298 @@
+//! A small zlib compressor, so uploads can be decompressed by the device's `deflate` or `zlib`
+//! module. It uses fixed Huffman codes and a small window to keep the device's memory use down.
+use std::cmp::min;
+
+/// Base 2 logarithm of the window size, which the device allocates when decompressing
+pub const WINDOW_BITS: u32 = 10;
+
+const WINDOW_SIZE: usize = 1 << WINDOW_BITS;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// How many earlier positions with the same hash are tried for each match
+const MAX_CHAIN: usize = 32;
+const HASH_BITS: u32 = 12;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Writes bits least significant first, as deflate streams are packed
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, count: u32) {
+        self.buffer |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes are packed starting from their most significant bit
+    fn code(&mut self, code: u32, length: u32) {
+        let reversed = code.reverse_bits() >> (32 - length);
+        self.bits(reversed, length);
+    }
+
+    /// A literal byte or length symbol from the fixed Huffman code
+    fn symbol(&mut self, symbol: u16) {
+        let symbol = u32::from(symbol);
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn matched(&mut self, length: usize, distance: usize) {
+        let index = LENGTH_BASE
+            .iter()
+            .rposition(|&base| usize::from(base) <= length)
+            .unwrap();
+        self.symbol(257 + index as u16);
+        self.bits(
+            (length - usize::from(LENGTH_BASE[index])) as u32,
+            u32::from(LENGTH_EXTRA[index]),
+        );
+
+        let index = DISTANCE_BASE
+            .iter()
+            .rposition(|&base| usize::from(base) <= distance)
+            .unwrap();
+        self.code(index as u32, 5);
+        self.bits(
+            (distance - usize::from(DISTANCE_BASE[index])) as u32,
+            u32::from(DISTANCE_EXTRA[index]),
+        );
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.out
+    }
+}
+
+fn hash(data: &[u8]) -> usize {
+    let n = (u32::from(data[0]) << 16) | (u32::from(data[1]) << 8) | u32::from(data[2]);
+    (n.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+/// Compress `data` into a zlib stream
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter {
+        out: Vec::with_capacity(data.len() / 2 + 16),
+        buffer: 0,
+        count: 0,
+    };
+    // CMF with the window size, and FLG making the header a multiple of 31
+    let cmf = ((WINDOW_BITS - 8) << 4 | 8) as u8;
+    let flg = 31 - ((u32::from(cmf) << 8) % 31) as u8;
+    writer.out.extend_from_slice(&[cmf, flg]);
+
+    // A single final block with fixed Huffman codes
+    writer.bits(1, 1);
+    writer.bits(1, 2);
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut previous = vec![usize::MAX; data.len()];
+    let insert = |head: &mut Vec<usize>, previous: &mut Vec<usize>, position: usize| {
+        if position + MIN_MATCH <= data.len() {
+            let h = hash(&data[position..]);
+            previous[position] = head[h];
+            head[h] = position;
+        }
+    };
+
+    let mut position = 0;
+    while position < data.len() {
+        let mut best = (0, 0);
+        if position + MIN_MATCH <= data.len() {
+            let limit = min(MAX_MATCH, data.len() - position);
+            let mut candidate = head[hash(&data[position..])];
+            let mut chain = 0;
+            while candidate != usize::MAX
+                && position - candidate <= WINDOW_SIZE
+                && chain < MAX_CHAIN
+            {
+                let length = data[candidate..]
+                    .iter()
+                    .zip(&data[position..position + limit])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best.0 {
+                    best = (length, position - candidate);
+                    if length == limit {
+                        break;
+                    }
+                }
+                candidate = previous[candidate];
+                chain += 1;
+            }
+        }
+
+        if best.0 >= MIN_MATCH {
+            writer.matched(best.0, best.1);
+            for offset in 0..best.0 {
+                insert(&mut head, &mut previous, position + offset);
+            }
+            position += best.0;
+        } else {
+            writer.symbol(u16::from(data[position]));
+            insert(&mut head, &mut previous, position);
+            position += 1;
+        }
+    }
+    writer.symbol(256);
+
+    let mut out = writer.finish();
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads bits least significant first, as [`BitWriter`] writes them
+    struct BitReader<'a> {
+        data: &'a [u8],
+        position: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, count: u32) -> u32 {
+            let mut value = 0;
+            for i in 0..count {
+                let bit = self.data[self.position / 8] >> (self.position % 8) & 1;
+                value |= u32::from(bit) << i;
+                self.position += 1;
+            }
+            value
+        }
+
+        fn code(&mut self, length: u32) -> u32 {
+            (0..length).fold(0, |code, _| code << 1 | self.bits(1))
+        }
+
+        /// A literal byte or length symbol of the fixed Huffman code
+        fn symbol(&mut self) -> u16 {
+            let code = self.code(7);
+            if code <= 0x17 {
+                return 256 + code as u16;
+            }
+            let code = code << 1 | self.bits(1);
+            match code {
+                0x30..=0xbf => (code - 0x30) as u16,
+                0xc0..=0xc7 => (280 + code - 0xc0) as u16,
+                _ => (144 + (code << 1 | self.bits(1)) - 0x190) as u16,
+            }
+        }
+    }
+
+    /// Inflate a zlib stream made of fixed Huffman blocks, checking its header and checksum
+    fn inflate(stream: &[u8]) -> Vec<u8> {
+        assert_eq!(stream[0] & 0x0f, 8);
+        assert_eq!((u16::from(stream[0]) << 8 | u16::from(stream[1])) % 31, 0);
+        let mut reader = BitReader {
+            data: &stream[2..],
+            position: 0,
+        };
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            let last = reader.bits(1) == 1;
+            assert_eq!(reader.bits(2), 1, "Expected a fixed Huffman block");
+            loop {
+                match reader.symbol() {
+                    symbol @ 0..=255 => out.push(symbol as u8),
+                    256 => break,
+                    symbol => {
+                        let index = usize::from(symbol - 257);
+                        let extra = reader.bits(u32::from(LENGTH_EXTRA[index]));
+                        let length = usize::from(LENGTH_BASE[index]) + extra as usize;
+                        let index = reader.code(5) as usize;
+                        let extra = reader.bits(u32::from(DISTANCE_EXTRA[index]));
+                        let distance = usize::from(DISTANCE_BASE[index]) + extra as usize;
+                        for _ in 0..length {
+                            out.push(out[out.len() - distance]);
+                        }
+                    }
+                }
+            }
+            if last {
+                break;
+            }
+        }
+        let checksum = &stream[stream.len() - 4..];
+        assert_eq!(checksum, adler32(&out).to_be_bytes());
+        out
+    }
+
+    #[test]
+    fn inflates_what_zlib_compressed() {
+        // zlib.compress() of Python, with a window of 2^10 bytes
+        let stream = [
+            0x28, 0xcf, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0x75, 0x14, 0x8a,
+            0x53, 0x8b, 0x0a, 0x32, 0x93, 0xf3, 0x15, 0x01, 0xd0, 0xc8, 0x0c, 0x13,
+        ];
+        assert_eq!(inflate(&stream), b"hello hello hello hello, serpico!");
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(
+            compress(b""),
+            [0x28, 0x15, 0x03, 0x00, 0x00, 0x00, 0x00, 0x01]
+        );
+        assert!(inflate(&compress(b"")).is_empty());
+    }
+
+    #[test]
+    fn round_trip() {
+        let text = b"import machine\nled = machine.Pin(25, machine.Pin.OUT)\n".repeat(40);
+        let bytes: Vec<u8> = (0..5000u32).map(|n| (n * n % 251) as u8).collect();
+        for data in [&text[..], &bytes[..], b"a", &[0xff; 1000]] {
+            let compressed = compress(data);
+            assert_eq!(inflate(&compressed), data);
+        }
+        assert!(compress(&text).len() < text.len() / 4);
+    }
+
+    #[test]
+    fn adler32_vector() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+}
@@ -0,0 +1,226 @@
+//! An open connection to a MicroPython device
+use anyhow::Result;
+use serialport::SerialPort;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use crate::chatter::EchoPolicy;
+use crate::encoding::Decoding;
+use crate::lock::DeviceLock;
+use crate::serial::{ExecMode, Firmware};
+use crate::tap::{Tap, TapPort};
+
+/// The size of the buffers used for reading from the device unless another one is configured
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// What MicroPython prints when it soft reboots, unless the board is configured otherwise
+pub const DEFAULT_REBOOT_BANNER: &str = "soft reboot\r\n";
+
+/// How many more times the handshake ahead of a script is tried when it fails, unless told
+/// otherwise
+pub const DEFAULT_HANDSHAKE_RETRIES: u32 = 2;
+
+/// An open connection to a MicroPython device, created with [`crate::port::PortBuilder`]
+pub struct Device {
+    port: Box<dyn SerialPort>,
+    buffer_size: usize,
+    exec_mode: ExecMode,
+    firmware: Option<Firmware>,
+    raw_paste: bool,
+    reboot_banner: Option<String>,
+    reboot_limit: Option<Duration>,
+    echo_policy: EchoPolicy,
+    decoding: Decoding,
+    handshake_retries: u32,
+    /// What was read from the port past the end of the last script, to be read ahead of the port
+    unread: Vec<u8>,
+    /// Held for as long as the device is open
+    lock: Option<DeviceLock>,
+}
+
+impl Device {
+    pub fn new(port: Box<dyn SerialPort>, buffer_size: usize) -> Device {
+        Device {
+            port,
+            buffer_size,
+            exec_mode: ExecMode::default(),
+            firmware: None,
+            raw_paste: true,
+            reboot_banner: Some(DEFAULT_REBOOT_BANNER.to_string()),
+            reboot_limit: None,
+            echo_policy: EchoPolicy::default(),
+            decoding: Decoding::default(),
+            handshake_retries: DEFAULT_HANDSHAKE_RETRIES,
+            unread: Vec::new(),
+            lock: None,
+        }
+    }
+
+    /// Keep the board locked with `lock` until the device is dropped
+    pub fn with_lock(mut self, lock: DeviceLock) -> Device {
+        self.lock = Some(lock);
+        self
+    }
+
+    /// The serial port of the device
+    pub fn port(&mut self) -> &mut dyn SerialPort {
+        &mut *self.port
+    }
+
+    /// Pass all traffic of the device's port to `tap`
+    pub fn tap(self, tap: impl Tap + 'static) -> Device {
+        Device {
+            port: Box::new(TapPort::new(self.port, tap)),
+            buffer_size: self.buffer_size,
+            exec_mode: self.exec_mode,
+            firmware: self.firmware,
+            raw_paste: self.raw_paste,
+            reboot_banner: self.reboot_banner,
+            reboot_limit: self.reboot_limit,
+            echo_policy: self.echo_policy,
+            decoding: self.decoding,
+            handshake_retries: self.handshake_retries,
+            unread: self.unread,
+            lock: self.lock,
+        }
+    }
+
+    /// The size of the buffers used for reading from the device
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// How many bytes the device has sent that haven't been read yet
+    pub fn bytes_available(&mut self) -> Result<usize> {
+        Ok(self.unread.len() + self.port.bytes_to_read()? as usize)
+    }
+
+    /// What the device sent right after the last script's output, before the port is read again
+    pub fn take_unread(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.unread)
+    }
+
+    /// Leave `unread` to be read ahead of the port, such as what was read past a script's output
+    pub fn set_unread(&mut self, unread: Vec<u8>) {
+        self.unread = unread;
+    }
+
+    /// Read what the device has sent so far, without waiting for more
+    pub fn read_available(&mut self) -> Result<Vec<u8>> {
+        let mut read = self.take_unread();
+        let mut buf = vec![0; self.buffer_size];
+        loop {
+            let available = self.port.bytes_to_read()? as usize;
+            if available == 0 {
+                return Ok(read);
+            }
+            let count = self
+                .port
+                .read(&mut buf[..available.min(self.buffer_size)])?;
+            if count == 0 {
+                return Ok(read);
+            }
+            read.extend_from_slice(&buf[..count]);
+        }
+    }
+
+    /// Read everything the device prints until it has been quiet for `timeout`, returning what it
+    /// printed. A device that never goes quiet is read from for as long as it keeps printing.
+    pub fn drain(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        let port_timeout = self.port.timeout();
+        self.port.set_timeout(timeout)?;
+        let mut drained = self.take_unread();
+        let mut buf = vec![0; self.buffer_size];
+        let result = loop {
+            match self.port.read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(count) => drained.extend_from_slice(&buf[..count]),
+                Err(e) if e.kind() == ErrorKind::TimedOut => break Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        self.port.set_timeout(port_timeout)?;
+        result?;
+        Ok(drained)
+    }
+
+    /// How scripts are sent to the device. Once [`ExecMode::Auto`] has had to fall back to paste
+    /// mode, this is [`ExecMode::Paste`].
+    pub fn exec_mode(&self) -> ExecMode {
+        self.exec_mode
+    }
+
+    pub fn set_exec_mode(&mut self, exec_mode: ExecMode) {
+        self.exec_mode = exec_mode;
+    }
+
+    /// The firmware the device runs, if it's known. It's probed the first time a script is run
+    /// unless it has been set.
+    pub fn firmware(&self) -> Option<Firmware> {
+        self.firmware
+    }
+
+    pub fn set_firmware(&mut self, firmware: Option<Firmware>) {
+        self.firmware = firmware;
+    }
+
+    /// Whether scripts are uploaded in raw-paste mode. Until the device turns out not to support
+    /// it, it's assumed that it does.
+    pub fn raw_paste(&self) -> bool {
+        self.raw_paste
+    }
+
+    pub fn set_raw_paste(&mut self, raw_paste: bool) {
+        self.raw_paste = raw_paste;
+    }
+
+    /// What the device prints when it soft reboots, if anything. Custom firmware and boards that
+    /// run a frozen app can print something else, or nothing at all.
+    pub fn reboot_banner(&self) -> Option<&str> {
+        self.reboot_banner.as_deref()
+    }
+
+    pub fn set_reboot_banner(&mut self, reboot_banner: Option<String>) {
+        self.reboot_banner = reboot_banner;
+    }
+
+    /// The longest the board may take to soft reboot, if it's known to take less than most
+    pub fn reboot_limit(&self) -> Option<Duration> {
+        self.reboot_limit
+    }
+
+    pub fn set_reboot_limit(&mut self, reboot_limit: Option<Duration>) {
+        self.reboot_limit = reboot_limit;
+    }
+
+    /// What of the device's output is passed on where it isn't framed, such as when following it
+    /// after a script. The output of scripts never has the handshake in it.
+    pub fn echo_policy(&self) -> EchoPolicy {
+        self.echo_policy
+    }
+
+    pub fn set_echo_policy(&mut self, echo_policy: EchoPolicy) {
+        self.echo_policy = echo_policy;
+    }
+
+    /// How the device's output is decoded where it's followed on the terminal
+    pub fn decoding(&self) -> Decoding {
+        self.decoding
+    }
+
+    pub fn set_decoding(&mut self, decoding: Decoding) {
+        self.decoding = decoding;
+    }
+
+    /// How many more times the handshake ahead of a script is tried when it fails before any of
+    /// the script has been sent, such as when the board is still printing its boot output right
+    /// after being plugged in
+    pub fn handshake_retries(&self) -> u32 {
+        self.handshake_retries
+    }
+
+    pub fn set_handshake_retries(&mut self, handshake_retries: u32) {
+        self.handshake_retries = handshake_retries;
+    }
+}
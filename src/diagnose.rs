@@ -0,0 +1,95 @@
+//! Telling what to do about the errors that commonly come up when talking to a board. Errors are
+//! classified by their type rather than by their message, wherever they are in the chain of
+//! context.
+use std::fmt;
+
+use crate::port;
+use crate::serial::{Disconnected, RawPasteFailed, Rebooted, StageTimeout};
+
+/// A class of failure that there's advice for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Diagnosis {
+    /// The port couldn't be opened for lack of permission
+    PermissionDenied,
+    /// Something else has the port open
+    Busy,
+    /// The device went away while it was being talked to
+    Disconnected,
+    /// The device never got into the raw REPL
+    Unresponsive,
+    /// The device crashed or was reset while running a script
+    Rebooted,
+    /// The device didn't get raw-paste mode right
+    RawPasteFailed,
+}
+
+/// The advice for `error`, if it's of a class there is any for
+pub fn diagnose(error: &anyhow::Error) -> Option<Diagnosis> {
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<serialport::Error>() {
+            if port::is_permission_denied(e) {
+                return Some(Diagnosis::PermissionDenied);
+            }
+            if port::is_busy(e) {
+                return Some(Diagnosis::Busy);
+            }
+        } else if cause.is::<Disconnected>() {
+            return Some(Diagnosis::Disconnected);
+        } else if let Some(e) = cause.downcast_ref::<StageTimeout>() {
+            if e.unresponsive() {
+                return Some(Diagnosis::Unresponsive);
+            }
+            if e.raw_paste() {
+                return Some(Diagnosis::RawPasteFailed);
+            }
+        } else if cause.is::<Rebooted>() {
+            return Some(Diagnosis::Rebooted);
+        } else if cause.is::<RawPasteFailed>() {
+            return Some(Diagnosis::RawPasteFailed);
+        }
+    }
+    None
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Diagnosis::PermissionDenied if cfg!(target_os = "linux") => write!(
+                f,
+                "Serial ports usually belong to the dialout group (uucp on Arch), add yourself \
+                 with `sudo usermod -aG dialout $USER` and log in again, or install a udev rule \
+                 that gives you access to the board"
+            ),
+            Diagnosis::PermissionDenied => write!(
+                f,
+                "Check that you have permission to read and write the serial port"
+            ),
+            Diagnosis::Busy => write!(
+                f,
+                "Another program has the port open, such as a terminal or ModemManager. Close it, \
+                 or use --force to terminate it"
+            ),
+            Diagnosis::Disconnected => write!(
+                f,
+                "Check the cable, then press the board's reset button or unplug it and plug it in \
+                 again before trying again"
+            ),
+            Diagnosis::Unresponsive => write!(
+                f,
+                "The board didn't answer the REPL. Check that it runs MicroPython at the baud rate \
+                 given with --baud, or press its reset button in case a program ignores Ctrl-C. \
+                 Firmware without a raw REPL can be used with --exec-mode paste"
+            ),
+            Diagnosis::Rebooted => write!(
+                f,
+                "The script may have crashed the board or starved a watchdog, run it with \
+                 --reconnect --follow to see what the board prints as it boots"
+            ),
+            Diagnosis::RawPasteFailed => write!(
+                f,
+                "The firmware doesn't handle raw-paste mode well, upgrade MicroPython to 1.14 or \
+                 later, or use --no-raw-paste"
+            ),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Comparing a local project with the files on the device, as `sync` would change them
+use std::fmt;
+
+/// Lines of context around each change in a diff
+const CONTEXT: usize = 3;
+
+/// Files with more lines than this, multiplied together, are too large to diff on the fly
+const MAX_CELLS: usize = 4_000_000;
+
+/// How a file differs between the local project and the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// Only local, sync would copy it
+    Added,
+    /// On both, with different contents
+    Changed,
+    /// Only on the device
+    Missing,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Added => write!(f, "+"),
+            Change::Changed => write!(f, "M"),
+            Change::Missing => write!(f, "-"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Same,
+    Removed,
+    Inserted,
+}
+
+/// A unified diff of the text `old`, named `old_name`, to `new`, or `None` if it's too large to
+/// diff
+pub fn unified(old_name: &str, old: &str, new_name: &str, new: &str) -> Option<String> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    if old.len().saturating_mul(new.len()) > MAX_CELLS {
+        return None;
+    }
+
+    // The length of the longest common subsequence of old[i..] and new[j..]
+    let width = new.len() + 1;
+    let mut common = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i * width + j] = match old[i] == new[j] {
+                true => common[(i + 1) * width + j + 1] + 1,
+                false => common[(i + 1) * width + j].max(common[i * width + j + 1]),
+            };
+        }
+    }
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            ops.push((Op::Same, old[i]));
+            (i, j) = (i + 1, j + 1);
+        } else if i < old.len()
+            && (j == new.len() || common[(i + 1) * width + j] >= common[i * width + j + 1])
+        {
+            ops.push((Op::Removed, old[i]));
+            i += 1;
+        } else {
+            ops.push((Op::Inserted, new[j]));
+            j += 1;
+        }
+    }
+
+    let mut diff = format!("--- {}\n+++ {}\n", old_name, new_name);
+    let changed: Vec<usize> = (0..ops.len()).filter(|&k| ops[k].0 != Op::Same).collect();
+    let mut k = 0;
+    while k < changed.len() {
+        // Changes closer together than twice the context share a hunk
+        let first = changed[k];
+        while k + 1 < changed.len() && changed[k + 1] - changed[k] <= 2 * CONTEXT {
+            k += 1;
+        }
+        let start = first.saturating_sub(CONTEXT);
+        let end = (changed[k] + CONTEXT + 1).min(ops.len());
+        k += 1;
+
+        let before = |op: Op| ops[..start].iter().filter(|(o, _)| *o != op).count();
+        let count = |op: Op| ops[start..end].iter().filter(|(o, _)| *o != op).count();
+        let range = |before: usize, count: usize| match count {
+            0 => format!("{},0", before),
+            _ => format!("{},{}", before + 1, count),
+        };
+        diff.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            range(before(Op::Inserted), count(Op::Inserted)),
+            range(before(Op::Removed), count(Op::Removed))
+        ));
+        for (op, line) in &ops[start..end] {
+            let sign = match op {
+                Op::Same => ' ',
+                Op::Removed => '-',
+                Op::Inserted => '+',
+            };
+            diff.push(sign);
+            diff.push_str(line);
+            diff.push('\n');
+        }
+    }
+    Some(diff)
+}
@@ -0,0 +1,34 @@
+//! Parsing human friendly durations such as `500ms`, `2s` or `1m`
+use anyhow::{bail, Result};
+use std::time::Duration;
+
+/// Parse a duration made of a number and an optional unit: `ms`, `s`, `m` or `h`. A number
+/// without a unit is taken as seconds.
+pub fn parse(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let number: f64 = match number.parse() {
+        Ok(number) => number,
+        Err(_) => bail!("Invalid duration {:?}", value),
+    };
+    let seconds = match unit.trim() {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        unit => bail!(
+            "Unknown unit {:?} in duration {:?}, use ms, s, m or h",
+            unit,
+            value
+        ),
+    };
+
+    match Duration::try_from_secs_f64(seconds) {
+        Ok(duration) => Ok(duration),
+        Err(_) => bail!("Duration {:?} is too long", value),
+    }
+}
@@ -0,0 +1,166 @@
+//! How the bytes the device prints are decoded for the terminal and for the text of JSON objects
+//! and records. MicroPython prints UTF-8, but boards also print Latin-1 or CP437 text from C
+//! code, or raw sensor bytes.
+use anyhow::{bail, Result};
+use std::fmt;
+
+/// What the device's output is taken to be
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    /// Latin-1, where every byte is a character
+    Latin1,
+    /// Bytes, shown as they are. Where text is needed, bytes that aren't UTF-8 are written as
+    /// `\xNN` escapes.
+    Raw,
+}
+
+impl Encoding {
+    /// Parse `utf-8`, `latin1` or `raw`
+    pub fn parse(value: &str) -> Result<Encoding> {
+        match value.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Ok(Encoding::Utf8),
+            "latin1" | "latin-1" | "iso-8859-1" => Ok(Encoding::Latin1),
+            "raw" => Ok(Encoding::Raw),
+            _ => bail!("Unknown encoding {:?}, use utf-8, latin1 or raw", value),
+        }
+    }
+}
+
+/// UTF-8 output that has a sequence in it that isn't valid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidOutput {
+    /// Where the sequence starts, counting from the start of the output
+    pub offset: usize,
+}
+
+impl fmt::Display for InvalidOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The device's output isn't valid UTF-8 at byte {}, use --encoding latin1 or raw for \
+             output that isn't text",
+            self.offset
+        )
+    }
+}
+
+impl std::error::Error for InvalidOutput {}
+
+/// How output is decoded: its encoding, and for UTF-8 whether sequences that aren't valid fail
+/// rather than being replaced with U+FFFD
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Decoding {
+    pub encoding: Encoding,
+    pub strict: bool,
+}
+
+impl Decoding {
+    /// The text of output read in full, such as a script's stdout
+    pub fn text(&self, bytes: &[u8]) -> Result<String> {
+        match self.encoding {
+            Encoding::Utf8 if self.strict => match std::str::from_utf8(bytes) {
+                Ok(text) => Ok(text.to_string()),
+                Err(e) => bail!(InvalidOutput {
+                    offset: e.valid_up_to()
+                }),
+            },
+            Encoding::Utf8 => Ok(String::from_utf8_lossy(bytes).to_string()),
+            Encoding::Latin1 => Ok(bytes.iter().map(|&byte| char::from(byte)).collect()),
+            Encoding::Raw => Ok(escape_invalid(bytes)),
+        }
+    }
+
+    /// A decoder for output read a chunk at a time
+    pub fn decoder(&self) -> Decoder {
+        Decoder {
+            decoding: *self,
+            partial: Vec::new(),
+            offset: 0,
+        }
+    }
+}
+
+/// Decodes output for the terminal a chunk at a time, holding back a UTF-8 sequence that the
+/// next chunk finishes. What it passes on is UTF-8, but for [`Encoding::Raw`].
+#[derive(Debug, Clone, Default)]
+pub struct Decoder {
+    decoding: Decoding,
+    /// The start of a sequence that hasn't been finished yet
+    partial: Vec<u8>,
+    /// How much output has been decoded, for where an invalid sequence is
+    offset: usize,
+}
+
+impl Decoder {
+    /// What's shown of `bytes`
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self.decoding.encoding {
+            Encoding::Raw => Ok(bytes.to_vec()),
+            Encoding::Latin1 => Ok(latin1(bytes)),
+            Encoding::Utf8 => {
+                let mut pending = std::mem::take(&mut self.partial);
+                pending.extend_from_slice(bytes);
+                // A sequence cut short at the end may still be finished by the next chunk
+                let mut end = 0;
+                while end < pending.len() {
+                    match std::str::from_utf8(&pending[end..]) {
+                        Ok(_) => end = pending.len(),
+                        Err(e) => match e.error_len() {
+                            Some(len) => end += e.valid_up_to() + len,
+                            None => {
+                                end += e.valid_up_to();
+                                break;
+                            }
+                        },
+                    }
+                }
+                self.partial = pending.split_off(end);
+                self.utf8(&pending)
+            }
+        }
+    }
+
+    /// What's left of a sequence that was never finished
+    pub fn finish(&mut self) -> Result<Vec<u8>> {
+        let partial = std::mem::take(&mut self.partial);
+        match partial.is_empty() {
+            true => Ok(partial),
+            false => self.utf8(&partial),
+        }
+    }
+
+    fn utf8(&mut self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let offset = self.offset;
+        self.offset += bytes.len();
+        match std::str::from_utf8(bytes) {
+            Ok(_) => Ok(bytes.to_vec()),
+            Err(e) if self.decoding.strict => bail!(InvalidOutput {
+                offset: offset + e.valid_up_to()
+            }),
+            Err(_) => Ok(String::from_utf8_lossy(bytes).into_owned().into_bytes()),
+        }
+    }
+}
+
+/// Latin-1 bytes as UTF-8
+fn latin1(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .iter()
+        .map(|&byte| char::from(byte))
+        .collect::<String>()
+        .into_bytes()
+}
+
+/// `bytes` as text, with the bytes that aren't part of valid UTF-8 written as `\xNN`
+pub fn escape_invalid(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        text.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            text.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    text
+}
@@ -0,0 +1,115 @@
+//! Flashing firmware onto Espressif chips with esptool, which is run as a subprocess. The serial
+//! port must not be open while esptool runs, as it uses the port's control lines to put the chip
+//! into its ROM bootloader and to reset it once the image is written.
+use anyhow::{bail, Result};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// The chips that can be flashed, with the offset MicroPython's firmware images go at
+const CHIPS: &[(&str, u32)] = &[
+    ("esp32", 0x1000),
+    ("esp32s2", 0x1000),
+    ("esp32s3", 0x0),
+    ("esp32c3", 0x0),
+    ("esp32c6", 0x0),
+    ("esp8266", 0x0),
+];
+
+/// The names esptool is installed as, tried in order when `ESPTOOL` isn't set
+const PROGRAMS: &[&str] = &["esptool.py", "esptool"];
+
+/// Whether `chip` is an Espressif chip that esptool flashes
+pub fn is_chip(chip: &str) -> bool {
+    CHIPS.iter().any(|(name, _)| *name == chip)
+}
+
+/// The names of the chips that can be flashed
+pub fn chips() -> impl Iterator<Item = &'static str> {
+    CHIPS.iter().map(|(name, _)| *name)
+}
+
+/// Where `chip` expects a MicroPython firmware image to be written
+fn offset(chip: &str) -> Result<u32> {
+    match CHIPS.iter().find(|(name, _)| *name == chip) {
+        Some((_, offset)) => Ok(*offset),
+        None => bail!("Unknown chip {}", chip),
+    }
+}
+
+/// The esptool to run, `ESPTOOL` if it's set and otherwise the first one found on the PATH
+pub fn find() -> Result<PathBuf> {
+    if let Some(program) = env::var_os("ESPTOOL") {
+        return Ok(PathBuf::from(program));
+    }
+    let path = env::var_os("PATH").unwrap_or_default();
+    for program in PROGRAMS {
+        for dir in env::split_paths(&path) {
+            let candidate = dir.join(program);
+            if candidate.is_file() {
+                return Ok(candidate);
+            }
+        }
+    }
+    bail!("Couldn't find esptool on the PATH, install it with `pip install esptool` or set ESPTOOL")
+}
+
+/// Write `image` to the `chip` at `port`, erasing the whole flash first if `erase` is set. The
+/// chip is reset into the new firmware when done.
+pub fn flash(
+    program: &Path,
+    chip: &str,
+    port: &Path,
+    image: &Path,
+    baud: u32,
+    erase: bool,
+    quiet: bool,
+) -> Result<()> {
+    let offset = offset(chip)?;
+    if erase {
+        run(program, chip, port, baud, &["erase_flash"], quiet)?;
+    }
+    run(
+        program,
+        chip,
+        port,
+        baud,
+        &[
+            "write_flash",
+            "-z",
+            &format!("{:#x}", offset),
+            &image.to_string_lossy(),
+        ],
+        quiet,
+    )
+}
+
+fn run(
+    program: &Path,
+    chip: &str,
+    port: &Path,
+    baud: u32,
+    command: &[&str],
+    quiet: bool,
+) -> Result<()> {
+    let mut esptool = Command::new(program);
+    esptool
+        .arg("--chip")
+        .arg(chip)
+        .arg("--port")
+        .arg(port)
+        .arg("--baud")
+        .arg(baud.to_string())
+        .args(command);
+    if quiet {
+        esptool.stdout(Stdio::null());
+    }
+    let status = match esptool.status() {
+        Ok(status) => status,
+        Err(e) => bail!("Couldn't run {}: {}", program.display(), e),
+    };
+    if !status.success() {
+        bail!("esptool {} failed with {}", command[0], status);
+    }
+    Ok(())
+}
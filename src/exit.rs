@@ -0,0 +1,72 @@
+//! The exit codes of serpico. They are kept stable, so that wrapper scripts and CI can tell what
+//! went wrong without parsing error messages. A script that raises `SystemExit` exits with its
+//! code, whatever it is.
+use crate::call::CallFailed;
+use crate::port;
+use crate::script::UploadCorrupted;
+use crate::serial::{
+    Disconnected, Interrupted, MaxRuntimeExceeded, MultipleDevices, NoDevice, RawPasteFailed,
+    ReadTimeout, Rebooted, StageTimeout,
+};
+use crate::syntax::SyntaxError;
+
+/// The script or a function called with `serpico call` raised an uncaught exception, or tests
+/// failed
+pub const SCRIPT_FAILED: i32 = 1;
+/// The script didn't compile. Invalid command lines exit with this too.
+pub const SYNTAX_ERROR: i32 = 2;
+/// No device was given, and none was found
+pub const NO_DEVICE: i32 = 3;
+/// No device was given, and more than one was found
+pub const MULTIPLE_DEVICES: i32 = 4;
+/// The device couldn't be opened, or the connection to it was lost
+pub const CONNECT_FAILED: i32 = 5;
+/// The device didn't respond the way MicroPython does
+pub const PROTOCOL_ERROR: i32 = 6;
+/// The script ran past `--timeout` or `--max-runtime`
+pub const TIMEOUT: i32 = 7;
+/// Any other error
+pub const ERROR: i32 = 8;
+/// Ctrl-C was pressed
+pub const CANCELLED: i32 = 130;
+
+/// The exit codes, for the command line help
+pub const HELP: &str = "Exit codes:
+    0    Success
+    1    The script or called function raised an exception, or tests failed
+    2    The script didn't compile, or the command line is invalid
+    3    No device found
+    4    Multiple devices found
+    5    Couldn't connect to the device, or lost the connection
+    6    The device didn't follow the REPL protocol
+    7    Timed out
+    8    Any other error
+    130  Cancelled with Ctrl-C
+A script ending with SystemExit exits with its code.";
+
+/// The exit code for failing with `error`, classified by the types in it and its context
+pub fn code(error: &anyhow::Error) -> i32 {
+    if error.is::<Interrupted>() {
+        CANCELLED
+    } else if error.is::<CallFailed>() {
+        SCRIPT_FAILED
+    } else if error.is::<SyntaxError>() {
+        SYNTAX_ERROR
+    } else if error.is::<NoDevice>() {
+        NO_DEVICE
+    } else if error.is::<MultipleDevices>() {
+        MULTIPLE_DEVICES
+    } else if error.is::<port::ConnectFailed>() || error.is::<Disconnected>() {
+        CONNECT_FAILED
+    } else if error.is::<ReadTimeout>() || error.is::<MaxRuntimeExceeded>() {
+        TIMEOUT
+    } else if error.is::<StageTimeout>()
+        || error.is::<RawPasteFailed>()
+        || error.is::<Rebooted>()
+        || error.is::<UploadCorrupted>()
+    {
+        PROTOCOL_ERROR
+    } else {
+        ERROR
+    }
+}
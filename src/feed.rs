@@ -0,0 +1,133 @@
+//! Streaming a binary payload from the host to the running script over the REPL's link, such as
+//! a firmware blob or audio samples for a handler on the device, without going through its
+//! filesystem.
+//!
+//! The payload is sent in frames of a 4 byte big-endian length and that many bytes, ending with a
+//! frame of length 0. The script reads them with `serpico_frames()`, defined by [`HELPER`], which
+//! turns off the keyboard interrupt while it reads, so that a 0x03 in the payload doesn't stop the
+//! script, and asks for each frame by printing [`REQUEST`], so that no more is sent than the
+//! device can hold.
+use anyhow::{bail, Result};
+use serialport::SerialPort;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+/// What the device prints to ask for the next frame. It's taken out of the script's output until
+/// the last frame has been sent.
+pub const REQUEST: u8 = 0x06;
+
+/// The most payload sent in one frame, small enough for the stdin buffers of UART REPLs
+pub const FRAME_SIZE: usize = 256;
+
+/// Defines `serpico_frames()`, a generator of the payload's frames as `bytes`
+pub const HELPER: &str = r#"def serpico_frames():
+    import sys
+    try:
+        from micropython import kbd_intr
+    except ImportError:
+        kbd_intr = lambda c: None
+    stdin = sys.stdin.buffer
+    kbd_intr(-1)
+    try:
+        while True:
+            sys.stdout.write("\x06")
+            size = int.from_bytes(stdin.read(4), "big")
+            if not size:
+                return
+            yield stdin.read(size)
+    finally:
+        kbd_intr(3)
+"#;
+
+/// Where the payload is read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// The host's stdin
+    Stdin,
+    File(PathBuf),
+}
+
+impl Source {
+    /// A path, or `-` for stdin
+    pub fn parse(value: &str) -> Result<Source> {
+        match value {
+            "" => bail!("Expected a file to feed, or - for stdin"),
+            "-" => Ok(Source::Stdin),
+            _ => Ok(Source::File(PathBuf::from(value))),
+        }
+    }
+
+    fn open(&self) -> Result<Box<dyn Read>> {
+        match self {
+            Source::Stdin => Ok(Box::new(io::stdin())),
+            Source::File(path) => match File::open(path) {
+                Ok(file) => Ok(Box::new(file)),
+                Err(e) => bail!("Couldn't open {}: {}", path.display(), e),
+            },
+        }
+    }
+}
+
+/// Sends the payload a frame at a time, as the device asks for them
+pub struct Feed {
+    source: Box<dyn Read>,
+    /// Frames asked for that haven't been sent yet
+    requested: usize,
+    /// Whether the last frame has been sent
+    done: bool,
+}
+
+impl Feed {
+    pub fn open(source: &Source) -> Result<Feed> {
+        Ok(Feed {
+            source: source.open()?,
+            requested: 0,
+            done: false,
+        })
+    }
+
+    /// The script's output less the requests in it, noting them to be answered
+    pub fn filter(&mut self, bytes: &[u8]) -> Vec<u8> {
+        if self.done {
+            return bytes.to_vec();
+        }
+        let mut passed = Vec::with_capacity(bytes.len());
+        for &byte in bytes {
+            match byte {
+                REQUEST => self.requested += 1,
+                _ => passed.push(byte),
+            }
+        }
+        passed
+    }
+
+    /// Send a frame for each request, the empty frame once the payload has run out
+    pub fn send(&mut self, port: &mut dyn SerialPort) -> Result<()> {
+        while self.requested > 0 && !self.done {
+            self.requested -= 1;
+            let mut frame = vec![0; FRAME_SIZE];
+            let size = read_frame(&mut self.source, &mut frame)?;
+            frame.truncate(size);
+            port.write_all(&(size as u32).to_be_bytes())?;
+            port.write_all(&frame)?;
+            port.flush()?;
+            self.done = size == 0;
+        }
+        Ok(())
+    }
+}
+
+/// Fill as much of `frame` as the source has left, returning how much that was
+fn read_frame(source: &mut impl Read, frame: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < frame.len() {
+        match source.read(&mut frame[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => bail!("Couldn't read the payload to feed: {}", e),
+        }
+    }
+    Ok(filled)
+}
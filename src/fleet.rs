@@ -0,0 +1,397 @@
+//! Running a command on every board of a fleet, listed in a TOML file, a few boards at a time.
+//! Each board gets a serpico of its own, with its output going to a log file for the board.
+//!
+//! A table for each board gives its serial number, or the device to connect to, and optionally
+//! variables for the scripts run on it:
+//!
+//! ```toml
+//! [kitchen]
+//! serial = "E66038B71340"
+//! vars = { ROOM = "kitchen", INTERVAL = 30 }
+//! ```
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::output::{format_time, Stream};
+use crate::serial::discover_micropython_devices;
+use crate::sink::SharedSink;
+use crate::toml::{self, Value};
+
+/// Set to the name of the board for each serpico run on one, which leaves the hooks of
+/// serpico.toml to the serpico running the fleet
+pub const BOARD_VAR: &str = "SERPICO_FLEET_BOARD";
+
+/// How many boards are worked on at once unless told otherwise
+pub const DEFAULT_JOBS: usize = 4;
+
+/// A board of the fleet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Board {
+    /// The name of its table, which names its log file too
+    pub name: String,
+    pub serial: Option<String>,
+    /// The device to connect to, instead of finding the board by serial number
+    pub device: Option<PathBuf>,
+    /// Variables set for scripts run on the board, as `--set` does
+    pub vars: Vec<(String, String)>,
+}
+
+/// Read the boards of the fleet file at `path`
+pub fn load(path: &Path) -> Result<Vec<Board>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => bail!("Couldn't read {}: {}", path.display(), e),
+    };
+    let entries = match toml::parse(&text) {
+        Ok(Value::Table(entries)) => entries,
+        Ok(_) => unreachable!(),
+        Err(e) => bail!("Couldn't parse {}: {}", path.display(), e),
+    };
+    let mut boards = Vec::new();
+    for (name, value) in entries {
+        if !matches!(value, Value::Table(_)) {
+            bail!(
+                "{} in {} should be a table of the board",
+                name,
+                path.display()
+            );
+        }
+        let string = |key: &str| match value.get(key) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            Some(other) => bail!("{}.{} should be a string, not {}", name, key, other.kind()),
+        };
+        let vars = match value.get("vars") {
+            None => Vec::new(),
+            Some(Value::Table(vars)) => {
+                let mut strings = Vec::new();
+                for (key, var) in vars {
+                    let var = match var {
+                        Value::String(var) => var.clone(),
+                        Value::Integer(var) => var.to_string(),
+                        Value::Float(var) => var.to_string(),
+                        Value::Boolean(var) => var.to_string(),
+                        other => bail!("{}.vars.{} can't be {}", name, key, other.kind()),
+                    };
+                    strings.push((key.clone(), var));
+                }
+                strings
+            }
+            Some(other) => bail!("{}.vars should be a table, not {}", name, other.kind()),
+        };
+        let board = Board {
+            serial: string("serial")?,
+            device: string("device")?.map(PathBuf::from),
+            vars,
+            name,
+        };
+        if board.serial.is_none() && board.device.is_none() {
+            bail!("{} has neither a serial number nor a device", board.name);
+        }
+        boards.push(board);
+    }
+    if boards.is_empty() {
+        bail!("{} lists no boards", path.display());
+    }
+    Ok(boards)
+}
+
+/// `args` without the options named in `names` and their values, given either as `--name VALUE`
+/// or as `--name=VALUE`, and without the flags named in `flags`, which take no value. Arguments
+/// after `--` are kept as they are.
+pub fn without_options(args: &[String], names: &[&str], flags: &[&str]) -> Vec<String> {
+    let mut kept = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            kept.push(arg.clone());
+            kept.extend(args.cloned());
+            break;
+        }
+        let name = arg.split('=').next().unwrap_or("");
+        if flags.contains(&arg.as_str()) {
+            continue;
+        } else if !names.contains(&name) {
+            kept.push(arg.clone());
+        } else if !arg.contains('=') {
+            args.next();
+        }
+    }
+    kept
+}
+
+/// How running the command on a board went
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// No attached board has the serial number
+    NotAttached,
+    /// serpico exited with this code
+    Exited(i32),
+    /// serpico couldn't be started, or was killed
+    Failed(String),
+}
+
+impl Outcome {
+    pub fn succeeded(&self) -> bool {
+        *self == Outcome::Exited(0)
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::NotAttached => write!(f, "not attached"),
+            Outcome::Exited(0) => write!(f, "ok"),
+            Outcome::Exited(code) => write!(f, "failed with exit code {}", code),
+            Outcome::Failed(e) => write!(f, "failed: {}", e),
+        }
+    }
+}
+
+/// How the output of each board is captured
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capture {
+    /// Prefix each line with the host's time when it arrived, one clock for every board, so that
+    /// their logs can be lined up afterwards
+    pub timestamps: bool,
+    /// Also print the lines of every board as they arrive, each with its time and board, so that
+    /// they're merged in the order they happened
+    pub merge: bool,
+    /// Where the merged lines are written
+    pub sink: SharedSink,
+}
+
+impl Capture {
+    /// Whether the output goes straight to the log files, untouched
+    fn untouched(&self) -> bool {
+        !self.timestamps && !self.merge
+    }
+}
+
+/// How running the command on a board went, and where its output went
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub board: String,
+    pub device: Option<PathBuf>,
+    pub outcome: Outcome,
+    pub duration: Duration,
+    pub log: PathBuf,
+}
+
+/// Run the command that `command` makes for each board and the device it was found at, `jobs` at
+/// a time, with its output written to `name.log` in `logs` as `capture` says. Unless `quiet` is
+/// set, each board is reported as it finishes. The reports are in the order of `boards`.
+pub fn run(
+    boards: &[Board],
+    jobs: usize,
+    logs: &Path,
+    quiet: bool,
+    capture: Capture,
+    command: impl Fn(&Board, &Path) -> Command + Sync,
+) -> Result<Vec<Report>> {
+    if let Err(e) = fs::create_dir_all(logs) {
+        bail!("Couldn't create {}: {}", logs.display(), e);
+    }
+    let attached = match boards.iter().any(|board| board.device.is_none()) {
+        true => discover_micropython_devices()?,
+        false => Vec::new(),
+    };
+    let pending = Mutex::new(boards.iter().enumerate().collect::<VecDeque<_>>());
+    let reports = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, boards.len()) {
+            scope.spawn(|| loop {
+                let (index, board) = match pending.lock().unwrap().pop_front() {
+                    Some(next) => next,
+                    None => return,
+                };
+                let device = board.device.clone().or_else(|| {
+                    attached
+                        .iter()
+                        .find(|info| info.serial_number == board.serial)
+                        .map(|info| info.path.clone())
+                });
+                let log = logs.join(format!("{}.log", board.name));
+                let start = Instant::now();
+                let outcome = match &device {
+                    Some(device) if capture.untouched() => run_one(command(board, device), &log),
+                    Some(device) => {
+                        run_captured(command(board, device), &board.name, &log, &capture)
+                    }
+                    None => Outcome::NotAttached,
+                };
+                let report = Report {
+                    board: board.name.clone(),
+                    device,
+                    outcome,
+                    duration: start.elapsed(),
+                    log,
+                };
+                if !quiet {
+                    eprintln!("{}: {}", report.board, report.outcome);
+                }
+                reports.lock().unwrap().push((index, report));
+            });
+        }
+    });
+
+    let mut reports = reports.into_inner().unwrap();
+    reports.sort_by_key(|(index, _)| *index);
+    Ok(reports.into_iter().map(|(_, report)| report).collect())
+}
+
+fn run_one(mut command: Command, log: &Path) -> Outcome {
+    let output = match File::create(log).and_then(|file| Ok((file.try_clone()?, file))) {
+        Ok(output) => output,
+        Err(e) => return Outcome::Failed(format!("couldn't create {}: {}", log.display(), e)),
+    };
+    let status = command
+        .stdin(Stdio::null())
+        .stdout(output.0)
+        .stderr(output.1)
+        .status();
+    match status {
+        Ok(status) => match status.code() {
+            Some(code) => Outcome::Exited(code),
+            None => Outcome::Failed(format!("killed, {}", status)),
+        },
+        Err(e) => Outcome::Failed(e.to_string()),
+    }
+}
+
+/// Like [`run_one`], reading the output line by line to stamp each line with when it arrived
+fn run_captured(mut command: Command, board: &str, log: &Path, capture: &Capture) -> Outcome {
+    let file = match File::create(log) {
+        Ok(file) => Mutex::new(file),
+        Err(e) => return Outcome::Failed(format!("couldn't create {}: {}", log.display(), e)),
+    };
+    let mut child = match command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => return Outcome::Failed(e.to_string()),
+    };
+    let stdout = child
+        .stdout
+        .take()
+        .map(|out| (Stream::Stdout, Box::new(out) as Box<dyn Read + Send>));
+    let stderr = child
+        .stderr
+        .take()
+        .map(|err| (Stream::Stderr, Box::new(err) as Box<dyn Read + Send>));
+
+    // The lines of stdout and stderr are interleaved as they arrive
+    thread::scope(|scope| {
+        for (stream, output) in [stdout, stderr].into_iter().flatten() {
+            let file = &file;
+            scope.spawn(move || {
+                for line in BufReader::new(output)
+                    .split(b'\n')
+                    .map_while(|line| line.ok())
+                {
+                    let time = format_time(SystemTime::now());
+                    let line = String::from_utf8_lossy(&line);
+                    let line = line.trim_end_matches('\r');
+                    if capture.merge {
+                        let merged = format!("{} [{}] {}\n", time, board, line);
+                        let _ = capture.sink.0.write(stream, merged.as_bytes());
+                    }
+                    let mut file = file.lock().unwrap();
+                    let _ = match capture.timestamps {
+                        true => writeln!(file, "{} {}", time, line),
+                        false => writeln!(file, "{}", line),
+                    };
+                }
+            });
+        }
+    });
+    match child.wait() {
+        Ok(status) => match status.code() {
+            Some(code) => Outcome::Exited(code),
+            None => Outcome::Failed(format!("killed, {}", status)),
+        },
+        Err(e) => Outcome::Failed(e.to_string()),
+    }
+}
+
+/// A table of how each board went
+pub fn summary(reports: &[Report]) -> String {
+    let rows: Vec<[String; 4]> = reports
+        .iter()
+        .map(|report| {
+            [
+                report.board.clone(),
+                report
+                    .device
+                    .as_ref()
+                    .map_or("-".to_string(), |device| device.display().to_string()),
+                report.outcome.to_string(),
+                match report.outcome {
+                    Outcome::NotAttached => "-".to_string(),
+                    _ => format!("{:.1}s", report.duration.as_secs_f64()),
+                },
+            ]
+        })
+        .collect();
+    let header = ["Board", "Device", "Result", "Time"].map(String::from);
+    let widths: Vec<usize> = (0..4)
+        .map(|column| {
+            rows.iter()
+                .chain([&header])
+                .map(|row| row[column].chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let mut table = String::new();
+    for row in [&header].into_iter().chain(&rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::sink::Memory;
+    use std::sync::Arc;
+
+    #[test]
+    fn merged_lines_go_to_the_sink() {
+        let memory = Arc::new(Memory::default());
+        let capture = Capture {
+            timestamps: true,
+            merge: true,
+            sink: SharedSink(memory.clone()),
+        };
+        let log = std::env::temp_dir().join(format!("serpico-fleet-{}.log", std::process::id()));
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("echo out; echo err >&2");
+        let outcome = run_captured(command, "kitchen", &log, &capture);
+        let logged = fs::read_to_string(&log).unwrap();
+        let _ = fs::remove_file(&log);
+        assert_eq!(outcome, Outcome::Exited(0));
+        let (stdout, stderr) = memory.contents();
+        assert!(String::from_utf8_lossy(&stdout).ends_with(" [kitchen] out\n"));
+        assert!(String::from_utf8_lossy(&stderr).ends_with(" [kitchen] err\n"));
+        assert_eq!(logged.lines().count(), 2);
+    }
+}
@@ -0,0 +1,546 @@
+//! Working with files on the device's filesystem
+use anyhow::{bail, Result};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::checksum::Algorithm;
+use crate::device::Device;
+use crate::script::{quote, quote_bytes};
+use crate::serial::eval;
+use crate::{base64, deflate};
+
+/// How many bytes of a file are written by each helper script
+const WRITE_CHUNK_SIZE: usize = 4096;
+
+const CAPABILITIES_QUERY: &str = "\
+def _serpico_has(name):
+    try:
+        __import__(name)
+        return 1
+    except ImportError:
+        return 0
+print(_serpico_has('binascii'), _serpico_has('deflate'), _serpico_has('zlib'))
+del _serpico_has
+";
+
+/// How file contents are decompressed on the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// The `deflate` module of MicroPython 1.21 and later
+    Deflate,
+    /// The `zlib` module of older firmware
+    Zlib,
+}
+
+/// How file contents are encoded in the helper scripts that write them, negotiated per device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transfer {
+    /// Send data as base64 rather than as bytes literals
+    pub base64: bool,
+    pub compression: Compression,
+}
+
+impl Default for Transfer {
+    /// Works on any device, but is the slowest
+    fn default() -> Self {
+        Transfer {
+            base64: false,
+            compression: Compression::None,
+        }
+    }
+}
+
+impl fmt::Display for Transfer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let encoding = if self.base64 { "base64" } else { "literal" };
+        let compression = match self.compression {
+            Compression::None => "uncompressed",
+            Compression::Deflate => "deflate",
+            Compression::Zlib => "zlib",
+        };
+        write!(f, "{}, {}", encoding, compression)
+    }
+}
+
+impl Transfer {
+    /// Find the fastest way to send files to the device, only compressing if `compress` is set
+    pub fn negotiate(
+        device: &mut Device,
+        compress: bool,
+        timeout: Option<Duration>,
+    ) -> Result<Transfer> {
+        let output = eval(device, CAPABILITIES_QUERY, timeout)?;
+        let output = String::from_utf8_lossy(&output);
+        let has: Vec<bool> = output.split_whitespace().map(|flag| flag == "1").collect();
+        if has.len() != 3 {
+            bail!("Unexpected module list from device: {:?}", output.trim());
+        }
+
+        // Compressed data is binary, so it's only worth sending when it can be sent as base64
+        let compression = if !compress || !has[0] {
+            Compression::None
+        } else if has[1] {
+            Compression::Deflate
+        } else if has[2] {
+            Compression::Zlib
+        } else {
+            Compression::None
+        };
+
+        Ok(Transfer {
+            base64: has[0],
+            compression,
+        })
+    }
+
+    /// A script that appends or writes `chunk` to `path`
+    fn script(&self, path: &str, mode: &str, chunk: &[u8]) -> String {
+        let compressed;
+        let (data, compression) = match self.compression {
+            Compression::None => (chunk, Compression::None),
+            _ => {
+                compressed = deflate::compress(chunk);
+                if compressed.len() < chunk.len() {
+                    (compressed.as_slice(), self.compression)
+                } else {
+                    (chunk, Compression::None)
+                }
+            }
+        };
+
+        let mut script = String::new();
+        let mut data = if self.base64 {
+            script.push_str("import binascii\n");
+            format!("binascii.a2b_base64('{}')", base64::encode(data))
+        } else {
+            quote_bytes(data)
+        };
+        match compression {
+            Compression::None => {}
+            Compression::Deflate => {
+                script.push_str("import deflate, io\n");
+                data = format!(
+                    "deflate.DeflateIO(io.BytesIO({}), deflate.ZLIB).read()",
+                    data
+                );
+            }
+            Compression::Zlib => {
+                script.push_str("import zlib\n");
+                data = format!("zlib.decompress({}, {})", data, deflate::WINDOW_BITS);
+            }
+        }
+        script.push_str(&format!(
+            "with open({}, '{}') as _f:\n    _f.write({})\n",
+            quote(path),
+            mode,
+            data
+        ));
+        script
+    }
+}
+
+/// Write `data` to `path` on the device, replacing the file if it exists
+pub fn write_file(
+    device: &mut Device,
+    path: &str,
+    data: &[u8],
+    transfer: &Transfer,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    if data.is_empty() {
+        eval(
+            device,
+            &format!("open({}, 'wb').close()\n", quote(path)),
+            timeout,
+        )?;
+        return Ok(());
+    }
+
+    write_from(device, path, data, 0, transfer, timeout)
+}
+
+/// Write `data` from `offset` on to `path` on the device, appending to the first `offset` bytes
+/// already there, a chunk at a time
+pub fn write_from(
+    device: &mut Device,
+    path: &str,
+    data: &[u8],
+    offset: usize,
+    transfer: &Transfer,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    for (index, chunk) in data[offset..].chunks(WRITE_CHUNK_SIZE).enumerate() {
+        let mode = if index == 0 && offset == 0 {
+            "wb"
+        } else {
+            "ab"
+        };
+        eval(device, &transfer.script(path, mode, chunk), timeout)?;
+    }
+    Ok(())
+}
+
+/// Prints the size of the file `_path`, or -1 if there's none. `_path` is set ahead of it.
+const SIZE_QUERY: &str = "\
+import os
+try:
+    print(os.stat(_path)[6])
+except OSError:
+    print(-1)
+del _path
+";
+
+/// Where writing `data` to `path` can resume from, after an earlier write was cut off: the end
+/// of what's already on the device if it matches the start of `data`, which is all of `data` if
+/// the file is complete. Anything else starts over from 0.
+pub fn resume_offset(
+    device: &mut Device,
+    path: &str,
+    data: &[u8],
+    algorithm: Algorithm,
+    timeout: Option<Duration>,
+) -> Result<usize> {
+    let script = format!("_path = {}\n{}", quote(path), SIZE_QUERY);
+    let output = eval(device, &script, timeout)?;
+    let size: i64 = match String::from_utf8_lossy(&output).trim().parse() {
+        Ok(size) => size,
+        Err(_) => bail!("Unexpected size of {} from device", path),
+    };
+    if size <= 0 || size as usize > data.len() {
+        return Ok(0);
+    }
+    let offset = size as usize;
+    let written = checksum_script(path, algorithm, Some(offset));
+    let written = String::from_utf8_lossy(&eval(device, &written, timeout)?)
+        .trim()
+        .to_string();
+    match written == algorithm.digest(&data[..offset]) {
+        true => Ok(offset),
+        false => Ok(0),
+    }
+}
+
+/// Copy the local file at `local` to `remote` on the device, returning its contents
+pub fn put(
+    device: &mut Device,
+    local: &Path,
+    remote: &str,
+    transfer: &Transfer,
+    timeout: Option<Duration>,
+) -> Result<Vec<u8>> {
+    let data = match fs::read(local) {
+        Ok(data) => data,
+        Err(e) => bail!("Couldn't read file {}: {}", local.display(), e),
+    };
+    write_file(device, remote, &data, transfer, timeout)?;
+    Ok(data)
+}
+
+/// Lists everything under the directory `_root`, a line for each with `d` for a directory or `f`
+/// for a file, its size and its path. `_root` is set ahead of it.
+const WALK: &str = "\
+import os
+def _serpico_walk(dir):
+    for entry in os.ilistdir(dir):
+        path = dir.rstrip('/') + '/' + entry[0]
+        if entry[1] & 0x4000:
+            print('d', 0, path)
+            _serpico_walk(path)
+        else:
+            print('f', entry[3] if len(entry) > 3 else os.stat(path)[6], path)
+try:
+    _serpico_walk(_root)
+finally:
+    del _serpico_walk, _root
+";
+
+/// Prints the contents of the file `_path` as hex, a line for each chunk. `_path` is set ahead of
+/// it.
+const READ: &str = "\
+try:
+    from binascii import hexlify as _hex
+except ImportError:
+    def _hex(data):
+        return ''.join('%02x' % b for b in data).encode()
+with open(_path, 'rb') as _f:
+    while True:
+        _b = _f.read(512)
+        if not _b:
+            break
+        print(_hex(_b).decode())
+del _hex, _f, _b, _path
+";
+
+/// A file or directory on the device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The absolute path
+    pub path: String,
+    /// The size in bytes of a file, 0 for a directory
+    pub size: u64,
+    pub is_dir: bool,
+}
+
+/// Every file and directory under the absolute directory `root` on the device, with directories
+/// ahead of their contents
+pub fn walk(device: &mut Device, root: &str, timeout: Option<Duration>) -> Result<Vec<Entry>> {
+    let output = eval(
+        device,
+        &format!("_root = {}\n{}", quote(root), WALK),
+        timeout,
+    )?;
+    let output = String::from_utf8_lossy(&output);
+    let mut entries = Vec::new();
+    for line in output.lines() {
+        let mut fields = line.trim_end_matches('\r').splitn(3, ' ');
+        let entry = match (fields.next(), fields.next().map(str::parse), fields.next()) {
+            (Some(kind @ ("d" | "f")), Some(Ok(size)), Some(path)) => Entry {
+                path: path.to_string(),
+                size,
+                is_dir: kind == "d",
+            },
+            _ => bail!("Unexpected directory listing from device: {:?}", line),
+        };
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// The contents of the file at `path` on the device
+pub fn read_file(device: &mut Device, path: &str, timeout: Option<Duration>) -> Result<Vec<u8>> {
+    let output = eval(
+        device,
+        &format!("_path = {}\n{}", quote(path), READ),
+        timeout,
+    )?;
+    let hex: Vec<u8> = output
+        .into_iter()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .collect();
+    let digit = |byte: u8| (byte as char).to_digit(16);
+    let mut data = Vec::with_capacity(hex.len() / 2);
+    for pair in hex.chunks(2) {
+        match (digit(pair[0]), pair.get(1).copied().and_then(digit)) {
+            (Some(high), Some(low)) => data.push((high * 16 + low) as u8),
+            _ => bail!("Unexpected contents of {} from device", path),
+        }
+    }
+    Ok(data)
+}
+
+/// Every file under the local directory `dir`, with its path relative to `dir` using `/`
+/// separators, sorted so parents come before their contents
+pub fn local_files(dir: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let mut files = Vec::new();
+    let mut pending = vec![(dir.to_path_buf(), String::new())];
+    while let Some((dir, relative)) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => bail!("Couldn't read directory {}: {}", dir.display(), e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = entry.path();
+            let relative = if relative.is_empty() {
+                name
+            } else {
+                format!("{}/{}", relative, name)
+            };
+            if entry.file_type()?.is_dir() {
+                pending.push((path, relative));
+            } else {
+                files.push((path, relative));
+            }
+        }
+    }
+    files.sort_by(|a, b| a.1.cmp(&b.1));
+    Ok(files)
+}
+
+/// Join a path on the device with a relative path
+pub fn join(dir: &str, relative: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), relative)
+}
+
+/// The directory part of a path on the device
+pub fn parent(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) => "/",
+        Some(index) => &path[..index],
+        None => "",
+    }
+}
+
+const CHECKSUM_QUERY: &str = "\
+try:
+    import hashlib
+    hashlib.sha256
+    print('sha256')
+except (ImportError, AttributeError):
+    try:
+        import binascii
+        binascii.crc32
+        print('crc32')
+    except (ImportError, AttributeError):
+        print('none')
+";
+
+/// Find a checksum the device is able to calculate, preferring sha256
+pub fn checksum_algorithm(device: &mut Device, timeout: Option<Duration>) -> Result<Algorithm> {
+    let output = eval(device, CHECKSUM_QUERY, timeout)?;
+    match String::from_utf8_lossy(&output).trim() {
+        "sha256" => Ok(Algorithm::Sha256),
+        "crc32" => Ok(Algorithm::Crc32),
+        _ => bail!("The device has neither hashlib.sha256 nor binascii.crc32, unable to verify"),
+    }
+}
+
+/// Calculate the checksum of the file at `path` on the device, as lowercase hex
+pub fn checksum(
+    device: &mut Device,
+    path: &str,
+    algorithm: Algorithm,
+    timeout: Option<Duration>,
+) -> Result<String> {
+    let output = eval(device, &checksum_script(path, algorithm, None), timeout)?;
+    Ok(String::from_utf8_lossy(&output).trim().to_string())
+}
+
+/// Prints the checksum of the file at `path`, or of its first `length` bytes
+fn checksum_script(path: &str, algorithm: Algorithm, length: Option<usize>) -> String {
+    let (setup, update, result) = match algorithm {
+        Algorithm::Sha256 => (
+            "import hashlib, binascii\n_h = hashlib.sha256()\n",
+            "_h.update(_b)",
+            "binascii.hexlify(_h.digest()).decode()",
+        ),
+        Algorithm::Crc32 => (
+            "import binascii\n_h = 0\n",
+            "_h = binascii.crc32(_b, _h)",
+            "'%08x' % (_h & 0xffffffff)",
+        ),
+    };
+    let (limit, read) = match length {
+        Some(length) => (
+            format!("_n = {}\n", length),
+            "_f.read(min(512, _n))\n        _n -= len(_b)",
+        ),
+        None => (String::new(), "_f.read(512)"),
+    };
+    format!(
+        "\
+{}{}with open({}, 'rb') as _f:
+    while True:
+        _b = {}
+        if not _b:
+            break
+        {}
+print({})
+",
+        setup,
+        limit,
+        quote(path),
+        read,
+        update,
+        result
+    )
+}
+
+/// Create the absolute directory `path` on the device, along with any missing parents
+pub fn make_dirs(device: &mut Device, path: &str, timeout: Option<Duration>) -> Result<()> {
+    let script = format!(
+        "\
+import os
+_p = ''
+for _d in {}.split('/'):
+    if not _d:
+        continue
+    _p += '/' + _d
+    try:
+        os.mkdir(_p)
+    except OSError:
+        pass
+",
+        quote(path)
+    );
+    eval(device, &script, timeout)?;
+    Ok(())
+}
+
+/// Remove the file at `path` on the device
+pub fn remove(device: &mut Device, path: &str, timeout: Option<Duration>) -> Result<()> {
+    eval(
+        device,
+        &format!("import os\nos.remove({})\n", quote(path)),
+        timeout,
+    )?;
+    Ok(())
+}
+
+/// The filesystems the device's flash can be formatted with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filesystem {
+    Lfs2,
+    Fat,
+}
+
+impl Filesystem {
+    pub fn parse(value: &str) -> Result<Filesystem> {
+        match value {
+            "lfs2" => Ok(Filesystem::Lfs2),
+            "fat" => Ok(Filesystem::Fat),
+            _ => bail!("Expected lfs2 or fat, got {:?}", value),
+        }
+    }
+}
+
+/// Finds the block device the filesystem lives on for each port, and the filesystem the port
+/// formats it with itself, then formats and mounts it at the root. `_fs`, the filesystem to use
+/// instead, is set ahead of it.
+const FORMAT: &str = "\
+import os, sys
+_platform = sys.platform
+if _platform == 'rp2':
+    import rp2
+    _bdev = rp2.Flash()
+elif _platform == 'esp32':
+    import esp32
+    _bdev = esp32.Partition.find(esp32.Partition.TYPE_DATA, label='vfs')[0]
+elif _platform == 'esp8266':
+    from flashbdev import bdev as _bdev
+elif _platform == 'pyboard':
+    import pyb
+    _bdev = pyb.Flash(start=0)
+else:
+    raise OSError('unknown block device on ' + _platform)
+if _fs is None:
+    _fs = 'fat' if _platform in ('esp8266', 'pyboard') else 'lfs2'
+_vfs = os.VfsLfs2 if _fs == 'lfs2' else os.VfsFat
+try:
+    os.umount('/')
+except OSError:
+    pass
+_vfs.mkfs(_bdev)
+os.mount(_vfs(_bdev), '/')
+print(_fs)
+";
+
+/// Wipe the device's filesystem and make a new, empty one, with the port's usual filesystem unless
+/// `filesystem` is given. Returns the name of the filesystem made.
+pub fn format(
+    device: &mut Device,
+    filesystem: Option<Filesystem>,
+    timeout: Option<Duration>,
+) -> Result<String> {
+    let fs = match filesystem {
+        Some(Filesystem::Lfs2) => "'lfs2'",
+        Some(Filesystem::Fat) => "'fat'",
+        None => "None",
+    };
+    let output = eval(device, &format!("_fs = {}\n{}", fs, FORMAT), timeout)?;
+    Ok(String::from_utf8_lossy(&output).trim().to_string())
+}
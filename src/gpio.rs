@@ -0,0 +1,96 @@
+//! Driving and reading pins with one-off commands, for bringing up hardware without writing a
+//! script for it
+use anyhow::{bail, Result};
+
+use crate::script::literal;
+
+/// What to do with a pin
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    High,
+    Low,
+    /// Read the pin as an input, with a pull resistor if given
+    Read {
+        pull: Option<Pull>,
+    },
+    /// Drive the pin as an output at the opposite of its current level
+    Toggle,
+    /// Output a PWM signal, with a duty cycle in percent
+    Pwm {
+        duty: f64,
+        frequency: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pull {
+    Up,
+    Down,
+}
+
+impl Pull {
+    pub fn parse(value: &str) -> Result<Pull> {
+        match value {
+            "up" => Ok(Pull::Up),
+            "down" => Ok(Pull::Down),
+            _ => bail!("Expected up or down, got {:?}", value),
+        }
+    }
+}
+
+/// Code that does `action` with `pin`, a number or a name such as `LED` or `GP15`. Everything
+/// but PWM prints the level of the pin afterwards.
+pub fn script(pin: &str, action: &Action) -> Result<String> {
+    let pin = literal(pin);
+    let script = match action {
+        Action::High | Action::Low => format!(
+            "import machine
+pin = machine.Pin({}, machine.Pin.OUT)
+pin.value({})
+print(pin.value())
+",
+            pin,
+            (*action == Action::High) as u8
+        ),
+        Action::Read { pull } => format!(
+            "import machine
+print(machine.Pin({}, machine.Pin.IN, pull={}).value())
+",
+            pin,
+            match pull {
+                Some(Pull::Up) => "machine.Pin.PULL_UP",
+                Some(Pull::Down) => "machine.Pin.PULL_DOWN",
+                None => "None",
+            }
+        ),
+        Action::Toggle => format!(
+            "import machine
+pin = machine.Pin({})
+pin.init(machine.Pin.OUT, value=1 - pin.value())
+print(pin.value())
+",
+            pin
+        ),
+        Action::Pwm { duty, frequency } => {
+            if !(0.0..=100.0).contains(duty) {
+                bail!("The duty cycle is a percentage, from 0 to 100");
+            }
+            format!(
+                "import machine
+pwm = machine.PWM(machine.Pin({}))
+pwm.freq({})
+try:
+    pwm.duty_u16({})
+except AttributeError:
+    # Older ESP8266 and ESP32 firmware only has the 10 bit duty
+    pwm.duty({})
+",
+                pin,
+                frequency,
+                (duty / 100.0 * 65535.0).round() as u32,
+                (duty / 100.0 * 1023.0).round() as u32
+            )
+        }
+    };
+    Ok(script)
+}
@@ -0,0 +1,70 @@
+//! Scanning an I2C bus for devices, often the first thing to check when wiring up a sensor
+use anyhow::{bail, Result};
+use std::fmt::Write;
+
+/// Code that scans the I2C bus and prints the address of each device found, one per line. The
+/// port's default pins for the bus are used unless `pins` gives the SDA and SCL pins, which the
+/// ESP8266 has no default for, see [`crate::board::Quirks::i2c_pins`].
+pub fn scan_script(bus: u32, pins: Option<(u32, u32)>, frequency: u32) -> String {
+    let (sda, scl) = match pins {
+        Some((sda, scl)) => (sda.to_string(), scl.to_string()),
+        None => (String::from("None"), String::from("None")),
+    };
+    format!(
+        "import machine, sys
+bus = {}
+sda = {}
+scl = {}
+freq = {}
+if sys.platform == 'esp8266':
+    # The ESP8266 has no hardware I2C
+    if sda is None:
+        raise ValueError('the ESP8266 needs the SDA and SCL pins')
+    i2c = machine.SoftI2C(scl=machine.Pin(scl), sda=machine.Pin(sda), freq=freq)
+elif sda is None:
+    i2c = machine.I2C(bus, freq=freq)
+else:
+    i2c = machine.I2C(bus, scl=machine.Pin(scl), sda=machine.Pin(sda), freq=freq)
+for address in i2c.scan():
+    print(address)
+",
+        bus, sda, scl, frequency
+    )
+}
+
+/// The addresses printed by the scan script
+pub fn parse_addresses(output: &str) -> Result<Vec<u8>> {
+    let mut addresses = Vec::new();
+    for line in output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        match line.parse() {
+            Ok(address) => addresses.push(address),
+            Err(_) => bail!("Unexpected output from the I2C scan: {:?}", line),
+        }
+    }
+    Ok(addresses)
+}
+
+/// A grid of the 7 bit addresses like `i2cdetect` prints, with the found ones filled in
+pub fn table(addresses: &[u8]) -> String {
+    let mut table = String::from("   ");
+    for column in 0..16 {
+        write!(table, "  {:x}", column).unwrap();
+    }
+    for row in 0..8 {
+        write!(table, "\n{:02x}:", row * 16).unwrap();
+        for column in 0..16 {
+            let address = row * 16 + column;
+            if addresses.contains(&address) {
+                write!(table, " {:02x}", address).unwrap();
+            } else {
+                table.push_str(" --");
+            }
+        }
+    }
+    table.push('\n');
+    table
+}
@@ -0,0 +1,116 @@
+//! `.serpicoignore`, which keeps local files such as build artifacts, `.git` and caches from being
+//! copied to the device's flash, written like a `.gitignore`:
+//!
+//! - A pattern without a `/` matches a file or directory of that name anywhere, such as `*.pyc`
+//! - A pattern with a `/` matches paths relative to the synced directory, such as `/build` or
+//!   `docs/*.md`, where `**` matches any number of directories
+//! - A pattern ending with `/` only matches directories
+//! - A pattern starting with `!` includes again what an earlier pattern ignored, except in
+//!   directories that are ignored themselves
+//! - Blank lines and lines starting with `#` are skipped
+use anyhow::{bail, Result};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::config::{matches_component, matches_components};
+
+/// The file in the synced directory that the patterns are read from. It isn't copied itself.
+pub const FILE_NAME: &str = ".serpicoignore";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    /// The components of the pattern if it's anchored to the synced directory, otherwise the one
+    /// component matched against any file or directory name
+    pattern: Vec<String>,
+    anchored: bool,
+    negated: bool,
+    dir_only: bool,
+}
+
+/// The patterns of files not to copy
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Ignore {
+    rules: Vec<Rule>,
+}
+
+impl Ignore {
+    /// The patterns of the `.serpicoignore` in `dir`, none if it has none
+    pub fn load(dir: &Path) -> Result<Ignore> {
+        let path = dir.join(FILE_NAME);
+        match fs::read_to_string(&path) {
+            Ok(text) => Ok(Ignore::parse(&text)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Ignore::default()),
+            Err(e) => bail!("Couldn't read {}: {}", path.display(), e),
+        }
+    }
+
+    /// The patterns of a `.serpicoignore`, a line each
+    pub fn parse(text: &str) -> Ignore {
+        let mut ignore = Ignore::default();
+        for line in text.lines() {
+            ignore.add(line);
+        }
+        ignore
+    }
+
+    /// Add a pattern after those already added, so that it overrides them
+    pub fn add(&mut self, pattern: &str) {
+        let pattern = pattern.trim_end();
+        if pattern.is_empty() || pattern.starts_with('#') {
+            return;
+        }
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        // A backslash keeps a leading `#` or `!` from being taken as a comment or a negation
+        let pattern = pattern.strip_prefix('\\').unwrap_or(pattern);
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        if pattern.is_empty() {
+            return;
+        }
+        let anchored = pattern.contains('/');
+        self.rules.push(Rule {
+            pattern: pattern
+                .trim_start_matches('/')
+                .split('/')
+                .map(String::from)
+                .collect(),
+            anchored,
+            negated,
+            dir_only,
+        });
+    }
+
+    /// Whether the file at `relative`, with `/` separators, is ignored, as it is when a directory
+    /// leading to it is
+    pub fn ignores(&self, relative: &str) -> bool {
+        if relative == FILE_NAME {
+            return true;
+        }
+        let components: Vec<&str> = relative.split('/').collect();
+        (1..=components.len())
+            .any(|end| self.ignores_one(&components[..end], end < components.len()))
+    }
+
+    /// Whether the last rule matching the file or directory at `components` ignores it
+    fn ignores_one(&self, components: &[&str], is_dir: bool) -> bool {
+        let name = components.last().copied().unwrap_or("");
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| {
+                let patterns: Vec<&str> = rule.pattern.iter().map(String::as_str).collect();
+                (is_dir || !rule.dir_only)
+                    && match rule.anchored {
+                        true => matches_components(&patterns, components),
+                        false => matches_component(patterns[0], name),
+                    }
+            })
+            .is_some_and(|rule| !rule.negated)
+    }
+}
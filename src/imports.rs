@@ -0,0 +1,63 @@
+//! Finding the local modules a script imports, so they can be copied to the device with it
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The local modules that `script` imports, directly or through other local modules. Modules are
+/// looked up next to the script, as a `.py` file or a package directory. Each is returned with
+/// its path relative to the script's directory, using `/` separators.
+pub fn local_imports(script: &Path) -> Vec<(PathBuf, String)> {
+    let dir = script.parent().unwrap_or_else(|| Path::new(""));
+    let mut found: BTreeMap<String, PathBuf> = BTreeMap::new();
+    let mut pending = vec![script.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(_) => continue,
+        };
+        for module in imported_modules(&source) {
+            let relative = module.replace('.', "/");
+            let mut candidates = vec![format!("{}.py", relative)];
+            // Each package on the way to the module has an __init__.py to run
+            let parts: Vec<&str> = relative.split('/').collect();
+            for end in 1..=parts.len() {
+                candidates.push(format!("{}/__init__.py", parts[..end].join("/")));
+            }
+            for candidate in candidates {
+                let local = dir.join(&candidate);
+                if !found.contains_key(&candidate) && local.is_file() {
+                    pending.push(local.clone());
+                    found.insert(candidate, local);
+                }
+            }
+        }
+    }
+    found
+        .into_iter()
+        .map(|(relative, local)| (local, relative))
+        .collect()
+}
+
+/// The modules named by the import statements in `source`
+fn imported_modules(source: &str) -> Vec<String> {
+    let mut modules = Vec::new();
+    for line in source.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if let Some(rest) = line.strip_prefix("import ") {
+            for name in rest.split(',') {
+                if let Some(module) = name.split_whitespace().next() {
+                    modules.push(module.to_string());
+                }
+            }
+        } else if let Some(rest) = line.strip_prefix("from ") {
+            // Relative imports are resolved against the script's directory as well
+            if let Some(module) = rest.split_whitespace().next() {
+                let module = module.trim_start_matches('.');
+                if !module.is_empty() {
+                    modules.push(module.to_string());
+                }
+            }
+        }
+    }
+    modules
+}
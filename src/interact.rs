@@ -0,0 +1,179 @@
+//! Scripted interaction with a running script: wait for output, then send input
+use anyhow::{bail, Context, Result};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+
+/// A step of an interaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// Wait until the device outputs this text
+    Expect(String),
+    /// Send this text to the script's stdin
+    Send(String),
+}
+
+/// A sequence of expect/send steps driven by the script's output
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Interaction {
+    steps: VecDeque<Step>,
+    /// Output seen since the last expectation was met
+    seen: Vec<u8>,
+}
+
+impl Interaction {
+    pub fn new(steps: Vec<Step>) -> Interaction {
+        Interaction {
+            steps: steps.into(),
+            seen: Vec::new(),
+        }
+    }
+
+    /// Load an interaction file, see [`Interaction::parse`]
+    pub fn load(path: &Path) -> Result<Interaction> {
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read interaction file {}", path.display()))?;
+        Interaction::parse(&source)
+            .with_context(|| format!("Invalid interaction file {}", path.display()))
+    }
+
+    /// Parse a YAML list of steps, each with an `expect` and/or a `send`:
+    ///
+    /// ```yaml
+    /// - expect: "Name? "
+    ///   send: "serpico\n"
+    /// - expect: Done
+    /// ```
+    ///
+    /// Values can be plain, single quoted or double quoted with `\n`, `\r`, `\t`, `\\`, `\"` and
+    /// `\xNN` escapes.
+    pub fn parse(source: &str) -> Result<Interaction> {
+        let mut steps = Vec::new();
+        for (number, line) in source.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let entry = match trimmed.strip_prefix("- ") {
+                Some(entry) => entry,
+                None if line.starts_with(' ') && !steps.is_empty() => trimmed,
+                None => bail!(
+                    "Line {}: expected a list item starting with \"- \"",
+                    number + 1
+                ),
+            };
+            let (key, value) = match entry.split_once(':') {
+                Some((key, value)) => (key.trim(), parse_value(value.trim(), number + 1)?),
+                None => bail!(
+                    "Line {}: expected \"expect: ...\" or \"send: ...\"",
+                    number + 1
+                ),
+            };
+            match key {
+                "expect" => steps.push(Step::Expect(value)),
+                "send" => steps.push(Step::Send(value)),
+                _ => bail!("Line {}: unknown key {:?}", number + 1, key),
+            }
+        }
+        Ok(Interaction::new(steps))
+    }
+
+    /// Whether all steps have been completed
+    pub fn is_done(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// The expectation currently being waited for, if any
+    pub fn expecting(&self) -> Option<&str> {
+        match self.steps.front() {
+            Some(Step::Expect(expected)) => Some(expected),
+            _ => None,
+        }
+    }
+
+    /// Feed output from the device, returning the input that should be sent in response
+    pub fn feed(&mut self, output: &[u8]) -> Vec<u8> {
+        self.seen.extend_from_slice(output);
+        let mut input = Vec::new();
+
+        loop {
+            match self.steps.front() {
+                Some(Step::Send(text)) => {
+                    input.extend_from_slice(text.as_bytes());
+                    self.steps.pop_front();
+                }
+                Some(Step::Expect(expected)) => {
+                    let expected = expected.as_bytes();
+                    match find(&self.seen, expected) {
+                        Some(position) => {
+                            self.seen.drain(..position + expected.len());
+                            self.steps.pop_front();
+                        }
+                        None => {
+                            // Only the tail can still be the start of a match
+                            let keep = expected.len().saturating_sub(1);
+                            if self.seen.len() > keep {
+                                self.seen.drain(..self.seen.len() - keep);
+                            }
+                            break;
+                        }
+                    }
+                }
+                None => break,
+            }
+        }
+
+        input
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn parse_value(value: &str, line: usize) -> Result<String> {
+    if let Some(quoted) = value.strip_prefix('\'') {
+        match quoted.strip_suffix('\'') {
+            Some(quoted) => return Ok(quoted.replace("''", "'")),
+            None => bail!("Line {}: unterminated single quoted value", line),
+        }
+    }
+    let quoted = match value.strip_prefix('"') {
+        Some(quoted) => match quoted.strip_suffix('"') {
+            Some(quoted) => quoted,
+            None => bail!("Line {}: unterminated double quoted value", line),
+        },
+        None => return Ok(value.to_string()),
+    };
+
+    let mut unescaped = String::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('t') => unescaped.push('\t'),
+            Some('\\') => unescaped.push('\\'),
+            Some('"') => unescaped.push('"'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(byte) => unescaped.push(char::from(byte)),
+                    Err(_) => bail!("Line {}: invalid escape \\x{}", line, hex),
+                }
+            }
+            Some(c) => bail!("Line {}: unknown escape \\{}", line, c),
+            None => bail!("Line {}: value ends with a backslash", line),
+        }
+    }
+    Ok(unescaped)
+}
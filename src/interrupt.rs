@@ -0,0 +1,77 @@
+//! Catching Ctrl-C on the host so it can be forwarded to the device instead of killing serpico
+//! while the device is still in the raw REPL
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// After this many Ctrl-C presses that weren't handled, give up and exit immediately
+#[cfg(unix)]
+const FORCE_EXIT_AFTER: usize = 3;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static PRESSES: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(unix)]
+extern "C" fn handle_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    if PRESSES.fetch_add(1, Ordering::SeqCst) + 1 >= FORCE_EXIT_AFTER {
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// Install a Ctrl-C handler that records the interrupt instead of terminating the process
+#[cfg(unix)]
+pub fn install() -> Result<()> {
+    let handler = handle_sigint as extern "C" fn(libc::c_int);
+    let previous = unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+    if previous == libc::SIG_ERR {
+        anyhow::bail!("Unable to install Ctrl-C handler");
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn install() -> Result<()> {
+    Ok(())
+}
+
+/// Check whether Ctrl-C was pressed since the last call
+pub fn take() -> bool {
+    if INTERRUPTED.swap(false, Ordering::SeqCst) {
+        PRESSES.store(0, Ordering::SeqCst);
+        true
+    } else {
+        false
+    }
+}
+
+/// Catches Ctrl-C like [`install`] while it's alive, putting back whatever handled Ctrl-C before
+/// once dropped
+pub struct Catch {
+    #[cfg(unix)]
+    previous: libc::sighandler_t,
+}
+
+/// Catch Ctrl-C until the returned guard is dropped
+#[cfg(unix)]
+pub fn catch() -> Result<Catch> {
+    let handler = handle_sigint as extern "C" fn(libc::c_int);
+    let previous = unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+    if previous == libc::SIG_ERR {
+        anyhow::bail!("Unable to install Ctrl-C handler");
+    }
+    Ok(Catch { previous })
+}
+
+#[cfg(not(unix))]
+pub fn catch() -> Result<Catch> {
+    Ok(Catch {})
+}
+
+impl Drop for Catch {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            libc::signal(libc::SIGINT, self.previous)
+        };
+    }
+}
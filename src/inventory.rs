@@ -0,0 +1,173 @@
+//! Gathering what a board is and has, for keeping track of the boards in a lab
+use anyhow::{bail, Result};
+use std::fmt;
+use std::time::Duration;
+
+use crate::device::Device;
+use crate::json::Value;
+use crate::progress::format_bytes;
+use crate::serial::eval;
+
+/// Prints a tab separated key and value for each thing the board tells about itself. Whatever
+/// the port doesn't support is left out.
+const GATHER: &str = "\
+import gc, os, sys
+def _serpico_gather():
+    print('implementation', sys.implementation.name, sep='\\t')
+    print('version', sys.version, sep='\\t')
+    print('platform', sys.platform, sep='\\t')
+    try:
+        uname = os.uname()
+        print('machine', uname.machine, sep='\\t')
+        print('release', uname.release, sep='\\t')
+    except Exception:
+        pass
+    import binascii
+    try:
+        import machine
+        print('unique_id', binascii.hexlify(machine.unique_id()).decode(), sep='\\t')
+    except Exception:
+        pass
+    try:
+        import network
+        mac = network.WLAN(network.STA_IF).config('mac')
+        print('mac', binascii.hexlify(mac, ':').decode(), sep='\\t')
+    except Exception:
+        pass
+    try:
+        import esp
+        print('flash_size', esp.flash_size(), sep='\\t')
+    except Exception:
+        pass
+    gc.collect()
+    print('ram_free', gc.mem_free(), sep='\\t')
+    print('ram_total', gc.mem_free() + gc.mem_alloc(), sep='\\t')
+    try:
+        stat = os.statvfs('/')
+        print('fs_total', stat[0] * stat[2], sep='\\t')
+        print('fs_free', stat[0] * stat[3], sep='\\t')
+    except Exception:
+        pass
+try:
+    _serpico_gather()
+finally:
+    del _serpico_gather
+";
+
+/// What a board told about itself. Anything its port doesn't support is `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Inventory {
+    /// Such as `micropython`
+    pub implementation: Option<String>,
+    /// `sys.version`, such as `3.4.0; MicroPython v1.22.0 on 2023-12-27`
+    pub version: Option<String>,
+    pub platform: Option<String>,
+    /// The board and chip, such as `Generic ESP32 module with ESP32`
+    pub machine: Option<String>,
+    pub release: Option<String>,
+    /// `machine.unique_id()` in hex
+    pub unique_id: Option<String>,
+    /// The MAC address of the WiFi station interface
+    pub mac: Option<String>,
+    /// In bytes, as are the sizes below
+    pub flash_size: Option<u64>,
+    pub ram_total: Option<u64>,
+    pub ram_free: Option<u64>,
+    pub fs_total: Option<u64>,
+    pub fs_free: Option<u64>,
+}
+
+impl Inventory {
+    /// Read the output of [`GATHER`]
+    pub fn parse(output: &str) -> Result<Inventory> {
+        let mut inventory = Inventory::default();
+        for line in output.lines() {
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = match line.split_once('\t') {
+                Some(entry) => entry,
+                None => bail!("Unexpected inventory from device: {:?}", line),
+            };
+            let size = || match value.parse() {
+                Ok(size) => Ok(Some(size)),
+                Err(_) => bail!("Unexpected {} from device: {:?}", key, value),
+            };
+            let text = Some(value.to_string());
+            match key {
+                "implementation" => inventory.implementation = text,
+                "version" => inventory.version = text,
+                "platform" => inventory.platform = text,
+                "machine" => inventory.machine = text,
+                "release" => inventory.release = text,
+                "unique_id" => inventory.unique_id = text,
+                "mac" => inventory.mac = text,
+                "flash_size" => inventory.flash_size = size()?,
+                "ram_total" => inventory.ram_total = size()?,
+                "ram_free" => inventory.ram_free = size()?,
+                "fs_total" => inventory.fs_total = size()?,
+                "fs_free" => inventory.fs_free = size()?,
+                _ => bail!("Unexpected inventory from device: {:?}", line),
+            }
+        }
+        Ok(inventory)
+    }
+
+    /// The inventory as a JSON object, with `null` for what's unknown
+    pub fn to_json(&self) -> Value {
+        let size = |size: Option<u64>| size.map(|size| size as f64).into();
+        Value::object([
+            ("implementation", self.implementation.clone().into()),
+            ("version", self.version.clone().into()),
+            ("platform", self.platform.clone().into()),
+            ("machine", self.machine.clone().into()),
+            ("release", self.release.clone().into()),
+            ("unique_id", self.unique_id.clone().into()),
+            ("mac", self.mac.clone().into()),
+            ("flash_size", size(self.flash_size)),
+            (
+                "ram",
+                Value::object([
+                    ("total", size(self.ram_total)),
+                    ("free", size(self.ram_free)),
+                ]),
+            ),
+            (
+                "filesystem",
+                Value::object([("total", size(self.fs_total)), ("free", size(self.fs_free))]),
+            ),
+        ])
+    }
+}
+
+/// Ask the device what it is and has
+pub fn gather(device: &mut Device, timeout: Option<Duration>) -> Result<Inventory> {
+    let output = eval(device, GATHER, timeout)?;
+    Inventory::parse(&String::from_utf8_lossy(&output))
+}
+
+impl fmt::Display for Inventory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_string());
+        let size =
+            |size: Option<u64>| size.map_or("-".to_string(), |size| format_bytes(size as f64));
+        writeln!(f, "Firmware:   {}", text(&self.version))?;
+        writeln!(f, "Machine:    {}", text(&self.machine))?;
+        writeln!(f, "Unique ID:  {}", text(&self.unique_id))?;
+        writeln!(f, "MAC:        {}", text(&self.mac))?;
+        writeln!(f, "Flash:      {}", size(self.flash_size))?;
+        writeln!(
+            f,
+            "RAM:        {} free of {}",
+            size(self.ram_free),
+            size(self.ram_total)
+        )?;
+        write!(
+            f,
+            "Filesystem: {} free of {}",
+            size(self.fs_free),
+            size(self.fs_total)
+        )
+    }
+}
@@ -0,0 +1,356 @@
+//! Just enough JSON for machine readable output and the JSON-RPC server
+use anyhow::{bail, Result};
+use std::fmt;
+
+/// A parsed JSON value. Objects keep their keys in order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// The value of `key` if this is an object that has it
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(entries) => entries
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Build an object from its entries
+    pub fn object<'a>(entries: impl IntoIterator<Item = (&'a str, Value)>) -> Value {
+        Value::Object(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Number(value)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Number(value) if value.is_finite() => write!(f, "{}", value),
+            Value::Number(_) => write!(f, "null"),
+            Value::String(value) => write!(f, "{}", quote(value)),
+            Value::Array(values) => {
+                write!(f, "[")?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Value::Object(entries) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in entries.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", quote(key), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+/// Quote `value` as a JSON string
+pub fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// How deeply arrays and objects may be nested, so that a document from a device can't exhaust
+/// the stack
+pub const MAX_DEPTH: usize = 128;
+
+/// Parse a JSON document
+pub fn parse(text: &str) -> Result<Value> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        position: 0,
+        depth: 0,
+    };
+    let value = parser.value()?;
+    parser.whitespace();
+    if parser.position < parser.chars.len() {
+        bail!("Unexpected trailing characters at {}", parser.position);
+    }
+    Ok(value)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    /// How many values the one being parsed is inside of
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Result<char> {
+        match self.peek() {
+            Some(c) => {
+                self.position += 1;
+                Ok(c)
+            }
+            None => bail!("Unexpected end of JSON"),
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.next()? {
+            c if c == expected => Ok(()),
+            c => bail!(
+                "Expected {:?} at {}, got {:?}",
+                expected,
+                self.position - 1,
+                c
+            ),
+        }
+    }
+
+    fn whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.position += 1;
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str, value: Value) -> Result<Value> {
+        for expected in keyword.chars() {
+            self.expect(expected)?;
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        if self.depth == MAX_DEPTH {
+            bail!(
+                "JSON nested deeper than {} levels at {}",
+                MAX_DEPTH,
+                self.position
+            );
+        }
+        self.depth += 1;
+        let value = self.item();
+        self.depth -= 1;
+        value
+    }
+
+    fn item(&mut self) -> Result<Value> {
+        self.whitespace();
+        match self.peek() {
+            Some('n') => self.keyword("null", Value::Null),
+            Some('t') => self.keyword("true", Value::Bool(true)),
+            Some('f') => self.keyword("false", Value::Bool(false)),
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some('[') => {
+                self.position += 1;
+                let mut values = Vec::new();
+                self.whitespace();
+                if self.peek() == Some(']') {
+                    self.position += 1;
+                    return Ok(Value::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    self.whitespace();
+                    match self.next()? {
+                        ',' => continue,
+                        ']' => return Ok(Value::Array(values)),
+                        c => bail!("Expected ',' or ']' at {}, got {:?}", self.position - 1, c),
+                    }
+                }
+            }
+            Some('{') => {
+                self.position += 1;
+                let mut entries = Vec::new();
+                self.whitespace();
+                if self.peek() == Some('}') {
+                    self.position += 1;
+                    return Ok(Value::Object(entries));
+                }
+                loop {
+                    self.whitespace();
+                    let key = self.string()?;
+                    self.whitespace();
+                    self.expect(':')?;
+                    entries.push((key, self.value()?));
+                    self.whitespace();
+                    match self.next()? {
+                        ',' => continue,
+                        '}' => return Ok(Value::Object(entries)),
+                        c => bail!("Expected ',' or '}}' at {}, got {:?}", self.position - 1, c),
+                    }
+                }
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let start = self.position;
+                while self
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_digit() || "+-.eE".contains(c))
+                {
+                    self.position += 1;
+                }
+                let number: String = self.chars[start..self.position].iter().collect();
+                match number.parse() {
+                    Ok(number) => Ok(Value::Number(number)),
+                    Err(_) => bail!("Invalid number {:?} at {}", number, start),
+                }
+            }
+            Some(c) => bail!("Unexpected {:?} at {}", c, self.position),
+            None => bail!("Unexpected end of JSON"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut value = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(value),
+                '\\' => match self.next()? {
+                    'n' => value.push('\n'),
+                    'r' => value.push('\r'),
+                    't' => value.push('\t'),
+                    'b' => value.push('\u{8}'),
+                    'f' => value.push('\u{c}'),
+                    'u' => {
+                        let mut code = self.hex()?;
+                        // A surrogate pair for characters outside the basic plane
+                        if (0xd800..0xdc00).contains(&code) {
+                            self.expect('\\')?;
+                            self.expect('u')?;
+                            let low = self.hex()?;
+                            if !(0xdc00..=0xdfff).contains(&low) {
+                                bail!("Invalid low surrogate at {}", self.position - 6);
+                            }
+                            code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                        }
+                        value.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                    }
+                    c => value.push(c),
+                },
+                c => value.push(c),
+            }
+        }
+    }
+
+    fn hex(&mut self) -> Result<u32> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let c = self.next()?;
+            match c.to_digit(16) {
+                Some(digit) => code = code * 16 + digit,
+                None => bail!("Invalid unicode escape at {}", self.position - 1),
+            }
+        }
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn surrogate_pair() {
+        let value = parse(r#""\ud83d\ude00""#).unwrap();
+        assert_eq!(value.as_str(), Some("\u{1f600}"));
+    }
+
+    #[test]
+    fn invalid_low_surrogate() {
+        assert!(parse(r#""\ud83d\u0041""#).is_err());
+        assert!(parse(r#""\ud83d\uffff""#).is_err());
+    }
+
+    #[test]
+    fn nesting_limit() {
+        let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(parse(&"[".repeat(100_000)).is_err());
+    }
+}
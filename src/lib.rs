@@ -1 +1,87 @@
+pub mod adc;
+pub mod alert;
+pub mod base64;
+pub mod bench;
+#[cfg(all(feature = "ble", target_os = "linux"))]
+pub mod ble;
+pub mod board;
+#[cfg(unix)]
+pub mod bridge;
+pub mod call;
+pub mod chatter;
+pub mod checksum;
+pub mod circuitpython;
+pub mod compile;
+pub mod config;
+#[cfg(unix)]
+pub mod daemon;
+pub mod deflate;
+pub mod device;
+pub mod diagnose;
+pub mod diff;
+pub mod duration;
+pub mod encoding;
+pub mod esptool;
+pub mod exit;
+pub mod feed;
+pub mod fleet;
+pub mod fs;
+pub mod gpio;
+pub mod i2c;
+pub mod ignore;
+pub mod imports;
+pub mod interact;
+pub mod interrupt;
+pub mod inventory;
+pub mod json;
+pub mod lineedit;
+pub mod lock;
+pub mod logfile;
+pub mod magic;
+pub mod matcher;
+pub mod mem;
+pub mod minify;
+pub mod normalize;
+pub mod output;
+pub mod picotool;
+pub mod pins;
+pub mod plugin;
+pub mod port;
+pub mod preprocess;
+pub mod probe;
+pub mod progress;
+pub mod record;
+pub mod regex;
+pub mod repl;
+pub mod reset;
+pub mod rpc;
+pub mod rtc;
+pub mod script;
+pub mod sdcard;
 pub mod serial;
+pub mod session;
+pub mod sink;
+#[cfg(unix)]
+pub mod sniff;
+pub mod snippet;
+pub mod socket;
+pub mod split;
+pub mod stubs;
+pub mod subprocess;
+pub mod syntax;
+pub mod tap;
+pub mod tar;
+pub mod template;
+pub mod terminal;
+pub mod toml;
+pub mod trace;
+pub mod traceback;
+pub mod uf2;
+pub mod unittest;
+pub mod version;
+pub mod watch;
+pub mod wdt;
+#[cfg(unix)]
+pub mod webrepl;
+pub mod wifi;
+pub mod window;
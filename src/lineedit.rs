@@ -0,0 +1,763 @@
+//! Editing lines on the host before they're sent to the device's REPL. Over a serial link every
+//! key press takes a round trip to be echoed, so the line is edited locally with the usual
+//! readline keys, browsed from a history kept across sessions, and sent whole when Enter is
+//! pressed. The device's echo of the line is then dropped, as it's already on the screen.
+use anyhow::{bail, Result};
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{magic, repl};
+
+/// The most lines kept in the history file
+const MAX_HISTORY: usize = 1000;
+
+/// Inserted for Tab where there's nothing to complete, the indentation of MicroPython's REPL
+const INDENT: &str = "    ";
+
+/// How wide the list of completions is laid out
+const COMPLETIONS_WIDTH: usize = 80;
+
+/// The history file unless another is given, `~/.serpico_history`
+pub fn default_history_path() -> Option<PathBuf> {
+    match env::var_os("HOME") {
+        Some(home) if !home.is_empty() => Some(PathBuf::from(home).join(".serpico_history")),
+        _ => None,
+    }
+}
+
+/// The lines entered before, oldest first, saved to a file as they're entered
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct History {
+    path: Option<PathBuf>,
+    entries: Vec<String>,
+}
+
+impl History {
+    /// The history in the file at `path`, which is created when the first line is entered. Without
+    /// a path the history only lasts the session.
+    pub fn load(path: Option<&Path>) -> Result<History> {
+        let mut history = History {
+            path: path.map(Path::to_path_buf),
+            entries: Vec::new(),
+        };
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(history),
+        };
+        match fs::read_to_string(path) {
+            Ok(text) => history.entries = text.lines().map(String::from).collect(),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => bail!("Couldn't read history {}: {}", path.display(), e),
+        }
+        if history.entries.len() > MAX_HISTORY {
+            history.entries.drain(..history.entries.len() - MAX_HISTORY);
+            let mut text = history.entries.join("\n");
+            text.push('\n');
+            if let Err(e) = fs::write(path, text) {
+                bail!("Couldn't write history {}: {}", path.display(), e);
+            }
+        }
+        Ok(history)
+    }
+
+    /// Add an entered line, unless it's blank or the same as the last one
+    pub fn push(&mut self, line: &str) -> Result<()> {
+        if line.trim().is_empty() || self.entries.last().map(String::as_str) == Some(line) {
+            return Ok(());
+        }
+        self.entries.push(line.to_string());
+        if let Some(path) = &self.path {
+            let appended = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", line));
+            if let Err(e) = appended {
+                bail!("Couldn't write history {}: {}", path.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// The newest entry before `before` that contains `query`
+    fn search(&self, query: &str, before: usize) -> Option<usize> {
+        self.entries[..before.min(self.entries.len())]
+            .iter()
+            .rposition(|entry| entry.contains(query))
+    }
+}
+
+/// A key press, decoded from the bytes and escape sequences the terminal sends for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    /// A control character, by its letter
+    Ctrl(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Escape,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    WordLeft,
+    WordRight,
+    Unknown,
+}
+
+/// Decodes key presses, keeping a sequence that's split across reads until the rest arrives
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Keys {
+    pending: Vec<u8>,
+    /// A pasted `\r\n` is one Enter, even when the `\n` comes in the next read
+    after_cr: bool,
+}
+
+impl Keys {
+    fn decode(&mut self, bytes: &[u8]) -> Vec<Key> {
+        self.pending.extend_from_slice(bytes);
+        let mut keys = Vec::new();
+        let mut start = 0;
+        while start < self.pending.len() {
+            let (key, length) = match decode_key(&self.pending[start..]) {
+                Some(decoded) => decoded,
+                None => break,
+            };
+            let byte = self.pending[start];
+            if !(self.after_cr && byte == b'\n') {
+                keys.push(key);
+            }
+            self.after_cr = byte == b'\r';
+            start += length;
+        }
+        self.pending.drain(..start);
+        keys
+    }
+
+    /// Nothing more has arrived, so an Escape on its own was pressed rather than starting a
+    /// sequence
+    fn idle(&mut self) -> Option<Key> {
+        if self.pending == [0x1b] {
+            self.pending.clear();
+            Some(Key::Escape)
+        } else {
+            None
+        }
+    }
+}
+
+/// The key at the start of `bytes` and how many bytes it takes, or `None` if it isn't complete
+fn decode_key(bytes: &[u8]) -> Option<(Key, usize)> {
+    let key = match bytes[0] {
+        0x1b => return decode_escape(bytes),
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        0x7f | 0x08 => Key::Backspace,
+        byte @ 0x01..=0x1a => Key::Ctrl((b'a' + byte - 1) as char),
+        byte if byte < 0x20 => Key::Unknown,
+        byte if byte < 0x80 => Key::Char(byte as char),
+        byte => {
+            let length = match byte.leading_ones() {
+                2 => 2,
+                3 => 3,
+                4 => 4,
+                _ => return Some((Key::Unknown, 1)),
+            };
+            if bytes.len() < length {
+                return None;
+            }
+            let key = match std::str::from_utf8(&bytes[..length]) {
+                Ok(text) => Key::Char(text.chars().next().unwrap()),
+                Err(_) => Key::Unknown,
+            };
+            return Some((key, length));
+        }
+    };
+    Some((key, 1))
+}
+
+fn decode_escape(bytes: &[u8]) -> Option<(Key, usize)> {
+    match bytes.get(1)? {
+        b'[' => {
+            let end = 2 + bytes[2..]
+                .iter()
+                .position(|byte| (0x40..=0x7e).contains(byte))?;
+            let key = match &bytes[2..=end] {
+                b"A" => Key::Up,
+                b"B" => Key::Down,
+                b"C" => Key::Right,
+                b"D" => Key::Left,
+                b"H" | b"1~" | b"7~" => Key::Home,
+                b"F" | b"4~" | b"8~" => Key::End,
+                b"3~" => Key::Delete,
+                b"1;5C" | b"1;3C" => Key::WordRight,
+                b"1;5D" | b"1;3D" => Key::WordLeft,
+                _ => Key::Unknown,
+            };
+            Some((key, end + 1))
+        }
+        b'O' => {
+            let key = match bytes.get(2)? {
+                b'A' => Key::Up,
+                b'B' => Key::Down,
+                b'C' => Key::Right,
+                b'D' => Key::Left,
+                b'H' => Key::Home,
+                b'F' => Key::End,
+                _ => Key::Unknown,
+            };
+            Some((key, 3))
+        }
+        b'b' => Some((Key::WordLeft, 2)),
+        b'f' => Some((Key::WordRight, 2)),
+        _ => Some((Key::Escape, 1)),
+    }
+}
+
+/// Searching the history for an entry containing what's typed, with Ctrl-R
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Search {
+    query: String,
+    /// The entry shown, which stays when the query stops matching
+    found: Option<usize>,
+    failed: bool,
+}
+
+/// The names an object on the device has, as `dir()` lists them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Names {
+    names: Vec<String>,
+    /// Whether the object is a module or a class, whose names stay the same as lines are run, so
+    /// the device doesn't need to be asked again
+    lasting: bool,
+}
+
+impl Names {
+    /// Parse what [`names_query`] printed: the type of the object, then a name per line
+    fn parse(output: &[u8]) -> Names {
+        let output = String::from_utf8_lossy(output);
+        let mut lines = output.lines().map(str::trim);
+        let kind = lines.next().unwrap_or_default();
+        let mut names: Vec<String> = lines
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect();
+        names.sort();
+        names.dedup();
+        Names {
+            names,
+            lasting: kind == "module" || kind == "type",
+        }
+    }
+}
+
+/// A script printing the type of `object` and the names it has, or the globals and builtins for
+/// an empty `object`. It runs in the REPL's globals, so it doesn't assign anything.
+fn names_query(object: &str) -> String {
+    match object {
+        "" => {
+            "print('globals')\nprint('\\n'.join(dir() + dir(__import__('builtins'))))\n".to_string()
+        }
+        object => format!(
+            "print(type({0}).__name__)\nprint('\\n'.join(dir({0})))\n",
+            object
+        ),
+    }
+}
+
+/// Whether `expression` is only names and attributes, so it can be evaluated to complete it
+/// without running anything
+fn is_dotted_name(expression: &str) -> bool {
+    expression.split('.').all(|name| {
+        let mut chars = name.chars();
+        matches!(chars.next(), Some(c) if c.is_alphabetic() || c == '_')
+            && chars.all(|c| c.is_alphanumeric() || c == '_')
+    })
+}
+
+/// The line being edited and what the device has printed on the line before it
+pub struct LineEditor {
+    history: History,
+    keys: Keys,
+    line: Vec<char>,
+    /// Where in `line` the cursor is
+    cursor: usize,
+    /// What's on the screen before the line, from the device: its prompt, or the start of a line
+    /// a program is printing, without escape sequences
+    prompt: Vec<u8>,
+    /// Whether the device's output is in the middle of an escape sequence
+    in_escape: bool,
+    /// The entry of the history shown with Up and Down, and the line typed before browsing it
+    browsing: Option<(usize, Vec<char>)>,
+    search: Option<Search>,
+    /// The last text removed with Ctrl-K, Ctrl-U or Ctrl-W, put back with Ctrl-Y
+    killed: Vec<char>,
+    /// The line sent to the device, which it echoes back
+    echo: VecDeque<u8>,
+    /// The names of objects on the device looked up for Tab, by the expression for the object
+    names: HashMap<String, Names>,
+    /// Lines entered that are commands for serpico rather than the device
+    commands: VecDeque<String>,
+}
+
+impl LineEditor {
+    pub fn new(history: History) -> LineEditor {
+        LineEditor {
+            history,
+            keys: Keys::default(),
+            line: Vec::new(),
+            cursor: 0,
+            prompt: Vec::new(),
+            in_escape: false,
+            browsing: None,
+            search: None,
+            killed: Vec::new(),
+            echo: VecDeque::new(),
+            names: HashMap::new(),
+            commands: VecDeque::new(),
+        }
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// Handle keys typed on the host, returning what to send to the device. Tab completes names
+    /// from the device, running scripts with `eval` to ask it what they are.
+    pub fn keys(
+        &mut self,
+        bytes: &[u8],
+        screen: &mut dyn Write,
+        eval: &mut dyn FnMut(&str) -> Result<Vec<u8>>,
+    ) -> Result<Vec<u8>> {
+        let mut send = Vec::new();
+        for key in self.keys.decode(bytes) {
+            match key {
+                Key::Tab => self.complete(screen, eval)?,
+                key => self.key(key, &mut send)?,
+            }
+        }
+        self.draw(screen)?;
+        Ok(send)
+    }
+
+    /// The next line entered that's a command for serpico, like `%run file.py`
+    pub fn take_command(&mut self) -> Option<String> {
+        self.commands.pop_front()
+    }
+
+    /// A command has been run and whatever it showed is below its line, so the prompt is shown
+    /// again for the next line. The device printed nothing for the command, it's still at its
+    /// prompt.
+    pub fn command_done(&mut self, screen: &mut dyn Write) -> io::Result<()> {
+        self.prompt = b">>> ".to_vec();
+        self.draw(screen)
+    }
+
+    /// Nothing has been typed for a while
+    pub fn idle(&mut self, screen: &mut dyn Write) -> Result<Vec<u8>> {
+        let mut send = Vec::new();
+        if let Some(key) = self.keys.idle() {
+            self.key(key, &mut send)?;
+            self.draw(screen)?;
+        }
+        Ok(send)
+    }
+
+    /// Handle pasted text. A block of lines goes to the device's paste mode with the line typed so
+    /// far in front of it, a single line is added to the line being edited.
+    pub fn paste(&mut self, text: &[u8], screen: &mut dyn Write) -> Result<Vec<u8>> {
+        let mut send = Vec::new();
+        if repl::is_block(text) {
+            self.search = None;
+            let mut block: Vec<u8> = self.line.iter().collect::<String>().into_bytes();
+            block.extend_from_slice(text);
+            send = repl::paste_mode(&block);
+            self.echo.clear();
+            self.finish_line();
+        } else {
+            let text = String::from_utf8_lossy(text);
+            let line = text.trim_end_matches(['\r', '\n']);
+            self.accept_search();
+            self.insert(&line.chars().collect::<Vec<_>>());
+            if line.len() < text.len() {
+                self.key(Key::Enter, &mut send)?;
+            }
+        }
+        self.draw(screen)?;
+        Ok(send)
+    }
+
+    /// Show output from the device, without its echo of the line sent, above the line being
+    /// edited
+    pub fn received(&mut self, bytes: &[u8], screen: &mut dyn Write) -> io::Result<()> {
+        let mut shown = Vec::with_capacity(bytes.len());
+        for &byte in bytes {
+            if self.echo.front() == Some(&byte) {
+                self.echo.pop_front();
+            } else {
+                self.echo.clear();
+                shown.push(byte);
+            }
+        }
+        if shown.is_empty() {
+            return Ok(());
+        }
+
+        let editing = !self.line.is_empty() || self.search.is_some();
+        if editing {
+            // Back to the end of the device's line, where the output continues
+            screen.write_all(b"\r\x1b[K")?;
+            screen.write_all(&self.prompt)?;
+        }
+        screen.write_all(&shown)?;
+        self.track_prompt(&shown);
+        if editing {
+            self.draw(screen)?;
+        }
+        screen.flush()
+    }
+
+    /// Follow what the device prints on its current line, so it can be redrawn
+    fn track_prompt(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.in_escape {
+                self.in_escape = !(0x40..=0x7e).contains(&byte) || byte == b'[';
+                continue;
+            }
+            match byte {
+                0x1b => self.in_escape = true,
+                b'\r' | b'\n' => self.prompt.clear(),
+                0x08 => {
+                    self.prompt.pop();
+                }
+                byte if byte < 0x20 => {}
+                byte => self.prompt.push(byte),
+            }
+        }
+    }
+
+    fn key(&mut self, key: Key, send: &mut Vec<u8>) -> Result<()> {
+        if self.search.is_some() {
+            match key {
+                Key::Ctrl('r') => self.search_older(),
+                Key::Char(c) => self.search_query(|query| query.push(c)),
+                Key::Backspace => self.search_query(|query| {
+                    query.pop();
+                }),
+                Key::Ctrl('g') | Key::Ctrl('c') | Key::Escape => self.search = None,
+                // Any other key takes the entry found to edit, and then does what it does
+                _ => {
+                    self.accept_search();
+                    return self.key(key, send);
+                }
+            }
+            return Ok(());
+        }
+
+        match key {
+            Key::Char(c) => self.insert(&[c]),
+            // Commands for serpico are only taken at the prompt, anything else might be input
+            Key::Enter if self.line.first() == Some(&magic::PREFIX) && self.prompt == b">>> " => {
+                let line: String = self.line.iter().collect();
+                self.history.push(&line)?;
+                self.commands.push_back(line);
+                self.finish_line();
+            }
+            Key::Enter => {
+                let line: String = self.line.iter().collect();
+                self.history.push(&line)?;
+                send.extend_from_slice(line.as_bytes());
+                send.push(b'\r');
+                self.echo = line.bytes().collect();
+                self.finish_line();
+            }
+            Key::Ctrl('c') => {
+                send.push(0x03);
+                self.echo.clear();
+                self.finish_line();
+            }
+            // On an empty line these are the device's: Ctrl-D soft reboots or ends paste mode,
+            // Ctrl-E starts paste mode, and Backspace takes back the REPL's indentation
+            Key::Ctrl('d') if self.line.is_empty() => send.push(0x04),
+            Key::Ctrl('e') if self.line.is_empty() => send.push(0x05),
+            Key::Backspace if self.line.is_empty() => send.push(0x08),
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            Key::Delete | Key::Ctrl('d') if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            Key::Left | Key::Ctrl('b') => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right | Key::Ctrl('f') => self.cursor = (self.cursor + 1).min(self.line.len()),
+            Key::Home | Key::Ctrl('a') => self.cursor = 0,
+            Key::End | Key::Ctrl('e') => self.cursor = self.line.len(),
+            Key::WordLeft => self.cursor = self.word_start(),
+            Key::WordRight => self.cursor = self.word_end(),
+            Key::Ctrl('k') => self.kill(self.cursor, self.line.len()),
+            Key::Ctrl('u') => self.kill(0, self.cursor),
+            Key::Ctrl('w') => self.kill(self.word_start(), self.cursor),
+            Key::Ctrl('y') => self.insert(&self.killed.clone()),
+            Key::Up | Key::Ctrl('p') => self.browse_older(),
+            Key::Down | Key::Ctrl('n') => self.browse_newer(),
+            Key::Ctrl('r') => self.search = Some(Search::default()),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Complete the name before the cursor, or list what it could be, indenting if there's no name
+    fn complete(
+        &mut self,
+        screen: &mut dyn Write,
+        eval: &mut dyn FnMut(&str) -> Result<Vec<u8>>,
+    ) -> io::Result<()> {
+        self.accept_search();
+        let start = self.line[..self.cursor]
+            .iter()
+            .rposition(|&c| !(c.is_alphanumeric() || c == '_' || c == '.'))
+            .map_or(0, |i| i + 1);
+        let word: String = self.line[start..self.cursor].iter().collect();
+        if word.is_empty() {
+            self.insert(&INDENT.chars().collect::<Vec<_>>());
+            return Ok(());
+        }
+        let (object, partial) = match word.rfind('.') {
+            Some(dot) => (&word[..dot], &word[dot + 1..]),
+            None => ("", word.as_str()),
+        };
+        if !object.is_empty() && !is_dotted_name(object) {
+            return Ok(());
+        }
+        let names = match self.names_of(object, eval) {
+            Some(names) => names,
+            None => return Ok(()),
+        };
+
+        // Private names only when they're being typed
+        let candidates: Vec<&String> = names
+            .iter()
+            .filter(|name| name.starts_with(partial))
+            .filter(|name| !name.starts_with('_') || partial.starts_with('_'))
+            .collect();
+        let first = match candidates.first() {
+            Some(first) => first.as_str(),
+            None => return Ok(()),
+        };
+        let common = candidates.iter().fold(first, |common, name| {
+            let length = common
+                .char_indices()
+                .zip(name.chars())
+                .find(|((_, a), b)| a != b)
+                .map_or(common.len().min(name.len()), |((i, _), _)| i);
+            &common[..length]
+        });
+        if common.len() > partial.len() {
+            let rest: Vec<char> = common[partial.len()..].chars().collect();
+            self.insert(&rest);
+        } else if candidates.len() > 1 {
+            self.list(screen, &candidates)?;
+        }
+        Ok(())
+    }
+
+    /// The names `object` has, from the cache or asked of the device. The device is only asked at
+    /// its prompt, as a program that's running would read the script as its input.
+    fn names_of(
+        &mut self,
+        object: &str,
+        eval: &mut dyn FnMut(&str) -> Result<Vec<u8>>,
+    ) -> Option<Vec<String>> {
+        if let Some(names) = self.names.get(object) {
+            return Some(names.names.clone());
+        }
+        if self.prompt != b">>> " {
+            return None;
+        }
+        let names = Names::parse(&eval(&names_query(object)).ok()?);
+        self.names.insert(object.to_string(), names.clone());
+        Some(names.names)
+    }
+
+    /// Show the completions below the line, which is drawn again after them
+    fn list(&self, screen: &mut dyn Write, names: &[&String]) -> io::Result<()> {
+        let column = names
+            .iter()
+            .map(|name| name.chars().count())
+            .max()
+            .unwrap_or(0)
+            + 2;
+        let columns = (COMPLETIONS_WIDTH / column).max(1);
+        screen.write_all(b"\r\n")?;
+        for row in names.chunks(columns) {
+            let row: Vec<String> = row.iter().map(|name| format!("{:column$}", name)).collect();
+            write!(screen, "{}\r\n", row.concat().trim_end())?;
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, chars: &[char]) {
+        self.line
+            .splice(self.cursor..self.cursor, chars.iter().copied());
+        self.cursor += chars.len();
+    }
+
+    fn kill(&mut self, start: usize, end: usize) {
+        if start < end {
+            self.killed = self.line.drain(start..end).collect();
+            self.cursor = start;
+        }
+    }
+
+    /// Where the word before the cursor starts
+    fn word_start(&self) -> usize {
+        let before = &self.line[..self.cursor];
+        let end = before
+            .iter()
+            .rposition(|c| c.is_alphanumeric())
+            .map_or(0, |i| i + 1);
+        before[..end]
+            .iter()
+            .rposition(|c| !c.is_alphanumeric())
+            .map_or(0, |i| i + 1)
+    }
+
+    /// Where the word after the cursor ends
+    fn word_end(&self) -> usize {
+        let after = &self.line[self.cursor..];
+        let start = after
+            .iter()
+            .position(|c| c.is_alphanumeric())
+            .unwrap_or(after.len());
+        self.cursor
+            + after[start..]
+                .iter()
+                .position(|c| !c.is_alphanumeric())
+                .map_or(after.len(), |i| start + i)
+    }
+
+    /// The line is done with, and stays on the screen as part of the device's line
+    fn finish_line(&mut self) {
+        self.prompt
+            .extend(self.line.iter().collect::<String>().bytes());
+        self.line.clear();
+        self.cursor = 0;
+        self.browsing = None;
+        // The line may change anything but modules and classes
+        self.names.retain(|_, names| names.lasting);
+    }
+
+    fn browse_older(&mut self) {
+        let index = match &self.browsing {
+            Some((0, _)) => return,
+            Some((index, _)) => index - 1,
+            None => match self.history.entries().len().checked_sub(1) {
+                Some(index) => index,
+                None => return,
+            },
+        };
+        let typed = match self.browsing.take() {
+            Some((_, typed)) => typed,
+            None => self.line.clone(),
+        };
+        self.browsing = Some((index, typed));
+        self.set_line(self.history.entries()[index].chars().collect());
+    }
+
+    fn browse_newer(&mut self) {
+        match self.browsing.take() {
+            Some((index, typed)) if index + 1 >= self.history.entries().len() => {
+                self.set_line(typed)
+            }
+            Some((index, typed)) => {
+                self.browsing = Some((index + 1, typed));
+                self.set_line(self.history.entries()[index + 1].chars().collect());
+            }
+            None => {}
+        }
+    }
+
+    fn set_line(&mut self, line: Vec<char>) {
+        self.cursor = line.len();
+        self.line = line;
+    }
+
+    /// Change the query, and look for it from the entry found, which may still have it
+    fn search_query(&mut self, change: impl FnOnce(&mut String)) {
+        let entries = self.history.entries().len();
+        let search = self.search.as_mut().unwrap();
+        change(&mut search.query);
+        let from = search.found.map_or(entries, |found| found + 1);
+        match self.history.search(&search.query, from) {
+            Some(found) => {
+                search.found = Some(found);
+                search.failed = false;
+            }
+            None => search.failed = true,
+        }
+    }
+
+    /// Look for an older entry with the query
+    fn search_older(&mut self) {
+        let entries = self.history.entries().len();
+        let search = self.search.as_mut().unwrap();
+        let before = search.found.unwrap_or(entries);
+        match self.history.search(&search.query, before) {
+            Some(found) => {
+                search.found = Some(found);
+                search.failed = false;
+            }
+            None => search.failed = true,
+        }
+    }
+
+    fn accept_search(&mut self) {
+        if let Some(Search {
+            found: Some(found), ..
+        }) = self.search.take()
+        {
+            self.browsing = None;
+            self.set_line(self.history.entries()[found].chars().collect());
+        }
+    }
+
+    /// Redraw the line after the device's prompt, with the cursor where it's being edited
+    fn draw(&self, screen: &mut dyn Write) -> io::Result<()> {
+        screen.write_all(b"\r\x1b[K")?;
+        match &self.search {
+            Some(search) => {
+                let found = match search.found {
+                    Some(found) => self.history.entries()[found].as_str(),
+                    None => "",
+                };
+                let failed = if search.failed { "failed " } else { "" };
+                write!(
+                    screen,
+                    "({}reverse-i-search)`{}': {}",
+                    failed, search.query, found
+                )?;
+            }
+            None => {
+                screen.write_all(&self.prompt)?;
+                screen.write_all(self.line.iter().collect::<String>().as_bytes())?;
+                let after = self.line.len() - self.cursor;
+                if after > 0 {
+                    write!(screen, "\x1b[{}D", after)?;
+                }
+            }
+        }
+        screen.flush()
+    }
+}
@@ -0,0 +1,233 @@
+//! Advisory locks that keep serpico processes from talking to the same board at once, which would
+//! interleave their bytes on its port and break both sessions. A board is locked by its USB serial
+//! number when it has one, so the lock holds wherever it's plugged in, and otherwise by its path.
+use anyhow::{bail, Result};
+use serialport::SerialPortType;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+#[cfg(not(unix))]
+use std::{thread::sleep, time::Duration};
+
+/// A lock on a board, released when dropped
+#[derive(Debug)]
+pub struct DeviceLock {
+    _file: Arc<LockFile>,
+}
+
+/// A lock file that holds the lock for as long as it's open
+#[derive(Debug)]
+struct LockFile {
+    file: File,
+    /// Without flock, the lock is the file itself, released by removing it
+    #[cfg(not(unix))]
+    path: PathBuf,
+}
+
+#[cfg(not(unix))]
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// The locks this process holds, shared by devices that open the same board again before the one
+/// before has been dropped, such as when reconnecting
+static HELD: Mutex<Vec<(PathBuf, Weak<LockFile>)>> = Mutex::new(Vec::new());
+
+/// How often a lock file held by another process is checked for, on platforms without flock
+#[cfg(not(unix))]
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Lock the board at `device`. If another process has it locked, wait for it to be released when
+/// `wait` is set and fail otherwise, naming the process.
+pub fn acquire(device: &Path, wait: bool) -> Result<DeviceLock> {
+    let dir = lock_dir();
+    if let Err(e) = fs::create_dir_all(&dir) {
+        bail!("Couldn't create {}: {}", dir.display(), e);
+    }
+    let path = dir.join(format!("{}.lock", sanitize(&lock_name(device))));
+    let mut held = HELD.lock().unwrap();
+    held.retain(|(_, file)| file.strong_count() > 0);
+    let shared = held
+        .iter()
+        .find(|(held, _)| *held == path)
+        .and_then(|(_, file)| file.upgrade());
+    if let Some(file) = shared {
+        return Ok(DeviceLock { _file: file });
+    }
+
+    let mut lock = lock(&path, device, wait)?;
+    // The holder is recorded for processes that find the board locked
+    let command: Vec<String> = env::args().collect();
+    lock.file.set_len(0)?;
+    lock.file.rewind()?;
+    write!(lock.file, "{}\n{}\n", std::process::id(), command.join(" "))?;
+    lock.file.flush()?;
+    let lock = Arc::new(lock);
+    held.push((path, Arc::downgrade(&lock)));
+    Ok(DeviceLock { _file: lock })
+}
+
+/// Fail for `device` being locked by `holder`, unless told to wait for it
+fn in_use(device: &Path, holder: &str, wait: bool) -> Result<()> {
+    if !wait {
+        bail!(
+            "{} is in use by {}, use --queue to wait for it to finish",
+            device.display(),
+            holder
+        );
+    }
+    eprintln!("Waiting for {} to finish with {}", holder, device.display());
+    Ok(())
+}
+
+/// Open the lock file at `path` and lock it with flock
+#[cfg(unix)]
+fn lock(path: &Path, device: &Path, wait: bool) -> Result<LockFile> {
+    let mut file = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(e) => bail!("Couldn't open {}: {}", path.display(), e),
+    };
+    if !try_lock(&file, false)? {
+        in_use(device, &holder(&mut file), wait)?;
+        try_lock(&file, true)?;
+    }
+    Ok(LockFile { file })
+}
+
+/// Create the lock file at `path`, which only one process can do until it's removed again
+#[cfg(not(unix))]
+fn lock(path: &Path, device: &Path, wait: bool) -> Result<LockFile> {
+    let mut waiting = false;
+    loop {
+        match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)
+        {
+            Ok(file) => {
+                return Ok(LockFile {
+                    file,
+                    path: path.to_path_buf(),
+                })
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => bail!("Couldn't create {}: {}", path.display(), e),
+        }
+        if !waiting {
+            let holder = match File::open(path) {
+                Ok(mut file) => holder(&mut file),
+                Err(_) => String::from("another process"),
+            };
+            in_use(device, &holder, wait)?;
+            waiting = true;
+        }
+        sleep(LOCK_POLL_INTERVAL);
+    }
+}
+
+/// Take the lock, returning whether it was taken. Unless `block` is set, this returns right away
+/// if another process has it.
+#[cfg(unix)]
+fn try_lock(file: &File, block: bool) -> Result<bool> {
+    let operation = if block {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_EX | libc::LOCK_NB
+    };
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(true);
+        }
+        let e = io::Error::last_os_error();
+        match e.kind() {
+            io::ErrorKind::Interrupted => continue,
+            io::ErrorKind::WouldBlock => return Ok(false),
+            _ => bail!("Couldn't lock the device: {}", e),
+        }
+    }
+}
+
+/// The process holding the lock, as recorded in the lock file
+fn holder(file: &mut File) -> String {
+    let mut recorded = String::new();
+    let _ = file.read_to_string(&mut recorded);
+    let mut lines = recorded.lines();
+    match (lines.next(), lines.next()) {
+        (Some(pid), Some(command)) => format!("PID {} ({})", pid, command),
+        (Some(pid), None) => format!("PID {}", pid),
+        _ => String::from("another process"),
+    }
+}
+
+/// What the board is locked by, its serial number if it has one
+fn lock_name(device: &Path) -> String {
+    let target = fs::canonicalize(device).unwrap_or_else(|_| device.to_path_buf());
+    let serial_number = serialport::available_ports()
+        .unwrap_or_default()
+        .into_iter()
+        .find(|port| fs::canonicalize(&port.port_name).is_ok_and(|path| path == target))
+        .and_then(|port| match port.port_type {
+            SerialPortType::UsbPort(usb) => usb.serial_number,
+            _ => None,
+        });
+    match serial_number {
+        Some(serial_number) => format!("serial-{}", serial_number),
+        None => format!("path-{}", target.display()),
+    }
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn lock_dir() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("serpico-locks"),
+        _ => env::temp_dir().join(format!("serpico-{}-locks", unsafe { libc::getuid() })),
+    }
+}
+
+/// The temp directory is the user's own on Windows
+#[cfg(not(unix))]
+fn lock_dir() -> PathBuf {
+    env::temp_dir().join("serpico-locks")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_lock_is_refused_until_released() {
+        let path = env::temp_dir().join(format!("serpico-test-{}.lock", std::process::id()));
+        let device = Path::new("/dev/ttyACM9");
+        let first = lock(&path, device, false).unwrap();
+        let error = lock(&path, device, false).unwrap_err();
+        assert!(error.to_string().contains("is in use by"), "{}", error);
+        drop(first);
+        drop(lock(&path, device, false).unwrap());
+        let _ = fs::remove_file(&path);
+    }
+}
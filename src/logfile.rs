@@ -0,0 +1,127 @@
+//! Logging everything the device prints to a file, with a timestamp on each line. The output of
+//! scripts, of `--follow` and of the REPL goes to the log installed with [`install`], whether or
+//! not it's echoed to the terminal.
+use anyhow::{bail, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::output::format_time;
+
+static LOG: Mutex<Option<OutputLog>> = Mutex::new(None);
+
+/// Send the device's output to `log` from now on
+pub fn install(log: OutputLog) {
+    *LOG.lock().unwrap_or_else(|e| e.into_inner()) = Some(log);
+}
+
+/// Add output of the device to the installed log, if there is one
+pub fn write(bytes: &[u8]) {
+    if let Some(log) = LOG.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        log.write(bytes);
+    }
+}
+
+/// Write out the partial line the output ended with, if any
+pub fn finish() {
+    if let Some(log) = LOG.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        log.finish();
+    }
+}
+
+/// Appends lines of output to a file. Control characters are left out.
+pub struct OutputLog {
+    path: PathBuf,
+    out: BufWriter<File>,
+    /// How large the file has grown, and how large it may grow before it's rotated
+    size: u64,
+    max_size: Option<u64>,
+    line: Vec<u8>,
+    line_start: Option<SystemTime>,
+}
+
+impl OutputLog {
+    /// Log to the file at `path`, adding to what it already holds. Once the file grows past
+    /// `max_size` it's renamed to `path.1`, replacing the previous one, and a new file is started.
+    pub fn create(path: &Path, max_size: Option<u64>) -> Result<OutputLog> {
+        let file = open(path)?;
+        let size = file.metadata()?.len();
+        Ok(OutputLog {
+            path: path.to_path_buf(),
+            out: BufWriter::new(file),
+            size,
+            max_size,
+            line: Vec::new(),
+            line_start: None,
+        })
+    }
+
+    fn write_line(&mut self) {
+        let time = self.line_start.take().unwrap_or_else(SystemTime::now);
+        let line = format!(
+            "{} {}\n",
+            format_time(time),
+            String::from_utf8_lossy(&self.line)
+        );
+        self.line.clear();
+        if self
+            .max_size
+            .is_some_and(|max_size| self.size > 0 && self.size + line.len() as u64 > max_size)
+        {
+            // The log carries on in the old file if it can't be rotated
+            let _ = self.rotate();
+        }
+        if self.out.write_all(line.as_bytes()).is_ok() {
+            self.size += line.len() as u64;
+        }
+        let _ = self.out.flush();
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.out.flush()?;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+        self.out = BufWriter::new(open(&self.path)?);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> Result<File> {
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => Ok(file),
+        Err(e) => bail!("Couldn't open log file {}: {}", path.display(), e),
+    }
+}
+
+impl OutputLog {
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            match byte {
+                b'\n' => self.write_line(),
+                b'\t' | 0x20.. if byte != 0x7f => {
+                    if self.line_start.is_none() {
+                        self.line_start = Some(SystemTime::now());
+                    }
+                    self.line.push(byte);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn finish(&mut self) {
+        if !self.line.is_empty() {
+            self.write_line();
+        }
+    }
+}
+
+impl Drop for OutputLog {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
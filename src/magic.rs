@@ -0,0 +1,166 @@
+//! Commands typed at the prompt of the edited REPL that serpico runs on the host, like
+//! `%run file.py`, while every other line goes to the device as it is
+use anyhow::{bail, Result};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::device::Device;
+use crate::fs::{self, Transfer};
+use crate::script::quote;
+use crate::serial::{eval, execute, return_to_prompt, ExecOptions, Script};
+
+/// What starts a line that's a command for serpico
+pub const PREFIX: char = '%';
+
+/// The commands, for `%help`
+const HELP: &str = "\
+%run FILE            Run a local file on the device, keeping the REPL's variables
+%put FILE [REMOTE]   Copy a local file to the device, into the root by default
+%ls [DIR]            List a directory on the device
+%time CODE           Run a statement or expression, timing it on the device
+%help                Show this list";
+
+/// Lists a directory, a line for each entry with whether it's a directory, its size and its name.
+/// `_dir` is set ahead of it.
+const LS: &str = "\
+import os as _os
+_entry = None
+for _entry in _os.ilistdir(_dir):
+    print('d' if _entry[1] & 0x4000 else 'f', _entry[3] if len(_entry) > 3 else 0, _entry[0])
+del _os, _dir, _entry
+";
+
+/// Runs `_source` in the REPL's globals, printing the result of an expression like the REPL does,
+/// then how many microseconds it took
+const TIME: &str = "\
+def _serpico_time(source):
+    import time
+    try:
+        code = compile(source, '<stdin>', 'eval')
+    except SyntaxError:
+        code = compile(source, '<stdin>', 'exec')
+    start = time.ticks_us()
+    result = eval(code, globals())
+    elapsed = time.ticks_diff(time.ticks_us(), start)
+    if result is not None:
+        print(repr(result))
+    print('Time: %d us' % elapsed)
+try:
+    _serpico_time(_source)
+finally:
+    del _serpico_time, _source
+";
+
+/// A command for serpico, parsed from a line typed at the REPL's prompt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Magic {
+    Run(PathBuf),
+    Put(PathBuf, Option<String>),
+    Ls(Option<String>),
+    Time(String),
+    Help,
+}
+
+impl Magic {
+    /// The command on `line`, which starts with [`PREFIX`]
+    pub fn parse(line: &str) -> Result<Magic> {
+        let line = line.trim().trim_start_matches(PREFIX);
+        let (name, rest) = match line.split_once(char::is_whitespace) {
+            Some((name, rest)) => (name, rest.trim()),
+            None => (line, ""),
+        };
+        let words: Vec<&str> = rest.split_whitespace().collect();
+        match (name, words.as_slice()) {
+            ("run", [file]) => Ok(Magic::Run(PathBuf::from(file))),
+            ("put", [file]) => Ok(Magic::Put(PathBuf::from(file), None)),
+            ("put", [file, remote]) => {
+                Ok(Magic::Put(PathBuf::from(file), Some(remote.to_string())))
+            }
+            ("ls", []) => Ok(Magic::Ls(None)),
+            ("ls", [dir]) => Ok(Magic::Ls(Some(dir.to_string()))),
+            ("time", [_, ..]) => Ok(Magic::Time(rest.to_string())),
+            ("help", []) => Ok(Magic::Help),
+            ("run" | "put" | "ls" | "time" | "help", _) => {
+                bail!("Wrong arguments for %{}, %help shows how to use it", name)
+            }
+            _ => bail!("Unknown command %{}, %help lists them", name),
+        }
+    }
+
+    /// Run the command, writing what it has to show to `screen`. The device is expected to be at
+    /// the friendly REPL's prompt, and is left there.
+    pub fn run(&self, device: &mut Device, screen: &mut dyn Write) -> Result<()> {
+        let result = match self {
+            Magic::Run(path) => run_file(device, path),
+            Magic::Put(local, remote) => put(device, local, remote.as_deref(), screen),
+            Magic::Ls(dir) => ls(device, dir.as_deref().unwrap_or("/"), screen),
+            Magic::Time(code) => time(device, code, screen),
+            Magic::Help => {
+                writeln!(screen, "{}", HELP)?;
+                return Ok(());
+            }
+        };
+        // Back to the prompt whether or not the command worked, it's gone through the raw REPL
+        let returned = return_to_prompt(device, None);
+        result?;
+        returned
+    }
+}
+
+fn run_file(device: &mut Device, path: &Path) -> Result<()> {
+    let options = ExecOptions {
+        soft_reset: false,
+        terminal: true,
+        ..ExecOptions::default()
+    };
+    execute(device, Script::open(path)?, &options)?;
+    Ok(())
+}
+
+fn put(
+    device: &mut Device,
+    local: &Path,
+    remote: Option<&str>,
+    screen: &mut dyn Write,
+) -> Result<()> {
+    let remote = match remote {
+        Some(remote) => remote.to_string(),
+        None => match local.file_name() {
+            Some(name) => fs::join("/", &name.to_string_lossy()),
+            None => bail!("Couldn't get the file name of {}", local.display()),
+        },
+    };
+    let transfer = Transfer::negotiate(device, true, None)?;
+    let data = fs::put(device, local, &remote, &transfer, None)?;
+    writeln!(screen, "Copied {} bytes to {}", data.len(), remote)?;
+    Ok(())
+}
+
+fn ls(device: &mut Device, dir: &str, screen: &mut dyn Write) -> Result<()> {
+    let script = format!("_dir = {}\n{}", quote(dir), LS);
+    let output = eval(device, &script, None)?;
+    let mut entries: Vec<(bool, &str, &str)> = Vec::new();
+    let output = String::from_utf8_lossy(&output);
+    for line in output.lines() {
+        let mut fields = line.trim_end().splitn(3, ' ');
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(kind), Some(size), Some(name)) => entries.push((kind == "d", size, name)),
+            _ => bail!("Unexpected directory listing from device: {:?}", line),
+        }
+    }
+    entries.sort_by(|a, b| b.0.cmp(&a.0).then(a.2.cmp(b.2)));
+    for (is_dir, size, name) in entries {
+        match is_dir {
+            true => writeln!(screen, "{:>9}  {}/", "", name)?,
+            false => writeln!(screen, "{:>9}  {}", size, name)?,
+        }
+    }
+    Ok(())
+}
+
+fn time(device: &mut Device, code: &str, screen: &mut dyn Write) -> Result<()> {
+    let script = format!("_source = {}\n{}", quote(code), TIME);
+    let output = eval(device, &script, None)?;
+    screen.write_all(&output)?;
+    Ok(())
+}
@@ -1,81 +1,4348 @@
 use anyhow::{bail, Result};
-use clap::Parser;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueSource};
+use std::env;
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::prelude::*;
-use std::path::PathBuf;
+use std::io::{self, prelude::*, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use serpico::serial::{execute, find_micropython_devices};
+use serialport::FlowControl;
+use serpico::alert::{Alerts, Trigger};
+use serpico::bench::{self, Direction};
+use serpico::board::Quirks;
+use serpico::chatter::EchoPolicy;
+use serpico::config::{self, Config, Hooks};
+use serpico::device::{self, Device};
+use serpico::diagnose::diagnose;
+use serpico::encoding::{Decoding, Encoding};
+use serpico::ignore::Ignore;
+use serpico::interact::Interaction;
+use serpico::inventory;
+use serpico::lineedit::{default_history_path, History, LineEditor};
+use serpico::logfile::OutputLog;
+use serpico::mem::Heap;
+use serpico::normalize::Normalizer;
+use serpico::output::{self, Echo, LineFilter, Timestamps};
+use serpico::port::{self, PortBuilder};
+use serpico::preprocess::Preprocessor;
+use serpico::record::{self, RecordWriter};
+use serpico::regex::Regex;
+use serpico::repl::repl;
+use serpico::reset::ResetStrategy;
+use serpico::sdcard::{self, SdCard};
+use serpico::serial::{
+    discover_micropython_devices, discover_usb_serial_ports, eval, execute, exit_raw_repl,
+    find_micropython_devices, follow, follow_until, listen, monitor, soft_reset, wait_for_device,
+    watch_devices, Channel, DeviceEvent, DeviceInfo, Disconnected, ExecError, ExecMode,
+    ExecOptions, ExecResult, Firmware, Interrupted, MultipleDevices, NoDevice, Rebooted, Script,
+};
+use serpico::session::{Recorder, ReplayPort, Session};
+use serpico::sink::{FileSink, JsonLines, SharedSink};
+use serpico::split::{self, Part, Split};
+use serpico::traceback::{Frame, SourceMap};
+use serpico::watch::Watcher;
+use serpico::window::Window;
+use serpico::{
+    adc, call, checksum, compile, diff, duration, esptool, exit, feed, fleet, fs, gpio, i2c,
+    imports, interrupt, json, logfile, mem, minify, picotool, pins, plugin, preprocess, probe,
+    progress, rpc, rtc, script, snippet, stubs, syntax, tar, template, uf2, unittest, version, wdt,
+    wifi,
+};
+#[cfg(unix)]
+use serpico::{bridge, daemon, sniff};
+
+/// How long to wait for a disconnected device to reappear
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often watched files are checked, and how long they have to stay unchanged after being saved
+const WATCH_INTERVAL: Duration = Duration::from_millis(100);
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// How long a board may take to show up in its bootloader, and to come back after flashing
+const FLASH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long making a filesystem may take, which erases the flash on some ports
+const FORMAT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Resets a board, so it boots into files just copied to it
+const RESET: &str = "import machine
+machine.reset()
+";
 
 #[derive(Parser, Debug)]
-#[clap(author, version, about)]
+#[clap(author, version, about, after_long_help = exit::HELP)]
 struct Args {
-    /// A file to execute on the MicroPython device
-    #[clap(value_parser)]
-    file: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
 
     /// An optional device to connect to, if not provided, Serpico will try to discover and use a
-    /// a discovered MicroPython device, only if one is found.
-    #[clap(short, long)]
+    /// a discovered MicroPython device, only if one is found. A `ws://` URL connects to the
+    /// device's WebREPL, a `tcp://host:port` URL to a REPL served over TCP and, with the `ble`
+    /// feature, `ble://AA:BB:CC:DD:EE:FF` to one served over Bluetooth's Nordic UART Service.
+    /// `micropython://` runs scripts on the unix port, `micropython:///path/to/micropython` on a
+    /// particular one.
+    #[clap(short, long, global = true)]
     device: Option<PathBuf>,
 
-    /// Optional timeout in seconds to set while waiting to read a message. If no timeout set, then
-    /// serpico will wait forever for messages.
-    #[clap(short, long)]
-    timeout: Option<usize>,
-
     /// Just print out the discovered MicroPython device and exit
     #[clap(short, long)]
     print_discovery: bool,
 
-    /// Verbose logging
+    /// Baud rate to use for the serial connection
+    #[clap(short, long, global = true, default_value_t = port::DEFAULT_BAUD_RATE)]
+    baud: u32,
+
+    /// Flow control to use for the serial connection: none, rtscts or xonxoff
+    #[clap(long, global = true, default_value = "none", value_parser = port::parse_flow_control)]
+    flow: FlowControl,
+
+    /// Reset the board with the DTR/RTS lines after connecting: none, esp32, esp32-bootloader or a
+    /// custom sequence such as `D0|R1|W0.1|R0`. Unless one is given, ESP boards behind a USB
+    /// serial bridge have both lines released, and other boards are left as they are.
+    #[clap(long, global = true, default_value = "none", value_parser = ResetStrategy::parse)]
+    reset: ResetStrategy,
+
+    /// Whether the reset strategy was given on the command line or in serpico.toml, rather than
+    /// left to the board's quirks
+    #[clap(skip)]
+    reset_configured: bool,
+
+    /// Size in bytes of the buffers used for reading from the device
+    #[clap(long, global = true, default_value_t = device::DEFAULT_BUFFER_SIZE)]
+    buffer_size: usize,
+
+    /// Terminate any other process holding the device's port open
+    #[clap(long, global = true)]
+    force: bool,
+
+    /// How many more times to try opening the port when it's busy or not accessible yet, as it
+    /// can be right after the device appears
+    #[clap(long, global = true, default_value_t = port::DEFAULT_OPEN_RETRIES)]
+    open_retries: u32,
+
+    /// How many more times to try getting a script going when the handshake ahead of it fails,
+    /// interrupting the board again each time, as it can when the board is still booting or has
+    /// left garbage on the line
+    #[clap(long, global = true, default_value_t = device::DEFAULT_HANDSHAKE_RETRIES)]
+    handshake_retries: u32,
+
+    /// If another serpico is using the device, wait for it to finish instead of failing
+    #[clap(long, global = true)]
+    queue: bool,
+
+    /// Open the port without claiming it exclusively, nor locking the board against other serpico
+    /// processes, to talk to it while a passive logger also has the port open. Anything else
+    /// writing to the port, or reading from it where the platform hands each byte to one reader,
+    /// breaks the session.
+    #[clap(long, global = true, conflicts_with = "queue")]
+    shared: bool,
+
+    /// Leave the banners and prompts the board prints as it leaves the raw REPL and soft reboots
+    /// out of output that follows a script or is monitored, and out of --log-file, so that only
+    /// what programs print is captured. The output of scripts themselves never has them in it.
+    #[clap(long, global = true)]
+    quiet_handshake: bool,
+
+    /// Which of the board's ports to connect to: `repl`, or `data` for the second USB-CDC
+    /// interface of boards built with one, such as to monitor or log what they send on it
+    #[clap(
+        long,
+        global = true,
+        value_name = "CHANNEL",
+        default_value = "repl",
+        value_parser = Channel::parse
+    )]
+    channel: Channel,
+
+    /// Send scripts in the standard raw REPL, for firmware that doesn't get raw-paste mode right
+    #[clap(long, global = true)]
+    no_raw_paste: bool,
+
+    /// How scripts are sent: raw for the raw REPL, paste for the friendly REPL's paste mode on
+    /// firmware without a raw REPL, or auto to fall back to paste mode when the raw REPL fails
+    #[clap(long, global = true, value_name = "MODE", default_value = "raw", value_parser = ExecMode::parse)]
+    exec_mode: ExecMode,
+
+    /// The firmware the device runs, micropython or circuitpython, probed for if not given
+    #[clap(long, global = true, value_name = "NAME", value_parser = Firmware::parse)]
+    firmware: Option<Firmware>,
+
+    /// How the device's output is decoded where it's shown or put in JSON: utf-8, latin1 for
+    /// boards that print Latin-1, or raw to pass the bytes on as they are
+    #[clap(long, global = true, value_name = "ENCODING", default_value = "utf-8", value_parser = Encoding::parse)]
+    encoding: Encoding,
+
+    /// Fail on output that isn't valid UTF-8 rather than replacing what isn't with U+FFFD
+    #[clap(long, global = true)]
+    strict_encoding: bool,
+
+    /// Only print what the device and the command are asked for, without progress bars or status
+    /// messages such as the files being copied
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Log every byte written to and read from the device to FILE, with timestamps
+    #[clap(long, global = true, value_name = "FILE")]
+    trace: Option<PathBuf>,
+
+    /// Add every line the device prints to FILE with a timestamp, whatever is shown on the terminal
+    #[clap(long, global = true, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+
+    /// With --log-file, once the file grows past SIZE, such as `10m`, move it to FILE.1 and start
+    /// a new one
+    #[clap(long, global = true, value_name = "SIZE", requires = "log-file", value_parser = bench::parse_size)]
+    log_max_size: Option<usize>,
+
+    /// Record everything exchanged with the device to FILE, for `serpico replay`
+    #[clap(long, global = true, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// The session being replayed, which stands in for the device
+    #[clap(skip)]
+    replay: Option<Session>,
+
+    /// The password of the WebREPL when the device is a `ws://` URL such as
+    /// `ws://192.168.4.1:8266`, read from $WEBREPL_PASSWORD if not given
+    #[clap(long, global = true, value_name = "PASSWORD")]
+    webrepl_password: Option<String>,
+
+    /// Use the connection held open by `serpico daemon` instead of opening the port
+    #[clap(long, global = true)]
+    via_daemon: bool,
+
+    /// The unix socket of the daemon, in $XDG_RUNTIME_DIR or a directory of the user's in the temp
+    /// directory by default. Its directory has to be the user's own, with mode 0700.
+    #[cfg(unix)]
+    #[clap(long, global = true, value_name = "PATH")]
+    daemon_socket: Option<PathBuf>,
+
+    /// Verbose logging, repeat for more detail: `-v` for discovery, firmware and transfer details,
+    /// `-vv` for the upload's flow control and how long each stage of running a script took
+    #[clap(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Use the named profile of serpico.toml, for projects that target several boards
+    #[clap(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Run the command on each board listed in FILE, a table per board with its `serial` or
+    /// `device` and optionally `vars` that `run` sets as --set does
+    #[clap(long, global = true, value_name = "FILE", conflicts_with = "device")]
+    fleet: Option<PathBuf>,
+
+    /// With --fleet, how many boards to work on at once
+    #[clap(long, global = true, default_value_t = fleet::DEFAULT_JOBS, requires = "fleet")]
+    jobs: usize,
+
+    /// With --fleet, the directory to write each board's output to, as NAME.log
+    #[clap(
+        long,
+        global = true,
+        value_name = "DIR",
+        default_value = "fleet-logs",
+        requires = "fleet"
+    )]
+    fleet_logs: PathBuf,
+
+    /// With --fleet, prefix each line of the boards' logs with the host's time when it arrived,
+    /// one clock for every board, so that the logs can be lined up afterwards
+    #[clap(long, global = true, requires = "fleet")]
+    fleet_timestamps: bool,
+
+    /// With --fleet, also print the lines of every board to stdout as they arrive, each with the
+    /// host's time and the board's name, merging them in the order they happened. Implies
+    /// --fleet-timestamps.
+    #[clap(long, global = true, requires = "fleet")]
+    fleet_merge: bool,
+
+    /// The project's serpico.toml, which fills in what isn't given on the command line
+    #[clap(skip)]
+    config: Config,
+}
+
+#[derive(Subcommand, Debug)]
+enum FsCommand {
+    /// Wipe the filesystem and make a new, empty one, after asking for confirmation
+    Format {
+        /// The filesystem to make, `lfs2` or `fat`, the one the port uses itself by default
+        #[clap(long = "fs", value_name = "FS", value_parser = fs::Filesystem::parse)]
+        filesystem: Option<fs::Filesystem>,
+    },
+    /// Mount an SD card on an SPI bus, for the session until the device resets. With an `[sd]`
+    /// table in serpico.toml, put and sync mount the card themselves and copy files onto it.
+    MountSd {
+        /// The SPI bus the card is on, from serpico.toml if not given
+        #[clap(long)]
+        spi: Option<u32>,
+
+        /// The card's chip select pin, from serpico.toml if not given
+        #[clap(long)]
+        cs: Option<u32>,
+
+        /// Where to mount the card
+        #[clap(long, value_name = "PATH")]
+        mount: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PicoCommand {
+    /// Reboot the board, into its application or into BOOTSEL. A board still running MicroPython
+    /// is rebooted into BOOTSEL even if its REPL doesn't respond, anything else takes picotool.
+    Reboot {
+        /// Reboot into BOOTSEL rather than into the application
+        #[clap(long)]
+        bootsel: bool,
+    },
+    /// Show what picotool reads from the board, which is rebooted to read it if not in BOOTSEL
+    Info,
+}
+
+#[derive(Subcommand, Debug)]
+enum PinAction {
+    /// Drive the pin high
+    High,
+    /// Drive the pin low
+    Low,
+    /// Read the pin as an input
+    Read {
+        /// Enable the pin's pull resistor, `up` or `down`
+        #[clap(long, value_parser = gpio::Pull::parse)]
+        pull: Option<gpio::Pull>,
+    },
+    /// Drive the pin at the opposite of its current level
+    Toggle,
+    /// Output a PWM signal on the pin
+    Pwm {
+        /// The duty cycle, in percent
+        duty: f64,
+
+        /// The frequency in Hz
+        #[clap(long, default_value_t = 1000)]
+        freq: u32,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Execute a file on the MicroPython device, which `serpico FILE` is short for
+    Run(RunArgs),
+    /// Open an interactive REPL on the MicroPython device, exit with Ctrl-]
+    Repl {
+        /// Edit lines on the host and send them when Enter is pressed, with readline keys, Up and
+        /// Down for the history, Ctrl-R to search it and Tab to complete names from the device.
+        /// Lines starting with % are commands run by serpico, %help lists them.
+        #[clap(long)]
+        edit: bool,
+
+        /// The file the history of edited lines is kept in, ~/.serpico_history by default
+        #[clap(long, value_parser, value_name = "FILE", requires = "edit")]
+        history: Option<PathBuf>,
+    },
+    /// Print everything the device sends until Ctrl-C is pressed, without resetting it or sending
+    /// it anything, to watch an application that's running
+    Monitor {
+        /// Prefix lines with a timestamp, relative to when monitoring started unless
+        /// `--timestamps=absolute` is given
+        #[clap(
+            long,
+            value_name = "MODE",
+            min_values = 0,
+            require_equals = true,
+            default_missing_value = "relative",
+            value_parser = Timestamps::parse
+        )]
+        timestamps: Option<Timestamps>,
+
+        /// Only show lines matching REGEX, can be given more than once
+        #[clap(long, value_name = "REGEX", value_parser = Regex::new)]
+        grep: Vec<Regex>,
+
+        /// Hide lines matching REGEX, can be given more than once
+        #[clap(long, value_name = "REGEX", value_parser = Regex::new)]
+        exclude: Vec<Regex>,
+
+        /// Show the output as a hexdump of offsets, hex bytes and printable characters
+        #[clap(long, conflicts_with_all = &["timestamps", "grep", "exclude"])]
+        hex: bool,
+
+        /// Only capture from a line matching REGEX, which is captured too, such as "TEST BEGIN"
+        #[clap(long, value_name = "REGEX", value_parser = Regex::new)]
+        start_on: Option<Regex>,
+
+        /// Stop capturing after a line matching REGEX, until the next line matching --start-on
+        #[clap(long, value_name = "REGEX", value_parser = Regex::new)]
+        stop_on: Option<Regex>,
+
+        /// Run COMMAND in the shell whenever a line matches REGEX, with the line in
+        /// `$SERPICO_LINE`, such as `--on-pattern 'battery low:notify-send "$SERPICO_LINE"'`. A
+        /// `:` in the regex is escaped as `\:`. A webhook is a `curl` command. The command runs
+        /// in the background, and isn't started again while it's still running. Can be given
+        /// more than once.
+        #[clap(long, value_name = "REGEX:COMMAND", value_parser = Trigger::parse)]
+        on_pattern: Vec<Trigger>,
+
+        /// Write a JSON object for each line, with the host's time, the stream and the line, such
+        /// as `{"time": "12:00:01.250", "stream": "stdout", "line": "ready"}`
+        #[clap(long, conflicts_with = "hex")]
+        json: bool,
+
+        /// Write the output to PATH rather than to stdout
+        #[clap(long, value_name = "PATH")]
+        out: Option<PathBuf>,
+    },
+    /// Record the lines the device prints as structured records with host timestamps, until Ctrl-C
+    /// is pressed, without resetting it or sending it anything
+    Log {
+        /// How to write the records, `ndjson` or `csv`
+        #[clap(long, default_value = "ndjson", value_parser = record::Format::parse)]
+        format: record::Format,
+
+        /// How to find the fields in a line: `line` for the whole line, `kv` for `key=value`
+        /// pairs or `json` for JSON objects. Lines without fields aren't recorded.
+        #[clap(long, value_name = "HOW", default_value = "line", value_parser = record::Fields::parse)]
+        parse: record::Fields,
+
+        /// With --format csv, the fields to write as columns, by default those of the first record
+        #[clap(long, value_name = "NAME", use_value_delimiter = true)]
+        columns: Vec<String>,
+
+        /// Write the records to FILE instead of stdout, the device's output is shown as it comes
+        /// unless --quiet is given
+        #[clap(long, value_name = "FILE")]
+        out: Option<PathBuf>,
+
+        /// Only capture from a line matching REGEX, which is captured too, such as "TEST BEGIN"
+        #[clap(long, value_name = "REGEX", value_parser = Regex::new)]
+        start_on: Option<Regex>,
+
+        /// Stop capturing after a line matching REGEX, until the next line matching --start-on
+        #[clap(long, value_name = "REGEX", value_parser = Regex::new)]
+        stop_on: Option<Regex>,
+
+        /// Write each section captured between --start-on and --stop-on to a new file, numbered
+        /// from --out, such as run-1.ndjson, run-2.ndjson for run.ndjson
+        #[clap(long, requires = "out")]
+        rotate: bool,
+    },
+    /// Call a function on the device with JSON arguments and print what it returns as JSON,
+    /// while anything it prints goes to stderr
+    Call {
+        /// The function, such as `sensors.read`, imported from its module
+        function: String,
+
+        /// The arguments as a JSON array, or an object for keyword arguments, such as `[1, "a"]`
+        #[clap(value_parser = json::parse)]
+        args: Option<json::Value>,
+
+        /// Optional timeout for the call, such as `10s`
+        #[clap(short, long, value_parser = duration::parse)]
+        timeout: Option<Duration>,
+    },
+    /// Write `.pyi` stubs of the modules built into the device's firmware, for editors to complete
+    /// what the board has
+    Stubs {
+        /// The directory to write the stubs to
+        #[clap(long, value_name = "DIR", default_value = "typings")]
+        out: PathBuf,
+
+        /// The modules to stub, by default every module built into the firmware
+        modules: Vec<String>,
+    },
+    /// Connect to each attached board, or the one given, and report what it is and has: its
+    /// firmware, unique ID and MAC, flash, RAM and filesystem sizes
+    Inventory {
+        /// Print a JSON document instead
+        #[clap(long)]
+        json: bool,
+
+        /// Optional timeout for each board, such as `10s`
+        #[clap(short, long, value_parser = duration::parse)]
+        timeout: Option<Duration>,
+    },
+    /// Show which firmware the device runs and how serpico talks to it
+    Info,
+    /// Print the configuration in effect, from serpico.toml and the command line
+    Config,
+    /// Copy a file to the device
+    Put {
+        /// The local file to copy
+        #[clap(value_parser)]
+        local: PathBuf,
+
+        /// Where to write the file on the device, by default with the same name in the root, or on
+        /// the SD card configured in serpico.toml
+        remote: Option<String>,
+
+        /// Print what would be copied where, without connecting to the device
+        #[clap(long)]
+        dry_run: bool,
+
+        #[clap(flatten)]
+        transfer: TransferArgs,
+    },
+    /// Copy every file of a local directory to the device, creating directories as needed. Files
+    /// matching the patterns of a .serpicoignore in the directory, written like a .gitignore, are
+    /// left out.
+    Sync {
+        /// The local directory to copy
+        #[clap(value_parser)]
+        local: PathBuf,
+
+        /// The directory on the device to copy into, the root or the SD card configured in
+        /// serpico.toml by default
+        remote: Option<String>,
+
+        /// Print what would be copied where, without connecting to the device
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Don't copy files matching PATTERN, written as in .serpicoignore, can be given more than
+        /// once
+        #[clap(long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+
+        #[clap(flatten)]
+        transfer: TransferArgs,
+    },
+    /// Compare a local directory with the device, printing each file that sync would add (`+`)
+    /// or change (`M`), and each file only on the device (`-`). Files that the .serpicoignore or
+    /// the sync patterns of serpico.toml leave out are skipped on both.
+    Diff {
+        /// The local directory to compare
+        #[clap(value_parser)]
+        local: PathBuf,
+
+        /// The directory on the device to compare with, optionally written with a leading `:`,
+        /// the root or the SD card configured in serpico.toml by default
+        remote: Option<String>,
+
+        /// Print how the lines of changed text files differ too
+        #[clap(long)]
+        content: bool,
+
+        /// Skip files matching PATTERN, written as in .serpicoignore, can be given more than once
+        #[clap(long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+
+        /// Compare each `.py` file as COMMAND transforms it, for files copied with the same
+        /// `--preprocess`, which would otherwise all differ. Can be given more than once, to
+        /// apply each in turn.
+        #[clap(long, value_name = "COMMAND", value_parser = preprocess::Command::parse)]
+        preprocess: Vec<preprocess::Command>,
+
+        /// Optional timeout while waiting for the device to list or read each file, such as `10s`
+        #[clap(short, long, value_parser = duration::parse)]
+        timeout: Option<Duration>,
+    },
+    /// Copy a project to each attached board it targets. The targets are the profiles of
+    /// serpico.toml, each finding its board by serial number or product and copying the files
+    /// its sync patterns include, less those the project's .serpicoignore matches.
+    Deploy {
+        /// The profiles to deploy, or `all` for every profile with a serial number or product
+        #[clap(long = "target", value_name = "PROFILE", required = true)]
+        targets: Vec<String>,
+
+        /// The local project directory
+        #[clap(value_parser, default_value = ".")]
+        local: PathBuf,
+
+        /// The directory on the boards to copy into
+        #[clap(long, default_value = "/")]
+        remote: String,
+
+        /// Print what would be copied where, without connecting to the boards
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Don't copy files matching PATTERN, written as in .serpicoignore, can be given more than
+        /// once
+        #[clap(long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+
+        #[clap(flatten)]
+        transfer: TransferArgs,
+    },
+    /// Copy a script to the device as /main.py, check it arrived intact and reset the board, so
+    /// that it runs the script at power on without serpico
+    Install {
+        /// The script to run at power on
+        #[clap(value_parser)]
+        script: PathBuf,
+
+        /// Write a /boot.py too, which connects to the WiFi network stored by `wifi connect` if
+        /// there is one
+        #[clap(long)]
+        boot: bool,
+
+        /// Leave the board running instead of resetting it
+        #[clap(long)]
+        no_reset: bool,
+
+        /// Don't compress the files, even if the device is able to decompress them
+        #[clap(long)]
+        no_compress: bool,
+
+        /// Optional timeout while waiting for the device to write each chunk, such as `10s`
+        #[clap(short, long, value_parser = duration::parse)]
+        timeout: Option<Duration>,
+    },
+    /// Archive every file and directory on the device into a tar file, to snapshot the board
+    /// before risky changes or to clone it onto another with restore
+    Backup {
+        /// The tar file to write
+        #[clap(value_parser)]
+        archive: PathBuf,
+
+        /// The directory on the device to archive, with paths in the archive relative to it
+        #[clap(long, default_value = "/")]
+        remote: String,
+
+        /// Optional timeout while waiting for the device to list or read each file, such as `10s`
+        #[clap(short, long, value_parser = duration::parse)]
+        timeout: Option<Duration>,
+    },
+    /// Copy every file and directory of a tar file, such as one written by backup, to the device,
+    /// replacing files that exist
+    Restore {
+        /// The tar file to extract
+        #[clap(value_parser)]
+        archive: PathBuf,
+
+        /// The directory on the device to extract into
+        #[clap(long, default_value = "/")]
+        remote: String,
+
+        /// Don't compress files, even if the device is able to decompress them
+        #[clap(long)]
+        no_compress: bool,
+
+        /// Optional timeout while waiting for the device to write each chunk, such as `10s`
+        #[clap(short, long, value_parser = duration::parse)]
+        timeout: Option<Duration>,
+    },
+    /// Copy every file of a local directory to boards over their WiFi, through their WebREPL, and
+    /// reset them so they run the new files. Boards that fail are reported after the rest are
+    /// updated.
+    Ota {
+        /// The local directory to copy
+        #[clap(value_parser)]
+        local: PathBuf,
+
+        /// The directory on the boards to copy into
+        #[clap(default_value = "/")]
+        remote: String,
+
+        /// A board to update, by its address such as `192.168.1.23` or a `ws://` URL, given once
+        /// per board
+        #[clap(long = "host", value_name = "HOST", required = true)]
+        hosts: Vec<String>,
+
+        /// Leave the boards running instead of resetting them
+        #[clap(long)]
+        no_reset: bool,
+
+        /// Print what would be copied where, without connecting to the boards
+        #[clap(long)]
+        dry_run: bool,
+
+        #[clap(flatten)]
+        transfer: TransferArgs,
+    },
+    /// Manage the device's filesystem
+    Fs {
+        #[clap(subcommand)]
+        command: FsCommand,
+    },
+    /// Sync a local project directory to the device, soft reset it so that main.py restarts and
+    /// print its output, again every time a local file changes
+    Dev {
+        /// The local project directory
+        #[clap(value_parser, default_value = ".")]
+        local: PathBuf,
+
+        /// The directory on the device to sync into
+        #[clap(long, default_value = "/")]
+        remote: String,
+
+        #[clap(flatten)]
+        transfer: TransferArgs,
+    },
+    /// Copy a directory of tests to the device and run each `test*.py` module in it with the
+    /// device's unittest module, exiting with an error if any test fails
+    Test {
+        /// The local directory with the tests, or a single test module
+        #[clap(default_value = "tests")]
+        path: PathBuf,
+
+        /// The directory on the device to copy the tests into
+        #[clap(long, default_value = "/tests")]
+        remote: String,
+
+        #[clap(flatten)]
+        transfer: TransferArgs,
+    },
+    /// Run a named snippet of Python from the [snippets] of serpico.toml or one of the built-ins,
+    /// or list them
+    Snippet {
+        /// The snippet to run, all snippets are listed if not given
+        name: Option<String>,
+
+        /// Optional timeout while waiting to read a message, such as `10s`
+        #[clap(short, long, value_parser = duration::parse)]
+        timeout: Option<Duration>,
+    },
+    /// Drive, read or output PWM on a pin
+    Pin {
+        /// The pin, by number or by name such as `LED`
+        pin: String,
+
+        #[clap(subcommand)]
+        action: PinAction,
+    },
+    /// Print readings of the ADC on a pin over and over, until interrupted with Ctrl-C
+    Adc {
+        /// The pin, by number or by name such as `ADC0`
+        pin: String,
+
+        /// How often to read the ADC
+        #[clap(long, value_parser = duration::parse, default_value = "100ms")]
+        interval: Duration,
+
+        /// Stop after this many readings
+        #[clap(long)]
+        count: Option<u64>,
+
+        /// Print the readings as CSV
+        #[clap(long)]
+        csv: bool,
+    },
+    /// Print the pinout of the board, as pin and adc take its pins by name
+    Pins {
+        /// The board, if its firmware doesn't tell, one of pico, pico-w, esp32-devkit,
+        /// esp32-s3-devkit, esp32-c3-devkit or pyboard
+        #[clap(long, value_parser = pins::Board::parse)]
+        board: Option<pins::Board>,
+    },
+    /// Set the device's clock from the host's, or with --ntp from an NTP server where the board is
+    /// on a network, falling back to the host's clock where it isn't
+    Rtc {
+        /// Set the clock from an NTP server, which sets it to UTC
+        #[clap(long)]
+        ntp: bool,
+
+        /// The NTP server
+        #[clap(long, default_value = rtc::DEFAULT_SERVER, requires = "ntp")]
+        server: String,
+
+        /// Set the clock from the host's to UTC rather than to the host's timezone
+        #[clap(long)]
+        utc: bool,
+    },
+    /// Scan an I2C bus and show the addresses of the devices on it
+    I2cScan {
+        /// The I2C bus
+        #[clap(long, default_value_t = 0)]
+        bus: u32,
+
+        /// The SDA pin, the port's default for the bus if not given
+        #[clap(long, requires = "scl")]
+        sda: Option<u32>,
+
+        /// The SCL pin, the port's default for the bus if not given
+        #[clap(long, requires = "sda")]
+        scl: Option<u32>,
+
+        /// The bus frequency in Hz
+        #[clap(long, default_value_t = 100_000)]
+        freq: u32,
+    },
+    /// Connect the board to WiFi, waiting for it to get an address, and store the credentials on
+    /// it for its own code to connect with at boot
+    Wifi {
+        /// The network to connect to
+        #[clap(long)]
+        ssid: String,
+
+        /// The network's password, left out for open networks
+        #[clap(long)]
+        psk: Option<String>,
+
+        /// How long the board may take to connect
+        #[clap(long, value_parser = duration::parse, default_value = "20s")]
+        timeout: Duration,
+
+        /// Where to store the credentials on the board, as JSON with `ssid` and `psk` keys
+        #[clap(long, value_name = "PATH", default_value = wifi::DEFAULT_CREDENTIALS)]
+        save: String,
+
+        /// Connect without storing the credentials
+        #[clap(long, conflicts_with = "save")]
+        no_save: bool,
+    },
+    /// Keep device connections open in the background for clients using --via-daemon, avoiding
+    /// opening and resetting the port on every run
+    #[cfg(unix)]
+    Daemon,
+    /// Flash a firmware image and wait for the board to come back as a MicroPython device.
+    ///
+    /// RP2040 boards take a UF2 image, and are rebooted into their BOOTSEL bootloader first unless
+    /// they're already there. Espressif chips take a .bin image, written with esptool to the board
+    /// at the device option, which needs no firmware on it yet.
+    Flash {
+        /// The firmware image
+        #[clap(value_parser)]
+        image: PathBuf,
+
+        /// The chip on the board, rp2040, esp32, esp32s2, esp32s3, esp32c3, esp32c6 or esp8266
+        #[clap(long, default_value = "rp2040")]
+        chip: String,
+
+        /// The mounted bootloader drive of an RP2040, found among the mounted drives by default
+        #[clap(long, value_name = "PATH")]
+        volume: Option<PathBuf>,
+
+        /// Erase the whole flash of an Espressif chip before writing the image
+        #[clap(long)]
+        erase: bool,
+
+        /// The baud rate esptool writes the image at
+        #[clap(long, default_value_t = 460800)]
+        flash_baud: u32,
+    },
+    /// Reach an RP2040 board without going through its REPL
+    Pico {
+        #[clap(subcommand)]
+        command: PicoCommand,
+    },
+    /// Recover an RP2040 board with a corrupted filesystem by erasing it, after which MicroPython
+    /// makes a fresh one
+    Nuke {
+        /// A flash_nuke.uf2 image to erase all of the flash with instead, for boards that don't
+        /// get as far as the REPL. The board is rebooted into its bootloader first unless it's
+        /// already there, and is left there.
+        #[clap(long, value_name = "PATH")]
+        uf2: Option<PathBuf>,
+
+        /// The mounted bootloader drive, found among the mounted drives by default
+        #[clap(long, value_name = "PATH", requires = "uf2")]
+        volume: Option<PathBuf>,
+    },
+    /// Relay bytes between two serial ports, printing what goes each way with timestamps, to
+    /// watch a board talk to another UART device through serpico
+    #[cfg(unix)]
+    Sniff {
+        /// The first port
+        #[clap(long, value_name = "PORT")]
+        a: PathBuf,
+
+        /// The second port
+        #[clap(long, value_name = "PORT")]
+        b: PathBuf,
+    },
+    /// Share the device over TCP, for use from other machines with a device of `tcp://host:port`.
+    /// Clients are served one at a time and the port stays open between them.
+    #[cfg(unix)]
+    Bridge {
+        /// The address to listen on, such as `0.0.0.0:5555` for all interfaces
+        #[clap(long, value_name = "ADDRESS")]
+        listen: String,
+    },
+    /// Serve JSON-RPC requests for discovery, execution and file operations, for editor and IDE
+    /// integrations
+    Serve {
+        /// Exchange messages over stdin and stdout, one per line
+        #[clap(long, required = true)]
+        stdio: bool,
+    },
+    /// Replay a session recorded with --record, running the recorded command against what the
+    /// device sent instead of a real device. Local files the command uses need to be present.
+    Replay {
+        /// The session file
+        #[clap(value_parser)]
+        session: PathBuf,
+    },
+    /// Run a script with snapshots of the heap from `micropython.mem_info(1)` at its start, ahead
+    /// of chosen lines and at its end, then print how much was used and free at each and the heap
+    /// maps of the first and last side by side, to track down what fragments the heap
+    ProfileMem {
+        /// The script to profile
+        #[clap(value_parser)]
+        file: PathBuf,
+
+        /// Also snapshot the heap just before local line LINE runs, which has to start a
+        /// statement, each time it runs. Can be given more than once.
+        #[clap(long = "at", value_name = "LINE")]
+        at: Vec<usize>,
+
+        /// Don't collect garbage before each snapshot, to see the garbage the script leaves
+        /// behind as well as what it keeps
+        #[clap(long)]
+        no_collect: bool,
+
+        /// Optional timeout while waiting to read a message, such as `10s`
+        #[clap(short, long, value_parser = duration::parse)]
+        timeout: Option<Duration>,
+    },
+    /// Measure the throughput of the link to the device
+    Bench {
+        /// Payload sizes to upload and download, such as `512`, `8k` or `1m`
+        #[clap(
+            short,
+            long,
+            multiple_values = true,
+            default_values = &["1k", "8k", "64k"],
+            value_parser = bench::parse_size
+        )]
+        sizes: Vec<usize>,
+
+        /// Optional timeout while waiting to read a message, such as `10s`
+        #[clap(short, long, value_parser = duration::parse)]
+        timeout: Option<Duration>,
+    },
+    /// List the attached MicroPython devices
+    Devices {
+        /// Open each USB serial port, whatever it says it is, and list those where a MicroPython
+        /// REPL answers with the banner it answers with. Ports that are busy or where nothing
+        /// answers are listed as such.
+        #[clap(long)]
+        probe: bool,
+
+        /// How long each port is given to answer
+        #[clap(long, value_parser = duration::parse, default_value = "500ms", requires = "probe")]
+        probe_timeout: Duration,
+    },
+    /// Continuously print connect and disconnect events for MicroPython devices
+    WatchDevices {
+        /// How often to poll for devices, in milliseconds
+        #[clap(short, long, default_value_t = 500)]
+        interval: u64,
+    },
+    /// A command provided by a `serpico-NAME` executable on the PATH
+    #[clap(external_subcommand)]
+    External(Vec<String>),
+}
+
+#[derive(clap::Args, Debug, Default)]
+struct TransferArgs {
+    /// Don't compress files, even if the device is able to decompress them
+    #[clap(long)]
+    no_compress: bool,
+
+    /// Optional timeout while waiting for the device to write each chunk, such as `10s`
+    #[clap(short, long, value_parser = duration::parse)]
+    timeout: Option<Duration>,
+
+    /// Checksum each file on the device after writing it and fail if it doesn't match, using
+    /// sha256 or crc32 where the device lacks hashlib
+    #[clap(long)]
+    verify: bool,
+
+    /// Carry on writing files that an earlier, interrupted transfer left partly written, from
+    /// as much as the device has of them if it matches, and skip those it finished
+    #[clap(long)]
+    resume: bool,
+
+    /// Write a JSON manifest of the verification to FILE, with the board's unique ID and
+    /// firmware, when it was verified and the checksums of each file
+    #[clap(long, value_name = "FILE", requires = "verify")]
+    report: Option<PathBuf>,
+
+    /// Sign off the manifest with HMAC-SHA256, keyed with the contents of KEY_FILE less any
+    /// trailing newline, writing the signature in hex to the manifest's path with `.sig` added
+    #[clap(long, value_name = "KEY_FILE", requires = "report")]
+    sign_key: Option<PathBuf>,
+
+    /// Check the `.py` files for syntax errors with the local Python before sending any of them.
+    /// The Python can be set with PYTHON.
+    #[clap(long)]
+    check: bool,
+
+    /// Pass each `.py` file through COMMAND before sending it: the shell runs it with the file on
+    /// stdin and its path in `$SERPICO_FILE`, and what it prints is sent instead. Can be given
+    /// more than once, to apply each in turn.
+    #[clap(long, value_name = "COMMAND", value_parser = preprocess::Command::parse)]
+    preprocess: Vec<preprocess::Command>,
+}
+
+#[derive(clap::Args, Debug)]
+struct RunArgs {
+    /// A file to execute on the MicroPython device
+    #[clap(value_parser)]
+    file: PathBuf,
+
+    /// Optional timeout to set while waiting to read a message, such as `500ms`, `2s` or `1m`. If
+    /// no timeout set, then serpico will wait forever for the script's output. Each stage of the
+    /// handshake has its own limit, which a longer timeout extends.
+    #[clap(short, long, value_parser = duration::parse)]
+    timeout: Option<Duration>,
+
+    /// Interrupt the script and fail if it's still running after this long, such as `30s`
+    #[clap(long, value_name = "DURATION", value_parser = duration::parse)]
+    max_runtime: Option<Duration>,
+
+    /// Prefix output lines with a timestamp, relative to the script start unless
+    /// `--timestamps=absolute` is given
+    #[clap(
+        long,
+        value_name = "MODE",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "relative",
+        value_parser = Timestamps::parse
+    )]
+    timestamps: Option<Timestamps>,
+
+    /// Don't color the device's stderr output. Color is also disabled when NO_COLOR is set or
+    /// stdout isn't a terminal.
+    #[clap(long)]
+    no_color: bool,
+
+    /// Only show lines of the script's output matching REGEX, can be given more than once.
+    /// Tracebacks are always shown.
+    #[clap(long, value_name = "REGEX", value_parser = Regex::new)]
+    grep: Vec<Regex>,
+
+    /// Hide lines of the script's output matching REGEX, can be given more than once
+    #[clap(long, value_name = "REGEX", value_parser = Regex::new)]
+    exclude: Vec<Regex>,
+
+    /// Show the script's output as a hexdump of offsets, hex bytes and printable characters, for
+    /// binary output or output with control characters. Tracebacks are shown as text.
+    #[clap(long, conflicts_with_all = &["timestamps", "grep", "exclude", "follow", "json"])]
+    hex: bool,
+
+    /// Write the script's output to stdout exactly as it arrives, for piping into other tools.
+    /// Without it output is handled a line at a time, to add timestamps, color tracebacks and
+    /// filter lines.
+    #[clap(long, conflicts_with_all = &["timestamps", "grep", "exclude", "hex"])]
+    raw: bool,
+
+    /// Put the terminal into raw mode while the script runs, for scripts with full-screen UIs. Its
+    /// output is passed through untouched and key presses are sent to it, Ctrl-] interrupts it.
+    #[clap(
+        long,
+        conflicts_with_all = &["timestamps", "grep", "exclude", "hex", "json", "detach", "interact"]
+    )]
+    terminal: bool,
+
+    /// Python to run ahead of the script, as part of it, such as to disable a watchdog. Replaces
+    /// `before` in the [run] table of serpico.toml.
+    #[clap(long, value_name = "CODE")]
+    before: Option<String>,
+
+    /// Python to run once the script has finished, whether or not it succeeded, without a soft
+    /// reset in between. Replaces `after` in the [run] table of serpico.toml.
+    #[clap(long, value_name = "CODE", conflicts_with = "detach")]
+    after: Option<String>,
+
+    /// Drive an interactive script with a file of expect/send steps, such as
+    /// `- expect: "Name? "` followed by `  send: "serpico\n"`
+    #[clap(long, value_name = "FILE")]
+    interact: Option<PathBuf>,
+
+    /// Stream the bytes of FILE, or of stdin for `-`, to the script, which reads them in frames
+    /// with `for frame in serpico_frames():`, such as to flash a firmware blob from a handler on
+    /// the device without copying it to its filesystem first
+    #[clap(
+        long,
+        value_name = "FILE",
+        value_parser = feed::Source::parse,
+        conflicts_with_all = &["terminal", "interact", "detach", "watch", "every"]
+    )]
+    feed: Option<feed::Source>,
+
+    /// Feed the hardware watchdog the script starts with machine.WDT from a timer on the device
+    /// until the script finishes, so that a long sequence driven from the host doesn't reset the
+    /// board. The watchdog then resets it as usual unless something else feeds it.
+    #[clap(long, conflicts_with = "detach")]
+    feed_wdt: bool,
+
+    /// After a failure, print a compiler style `file:line: error: message` diagnostic for the
+    /// failing line of the script
+    #[clap(long)]
+    diagnostics: bool,
+
+    /// Don't soft reboot the device before executing, so the script runs against the state of the
+    /// application that was running
+    #[clap(long)]
+    no_soft_reset: bool,
+
+    /// Keep printing the device's output after the script returns, for scripts that leave
+    /// background tasks or IRQ handlers running
     #[clap(short, long)]
-    verbose: bool,
+    follow: bool,
+
+    /// Start the script and exit immediately, without waiting for its output
+    #[clap(long, conflicts_with = "follow")]
+    detach: bool,
+
+    /// Open an interactive REPL on the same connection once the script finishes, with the
+    /// script's globals still available
+    #[clap(long, conflicts_with_all = &["follow", "detach"])]
+    then_repl: bool,
+
+    /// Once the script finishes, leave the raw REPL and soft reset the board, so that it runs
+    /// `boot.py` and `main.py` again and carries on with its application, as after a
+    /// provisioning script
+    #[clap(long, conflicts_with_all = &["follow", "detach", "then-repl", "watch", "every"])]
+    reset_after: bool,
+
+    /// Set a variable in the `config` dict available to the script, for example `--set SSID=home`.
+    /// Numbers are passed as numbers, everything else as strings.
+    #[clap(long = "set", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    vars: Vec<(String, String)>,
+
+    /// Replace `{{NAME}}` in the script with VALUE before sending it, for constants and secrets
+    /// that don't belong in the source, such as `--define WIFI_PASSWORD=hunter2`
+    #[clap(long = "define", value_name = "NAME=VALUE", value_parser = parse_key_value)]
+    defines: Vec<(String, String)>,
+
+    /// If the device disconnects, wait for it to reappear and reconnect. Before the script has
+    /// started it is retried, afterwards only --follow carries on, as it does when the device
+    /// reboots while running the script.
+    #[clap(long)]
+    reconnect: bool,
+
+    /// Strip comments, docstrings and blank lines from the script before sending it
+    #[clap(long)]
+    minify: bool,
+
+    /// Pass the script through COMMAND before anything else is done to it: the shell runs it with
+    /// the script on stdin and its path in `$SERPICO_FILE`, and what it prints is sent instead.
+    /// Can be given more than once, to apply each in turn.
+    #[clap(long, value_name = "COMMAND", value_parser = preprocess::Command::parse)]
+    preprocess: Vec<preprocess::Command>,
+
+    /// Expand tabs in the script's indentation to spaces, with tab stops WIDTH columns apart
+    #[clap(long, value_name = "WIDTH")]
+    expand_tabs: Option<usize>,
+
+    /// Compile the script with mpy-cross and send the bytecode instead of the source. The script is
+    /// imported as a module, so `__name__` isn't `"__main__"`. The mpy-cross binary can be set
+    /// with MPY_CROSS.
+    #[clap(long)]
+    compile: bool,
+
+    /// Check the script for syntax errors with the local Python before connecting, to fail on the
+    /// line in the local file without a round trip to the device. The Python can be set with
+    /// PYTHON.
+    #[clap(long)]
+    check: bool,
+
+    /// Print how long each stage took, from opening the port to reading the last of the output, to
+    /// tell whether time goes to the link, the reboot or the script
+    #[clap(long)]
+    timings: bool,
+
+    /// Print the free and used heap before and after the script, each measured after collecting
+    /// garbage, followed by `micropython.mem_info()`
+    #[clap(long, conflicts_with_all = &["follow", "detach"])]
+    mem_report: bool,
+
+    /// When to run the script as parts split between its top-level statements, one after the
+    /// other: `auto` when the device may not have the heap to compile it at once, `always` or
+    /// `never`. Scripts run with --compile or --detach aren't split.
+    #[clap(long, value_name = "WHEN", default_value = "auto", value_parser = Split::parse)]
+    split: Split,
+
+    /// Have the device check the script against its checksum before running it, failing if it
+    /// arrived corrupted, for noisy UART links. The device holds the source in memory as well as
+    /// compiling it, and the script isn't split into parts.
+    #[clap(long, conflicts_with = "compile")]
+    verify_upload: bool,
+
+    /// Print what would be connected to, copied and run, including the script as it would be sent,
+    /// without connecting to the device
+    #[clap(long, conflicts_with = "watch")]
+    dry_run: bool,
+
+    /// Instead of echoing the output, print a JSON object with the script's stdout, stderr, exit
+    /// code, how long it took and which device it ran on. Implies --quiet.
+    #[clap(long, conflicts_with_all = &["follow", "detach", "then-repl", "watch", "dry-run"])]
+    json: bool,
+
+    /// Keep the port open and run the script again every INTERVAL, such as `30s`, until Ctrl-C is
+    /// pressed, with a header ahead of each run's output. Runs start INTERVAL apart, a run that
+    /// takes longer is followed by the next straight away.
+    #[clap(
+        long,
+        value_name = "INTERVAL",
+        value_parser = duration::parse,
+        conflicts_with_all = &["follow", "detach", "then-repl", "watch", "json"]
+    )]
+    every: Option<Duration>,
+
+    /// Keep the port open and run the script again whenever the file is saved
+    #[clap(short, long, conflicts_with_all = &["follow", "detach", "then-repl"])]
+    watch: bool,
+
+    /// Copy the local modules the script imports, found next to it as `.py` files or packages, to
+    /// the root of the device before running it
+    #[clap(long)]
+    upload_imports: bool,
+
+    /// With --watch, also watch the local modules the script imports, copying them to the device
+    /// up front and when they change
+    #[clap(long, requires = "watch")]
+    watch_imports: bool,
+
+    /// Arguments to pass to the script in `sys.argv`, given after `--`
+    #[clap(last = true)]
+    script_args: Vec<String>,
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+impl RunArgs {
+    fn exec_options(&self, args: &Args) -> Result<ExecOptions> {
+        if self.terminal && !io::stdin().is_terminal() {
+            bail!("--terminal needs stdin to be a terminal");
+        }
+        let interaction = match &self.interact {
+            Some(path) => Some(Interaction::load(path)?),
+            None => None,
+        };
 
-    let device = match args.device {
-        Some(device) => device,
-        None => {
-            let mut devices = find_micropython_devices()?;
-            match devices.len() {
-                0 => bail!("No MicroPython devices founds"),
-                1 => {
-                    let device = devices.pop().unwrap();
-                    if args.verbose {
-                        println!("MicroPython device discovered at {}", device.display());
-                    }
-                    device
-                }
-                _ => bail!(
-                    "Multiple MicroPython devices found, please specify with the device option"
-                ),
+        Ok(ExecOptions {
+            timeout: self.timeout,
+            soft_reset: !self.no_soft_reset,
+            detach: self.detach,
+            forward_interrupt: true,
+            max_runtime: self.max_runtime,
+            echo: !self.json,
+            log: true,
+            timestamps: self.timestamps,
+            color: !self.raw && !self.terminal && !self.no_color && output::color_by_default(),
+            source_map: None,
+            filter: LineFilter {
+                include: self.grep.clone(),
+                exclude: self.exclude.clone(),
+            },
+            hexdump: self.hex,
+            terminal: self.terminal,
+            interaction,
+            feed: self.feed.clone(),
+            progress: !args.quiet && !self.json && progress::available(),
+            sink: SharedSink::default(),
+            // What the device prints goes out exactly as it arrives
+            decoding: match self.raw || self.terminal {
+                true => Decoding {
+                    encoding: Encoding::Raw,
+                    strict: false,
+                },
+                false => decoding(args),
+            },
+        })
+    }
+}
+
+/// How the device's output is decoded, as given with `--encoding` and `--strict-encoding`
+fn decoding(args: &Args) -> Decoding {
+    Decoding {
+        encoding: args.encoding,
+        strict: args.strict_encoding,
+    }
+}
+
+/// Where monitored output goes, as given with `--out` and `--json`
+fn monitor_sink(out: Option<&Path>, json: bool) -> Result<SharedSink> {
+    Ok(match (out, json) {
+        (Some(path), true) => SharedSink::new(JsonLines::new(create_file(path)?)),
+        (Some(path), false) => SharedSink::new(FileSink::new(create_file(path)?)),
+        (None, true) => SharedSink::new(JsonLines::new(io::stdout())),
+        (None, false) => SharedSink::default(),
+    })
+}
+
+/// Create the file at `path` to write to
+fn create_file(path: &Path) -> Result<io::BufWriter<File>> {
+    match File::create(path) {
+        Ok(file) => Ok(io::BufWriter::new(file)),
+        Err(e) => bail!("Couldn't create {}: {}", path.display(), e),
+    }
+}
+
+fn main() -> Result<()> {
+    let matches = Args::command().get_matches_from(command_line());
+    let args = with_config(&matches, None)?;
+    if let Some(path) = &args.log_file {
+        let max_size = args.log_max_size.map(|size| size as u64);
+        logfile::install(OutputLog::create(path, max_size)?);
+    }
+    let result = match &args.command {
+        _ if args.fleet.is_some() => run_fleet(&args),
+        // Each target is deployed to with the arguments its profile gives
+        Some(Command::Deploy {
+            targets,
+            local,
+            remote,
+            dry_run,
+            transfer,
+            ..
+        }) => deploy(&matches, &args, targets, local, remote, *dry_run, transfer),
+        _ => dispatch(&args),
+    };
+    logfile::finish();
+    match result {
+        // The device has been left in the friendly REPL, there's nothing more to report
+        Err(e) if e.is::<Interrupted>() => std::process::exit(exit::CANCELLED),
+        Err(e) => {
+            match diagnose(&e) {
+                Some(diagnosis) => eprintln!("Error: {:?}\n\nHint: {}", e, diagnosis),
+                None => eprintln!("Error: {:?}", e),
             }
+            std::process::exit(exit::code(&e))
         }
+        result => result,
+    }
+}
+
+/// The command line, with `run` put ahead of a script given in place of a command, so that
+/// `serpico script.py -t 5` runs it as `serpico run script.py -t 5` does. Plugins by the name
+/// still come first.
+fn command_line() -> Vec<OsString> {
+    with_run(env::args_os().collect())
+}
+
+/// `argv` with `run` put ahead of a script given in place of a command, see [`command_line`]
+fn with_run(mut argv: Vec<OsString>) -> Vec<OsString> {
+    let external = match Args::command()
+        .try_get_matches_from(&argv)
+        .and_then(|matches| Args::from_arg_matches(&matches))
+    {
+        Ok(Args {
+            command: Some(Command::External(external)),
+            ..
+        }) => external,
+        _ => return argv,
     };
+    let name = &external[0];
+    let script = Path::new(name).is_file() || name.ends_with(".py") || name.ends_with(".mpy");
+    if script && plugin::find(name).is_none() {
+        // The external command takes the rest of the command line
+        argv.insert(argv.len() - external.len(), OsString::from("run"));
+    }
+    argv
+}
 
-    if args.print_discovery {
-        println!("{}", device.to_str().unwrap());
-        return Ok(());
+/// Build the arguments from the command line, filling in what isn't given from serpico.toml with
+/// `profile` applied, or the profile given on the command line
+fn with_config(matches: &ArgMatches, profile: Option<&str>) -> Result<Args> {
+    let mut args = Args::from_arg_matches(matches)?;
+    let mut config = config::discover()?;
+    if let Some(profile) = profile.or(args.profile.as_deref()) {
+        config = config.with_profile(profile)?;
     }
 
-    let file_arg = match args.file {
-        Some(file) => file,
-        None => bail!("No file specified"),
+    if args.device.is_none() {
+        args.device = config.device.clone();
+    }
+    // Nothing but the JSON goes to stdout
+    if matches!(&args.command, Some(Command::Run(run_args)) if run_args.json) {
+        args.quiet = true;
+    }
+    if let Some(baud) = config.baud {
+        if matches.value_source("baud") != Some(ValueSource::CommandLine) {
+            args.baud = baud;
+        }
+    }
+    if let Some(reset) = &config.reset {
+        if matches.value_source("reset") != Some(ValueSource::CommandLine) {
+            args.reset = reset.clone();
+        }
+    }
+    args.reset_configured =
+        config.reset.is_some() || matches.value_source("reset") == Some(ValueSource::CommandLine);
+    let timeout = match &mut args.command {
+        Some(Command::Run(run_args)) => Some(&mut run_args.timeout),
+        Some(Command::Put { transfer, .. })
+        | Some(Command::Sync { transfer, .. })
+        | Some(Command::Ota { transfer, .. })
+        | Some(Command::Deploy { transfer, .. })
+        | Some(Command::Dev { transfer, .. })
+        | Some(Command::Test { transfer, .. }) => Some(&mut transfer.timeout),
+        Some(Command::Bench { timeout, .. })
+        | Some(Command::Snippet { timeout, .. })
+        | Some(Command::ProfileMem { timeout, .. }) => Some(timeout),
+        _ => None,
     };
+    if let Some(timeout) = timeout {
+        *timeout = timeout.or(config.timeout);
+    }
+    if let Some(Command::Run(run_args)) = &mut args.command {
+        run_args.before = run_args.before.take().or_else(|| config.run.before.clone());
+        run_args.after = run_args.after.take().or_else(|| config.run.after.clone());
+    }
 
-    let mut file = match File::open(file_arg.as_path()) {
-        Ok(file) => file,
-        Err(e) => bail!("Couldn't open file {}: {}", file_arg.display(), e),
-    };
-    let mut content = String::new();
-    match file.read_to_string(&mut content) {
-        Ok(_) => {}
-        Err(e) => bail!("Couldn't read file {}: {}", file_arg.display(), e),
+    // The serpico running the fleet runs the hooks, once for all of the boards
+    if env::var_os(fleet::BOARD_VAR).is_some() {
+        config.hooks = Hooks::default();
     }
 
-    execute(device, content, args.timeout)?;
+    args.config = config;
+    Ok(args)
+}
 
-    Ok(())
+fn dispatch(args: &Args) -> Result<()> {
+    match &args.command {
+        Some(Command::WatchDevices { interval }) => watch(*interval),
+        Some(Command::Devices {
+            probe,
+            probe_timeout,
+        }) => devices(args, *probe, *probe_timeout),
+        Some(Command::Repl { edit, history }) => {
+            let editor = match edit {
+                true => {
+                    let path = history.clone().or_else(default_history_path);
+                    Some(LineEditor::new(History::load(path.as_deref())?))
+                }
+                false => None,
+            };
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            start_repl(args, &mut port, editor)
+        }
+        Some(Command::Monitor {
+            timestamps,
+            grep,
+            exclude,
+            hex,
+            start_on,
+            stop_on,
+            on_pattern,
+            json,
+            out,
+        }) => {
+            let sink = monitor_sink(out.as_deref(), *json)?;
+            let device = resolve_device(args)?;
+            let mut port = open_device_with_reset(args, &device, &ResetStrategy::default())?;
+            let echo = Echo::new(*timestamps, false, None)
+                .filter(LineFilter {
+                    include: grep.clone(),
+                    exclude: exclude.clone(),
+                })
+                .hexdump(*hex)
+                .sink(sink)
+                .decoding(decoding(args));
+            if !args.quiet {
+                eprintln!("Monitoring {}, exit with Ctrl-C", device.display());
+            }
+            let window = match start_on.is_some() || stop_on.is_some() {
+                true => Some(Window::new(start_on.clone(), stop_on.clone())),
+                false => None,
+            };
+            interrupt::install()?;
+            monitor(
+                &mut port,
+                echo,
+                window,
+                &mut Alerts::new(on_pattern.clone()),
+            )
+        }
+        Some(Command::Log {
+            format,
+            parse,
+            columns,
+            out,
+            start_on,
+            stop_on,
+            rotate,
+        }) => {
+            let file: Box<dyn Write> = match out {
+                // Each section gets a file of its own when it starts
+                Some(_) if *rotate => Box::new(io::sink()),
+                Some(path) => Box::new(create_file(path)?),
+                None => Box::new(io::stdout()),
+            };
+            let mut records =
+                RecordWriter::new(*format, *parse, columns.clone(), file).decoding(decoding(args));
+            if start_on.is_some() || stop_on.is_some() {
+                records = records.window(Window::new(start_on.clone(), stop_on.clone()));
+            }
+            if let (Some(path), true) = (out, rotate) {
+                records = records.rotate(path.clone());
+            }
+            let mut echo = match out.is_some() && !args.quiet {
+                true => Some(Echo::new(None, false, None).decoding(decoding(args))),
+                false => None,
+            };
+            let device = resolve_device(args)?;
+            let mut port = open_device_with_reset(args, &device, &ResetStrategy::default())?;
+            interrupt::install()?;
+            listen(&mut port, |bytes| {
+                if let Some(echo) = &mut echo {
+                    echo.write(bytes)?;
+                }
+                records.write(bytes)
+            })
+        }
+        Some(Command::Call {
+            function,
+            args: call_args,
+            timeout,
+        }) => {
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            let returned = call::call(
+                &mut port,
+                function,
+                call_args.as_ref().unwrap_or(&json::Value::Null),
+                *timeout,
+            )?;
+            io::stderr().write_all(&returned.printed)?;
+            println!("{}", returned.value);
+            Ok(())
+        }
+        Some(Command::Stubs { out, modules }) => {
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            let modules = match modules.is_empty() {
+                true => stubs::module_names(&String::from_utf8_lossy(&eval(
+                    &mut port,
+                    stubs::LIST,
+                    None,
+                )?)),
+                false => modules.clone(),
+            };
+            let output = eval(&mut port, &stubs::inspect_script(&modules), None)?;
+            let firmware = stubs::parse(&String::from_utf8_lossy(&output))?;
+            let written = stubs::write(out, &firmware)?;
+            if !args.quiet {
+                for (module, error) in &firmware.failed {
+                    eprintln!("Skipped {}, which failed to import: {}", module, error);
+                }
+                eprintln!("Wrote {} stubs to {}", written.len(), out.display());
+            }
+            Ok(())
+        }
+        Some(Command::Inventory { json, timeout }) => inventory(args, *json, *timeout),
+        Some(Command::Info) => {
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            let firmware = version::detect(&mut port, None)?;
+            let quirks = Quirks::for_port(&device).detected(&firmware);
+            println!("Device:    {}", device.display());
+            println!("Firmware:  {}", firmware);
+            println!("Board:     {}", quirks);
+            println!(
+                "Upload:    {}",
+                if port.raw_paste() {
+                    "raw-paste"
+                } else {
+                    "raw REPL"
+                }
+            );
+            Ok(())
+        }
+        Some(Command::Config) => {
+            let effective = Config {
+                device: args.device.clone(),
+                baud: Some(args.baud),
+                reset: Some(args.reset.clone()),
+                ..args.config.clone()
+            };
+            println!("{}", effective);
+            Ok(())
+        }
+        Some(Command::Put {
+            local,
+            remote,
+            dry_run,
+            transfer,
+        }) => {
+            let remote = match remote {
+                Some(remote) => remote.clone(),
+                None => match local.file_name() {
+                    Some(name) => fs::join(&default_remote(args), &name.to_string_lossy()),
+                    None => bail!("Couldn't get the file name of {}", local.display()),
+                },
+            };
+            let files = [(local.clone(), remote)];
+            if *dry_run {
+                return dry_put(args, &files);
+            }
+            args.config.hooks.run_before()?;
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            mount_sd(args, &mut port, transfer.timeout)?;
+            put(args, &mut port, &files, transfer)?;
+            args.config.hooks.run_after()
+        }
+        Some(Command::Sync {
+            local,
+            remote,
+            dry_run,
+            transfer,
+            ..
+        }) => {
+            let remote = remote.clone().unwrap_or_else(|| default_remote(args));
+            let files = synced_files(args, local, &remote)?;
+            if *dry_run {
+                return dry_put(args, &files);
+            }
+            args.config.hooks.run_before()?;
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            mount_sd(args, &mut port, transfer.timeout)?;
+            put(args, &mut port, &files, transfer)?;
+            args.config.hooks.run_after()
+        }
+        Some(Command::Install {
+            script,
+            boot,
+            no_reset,
+            no_compress,
+            timeout,
+        }) => {
+            let data = match std::fs::read(script) {
+                Ok(data) => data,
+                Err(e) => bail!("Couldn't read file {}: {}", script.display(), e),
+            };
+            let mut files = vec![("/main.py", data)];
+            if *boot {
+                let boot = wifi::boot_script(wifi::DEFAULT_CREDENTIALS);
+                files.push(("/boot.py", boot.into_bytes()));
+            }
+            args.config.hooks.run_before()?;
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            install(args, &mut port, &files, *no_compress, *timeout)?;
+            drop(port);
+            if !*no_reset {
+                if !args.quiet {
+                    println!("Resetting the board");
+                }
+                // A board on its own USB goes away while it resets, so it's waited for
+                let serial = match Quirks::for_port(&device).reenumerates() {
+                    true => serial_number(&device)?,
+                    false => None,
+                };
+                run_detached(args, &device, RESET)?;
+                if let Some(serial) = serial {
+                    let start = Instant::now();
+                    while start.elapsed() < Duration::from_secs(2)
+                        && discover_micropython_devices()?
+                            .iter()
+                            .any(|info| info.serial_number.as_ref() == Some(&serial))
+                    {
+                        sleep(WATCH_INTERVAL);
+                    }
+                    let info = wait_for_device(&serial, RECONNECT_TIMEOUT)?;
+                    if !args.quiet {
+                        println!("The board is back at {}", info.path.display());
+                    }
+                }
+            }
+            args.config.hooks.run_after()
+        }
+        Some(Command::Backup {
+            archive,
+            remote,
+            timeout,
+        }) => {
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            backup(args, &mut port, archive, remote, *timeout)
+        }
+        Some(Command::Restore {
+            archive,
+            remote,
+            no_compress,
+            timeout,
+        }) => {
+            let data = match std::fs::read(archive) {
+                Ok(data) => data,
+                Err(e) => bail!("Couldn't read {}: {}", archive.display(), e),
+            };
+            let members = serpico::tar::read(&data)?;
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            restore(args, &mut port, &members, remote, *no_compress, *timeout)
+        }
+        Some(Command::Diff {
+            local,
+            remote,
+            content,
+            preprocess,
+            timeout,
+            ..
+        }) => {
+            let remote = match remote {
+                Some(remote) => remote.strip_prefix(':').unwrap_or(remote).to_string(),
+                None => default_remote(args),
+            };
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            mount_sd(args, &mut port, *timeout)?;
+            let preprocessors = preprocess::Pipeline::commands(preprocess);
+            diff(
+                args,
+                &mut port,
+                local,
+                &remote,
+                *content,
+                &preprocessors,
+                *timeout,
+            )
+        }
+        Some(Command::Ota {
+            local,
+            remote,
+            hosts,
+            no_reset,
+            dry_run,
+            transfer,
+        }) => {
+            let files = synced_files(args, local, remote)?;
+            if *dry_run {
+                return dry_put(args, &files);
+            }
+            args.config.hooks.run_before()?;
+            ota(args, hosts, &files, !*no_reset, transfer)?;
+            args.config.hooks.run_after()
+        }
+        Some(Command::Fs {
+            command: FsCommand::Format { filesystem },
+        }) => {
+            let device = resolve_device(args)?;
+            print!(
+                "This erases every file on {}, continue? [y/N] ",
+                device.display()
+            );
+            io::stdout().flush()?;
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer)?;
+            if !matches!(answer.trim(), "y" | "Y" | "yes") {
+                bail!("Not formatting");
+            }
+            let mut port = open_device(args, &device)?;
+            let made = fs::format(&mut port, *filesystem, Some(FORMAT_TIMEOUT))?;
+            if !args.quiet {
+                println!("Formatted {} with {}", device.display(), made);
+            }
+            Ok(())
+        }
+        Some(Command::Fs {
+            command: FsCommand::MountSd { spi, cs, mount },
+        }) => {
+            let configured = args.config.sd.as_ref();
+            let card = SdCard {
+                spi: match spi.or(configured.map(|sd| sd.spi)) {
+                    Some(spi) => spi,
+                    None => bail!("Give the SPI bus of the SD card with --spi"),
+                },
+                cs: match cs.or(configured.map(|sd| sd.cs)) {
+                    Some(cs) => cs,
+                    None => bail!("Give the chip select pin of the SD card with --cs"),
+                },
+                mount_point: mount
+                    .clone()
+                    .or_else(|| configured.map(|sd| sd.mount_point.clone()))
+                    .unwrap_or_else(|| sdcard::DEFAULT_MOUNT_POINT.to_string()),
+            };
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            let mounted = card.mount(&mut port, None)?;
+            if !args.quiet {
+                if mounted {
+                    println!("Mounted the SD card at {}", card.mount_point);
+                } else {
+                    println!("The SD card is already mounted at {}", card.mount_point);
+                }
+            }
+            Ok(())
+        }
+        Some(Command::Dev {
+            local,
+            remote,
+            transfer,
+        }) => dev(args, local, remote, transfer),
+        Some(Command::Pin { pin, action }) => {
+            let action = match action {
+                PinAction::High => gpio::Action::High,
+                PinAction::Low => gpio::Action::Low,
+                PinAction::Read { pull } => gpio::Action::Read { pull: *pull },
+                PinAction::Toggle => gpio::Action::Toggle,
+                PinAction::Pwm { duty, freq } => gpio::Action::Pwm {
+                    duty: *duty,
+                    frequency: *freq,
+                },
+            };
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            let pin = resolve_pin(&mut port, &device, pin)?;
+            let script = gpio::script(&pin, &action)?;
+            let output = eval(&mut port, &script, Some(Duration::from_secs(10)))?;
+            print!("{}", String::from_utf8_lossy(&output));
+            Ok(())
+        }
+        Some(Command::Adc {
+            pin,
+            interval,
+            count,
+            csv,
+        }) => {
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            let pin = resolve_pin(&mut port, &device, pin)?;
+            let script = adc::monitor_script(&pin, *interval, *count, *csv);
+            let options = ExecOptions {
+                soft_reset: false,
+                forward_interrupt: true,
+                color: output::color_by_default(),
+                ..ExecOptions::default()
+            };
+            let result = execute(&mut port, script.as_str(), &options)?;
+            // Interrupting is how monitoring is meant to end
+            if result.interrupted() {
+                return exit_raw_repl(&mut port);
+            }
+            if result.exit_code() != 0 {
+                std::process::exit(result.exit_code());
+            }
+            Ok(())
+        }
+        Some(Command::Pins { board }) => {
+            let board = match board {
+                Some(board) => *board,
+                None => {
+                    let device = resolve_device(args)?;
+                    let mut port = open_device(args, &device)?;
+                    let firmware = version::detect(&mut port, None)?;
+                    match Quirks::for_port(&device).detected(&firmware).board {
+                        Some(board) => board,
+                        None => bail!(
+                            "The pinout of {} isn't known, give the board with --board",
+                            firmware.machine.as_deref().unwrap_or("the board")
+                        ),
+                    }
+                }
+            };
+            if !args.quiet {
+                println!("{}", board);
+            }
+            print!("{}", board.pinout().table());
+            Ok(())
+        }
+        Some(Command::Rtc { ntp, server, utc }) => {
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            let timeout = Some(Duration::from_secs(10));
+            let mut synced = false;
+            if *ntp {
+                let output = eval(&mut port, &rtc::ntp_script(server), timeout)?;
+                match String::from_utf8_lossy(&output).trim() {
+                    "ok" => synced = true,
+                    reason => eprintln!("NTP failed, setting the host's time instead: {}", reason),
+                }
+            }
+            if !synced {
+                // The clock is set to whole seconds, so it's most accurate set as one begins
+                let now = SystemTime::now();
+                let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+                sleep(
+                    Duration::from_secs(1)
+                        - Duration::from_nanos(since_epoch.subsec_nanos() as u64),
+                );
+                eval(
+                    &mut port,
+                    &rtc::set_script(SystemTime::now(), *utc),
+                    timeout,
+                )?;
+            }
+            let output = eval(&mut port, rtc::READ, timeout)?;
+            println!("{}", String::from_utf8_lossy(&output).trim());
+            Ok(())
+        }
+        Some(Command::I2cScan {
+            bus,
+            sda,
+            scl,
+            freq,
+        }) => {
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            let pins = match sda.zip(*scl) {
+                Some(pins) => Some(pins),
+                None => Quirks::for_port(&device)
+                    .detected(&version::detect(&mut port, None)?)
+                    .i2c_pins(),
+            };
+            let script = i2c::scan_script(*bus, pins, *freq);
+            let output = eval(&mut port, &script, Some(Duration::from_secs(10)))?;
+            let addresses = i2c::parse_addresses(&String::from_utf8_lossy(&output))?;
+            print!("{}", i2c::table(&addresses));
+            if !args.quiet {
+                println!("{} devices found", addresses.len());
+            }
+            Ok(())
+        }
+        Some(Command::Wifi {
+            ssid,
+            psk,
+            timeout,
+            save,
+            no_save,
+        }) => {
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            if !args.quiet {
+                println!("Connecting {} to {}", device.display(), ssid);
+            }
+            let save = if *no_save { None } else { Some(save.as_str()) };
+            let script = wifi::connect_script(ssid, psk.as_deref(), *timeout, save);
+            // Waiting for the connection prints nothing, so reads have to wait as long as it may
+            let output = eval(&mut port, &script, Some(*timeout + Duration::from_secs(5)))?;
+            let address = String::from_utf8_lossy(&output).trim().to_string();
+            if let (Some(save), false) = (save, args.quiet) {
+                println!("Stored the credentials in {}", save);
+            }
+            println!("{}", address);
+            Ok(())
+        }
+        #[cfg(unix)]
+        Some(Command::Daemon) => {
+            let socket = socket_path(args);
+            if args.verbose > 0 {
+                println!("Listening on {}", socket.display());
+            }
+            daemon::serve(&socket, |device| port_builder(args, device).open())
+        }
+        Some(Command::Flash {
+            image,
+            chip,
+            volume,
+            erase,
+            flash_baud,
+        }) => {
+            if esptool::is_chip(chip) {
+                if volume.is_some() {
+                    bail!("--volume is only for RP2040 boards");
+                }
+                flash_esp(args, image, chip, *erase, *flash_baud)
+            } else if chip == "rp2040" {
+                if *erase {
+                    bail!("--erase is only for Espressif chips");
+                }
+                flash(args, image, volume.as_deref())
+            } else {
+                bail!(
+                    "Unknown chip {}, expected rp2040 or one of {}",
+                    chip,
+                    esptool::chips().collect::<Vec<_>>().join(", ")
+                );
+            }
+        }
+        Some(Command::Pico {
+            command: PicoCommand::Reboot { bootsel },
+        }) => reboot_pico(args, *bootsel),
+        Some(Command::Pico {
+            command: PicoCommand::Info,
+        }) => picotool::info(&picotool::find()?, uf2::find_volume().is_none()),
+        Some(Command::Nuke { uf2, volume }) => nuke(args, uf2.as_deref(), volume.as_deref()),
+        Some(Command::External(command)) => {
+            let (name, plugin_args) = command.split_first().unwrap();
+            let context = plugin::Context {
+                // Plugins that don't use the device mustn't fail for lack of one
+                device: resolve_device(args).ok(),
+                baud: Some(args.baud),
+                config: args.config.path.clone(),
+                profile: args.config.profile.clone(),
+                quiet: args.quiet,
+                verbose: args.verbose,
+            };
+            let exit_code = plugin::run(name, plugin_args, &context)?;
+            if exit_code != 0 {
+                std::process::exit(exit_code);
+            }
+            Ok(())
+        }
+        #[cfg(unix)]
+        Some(Command::Sniff { a, b }) => {
+            let open = |path: &Path| -> Result<Box<dyn serialport::SerialPort>> {
+                match serialport::new(path.to_string_lossy(), args.baud)
+                    .flow_control(args.flow)
+                    .timeout(Duration::from_millis(100))
+                    .open()
+                {
+                    Ok(port) => Ok(port),
+                    Err(e) => bail!("Couldn't open {}: {}", path.display(), e),
+                }
+            };
+            let (port_a, port_b) = (open(a)?, open(b)?);
+            if !args.quiet {
+                eprintln!(
+                    "Relaying between {} (a) and {} (b) at {} baud",
+                    a.display(),
+                    b.display(),
+                    args.baud
+                );
+            }
+            let mut stdout = io::stdout();
+            sniff::relay(port_a, port_b, |direction, time, data| {
+                let arrow = match direction {
+                    sniff::Direction::AToB => "a > b",
+                    sniff::Direction::BToA => "b > a",
+                };
+                let _ = writeln!(
+                    stdout,
+                    "{} {}  {}",
+                    output::format_time(time),
+                    arrow,
+                    sniff::format_data(data)
+                );
+                let _ = stdout.flush();
+            })
+        }
+        #[cfg(unix)]
+        Some(Command::Bridge { listen }) => {
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            if !args.quiet {
+                println!("Sharing {} on {}", device.display(), listen);
+            }
+            bridge::serve(listen, &mut port, |client, connected| {
+                if !args.quiet {
+                    let event = if connected {
+                        "connected"
+                    } else {
+                        "disconnected"
+                    };
+                    println!("Client {} {}", client, event);
+                }
+            })
+        }
+        Some(Command::Serve { .. }) => {
+            let stdin = io::stdin();
+            rpc::serve(stdin.lock(), io::stdout(), |device| {
+                open_device(args, device)
+            })
+        }
+        Some(Command::Replay { session }) => {
+            let session = Session::load(session)?;
+            let mut replayed =
+                with_config(&Args::command().try_get_matches_from(&session.args)?, None)?;
+            if matches!(replayed.command, Some(Command::Replay { .. })) {
+                bail!("Session is of a replay, which can't be replayed");
+            }
+            replayed.device = Some(PathBuf::from(&session.device));
+            replayed.record = None;
+            replayed.via_daemon = false;
+            replayed.replay = Some(session);
+            dispatch(&replayed)
+        }
+        Some(Command::ProfileMem {
+            file,
+            at,
+            no_collect,
+            timeout,
+        }) => {
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            let exit_code = profile_mem(&mut port, file, at, !no_collect, *timeout)?;
+            if exit_code != 0 {
+                std::process::exit(exit_code);
+            }
+            Ok(())
+        }
+        Some(Command::Bench { sizes, timeout }) => {
+            let device = resolve_device(args)?;
+            let mut port = open_device(args, &device)?;
+            run_bench(&mut port, sizes, *timeout)
+        }
+        Some(Command::Test {
+            path,
+            remote,
+            transfer,
+        }) => {
+            args.config.hooks.run_before()?;
+            if !test(args, path, remote, transfer)? {
+                std::process::exit(exit::SCRIPT_FAILED);
+            }
+            args.config.hooks.run_after()
+        }
+        Some(Command::Snippet { name: None, .. }) => {
+            list_snippets(args);
+            Ok(())
+        }
+        Some(Command::Snippet {
+            name: Some(name),
+            timeout,
+        }) => {
+            let exit_code = run_snippet(args, name, *timeout)?;
+            if exit_code != 0 {
+                std::process::exit(exit_code);
+            }
+            Ok(())
+        }
+        Some(Command::Run(run_args)) if run_args.dry_run => dry_run(args, run_args),
+        Some(Command::Run(run_args)) if !args.print_discovery => {
+            if run_args.check {
+                check_script(run_args)?;
+            }
+            args.config.hooks.run_before()?;
+            let exit_code = run(args, run_args)?;
+            if exit_code != 0 {
+                std::process::exit(exit_code);
+            }
+            args.config.hooks.run_after()
+        }
+        _ => {
+            let device = resolve_device(args)?;
+            if !args.print_discovery {
+                bail!("No file specified");
+            }
+            println!("{}", device.to_str().unwrap());
+            Ok(())
+        }
+    }
+}
+
+fn parse_key_value(value: &str) -> Result<(String, String)> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => bail!("Expected KEY=VALUE, got {:?}", value),
+    }
+}
+
+fn port_builder(args: &Args, device: &Path) -> PortBuilder {
+    port::new(device)
+        .baud_rate(args.baud)
+        .flow_control(args.flow)
+        .reset(args.reset.clone())
+        .buffer_size(args.buffer_size)
+        .force(args.force)
+        .open_retries(args.open_retries)
+        .queue(args.queue)
+        .shared(args.shared)
+        .trace(args.trace.as_ref())
+        .password(
+            args.webrepl_password
+                .clone()
+                .or_else(|| std::env::var("WEBREPL_PASSWORD").ok()),
+        )
+}
+
+/// Open the device's port, or connect to it through the daemon with --via-daemon
+fn open_device(args: &Args, device: &Path) -> Result<Device> {
+    let quirks = Quirks::for_port(device);
+    let reset = match quirks.reset() {
+        Some(reset) if !args.reset_configured => reset,
+        _ => args.reset.clone(),
+    };
+    if args.verbose > 0 && quirks != Quirks::default() {
+        eprintln!(
+            "Treating {} as {}, resetting with {}",
+            device.display(),
+            quirks,
+            reset
+        );
+    }
+    open_device_with_reset(args, device, &reset)
+}
+
+/// Like [`open_device`], applying `reset` instead of the reset strategy given
+fn open_device_with_reset(args: &Args, device: &Path, reset: &ResetStrategy) -> Result<Device> {
+    if let Some(session) = &args.replay {
+        let port = ReplayPort::new(session.clone());
+        let mut replayed = Device::new(Box::new(port), args.buffer_size);
+        replayed.set_handshake_retries(args.handshake_retries);
+        replayed.set_decoding(decoding(args));
+        return Ok(replayed);
+    }
+
+    let mut opened = if args.via_daemon {
+        connect_daemon(args, device)?
+    } else {
+        port_builder(args, device).reset(reset.clone()).open()?
+    };
+    if args.no_raw_paste {
+        opened.set_raw_paste(false);
+    }
+    opened.set_exec_mode(args.exec_mode);
+    opened.set_firmware(args.firmware);
+    opened.set_handshake_retries(args.handshake_retries);
+    opened.set_decoding(decoding(args));
+    opened.set_reboot_limit(Quirks::for_port(device).reboot_limit());
+    if args.quiet_handshake {
+        opened.set_echo_policy(EchoPolicy::Program);
+    }
+    if let Some(banner) = &args.config.reboot_banner {
+        opened.set_reboot_banner(Some(banner.clone()).filter(|banner| !banner.is_empty()));
+    }
+    match &args.record {
+        Some(path) => Ok(opened.tap(Recorder::create(path, &recorded_args(), device)?)),
+        None => Ok(opened),
+    }
+}
+
+/// The command line arguments, without --record, so the session can be replayed
+fn recorded_args() -> Vec<String> {
+    let mut recorded = Vec::new();
+    let mut args = std::env::args();
+    while let Some(arg) = args.next() {
+        if arg == "--record" {
+            args.next();
+        } else if !arg.starts_with("--record=") {
+            recorded.push(arg);
+        }
+    }
+    recorded
+}
+
+#[cfg(unix)]
+fn connect_daemon(args: &Args, device: &Path) -> Result<Device> {
+    daemon::connect(
+        &socket_path(args),
+        device,
+        args.buffer_size,
+        args.trace.as_deref(),
+    )
+}
+
+#[cfg(not(unix))]
+fn connect_daemon(_args: &Args, _device: &Path) -> Result<Device> {
+    bail!("The daemon is reached over a unix socket, which this platform doesn't have")
+}
+
+#[cfg(unix)]
+fn socket_path(args: &Args) -> PathBuf {
+    args.daemon_socket
+        .clone()
+        .unwrap_or_else(daemon::default_socket_path)
+}
+
+fn resolve_device(args: &Args) -> Result<PathBuf> {
+    let device = match &args.device {
+        Some(device) => device.clone(),
+        None if args.config.serial.is_some() || args.config.product.is_some() => {
+            let mut devices: Vec<DeviceInfo> = discover_micropython_devices()?
+                .into_iter()
+                .filter(|info| {
+                    args.config
+                        .matches(info.serial_number.as_deref(), info.product.as_deref())
+                })
+                .collect();
+            match devices.len() {
+                0 => bail!(NoDevice { configured: true }),
+                1 => devices.pop().unwrap().path,
+                _ => bail!(MultipleDevices { configured: true }),
+            }
+        }
+        None => {
+            let mut devices = find_micropython_devices()?;
+            match devices.len() {
+                0 => bail!(NoDevice { configured: false }),
+                1 => {
+                    let device = devices.pop().unwrap();
+                    if args.verbose > 0 {
+                        println!("MicroPython device discovered at {}", device.display());
+                    }
+                    device
+                }
+                _ => bail!(MultipleDevices { configured: false }),
+            }
+        }
+    };
+
+    match args.channel {
+        Channel::Repl => Ok(device),
+        Channel::Data => data_channel(&device),
+    }
+}
+
+/// The data port of the board whose REPL is at `device`
+fn data_channel(device: &Path) -> Result<PathBuf> {
+    let board = discover_micropython_devices()?
+        .into_iter()
+        .find(|info| info.path == device || info.data.as_deref() == Some(device));
+    match board.and_then(|board| board.data) {
+        Some(data) => Ok(data),
+        None => bail!(
+            "{} has no data channel, a second USB-CDC interface that `serpico devices` lists \
+             as data=",
+            device.display()
+        ),
+    }
+}
+
+fn run(args: &Args, run_args: &RunArgs) -> Result<i32> {
+    let device = resolve_device(args)?;
+
+    // The device is matched by serial number when reconnecting, as it may come back on another path
+    let serial_number = if run_args.reconnect {
+        serial_number(&device)?
+    } else {
+        None
+    };
+
+    let started = Instant::now();
+    let mut port = open_device(args, &device)?;
+    let opened = started.elapsed();
+    if args.verbose > 0 {
+        let firmware = version::detect(&mut port, run_args.timeout)?;
+        println!("Detected {}", firmware);
+    }
+
+    if run_args.watch {
+        return watch_runs(args, run_args, &mut port, serial_number.as_deref());
+    }
+    if run_args.upload_imports {
+        upload_imports(args, run_args, &mut port)?;
+    }
+    if let Some(interval) = run_args.every {
+        return repeat_runs(
+            args,
+            run_args,
+            &mut port,
+            serial_number.as_deref(),
+            interval,
+        );
+    }
+    let result = run_once(
+        args,
+        run_args,
+        &mut port,
+        serial_number.as_deref(),
+        Some(opened),
+    )?;
+    if run_args.json {
+        let info = discover_micropython_devices()?
+            .into_iter()
+            .find(|info| info.path == device);
+        let device = json::Value::object([
+            ("path", device.display().to_string().into()),
+            (
+                "serial_number",
+                info.as_ref()
+                    .and_then(|info| info.serial_number.clone())
+                    .into(),
+            ),
+            (
+                "product",
+                info.as_ref().and_then(|info| info.product.clone()).into(),
+            ),
+        ]);
+        let error = result.error().map(|error| match error {
+            ExecError::Syntax { line, msg, .. } => json::Value::object([
+                ("kind", "syntax".into()),
+                ("line", (line as f64).into()),
+                ("message", msg.into()),
+            ]),
+            ExecError::Runtime { traceback } => {
+                json::Value::object([("kind", "runtime".into()), ("traceback", traceback.into())])
+            }
+        });
+        let timings = &result.timings;
+        let stages = json::Value::object([
+            ("open", opened.as_secs_f64().into()),
+            ("handshake", timings.handshake().as_secs_f64().into()),
+            ("negotiation", timings.negotiation.as_secs_f64().into()),
+            ("upload", timings.upload.as_secs_f64().into()),
+            ("execution", timings.execution.as_secs_f64().into()),
+            ("output", timings.output.as_secs_f64().into()),
+        ]);
+        let report = json::Value::object([
+            ("device", device),
+            ("stdout", decoding(args).text(&result.stdout)?.into()),
+            ("stderr", decoding(args).text(&result.stderr)?.into()),
+            ("exception", result.exception().into()),
+            ("error", error.into()),
+            ("exit_code", f64::from(result.exit_code()).into()),
+            ("duration", (opened + timings.total()).as_secs_f64().into()),
+            ("timings", stages),
+        ]);
+        println!("{}", report);
+    }
+    Ok(result.exit_code())
+}
+
+/// Copy the local modules the script imports to the root of the device
+fn upload_imports(args: &Args, run_args: &RunArgs, port: &mut Device) -> Result<()> {
+    let files: Vec<(PathBuf, String)> = imports::local_imports(&run_args.file)
+        .into_iter()
+        .map(|(local, relative)| (local, fs::join("/", &relative)))
+        .collect();
+    if files.is_empty() {
+        return Ok(());
+    }
+    let transfer = TransferArgs {
+        timeout: run_args.timeout,
+        check: run_args.check,
+        preprocess: run_args.preprocess.clone(),
+        ..TransferArgs::default()
+    };
+    put(args, port, &files, &transfer)
+}
+
+/// The script as preprocessed and with its defines substituted, ahead of minifying it and adding
+/// the prelude
+fn script_source(run_args: &RunArgs) -> Result<String> {
+    let content = match std::fs::read_to_string(&run_args.file) {
+        Ok(content) => content,
+        Err(e) => bail!("Couldn't read file {}: {}", run_args.file.display(), e),
+    };
+    let mut content = preprocess::Pipeline::commands(&run_args.preprocess)
+        .process_text(&run_args.file, content)?;
+    if !run_args.defines.is_empty() {
+        content = template::substitute(&content, &run_args.defines);
+    }
+    Ok(content)
+}
+
+/// Check the script for syntax errors as it would be sent, less the prelude and minifying, so that
+/// the lines are those of the local file
+fn check_script(run_args: &RunArgs) -> Result<()> {
+    syntax::check(&run_args.file, &script_source(run_args)?)
+}
+
+/// Run the script again every time it changes, until Ctrl-C is pressed. With --watch-imports the
+/// local modules it imports are copied to the device up front, and again when they change.
+fn watch_runs(
+    args: &Args,
+    run_args: &RunArgs,
+    port: &mut Device,
+    serial_number: Option<&str>,
+) -> Result<i32> {
+    let imports = if run_args.watch_imports || run_args.upload_imports {
+        imports::local_imports(&run_args.file)
+    } else {
+        Vec::new()
+    };
+    let mut watched = vec![run_args.file.clone()];
+    if run_args.watch_imports {
+        watched.extend(imports.iter().map(|(local, _)| local.clone()));
+    }
+    let mut watcher = Watcher::new(watched);
+
+    let transfer = TransferArgs {
+        check: run_args.check,
+        preprocess: run_args.preprocess.clone(),
+        ..TransferArgs::default()
+    };
+    let remote = |(local, relative): &(PathBuf, String)| (local.clone(), fs::join("/", relative));
+    if !imports.is_empty() {
+        let files: Vec<(PathBuf, String)> = imports.iter().map(remote).collect();
+        put(args, port, &files, &transfer)?;
+    }
+
+    let mut exit_code = 0;
+    let mut checked = true;
+    loop {
+        if checked {
+            exit_code = match run_once(args, run_args, port, serial_number, None) {
+                Ok(result) => result.exit_code(),
+                // Without reconnecting, there's no device left to run on
+                Err(e) if serial_number.is_none() && e.is::<Disconnected>() => return Err(e),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    exit::code(&e)
+                }
+            };
+        }
+
+        if !args.quiet {
+            eprintln!(
+                "Watching {} for changes, stop with Ctrl-C",
+                run_args.file.display()
+            );
+        }
+        let changed = match watcher.wait(WATCH_INTERVAL, WATCH_DEBOUNCE) {
+            Some(changed) => changed,
+            None => return Ok(exit_code),
+        };
+        let changed_imports: Vec<(PathBuf, String)> = imports
+            .iter()
+            .filter(|(local, _)| changed.contains(local) && local.is_file())
+            .map(remote)
+            .collect();
+        if !changed_imports.is_empty() {
+            if let Err(e) = put(args, port, &changed_imports, &transfer) {
+                eprintln!("Error: {}", e);
+            }
+        }
+
+        // A script saved with a typo waits for the next save, rather than being run to fail
+        checked = match run_args.check {
+            true => match check_script(run_args) {
+                Ok(()) => true,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    exit_code = exit::SYNTAX_ERROR;
+                    false
+                }
+            },
+            false => true,
+        };
+    }
+}
+
+/// Run the script every `interval` until Ctrl-C is pressed, returning the exit code of the last
+/// run. Runs that fail don't stop the next.
+fn repeat_runs(
+    args: &Args,
+    run_args: &RunArgs,
+    port: &mut Device,
+    serial_number: Option<&str>,
+    interval: Duration,
+) -> Result<i32> {
+    interrupt::install()?;
+    let mut number = 0;
+    loop {
+        number += 1;
+        let started = Instant::now();
+        if !args.quiet {
+            println!(
+                "--- Run {} at {} ---",
+                number,
+                output::format_time(SystemTime::now())
+            );
+        }
+        let exit_code = match run_once(args, run_args, port, serial_number, None) {
+            Ok(result) if result.interrupted() => return Ok(result.exit_code()),
+            Ok(result) => result.exit_code(),
+            Err(e) if e.is::<Interrupted>() => return Err(e),
+            // Without reconnecting, there's no device left to run on
+            Err(e) if serial_number.is_none() && e.is::<Disconnected>() => return Err(e),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                exit::code(&e)
+            }
+        };
+
+        while started.elapsed() < interval {
+            if interrupt::take() {
+                return Ok(exit_code);
+            }
+            sleep(WATCH_INTERVAL.min(interval.saturating_sub(started.elapsed())));
+        }
+    }
+}
+
+/// Sync `local` to `remote`, restart main.py and print its output, until Ctrl-C is pressed. Only
+/// the files that changed are synced again, and files removed locally are removed from the device.
+fn dev(args: &Args, local: &Path, remote: &str, transfer_args: &TransferArgs) -> Result<()> {
+    let device = resolve_device(args)?;
+    let mut port = open_device(args, &device)?;
+    interrupt::install()?;
+
+    let mut watcher = Watcher::new(vec![local.to_path_buf()]);
+    let mut files = synced_files(args, local, remote)?;
+    let mut restart = true;
+    loop {
+        if restart {
+            let synced = args
+                .config
+                .hooks
+                .run_before()
+                .and_then(|_| put(args, &mut port, &files, transfer_args))
+                .and_then(|_| args.config.hooks.run_after());
+            if let Err(e) = synced {
+                eprintln!("Error: {}", e);
+            }
+            soft_reset(&mut port)?;
+        }
+
+        let mut changed = None;
+        let mut last_poll = Instant::now();
+        let stopped = follow_until(&mut port, || {
+            if last_poll.elapsed() < WATCH_INTERVAL {
+                return false;
+            }
+            last_poll = Instant::now();
+            changed = watcher.poll(WATCH_DEBOUNCE);
+            changed.is_some()
+        })?;
+        let changed = match changed {
+            Some(changed) if stopped => changed,
+            _ => return Ok(()),
+        };
+
+        files.clear();
+        restart = false;
+        for path in changed {
+            let relative = match path.strip_prefix(local) {
+                Ok(relative) => relative
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/"),
+                Err(_) => continue,
+            };
+            if !args.config.sync.includes(&relative) {
+                continue;
+            }
+            restart = true;
+            let target = fs::join(remote, &relative);
+            if path.is_file() {
+                files.push((path, target));
+            } else if let Err(e) = fs::remove(&mut port, &target, transfer_args.timeout) {
+                eprintln!("Error: {}", e);
+            } else if !args.quiet {
+                println!("Removed {}", target);
+            }
+        }
+    }
+}
+
+/// Run the command given on each board of the fleet, a serpico for each board run with the same
+/// command line and the board's device
+fn run_fleet(args: &Args) -> Result<()> {
+    let path = args.fleet.as_deref().unwrap();
+    match &args.command {
+        Some(Command::Run(_))
+        | Some(Command::Put { .. })
+        | Some(Command::Sync { .. })
+        | Some(Command::Diff { .. })
+        | Some(Command::Fs { .. })
+        | Some(Command::Call { .. })
+        | Some(Command::Info)
+        | Some(Command::Inventory { .. })
+        | Some(Command::Snippet { .. })
+        | Some(Command::Test { .. })
+        | Some(Command::Pin { .. })
+        | Some(Command::Pins { .. })
+        | Some(Command::Rtc { .. })
+        | Some(Command::Wifi { .. })
+        | Some(Command::Monitor { .. })
+        | Some(Command::Log { .. }) => {}
+        Some(Command::Deploy { .. }) => bail!(
+            "deploy finds its boards by the profiles of serpico.toml, use sync to copy a \
+             project to each board of a fleet"
+        ),
+        _ => bail!("This command can't be run on a fleet"),
+    }
+    let boards = fleet::load(path)?;
+    let run = matches!(args.command, Some(Command::Run(_)));
+    // Boards that are monitored are all captured at once, as they go on until Ctrl-C
+    let jobs = match args.command {
+        Some(Command::Monitor { .. }) | Some(Command::Log { .. }) => boards.len(),
+        _ => args.jobs,
+    };
+    let mut names = vec!["--fleet", "--jobs", "--fleet-logs"];
+    let mut flags = vec!["--fleet-timestamps", "--fleet-merge"];
+    // Merged lines go where monitor's --out and --json send them, instead of every board's
+    // serpico writing there
+    let sink = match &args.command {
+        Some(Command::Monitor { out, json, .. }) if args.fleet_merge => {
+            names.push("--out");
+            flags.push("--json");
+            monitor_sink(out.as_deref(), *json)?
+        }
+        _ => SharedSink::default(),
+    };
+    let capture = fleet::Capture {
+        timestamps: args.fleet_timestamps || args.fleet_merge,
+        merge: args.fleet_merge,
+        sink,
+    };
+    let program = env::current_exe()?;
+    let command_line: Vec<String> = env::args().skip(1).collect();
+    let command_line = fleet::without_options(&command_line, &names, &flags);
+
+    args.config.hooks.run_before()?;
+    if !args.quiet {
+        eprintln!(
+            "Running on {} boards, with their output in {}",
+            boards.len(),
+            args.fleet_logs.display()
+        );
+    }
+    let reports = fleet::run(
+        &boards,
+        jobs,
+        &args.fleet_logs,
+        args.quiet,
+        capture,
+        |board, device| {
+            let mut command = process::Command::new(&program);
+            command.env(fleet::BOARD_VAR, &board.name);
+            command.arg("--device").arg(device);
+            // Variables go to the run command, ahead of the script's own arguments
+            let end = command_line.iter().position(|arg| arg == "--");
+            let (options, script_args) = command_line.split_at(end.unwrap_or(command_line.len()));
+            command.args(options);
+            if run {
+                for (key, value) in &board.vars {
+                    command.arg("--set").arg(format!("{}={}", key, value));
+                }
+            }
+            command.args(script_args);
+            command
+        },
+    )?;
+    println!("\n{}", fleet::summary(&reports));
+
+    let failed: Vec<&str> = reports
+        .iter()
+        .filter(|report| !report.outcome.succeeded())
+        .map(|report| report.board.as_str())
+        .collect();
+    if !failed.is_empty() {
+        bail!(
+            "{} of {} boards failed: {}",
+            failed.len(),
+            reports.len(),
+            failed.join(", ")
+        );
+    }
+    args.config.hooks.run_after()
+}
+
+/// Gather the inventory of each attached board, or of the device given, carrying on past boards
+/// that fail and exiting with the error of the first that did
+fn inventory(args: &Args, json: bool, timeout: Option<Duration>) -> Result<()> {
+    let discovered = discover_micropython_devices()?;
+    let devices: Vec<DeviceInfo> = match &args.device {
+        Some(device) => vec![discovered
+            .into_iter()
+            .find(|info| &info.path == device)
+            .unwrap_or(DeviceInfo {
+                path: device.clone(),
+                serial_number: None,
+                product: None,
+                data: None,
+            })],
+        None => discovered
+            .into_iter()
+            .filter(|info| {
+                args.config
+                    .matches(info.serial_number.as_deref(), info.product.as_deref())
+            })
+            .collect(),
+    };
+    if devices.is_empty() {
+        let configured = args.config.serial.is_some() || args.config.product.is_some();
+        bail!(NoDevice { configured });
+    }
+
+    let mut reports = Vec::new();
+    let mut failed = None;
+    for info in devices {
+        let gathered = open_device(args, &info.path)
+            .and_then(|mut port| inventory::gather(&mut port, timeout));
+        let mut report = vec![
+            ("path".to_string(), info.path.display().to_string().into()),
+            (
+                "serial_number".to_string(),
+                info.serial_number.clone().into(),
+            ),
+            ("product".to_string(), info.product.clone().into()),
+        ];
+        match &gathered {
+            Ok(inventory) => match inventory.to_json() {
+                json::Value::Object(entries) => report.extend(entries),
+                _ => unreachable!(),
+            },
+            Err(e) => report.push(("error".to_string(), format!("{:#}", e).into())),
+        }
+        if !json {
+            println!("{}", info.path.display());
+            match &gathered {
+                Ok(inventory) => println!("{}\n", inventory),
+                Err(e) => println!("Error: {:#}\n", e),
+            }
+        }
+        reports.push(json::Value::Object(report));
+        if let Err(e) = gathered {
+            failed.get_or_insert(e);
+        }
+    }
+    if json {
+        println!(
+            "{}",
+            json::Value::object([("devices", json::Value::Array(reports))])
+        );
+    }
+    match failed {
+        Some(e) => std::process::exit(exit::code(&e)),
+        None => Ok(()),
+    }
+}
+
+/// Copy the tests to the device and run each test module, returning whether every test passed
+fn test(args: &Args, path: &Path, remote: &str, transfer_args: &TransferArgs) -> Result<bool> {
+    let (files, modules) = if path.is_dir() {
+        let files = fs::local_files(path)?;
+        let modules: Vec<PathBuf> = files
+            .iter()
+            .filter(|(_, relative)| !relative.contains('/') && unittest::is_test_module(relative))
+            .map(|(local, _)| local.clone())
+            .collect();
+        (files, modules)
+    } else {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => bail!("Couldn't get the file name of {}", path.display()),
+        };
+        (vec![(path.to_path_buf(), name)], vec![path.to_path_buf()])
+    };
+    if modules.is_empty() {
+        bail!("No test modules named test*.py in {}", path.display());
+    }
+    let files: Vec<(PathBuf, String)> = files
+        .into_iter()
+        .map(|(local, relative)| (local, fs::join(remote, &relative)))
+        .collect();
+
+    let device = resolve_device(args)?;
+    let mut port = open_device(args, &device)?;
+    put(args, &mut port, &files, transfer_args)?;
+
+    let options = ExecOptions {
+        timeout: transfer_args.timeout,
+        forward_interrupt: true,
+        color: output::color_by_default(),
+        ..ExecOptions::default()
+    };
+    let mut summary = unittest::Summary::default();
+    for local in &modules {
+        let module = match unittest::module_name(local) {
+            Some(module) => module,
+            None => bail!("Couldn't get the module name of {}", local.display()),
+        };
+        if !args.quiet {
+            println!("Running {}", module);
+        }
+        let result = execute(&mut port, unittest::runner(remote, &module), &options)?;
+        let mut output = String::from_utf8_lossy(&result.stdout).to_string();
+        output.push_str(&String::from_utf8_lossy(&result.stderr));
+        match unittest::parse(&output) {
+            Some(module_summary) => summary += module_summary,
+            // The module failed before unittest got to its summary, such as on an import error,
+            // which counts as a test that raised
+            None => {
+                summary.ran += 1;
+                summary.errors += 1;
+                summary
+                    .failed
+                    .push(format!("ERROR: {} didn't finish running", module));
+            }
+        }
+    }
+
+    println!();
+    for failed in summary.failed.iter() {
+        println!("{}", failed);
+    }
+    println!("{}", summary);
+    Ok(summary.succeeded())
+}
+
+/// The files under `local` that are synced to `remote`, as configured in serpico.toml
+/// Where files are copied without a remote path, onto the SD card if one is configured
+fn default_remote(args: &Args) -> String {
+    match &args.config.sd {
+        Some(sd) => sd.mount_point.clone(),
+        None => String::from("/"),
+    }
+}
+
+/// Mount the SD card configured in serpico.toml, if there is one
+fn mount_sd(args: &Args, device: &mut Device, timeout: Option<Duration>) -> Result<()> {
+    if let Some(sd) = &args.config.sd {
+        if sd.mount(device, timeout)? && args.verbose > 0 {
+            println!("Mounted the SD card at {}", sd.mount_point);
+        }
+    }
+    Ok(())
+}
+
+/// The .serpicoignore of `local`, with the patterns given with `--exclude`
+fn sync_ignore(args: &Args, local: &Path) -> Result<Ignore> {
+    let mut ignore = Ignore::load(local)?;
+    if let Some(Command::Sync { exclude, .. })
+    | Some(Command::Deploy { exclude, .. })
+    | Some(Command::Diff { exclude, .. }) = &args.command
+    {
+        for pattern in exclude {
+            ignore.add(pattern);
+        }
+    }
+    Ok(ignore)
+}
+
+fn synced_files(args: &Args, local: &Path, remote: &str) -> Result<Vec<(PathBuf, String)>> {
+    let ignore = sync_ignore(args, local)?;
+    Ok(fs::local_files(local)?
+        .into_iter()
+        .filter(|(_, relative)| args.config.sync.includes(relative) && !ignore.ignores(relative))
+        .map(|(path, relative)| (path, fs::join(remote, &relative)))
+        .collect())
+}
+
+/// The code run ahead of the script, setting up `sys.argv`, `config`, `serpico_frames()` and
+/// feeding the watchdog
+fn prelude(run_args: &RunArgs) -> String {
+    let mut prelude = String::new();
+    if !run_args.script_args.is_empty() {
+        let mut argv = vec![run_args.file.display().to_string()];
+        argv.extend(run_args.script_args.iter().cloned());
+        prelude.push_str(&script::argv_prelude(&argv));
+    }
+    if !run_args.vars.is_empty() {
+        prelude.push_str(&script::config_prelude(&run_args.vars));
+    }
+    if run_args.feed.is_some() {
+        prelude.push_str(feed::HELPER);
+    }
+    if run_args.feed_wdt {
+        prelude.push_str(wdt::HELPER);
+    }
+    if let Some(before) = &run_args.before {
+        prelude.push_str(before);
+        if !before.ends_with('\n') {
+            prelude.push('\n');
+        }
+    }
+    prelude
+}
+
+/// Print what running the script would do, without connecting to the device
+fn dry_run(args: &Args, run_args: &RunArgs) -> Result<()> {
+    if run_args.check {
+        check_script(run_args)?;
+    }
+    let device = resolve_device(args)?;
+    let hooks = &args.config.hooks;
+    dry_hooks(&hooks.before);
+    dry_connect(args, &device);
+    if run_args.upload_imports {
+        let files: Vec<(PathBuf, String)> = imports::local_imports(&run_args.file)
+            .into_iter()
+            .map(|(local, relative)| (local, fs::join("/", &relative)))
+            .collect();
+        dry_copy(&files)?;
+    }
+    if !run_args.no_soft_reset {
+        println!("Would soft reboot the device");
+    }
+
+    let mut content = script_source(run_args)?;
+    if run_args.minify {
+        content = minify::minify(&content).source;
+    }
+    content.insert_str(0, &prelude(run_args));
+    if run_args.compile {
+        println!(
+            "Would compile {} with mpy-cross for the device and run the bytecode:",
+            run_args.file.display()
+        );
+    } else {
+        println!(
+            "Would run {} ({}):",
+            run_args.file.display(),
+            progress::format_bytes(content.len() as f64)
+        );
+    }
+    println!("{}", content.trim_end());
+    match &run_args.feed {
+        Some(feed::Source::File(path)) => println!("Would feed {} to the script", path.display()),
+        Some(feed::Source::Stdin) => println!("Would feed stdin to the script"),
+        None => {}
+    }
+    if run_args.feed_wdt {
+        println!("Would stop feeding the watchdog after the script");
+    }
+    if let Some(after) = &run_args.after {
+        println!("Would run after the script:");
+        println!("{}", after.trim_end());
+    }
+    if run_args.verify_upload {
+        println!("Would have the device check the script's checksum before running it");
+    }
+    if run_args.reset_after {
+        println!("Would soft reset the device into its application");
+    }
+    dry_hooks(&hooks.after);
+    Ok(())
+}
+
+/// Print what copying the files would do, without connecting to the device
+fn dry_put(args: &Args, files: &[(PathBuf, String)]) -> Result<()> {
+    let device = resolve_device(args)?;
+    dry_hooks(&args.config.hooks.before);
+    dry_connect(args, &device);
+    dry_copy(files)?;
+    dry_hooks(&args.config.hooks.after);
+    Ok(())
+}
+
+fn dry_connect(args: &Args, device: &Path) {
+    let via = if args.via_daemon {
+        " via the daemon"
+    } else {
+        ""
+    };
+    print!(
+        "Would connect to {}{} at {} baud",
+        device.display(),
+        via,
+        args.baud
+    );
+    if args.reset.steps.is_empty() {
+        println!();
+    } else {
+        println!(", resetting it with {}", args.reset);
+    }
+}
+
+fn dry_copy(files: &[(PathBuf, String)]) -> Result<()> {
+    for (local, remote) in files {
+        let size = match std::fs::metadata(local) {
+            Ok(metadata) => metadata.len(),
+            Err(e) => bail!("Couldn't read {}: {}", local.display(), e),
+        };
+        println!(
+            "Would copy {} -> {} ({})",
+            local.display(),
+            remote,
+            progress::format_bytes(size as f64)
+        );
+    }
+    Ok(())
+}
+
+fn dry_hooks(commands: &[String]) {
+    for command in commands {
+        println!("Would run hook `{}`", command);
+    }
+}
+
+/// Run the script once on the open device, which took `opened` to open if it was just opened
+fn run_once(
+    args: &Args,
+    run_args: &RunArgs,
+    port: &mut Device,
+    serial_number: Option<&str>,
+    opened: Option<Duration>,
+) -> Result<ExecResult> {
+    let file_arg = &run_args.file;
+
+    // Scripts that are run as they are get streamed from the file, without reading it all in
+    let transform = run_args.minify
+        || run_args.verify_upload
+        || run_args.compile
+        || !run_args.defines.is_empty()
+        || !run_args.preprocess.is_empty();
+    let mut content = String::new();
+    let mut streamed = None;
+    if transform {
+        content = script_source(run_args)?;
+    } else {
+        streamed = Some(Script::open(file_arg)?);
+    }
+
+    let lines = if run_args.minify {
+        let minified = minify::minify(&content);
+        if args.verbose > 0 {
+            println!(
+                "Minified {} from {} to {}",
+                file_arg.display(),
+                progress::format_bytes(content.len() as f64),
+                progress::format_bytes(minified.source.len() as f64)
+            );
+        }
+        content = minified.source;
+        minified.lines
+    } else {
+        Vec::new()
+    };
+
+    let prelude = prelude(run_args);
+    content.insert_str(0, &prelude);
+
+    let mut options = run_args.exec_options(args)?;
+
+    // The device sees the script as <stdin>, with the prelude ahead of its first line. Compiled
+    // scripts are instead known by their file name and started by a small runner script.
+    let mut source_map = SourceMap::default();
+    if run_args.compile {
+        let name = match file_arg.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => bail!("Couldn't get the file name of {}", file_arg.display()),
+        };
+        let target = compile::detect_target(port, options.timeout)?;
+        let compiled = compile::compile(&content, &name, &target)?;
+        if args.verbose > 0 {
+            println!(
+                "Compiled {} to {} of bytecode v{}.{}",
+                file_arg.display(),
+                progress::format_bytes(compiled.len() as f64),
+                target.version,
+                target.sub_version
+            );
+        }
+        let transfer = fs::Transfer::negotiate(port, true, options.timeout)?;
+        fs::write_file(port, compile::FILE, &compiled, &transfer, options.timeout)?;
+        source_map.add_with_lines(&name, file_arg, prelude.lines().count(), lines.clone());
+        content = compile::runner();
+    } else if run_args.verify_upload {
+        // The checked script is run with exec, which knows it as <string>
+        source_map.add_with_lines("<string>", file_arg, prelude.lines().count(), lines.clone());
+    } else {
+        source_map.add_with_lines("<stdin>", file_arg, prelude.lines().count(), lines.clone());
+    }
+    if !run_args.raw && !run_args.terminal {
+        options.source_map = Some(source_map.clone());
+    }
+    // The heap is measured in the same session as the script, which then runs without a reset
+    let heap = if run_args.mem_report {
+        let heap = mem::measure(port, options.soft_reset, options.timeout)?;
+        options.soft_reset = false;
+        Some(heap)
+    } else {
+        None
+    };
+    // The file is opened again if the script has to be run again after reconnecting
+    let mut script = || -> Result<Script> {
+        let script = if run_args.verify_upload {
+            // What's inside the literal isn't normalized as it's sent, so it's normalized first
+            let mut normalized = String::new();
+            Normalizer::new(content.as_bytes(), run_args.expand_tabs)
+                .read_to_string(&mut normalized)?;
+            Script::from(script::verified(&normalized))
+        } else if transform {
+            Script::from(content.as_str())
+        } else {
+            match streamed.take() {
+                Some(script) => script,
+                None => Script::open(file_arg)?,
+            }
+            .with_prelude(prelude.clone())
+        };
+        Ok(script.normalize(run_args.expand_tabs))
+    };
+    // A script the device may not be able to compile at once is run a part at a time
+    let body = transform.then(|| &content[prelude.len()..]);
+    let parts = script_parts(args, run_args, port, &mut options, heap, body)?;
+    let run = |port: &mut Device| -> Result<ExecResult> {
+        Ok(match parts {
+            Some(parts) => {
+                let (result, part_map) =
+                    run_parts(port, parts, &prelude, &lines, run_args, &options)?;
+                source_map = part_map;
+                result
+            }
+            None => match execute(port, script()?, &options) {
+                Err(e) if serial_number.is_some() && e.is::<Disconnected>() => {
+                    let started = e.downcast_ref::<Disconnected>().unwrap().started;
+                    eprintln!("{}, waiting for it to reconnect", e);
+                    *port = reconnect(args, serial_number.unwrap())?;
+                    if started && !run_args.follow {
+                        bail!(e);
+                    }
+                    if started {
+                        ExecResult::default()
+                    } else {
+                        execute(port, script()?, &options)?
+                    }
+                }
+                // The device is still there, running whatever it booted into
+                Err(e) if run_args.reconnect && run_args.follow && e.is::<Rebooted>() => {
+                    eprintln!("{}, following what it prints", e);
+                    ExecResult::default()
+                }
+                result => result?,
+            },
+        })
+    };
+    // The firmware's `machine` is put back however the script ended
+    let result = if run_args.feed_wdt {
+        wdt::restoring(port, options.timeout, run)?
+    } else {
+        run(port)?
+    };
+    if run_args.verify_upload
+        && result
+            .exception()
+            .is_some_and(|exception| exception.contains(script::CORRUPTED))
+    {
+        bail!(script::UploadCorrupted);
+    }
+
+    if args.verbose >= 2 {
+        if port.raw_paste() {
+            eprintln!("Upload flow control: {}", result.flow);
+        } else {
+            eprintln!("Uploaded in the raw REPL, without flow control");
+        }
+    }
+
+    if run_args.timings || args.verbose >= 2 {
+        if let Some(opened) = opened {
+            eprintln!("{:<22}{:>9.3}s", "Port open", opened.as_secs_f64());
+        }
+        eprintln!("{}", result.timings);
+    }
+
+    if let (Some(after), false) = (&run_args.after, run_args.detach) {
+        run_after(port, after, &options)?;
+    }
+
+    if let Some(heap) = heap {
+        eprintln!("{}", mem::report(port, heap, options.timeout)?);
+    }
+
+    // Nothing of a script that doesn't compile ran, which is worth telling from a failure
+    if let Some(ExecError::Syntax { file, line, msg }) = result.error() {
+        if !args.quiet {
+            let frame = Frame {
+                file,
+                line,
+                function: None,
+            };
+            let location = match source_map.map(&frame) {
+                Some((path, line)) => format!("{}:{}", path.display(), line),
+                None => format!("{}:{}", frame.file, frame.line),
+            };
+            eprintln!("The script didn't compile, {}: {}", location, msg);
+        }
+    }
+
+    if run_args.diagnostics {
+        if let Some(exception) = result.exception() {
+            let traceback = String::from_utf8_lossy(&result.stderr);
+            if let Some(diagnostic) = source_map.diagnostic(&traceback, &exception) {
+                eprintln!("{}", diagnostic);
+            }
+        }
+    }
+
+    if result.interrupted() {
+        exit_raw_repl(port)?;
+    } else if run_args.follow {
+        exit_raw_repl(port)?;
+        follow(port)?;
+    } else if run_args.then_repl {
+        exit_raw_repl(port)?;
+        start_repl(args, port, None)?;
+    } else if run_args.reset_after {
+        if args.verbose > 0 {
+            println!("Soft resetting the device into its application");
+        }
+        soft_reset(port)?;
+    }
+
+    Ok(result)
+}
+
+/// The parts to run the script in, if it's split because the device may not have the heap to
+/// compile it at once. `content` is the script if it has been read already.
+fn script_parts(
+    args: &Args,
+    run_args: &RunArgs,
+    port: &mut Device,
+    options: &mut ExecOptions,
+    heap: Option<Heap>,
+    content: Option<&str>,
+) -> Result<Option<Vec<Part>>> {
+    if run_args.split == Split::Never
+        || run_args.compile
+        || run_args.detach
+        || run_args.verify_upload
+    {
+        return Ok(None);
+    }
+    let file_arg = &run_args.file;
+    let size = match content {
+        Some(content) => content.len(),
+        None => match file_arg.metadata() {
+            Ok(metadata) => metadata.len() as usize,
+            Err(e) => bail!("Couldn't read file {}: {}", file_arg.display(), e),
+        },
+    };
+    if run_args.split == Split::Auto && size < split::THRESHOLD {
+        return Ok(None);
+    }
+    // Measuring the heap soft reboots the device if the script would have
+    let free = match heap {
+        Some(heap) => heap.free,
+        None => {
+            let heap = mem::measure(port, options.soft_reset, options.timeout)?;
+            options.soft_reset = false;
+            heap.free
+        }
+    };
+    if run_args.split == Split::Auto && !split::needed(size, free) {
+        return Ok(None);
+    }
+    let mut read = String::new();
+    let source = match content {
+        Some(content) => content,
+        None => {
+            let mut file = match File::open(file_arg) {
+                Ok(file) => file,
+                Err(e) => bail!("Couldn't open file {}: {}", file_arg.display(), e),
+            };
+            if let Err(e) = file.read_to_string(&mut read) {
+                bail!("Couldn't read file {}: {}", file_arg.display(), e);
+            }
+            &read
+        }
+    };
+    let parts = split::split(source, split::part_size(free));
+    if parts.len() < 2 {
+        return Ok(None);
+    }
+    if args.verbose > 0 {
+        println!(
+            "Running {} in {} parts, with {} of heap free",
+            file_arg.display(),
+            parts.len(),
+            progress::format_bytes(free as f64)
+        );
+    }
+    Ok(Some(parts))
+}
+
+/// Run the parts of a split script one after the other in the same session, stopping at the first
+/// that raises. The prelude goes ahead of the first part, and `lines` maps the lines of the script
+/// to the local file if it was rewritten. Returns the source map of the last part run.
+fn run_parts(
+    port: &mut Device,
+    parts: Vec<Part>,
+    prelude: &str,
+    lines: &[usize],
+    run_args: &RunArgs,
+    options: &ExecOptions,
+) -> Result<(ExecResult, SourceMap)> {
+    let mut combined = ExecResult::default();
+    let mut source_map = SourceMap::default();
+    for (index, part) in parts.into_iter().enumerate() {
+        let mut part_options = options.clone();
+        let mut source = part.source;
+        let line_offset = if index == 0 {
+            source.insert_str(0, prelude);
+            prelude.lines().count()
+        } else {
+            part_options.soft_reset = false;
+            0
+        };
+        let local = (part.first_line..part.first_line + source.lines().count() - line_offset)
+            .map(|line| lines.get(line - 1).copied().unwrap_or(line))
+            .collect();
+        source_map = SourceMap::default();
+        source_map.add_with_lines("<stdin>", &run_args.file, line_offset, local);
+        if options.source_map.is_some() {
+            part_options.source_map = Some(source_map.clone());
+        }
+
+        let script = Script::from(source).normalize(run_args.expand_tabs);
+        let result = execute(port, script, &part_options)?;
+        combined.stdout.extend_from_slice(&result.stdout);
+        combined.stderr = result.stderr;
+        combined.timings += result.timings;
+        combined.flow = result.flow;
+        if combined.exception().is_some() {
+            break;
+        }
+    }
+    Ok((combined, source_map))
+}
+
+/// Run the --after code in the session the script ran in
+fn run_after(port: &mut Device, after: &str, options: &ExecOptions) -> Result<()> {
+    let options = ExecOptions {
+        soft_reset: false,
+        max_runtime: None,
+        source_map: None,
+        interaction: None,
+        progress: false,
+        ..options.clone()
+    };
+    let result = execute(port, after, &options)?;
+    if let Some(exception) = result.exception() {
+        bail!("The code run after the script raised {}", exception);
+    }
+    Ok(())
+}
+
+/// What `machine.Pin` takes for `pin`, a number or a name from the board's pinout. Names the
+/// pinout doesn't know are left to the firmware, but for `LED` on boards whose firmware doesn't
+/// name it, which get the pin their family has it on.
+fn resolve_pin(port: &mut Device, device: &Path, pin: &str) -> Result<String> {
+    if pin.parse::<u32>().is_ok() {
+        return Ok(pin.to_string());
+    }
+    let quirks = Quirks::for_port(device).detected(&version::detect(port, None)?);
+    let resolved = match quirks.pinout().and_then(|pinout| pinout.resolve(pin)) {
+        Some(id) => Some(id),
+        None if pin.eq_ignore_ascii_case("led") => quirks.led_pin(),
+        None => None,
+    };
+    Ok(resolved.unwrap_or(pin).to_string())
+}
+
+fn serial_number(device: &Path) -> Result<Option<String>> {
+    let serial_number = discover_micropython_devices()?
+        .into_iter()
+        .find(|info| info.path == device)
+        .and_then(|info| info.serial_number);
+    if serial_number.is_none() {
+        eprintln!(
+            "Serial number of {} is unknown, unable to reconnect",
+            device.display()
+        );
+    }
+    Ok(serial_number)
+}
+
+fn reconnect(args: &Args, serial_number: &str) -> Result<Device> {
+    let info = wait_for_device(serial_number, RECONNECT_TIMEOUT)?;
+    if !args.quiet {
+        eprintln!("Reconnected to {}", info.path.display());
+    }
+    open_device(args, &info.path)
+}
+
+/// Print how the files that sync would copy from `local` differ from those under `remote`
+fn diff(
+    args: &Args,
+    device: &mut Device,
+    local: &Path,
+    remote: &str,
+    content: bool,
+    preprocessors: &preprocess::Pipeline,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let files = synced_files(args, local, remote)?;
+    let ignore = sync_ignore(args, local)?;
+    let remote_files: Vec<fs::Entry> = fs::walk(device, remote, timeout)?
+        .into_iter()
+        .filter(|entry| {
+            let relative = entry.path[remote.trim_end_matches('/').len()..].trim_start_matches('/');
+            !entry.is_dir && args.config.sync.includes(relative) && !ignore.ignores(relative)
+        })
+        .collect();
+    let mut algorithm = None;
+    let mut changes = Vec::new();
+    for (path, remote_path) in &files {
+        let entry = match remote_files.iter().find(|entry| entry.path == *remote_path) {
+            Some(entry) => entry,
+            None => {
+                changes.push((diff::Change::Added, remote_path.clone(), Some(path), None));
+                continue;
+            }
+        };
+        let data = local_data(path, preprocessors)?;
+        // Files of the same size are told apart by their checksums
+        let changed = data.len() as u64 != entry.size || {
+            let algorithm = match algorithm {
+                Some(algorithm) => algorithm,
+                None => *algorithm.insert(fs::checksum_algorithm(device, timeout)?),
+            };
+            algorithm.digest(&data) != fs::checksum(device, remote_path, algorithm, timeout)?
+        };
+        if changed {
+            changes.push((
+                diff::Change::Changed,
+                remote_path.clone(),
+                Some(path),
+                Some(data),
+            ));
+        }
+    }
+    for entry in &remote_files {
+        if !files
+            .iter()
+            .any(|(_, remote_path)| *remote_path == entry.path)
+        {
+            changes.push((diff::Change::Missing, entry.path.clone(), None, None));
+        }
+    }
+    changes.sort_by(|a, b| a.1.cmp(&b.1));
+
+    for (change, remote_path, path, data) in &changes {
+        match path {
+            Some(path) => println!("{} {} ({})", change, remote_path, path.display()),
+            None => println!("{} {}", change, remote_path),
+        }
+        let (path, new) = match (content, path, data) {
+            (true, Some(path), Some(new)) => (path, new),
+            _ => continue,
+        };
+        let old = fs::read_file(device, remote_path, timeout)?;
+        let text = match (std::str::from_utf8(&old), std::str::from_utf8(new)) {
+            (Ok(old), Ok(new)) => diff::unified(
+                &format!("device:{}", remote_path),
+                old,
+                &path.display().to_string(),
+                new,
+            ),
+            _ => None,
+        };
+        match text {
+            Some(text) => print!("{}", text),
+            None => println!("Binary or large files differ"),
+        }
+    }
+    if changes.is_empty() && !args.quiet {
+        eprintln!("{} matches {} on the device", local.display(), remote);
+    }
+    Ok(())
+}
+
+/// Run the script with snapshots of the heap taken through it, printing its output followed by
+/// the snapshots, and return its exit code
+fn profile_mem(
+    device: &mut Device,
+    file: &Path,
+    points: &[usize],
+    collect: bool,
+    timeout: Option<Duration>,
+) -> Result<i32> {
+    let source = match std::fs::read_to_string(file) {
+        Ok(source) => source,
+        Err(e) => bail!("Couldn't read file {}: {}", file.display(), e),
+    };
+    let profiled = mem::instrument(&source, points, collect)?;
+    let mut source_map = SourceMap::default();
+    source_map.add_with_lines("<stdin>", file, profiled.offset, profiled.lines);
+    let options = ExecOptions {
+        timeout,
+        echo: false,
+        ..ExecOptions::default()
+    };
+    let result = execute(device, profiled.script.as_str(), &options)?;
+
+    let (output, snapshots) = mem::parse_snapshots(&String::from_utf8_lossy(&result.stdout));
+    print!("{}", output);
+    for line in String::from_utf8_lossy(&result.stderr).lines() {
+        eprintln!("{}", source_map.rewrite_line(line));
+    }
+    match (snapshots.first(), snapshots.last()) {
+        (Some(first), Some(last)) if !first.blocks.is_empty() => {
+            println!("{}", mem::summary(&snapshots));
+            println!("{}", mem::compare(first, last));
+        }
+        _ if result.exception().is_some() => {}
+        _ => bail!("The device printed no heap map, its firmware may lack micropython.mem_info"),
+    }
+    Ok(result.exit_code())
+}
+
+/// Write each file to its path on the device and check its checksum there
+fn install(
+    args: &Args,
+    device: &mut Device,
+    files: &[(&str, Vec<u8>)],
+    no_compress: bool,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let transfer = fs::Transfer::negotiate(device, !no_compress, timeout)?;
+    if args.verbose > 0 {
+        println!("Sending files as {}", transfer);
+    }
+    let algorithm = fs::checksum_algorithm(device, timeout)?;
+    for (path, data) in files {
+        fs::write_file(device, path, data, &transfer, timeout)?;
+        let expected = algorithm.digest(data);
+        let actual = fs::checksum(device, path, algorithm, timeout)?;
+        if expected != actual {
+            bail!(
+                "{} doesn't match what was sent: {} {} on the device, {} locally",
+                path,
+                algorithm,
+                actual,
+                expected
+            );
+        }
+        if !args.quiet {
+            println!(
+                "Installed {} ({}, {} verified)",
+                path,
+                progress::format_bytes(data.len() as f64),
+                algorithm
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Write every file and directory under `remote` on the device to the tar file `archive`
+fn backup(
+    args: &Args,
+    device: &mut Device,
+    archive: &Path,
+    remote: &str,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let entries = fs::walk(device, remote, timeout)?;
+    let file = match File::create(archive) {
+        Ok(file) => file,
+        Err(e) => bail!("Couldn't create {}: {}", archive.display(), e),
+    };
+    let mut writer = tar::Writer::new(io::BufWriter::new(file));
+    let mut total = 0;
+    for entry in &entries {
+        let relative = entry.path[remote.trim_end_matches('/').len()..].trim_start_matches('/');
+        if entry.is_dir {
+            writer.dir(relative)?;
+            continue;
+        }
+        let data = fs::read_file(device, &entry.path, timeout)?;
+        if data.len() as u64 != entry.size {
+            bail!(
+                "Read {} bytes of {} from the device, but it has {}",
+                data.len(),
+                entry.path,
+                entry.size
+            );
+        }
+        writer.file(relative, &data)?;
+        total += data.len();
+        if !args.quiet {
+            println!(
+                "{} ({})",
+                entry.path,
+                progress::format_bytes(data.len() as f64)
+            );
+        }
+    }
+    writer.finish()?;
+    if !args.quiet {
+        println!(
+            "Archived {} files, {}, to {}",
+            entries.iter().filter(|entry| !entry.is_dir).count(),
+            progress::format_bytes(total as f64),
+            archive.display()
+        );
+    }
+    Ok(())
+}
+
+/// Write each member of an archive to the device under `remote`, creating its directories
+fn restore(
+    args: &Args,
+    device: &mut Device,
+    members: &[tar::Member],
+    remote: &str,
+    no_compress: bool,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let transfer = fs::Transfer::negotiate(device, !no_compress, timeout)?;
+    if args.verbose > 0 {
+        println!("Sending files as {}", transfer);
+    }
+    let mut created: Vec<String> = Vec::new();
+    for member in members {
+        let relative = member.path.trim_start_matches("./").trim_start_matches('/');
+        if relative.is_empty() || relative == "." {
+            continue;
+        }
+        let path = fs::join(remote, relative);
+        let dir = match &member.data {
+            Some(_) => fs::parent(&path).to_string(),
+            None => path.clone(),
+        };
+        if !dir.is_empty() && dir != "/" && !created.contains(&dir) {
+            fs::make_dirs(device, &dir, timeout)?;
+            created.push(dir);
+        }
+        if let Some(data) = &member.data {
+            fs::write_file(device, &path, data, &transfer, timeout)?;
+            if !args.quiet {
+                println!("{} ({})", path, progress::format_bytes(data.len() as f64));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The contents of a local file as they're sent, passed through `preprocessors` if it's a `.py`
+/// file
+fn local_data(local: &Path, preprocessors: &preprocess::Pipeline) -> Result<Vec<u8>> {
+    let data = match std::fs::read(local) {
+        Ok(data) => data,
+        Err(e) => bail!("Couldn't read file {}: {}", local.display(), e),
+    };
+    match local.extension() == Some("py".as_ref()) {
+        true => preprocessors.process(local, data),
+        false => Ok(data),
+    }
+}
+
+/// Copy each local file to its remote path, creating the directories they're in
+fn put(
+    args: &Args,
+    device: &mut Device,
+    files: &[(PathBuf, String)],
+    transfer_args: &TransferArgs,
+) -> Result<()> {
+    let preprocessors = preprocess::Pipeline::commands(&transfer_args.preprocess);
+    // With --check every file is read up front, and what was checked is what's sent
+    let mut checked = Vec::new();
+    if transfer_args.check {
+        for (local, _) in files {
+            let data = local_data(local, &preprocessors)?;
+            if local.extension() == Some("py".as_ref()) {
+                syntax::check(local, &String::from_utf8_lossy(&data))?;
+            }
+            checked.push(data);
+        }
+    }
+    let mut checked = checked.into_iter();
+    let timeout = transfer_args.timeout;
+    let transfer = fs::Transfer::negotiate(device, !transfer_args.no_compress, timeout)?;
+    if args.verbose > 0 {
+        println!("Sending files as {}", transfer);
+    }
+    let algorithm = if transfer_args.verify || transfer_args.resume {
+        Some(fs::checksum_algorithm(device, timeout)?)
+    } else {
+        None
+    };
+
+    let mut created = Vec::new();
+    let mut verified = Vec::new();
+    for (local, remote) in files {
+        let dir = fs::parent(remote);
+        if !dir.is_empty() && dir != "/" && !created.iter().any(|created| created == dir) {
+            fs::make_dirs(device, dir, timeout)?;
+            created.push(dir.to_string());
+        }
+        let data = match checked.next() {
+            Some(data) => data,
+            None => local_data(local, &preprocessors)?,
+        };
+        match algorithm.filter(|_| transfer_args.resume) {
+            Some(algorithm) => {
+                let offset = fs::resume_offset(device, remote, &data, algorithm, timeout)?;
+                if offset > 0 && !args.quiet {
+                    match offset == data.len() {
+                        true => println!("{} is already on the device", remote),
+                        false => println!(
+                            "Resuming {} after {}",
+                            remote,
+                            progress::format_bytes(offset as f64)
+                        ),
+                    }
+                }
+                fs::write_from(device, remote, &data, offset, &transfer, timeout)?;
+            }
+            None => fs::write_file(device, remote, &data, &transfer, timeout)?,
+        }
+        if !args.quiet {
+            println!(
+                "{} -> {} ({})",
+                local.display(),
+                remote,
+                progress::format_bytes(data.len() as f64)
+            );
+        }
+
+        if let Some(algorithm) = algorithm.filter(|_| transfer_args.verify) {
+            let expected = algorithm.digest(&data);
+            let actual = fs::checksum(device, remote, algorithm, timeout)?;
+            if expected != actual {
+                eprintln!(
+                    "{} doesn't match {}: {} {} on the device, {} locally",
+                    remote,
+                    local.display(),
+                    algorithm,
+                    actual,
+                    expected
+                );
+            }
+            verified.push((local, remote, expected, actual));
+        }
+    }
+
+    let algorithm = match algorithm.filter(|_| transfer_args.verify) {
+        Some(algorithm) => algorithm,
+        None => return Ok(()),
+    };
+    let failed = verified
+        .iter()
+        .filter(|(_, _, expected, actual)| expected != actual)
+        .count();
+    if let Some(path) = &transfer_args.report {
+        let entries: Vec<String> = verified
+            .iter()
+            .map(|(local, remote, expected, actual)| {
+                format!(
+                    "{{\"local\": {}, \"remote\": {}, \"expected\": {}, \"actual\": {}, \"ok\": {}}}",
+                    json::quote(&local.display().to_string()),
+                    json::quote(remote),
+                    json::quote(expected),
+                    json::quote(actual),
+                    expected == actual
+                )
+            })
+            .collect();
+        let board = inventory::gather(device, timeout)?;
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let report = format!(
+            "{{\"serpico\": {}, \"time\": {}, \"unique_id\": {}, \"firmware\": {}, \
+             \"algorithm\": {}, \"ok\": {}, \"files\": [{}]}}\n",
+            json::quote(env!("CARGO_PKG_VERSION")),
+            time,
+            json::Value::from(board.unique_id),
+            json::Value::from(board.version),
+            json::quote(&algorithm.to_string()),
+            failed == 0,
+            entries.join(", ")
+        );
+        if let Err(e) = std::fs::write(path, &report) {
+            bail!("Couldn't write report {}: {}", path.display(), e);
+        }
+        if let Some(key_path) = &transfer_args.sign_key {
+            let key = match std::fs::read(key_path) {
+                Ok(key) => key,
+                Err(e) => bail!("Couldn't read key {}: {}", key_path.display(), e),
+            };
+            let key = key.strip_suffix(b"\n").unwrap_or(&key);
+            let key = key.strip_suffix(b"\r").unwrap_or(key);
+            let signature: String = checksum::hmac_sha256(key, report.as_bytes())
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            let mut signature_path = path.clone().into_os_string();
+            signature_path.push(".sig");
+            if let Err(e) = std::fs::write(&signature_path, signature + "\n") {
+                bail!("Couldn't write signature {:?}: {}", signature_path, e);
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} files failed verification", failed, verified.len());
+    }
+    if args.verbose > 0 {
+        println!("Verified {} files with {}", verified.len(), algorithm);
+    }
+    Ok(())
+}
+
+fn run_bench(device: &mut Device, sizes: &[usize], timeout: Option<Duration>) -> Result<()> {
+    let options = ExecOptions {
+        timeout,
+        ..ExecOptions::default()
+    };
+    let measurements = bench::run(device, sizes, &options)?;
+
+    println!(
+        "{:<10} {:>10} {:>10} {:>12} {:>10}",
+        "direction", "size", "time", "rate", "handshake"
+    );
+    for measurement in measurements.iter() {
+        let direction = match measurement.direction {
+            Direction::Upload => "upload",
+            Direction::Download => "download",
+        };
+        println!(
+            "{:<10} {:>10} {:>9.3}s {:>10}/s {:>9.3}s",
+            direction,
+            progress::format_bytes(measurement.size as f64),
+            measurement.duration().as_secs_f64(),
+            progress::format_bytes(measurement.bytes_per_sec()),
+            measurement.timings.handshake().as_secs_f64(),
+        );
+    }
+
+    Ok(())
+}
+
+fn list_snippets(args: &Args) {
+    for (name, source) in &args.config.snippets {
+        let first_line = source.lines().find(|line| !line.trim().is_empty());
+        println!("{:<14}{}", name, first_line.unwrap_or_default().trim());
+    }
+    for builtin in snippet::BUILTINS {
+        let overridden = args
+            .config
+            .snippets
+            .iter()
+            .any(|(name, _)| name == builtin.name);
+        if !overridden {
+            println!("{:<14}{}", builtin.name, builtin.description);
+        }
+    }
+}
+
+/// Run a snippet in the session the device is in, without a soft reset, returning the exit code
+fn run_snippet(args: &Args, name: &str, timeout: Option<Duration>) -> Result<i32> {
+    let source = match snippet::find(&args.config.snippets, name) {
+        Some(source) => source,
+        None => bail!("No snippet {:?}, list them with `serpico snippet`", name),
+    };
+    let device = resolve_device(args)?;
+    let mut port = open_device(args, &device)?;
+    let options = ExecOptions {
+        timeout,
+        soft_reset: false,
+        forward_interrupt: true,
+        color: output::color_by_default(),
+        ..ExecOptions::default()
+    };
+    let result = execute(&mut port, source, &options)?;
+    if result.interrupted() {
+        exit_raw_repl(&mut port)?;
+    }
+    Ok(result.exit_code())
+}
+
+fn flash(args: &Args, image_path: &Path, volume: Option<&Path>) -> Result<()> {
+    let image = read_uf2(image_path)?;
+    let (volume, serial) = bootloader_volume(args, volume)?;
+    if !args.quiet {
+        println!(
+            "Flashing {} ({}) to {}",
+            image_path.display(),
+            progress::format_bytes(image.len() as f64),
+            volume.display()
+        );
+    }
+    uf2::copy(&image, &volume)?;
+
+    let device = wait_for_micropython(serial.as_deref())?;
+    if !args.quiet {
+        println!("MicroPython is back at {}", device.display());
+    }
+    Ok(())
+}
+
+fn read_uf2(path: &Path) -> Result<Vec<u8>> {
+    let image = match std::fs::read(path) {
+        Ok(image) => image,
+        Err(e) => bail!("Couldn't read {}: {}", path.display(), e),
+    };
+    uf2::check(&image)?;
+    Ok(image)
+}
+
+/// The mounted bootloader drive, rebooting the board into its bootloader if there's none yet.
+/// The serial number of the board is returned too when it was rebooted, to find it again by.
+fn bootloader_volume(args: &Args, volume: Option<&Path>) -> Result<(PathBuf, Option<String>)> {
+    if let Some(volume) = volume.map(Path::to_path_buf).or_else(uf2::find_volume) {
+        return Ok((volume, None));
+    }
+    let device = resolve_device(args)?;
+    let serial = serial_number(&device)?;
+    if !args.quiet {
+        println!("Rebooting {} into its bootloader", device.display());
+    }
+    enter_bootsel(args, &device)?;
+    Ok((uf2::wait_for_volume(FLASH_TIMEOUT)?, serial))
+}
+
+/// Reboot an RP2040 over USB, by opening its serial port at 1200 baud where that's enough and
+/// otherwise with picotool
+fn reboot_pico(args: &Args, bootsel: bool) -> Result<()> {
+    let in_bootsel = uf2::find_volume().is_some();
+    if bootsel && !in_bootsel {
+        if let Ok(device) = resolve_device(args) {
+            if device.exists() {
+                if !args.quiet {
+                    println!("Rebooting {} into BOOTSEL", device.display());
+                }
+                picotool::touch(&device)?;
+                let volume = uf2::wait_for_volume(FLASH_TIMEOUT)?;
+                if !args.quiet {
+                    println!("The board is in BOOTSEL at {}", volume.display());
+                }
+                return Ok(());
+            }
+        }
+    }
+    // Outside of BOOTSEL picotool has to go through the running firmware
+    picotool::reboot(&picotool::find()?, bootsel, !in_bootsel)
+}
+
+/// Reboot the board into BOOTSEL from its REPL, or over USB where the REPL doesn't respond
+fn enter_bootsel(args: &Args, device: &Path) -> Result<()> {
+    if let Err(e) = run_detached(args, device, uf2::BOOTLOADER) {
+        if !device.exists() {
+            return Err(e);
+        }
+        eprintln!("{}, rebooting into BOOTSEL over USB instead", e);
+        picotool::touch(device)?;
+    }
+    Ok(())
+}
+
+/// Start `script` on the device without waiting for it, for scripts that reset the board
+fn run_detached(args: &Args, device: &Path, script: &str) -> Result<()> {
+    let mut port = open_device(args, device)?;
+    let options = ExecOptions {
+        soft_reset: false,
+        detach: true,
+        echo: false,
+        log: false,
+        ..ExecOptions::default()
+    };
+    match execute(&mut port, script, &options) {
+        Err(e) if !e.is::<Disconnected>() => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Wait for the board with `serial` to show up as a MicroPython device, or for any board if its
+/// serial number is unknown
+fn wait_for_micropython(serial: Option<&str>) -> Result<PathBuf> {
+    if let Some(serial) = serial {
+        return Ok(wait_for_device(serial, FLASH_TIMEOUT)?.path);
+    }
+    let start = Instant::now();
+    loop {
+        if let Some(device) = find_micropython_devices()?.into_iter().next() {
+            return Ok(device);
+        }
+        if start.elapsed() > FLASH_TIMEOUT {
+            bail!("Timed out waiting for the board to come back as a MicroPython device");
+        }
+        sleep(Duration::from_millis(250));
+    }
+}
+
+/// Recover a board with a corrupted filesystem, by erasing the filesystem from MicroPython or, for
+/// boards that don't get as far as the REPL, by flashing a flash_nuke.uf2 image that erases all
+/// of the flash
+fn nuke(args: &Args, image_path: Option<&Path>, volume: Option<&Path>) -> Result<()> {
+    if let Some(image_path) = image_path {
+        let image = read_uf2(image_path)?;
+        let (volume, _) = bootloader_volume(args, volume)?;
+        if !args.quiet {
+            println!("Erasing the flash with {}", image_path.display());
+        }
+        uf2::copy(&image, &volume)?;
+        // The board comes back in its bootloader once the flash is erased
+        let volume = uf2::wait_for_remount(&volume, FLASH_TIMEOUT)?;
+        if !args.quiet {
+            println!(
+                "Flash erased, the board is in its bootloader at {}, put MicroPython back with the \
+                 flash command",
+                volume.display()
+            );
+        }
+        return Ok(());
+    }
+
+    let device = resolve_device(args)?;
+    let serial = serial_number(&device)?;
+    if !args.quiet {
+        println!("Erasing the filesystem of {}", device.display());
+    }
+    run_detached(args, &device, uf2::ERASE_FILESYSTEM)?;
+    // Give the board a moment to go away, so it isn't found again before it has reset
+    let start = Instant::now();
+    while device.exists() && start.elapsed() < Duration::from_secs(2) {
+        sleep(Duration::from_millis(100));
+    }
+    let device = wait_for_micropython(serial.as_deref())?;
+    if !args.quiet {
+        println!(
+            "Filesystem erased, MicroPython is back at {} with an empty one",
+            device.display()
+        );
+    }
+    Ok(())
+}
+
+fn flash_esp(args: &Args, image: &Path, chip: &str, erase: bool, baud: u32) -> Result<()> {
+    // A blank board has no MicroPython to be discovered by, and esptool needs the port to itself
+    let device = match &args.device {
+        Some(device) => device.clone(),
+        None => bail!("Give the board's serial port with the device option"),
+    };
+    if args.via_daemon {
+        bail!("The daemon keeps the port open, which esptool needs to itself");
+    }
+    if !image.is_file() {
+        bail!("Couldn't read {}", image.display());
+    }
+    let program = esptool::find()?;
+    if !args.quiet {
+        println!(
+            "Flashing {} to {} with {}",
+            image.display(),
+            device.display(),
+            program.display()
+        );
+    }
+    esptool::flash(&program, chip, &device, image, baud, erase, args.quiet)?;
+
+    // The chip boots into the new firmware once esptool resets it, which can take a moment
+    let start = Instant::now();
+    let firmware = loop {
+        let detected = open_device(args, &device)
+            .and_then(|mut port| version::detect(&mut port, Some(Duration::from_secs(2))));
+        match detected {
+            Ok(firmware) => break firmware,
+            Err(e) if start.elapsed() > FLASH_TIMEOUT => {
+                bail!(
+                    "{} didn't boot into a REPL after flashing: {}",
+                    device.display(),
+                    e
+                )
+            }
+            Err(_) => sleep(Duration::from_millis(500)),
+        }
+    };
+    if !args.quiet {
+        println!("{} is running at {}", firmware, device.display());
+    }
+    Ok(())
+}
+
+/// Sync the project to the board of each target, skipping boards that aren't attached when
+/// deploying to all of them
+fn deploy(
+    matches: &ArgMatches,
+    args: &Args,
+    targets: &[String],
+    local: &Path,
+    remote: &str,
+    dry_run: bool,
+    transfer: &TransferArgs,
+) -> Result<()> {
+    let all = targets.iter().any(|target| target == "all");
+    let names: Vec<String> = if all {
+        args.config
+            .profiles
+            .iter()
+            .filter(|(_, profile)| profile.serial.is_some() || profile.product.is_some())
+            .map(|(name, _)| name.clone())
+            .collect()
+    } else {
+        targets.to_vec()
+    };
+    if names.is_empty() {
+        bail!("No profile in serpico.toml has a serial number or product to find its board by");
+    }
+
+    if !dry_run {
+        args.config.hooks.run_before()?;
+    }
+    let mut failed = Vec::new();
+    for name in &names {
+        let target = with_config(matches, Some(name))?;
+        if target.config.serial.is_none() && target.config.product.is_none() {
+            bail!(
+                "Profile {} has no serial number or product to find its board by",
+                name
+            );
+        }
+        let mut boards: Vec<DeviceInfo> = discover_micropython_devices()?
+            .into_iter()
+            .filter(|info| {
+                target
+                    .config
+                    .matches(info.serial_number.as_deref(), info.product.as_deref())
+            })
+            .collect();
+        let board = match boards.len() {
+            1 => boards.pop().unwrap().path,
+            0 if all => {
+                if !args.quiet {
+                    println!("Skipping {}, its board isn't attached", name);
+                }
+                continue;
+            }
+            0 => {
+                eprintln!("The board of {} isn't attached", name);
+                failed.push(name.as_str());
+                continue;
+            }
+            _ => {
+                eprintln!("Several attached boards match {}", name);
+                failed.push(name.as_str());
+                continue;
+            }
+        };
+        let files = synced_files(&target, local, remote)?;
+        if dry_run {
+            println!("Would deploy {} to {}", name, board.display());
+            dry_copy(&files)?;
+            continue;
+        }
+        if !args.quiet {
+            println!("Deploying {} to {}", name, board.display());
+        }
+        let deployed = open_device(&target, &board)
+            .and_then(|mut port| put(&target, &mut port, &files, transfer));
+        if let Err(e) = deployed {
+            eprintln!("Couldn't deploy {}: {}", name, e);
+            failed.push(name.as_str());
+        }
+    }
+    if !failed.is_empty() {
+        bail!(
+            "{} of {} targets failed: {}",
+            failed.len(),
+            names.len(),
+            failed.join(", ")
+        );
+    }
+    if dry_run {
+        return Ok(());
+    }
+    args.config.hooks.run_after()
+}
+
+/// Copy the files to each of the boards at `hosts` through their WebREPL
+fn ota(
+    args: &Args,
+    hosts: &[String],
+    files: &[(PathBuf, String)],
+    reset: bool,
+    transfer: &TransferArgs,
+) -> Result<()> {
+    let mut failed = Vec::new();
+    for host in hosts {
+        let url = if host.starts_with("ws://") {
+            host.clone()
+        } else {
+            format!("ws://{}", host)
+        };
+        if !args.quiet {
+            println!("Updating {}", url);
+        }
+        let updated = open_device(args, Path::new(&url)).and_then(|mut port| {
+            put(args, &mut port, files, transfer)?;
+            drop(port);
+            if reset {
+                run_detached(args, Path::new(&url), RESET)?;
+            }
+            Ok(())
+        });
+        if let Err(e) = updated {
+            eprintln!("Couldn't update {}: {}", url, e);
+            failed.push(host.as_str());
+        }
+    }
+    if !failed.is_empty() {
+        bail!(
+            "{} of {} boards failed to update: {}",
+            failed.len(),
+            hosts.len(),
+            failed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn start_repl(args: &Args, device: &mut Device, editor: Option<LineEditor>) -> Result<()> {
+    if !args.quiet {
+        println!("Connected to MicroPython REPL, exit with Ctrl-]");
+    }
+    repl(device, editor)
+}
+
+fn watch(interval: u64) -> Result<()> {
+    watch_devices(Duration::from_millis(interval), |event| {
+        match event {
+            DeviceEvent::Connected(info) => println!("connected    {}", describe(&info)),
+            DeviceEvent::Disconnected(info) => println!("disconnected {}", describe(&info)),
+        }
+        Ok(())
+    })
+}
+
+/// List the discovered MicroPython devices, or with `probe` every USB serial port and whether a
+/// MicroPython REPL answers on it. The ports are probed at once, so that the slow ones don't hold
+/// up the rest.
+fn devices(args: &Args, probe: bool, timeout: Duration) -> Result<()> {
+    if !probe {
+        for info in discover_micropython_devices()? {
+            println!("{}", describe(&info));
+        }
+        return Ok(());
+    }
+    let ports = discover_usb_serial_ports()?;
+    let answers: Vec<Result<Option<String>>> = thread::scope(|scope| {
+        let probes: Vec<_> = ports
+            .iter()
+            .map(|info| {
+                scope.spawn(move || {
+                    let mut port = port_builder(args, &info.path).open_retries(0).open()?;
+                    probe::probe(&mut port, timeout)
+                })
+            })
+            .collect();
+        probes
+            .into_iter()
+            .map(|probe| probe.join().unwrap())
+            .collect()
+    });
+    for (info, answer) in ports.iter().zip(answers) {
+        match answer {
+            Ok(Some(banner)) => println!("{} repl={:?}", describe(info), banner),
+            Ok(None) => println!("{} repl=-", describe(info)),
+            Err(e) => println!("{} error={:?}", describe(info), format!("{:#}", e)),
+        }
+    }
+    Ok(())
+}
+
+fn describe(info: &DeviceInfo) -> String {
+    let mut description = format!(
+        "{} serial={} board={}",
+        info.path.display(),
+        info.serial_number.as_deref().unwrap_or("-"),
+        info.product.as_deref().unwrap_or("-"),
+    );
+    if let Some(data) = &info.data {
+        description.push_str(&format!(" data={}", data.display()));
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serpico::sink::Memory;
+    use std::sync::Arc;
+
+    fn parse(argv: &[&str]) -> Args {
+        let argv = with_run(argv.iter().map(OsString::from).collect());
+        Args::try_parse_from(argv).unwrap()
+    }
+
+    fn run_args(args: &Args) -> &RunArgs {
+        match &args.command {
+            Some(Command::Run(run_args)) => run_args,
+            command => panic!("Expected run, got {:?}", command),
+        }
+    }
+
+    #[test]
+    fn raw_output_is_not_decoded() {
+        let args = Args::parse_from(["serpico", "run", "--raw", "script.py"]);
+        let options = run_args(&args).exec_options(&args).unwrap();
+        let memory = Arc::new(Memory::default());
+        let mut echo = Echo::new(None, options.color, None)
+            .sink(SharedSink(memory.clone()))
+            .decoding(options.decoding);
+        // Invalid UTF-8, then a sequence cut short at the end
+        let sent = b"caf\xe9 \xff\xfe\r\n\xc3";
+        echo.write(sent).unwrap();
+        assert_eq!(memory.contents().0, sent);
+    }
+
+    #[test]
+    fn script_in_place_of_a_command_is_run() {
+        let args = parse(&["serpico", "-d", "/dev/ttyACM0", "blink.py", "-t", "5"]);
+        assert_eq!(args.device.as_deref(), Some(Path::new("/dev/ttyACM0")));
+        let run_args = run_args(&args);
+        assert_eq!(run_args.file, Path::new("blink.py"));
+        assert_eq!(run_args.timeout, Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn commands_win_over_scripts() {
+        let args = parse(&["serpico", "put", "boot.py"]);
+        assert!(matches!(args.command, Some(Command::Put { .. })));
+        let args = parse(&["serpico", "run", "main.py"]);
+        assert_eq!(run_args(&args).file, Path::new("main.py"));
+        // Neither a command nor a script, so left for a plugin
+        let args = parse(&["serpico", "frobnicate"]);
+        assert!(matches!(args.command, Some(Command::External(_))));
+    }
 }
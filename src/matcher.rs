@@ -0,0 +1,155 @@
+//! Finding a byte string in a stream that arrives a read at a time, where it can be split across
+//! reads, without searching what has been read again. This is the Knuth-Morris-Pratt algorithm.
+
+/// Looks for one byte string in a stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Matcher {
+    needle: Vec<u8>,
+    /// For each length of matched prefix, the length of the longest proper prefix of the needle
+    /// that is also a suffix of it, which is how much is still matched after a mismatch
+    fallback: Vec<usize>,
+    /// How much of the needle the stream currently ends with
+    matched: usize,
+}
+
+impl Matcher {
+    pub fn new(needle: &[u8]) -> Matcher {
+        let mut fallback = vec![0; needle.len()];
+        let mut length = 0;
+        for index in 1..needle.len() {
+            while length > 0 && needle[index] != needle[length] {
+                length = fallback[length - 1];
+            }
+            if needle[index] == needle[length] {
+                length += 1;
+            }
+            fallback[index] = length;
+        }
+        Matcher {
+            needle: needle.to_vec(),
+            fallback,
+            matched: 0,
+        }
+    }
+
+    /// Feed the next bytes of the stream, returning the position just past the end of the first
+    /// match in `bytes` if the needle ends in them. After a match, the stream is searched afresh.
+    pub fn feed(&mut self, bytes: &[u8]) -> Option<usize> {
+        if self.needle.is_empty() {
+            return Some(0);
+        }
+        for (index, &byte) in bytes.iter().enumerate() {
+            while self.matched > 0 && byte != self.needle[self.matched] {
+                self.matched = self.fallback[self.matched - 1];
+            }
+            if byte == self.needle[self.matched] {
+                self.matched += 1;
+            }
+            if self.matched == self.needle.len() {
+                self.matched = 0;
+                return Some(index + 1);
+            }
+        }
+        None
+    }
+
+    /// How many bytes at the end of what has been fed could be the start of a match, and so can't
+    /// be passed on yet
+    pub fn matched(&self) -> usize {
+        self.matched
+    }
+
+    pub fn needle(&self) -> &[u8] {
+        &self.needle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whole_needle_in_one_read() {
+        let mut matcher = Matcher::new(b"OK\x04");
+        assert_eq!(matcher.feed(b"xxOK\x04yy"), Some(5));
+    }
+
+    #[test]
+    fn split_over_two_reads() {
+        let mut matcher = Matcher::new(b"raw REPL; CTRL-B to exit\r\n>");
+        assert_eq!(matcher.feed(b"...raw REPL; CT"), None);
+        assert_eq!(matcher.matched(), 12);
+        assert_eq!(matcher.feed(b"RL-B to exit\r\n>more"), Some(15));
+    }
+
+    #[test]
+    fn split_over_three_reads() {
+        let mut matcher = Matcher::new(b"\x04\x04>");
+        assert_eq!(matcher.feed(b"out\x04"), None);
+        assert_eq!(matcher.feed(b"\x04"), None);
+        assert_eq!(matcher.matched(), 2);
+        assert_eq!(matcher.feed(b">"), Some(1));
+    }
+
+    #[test]
+    fn one_byte_at_a_time() {
+        let mut matcher = Matcher::new(b"abcab");
+        let stream = b"ababcabcab";
+        let ends: Vec<usize> = stream
+            .iter()
+            .enumerate()
+            .filter(|&(_, byte)| matcher.feed(&[*byte]).is_some())
+            .map(|(index, _)| index + 1)
+            .collect();
+        assert_eq!(ends, vec![7]);
+    }
+
+    #[test]
+    fn overlapping_prefixes() {
+        let mut matcher = Matcher::new(b"aab");
+        assert_eq!(matcher.feed(b"aaab"), Some(4));
+
+        let mut matcher = Matcher::new(b"aab");
+        assert_eq!(matcher.feed(b"aa"), None);
+        assert_eq!(matcher.feed(b"a"), None);
+        assert_eq!(matcher.matched(), 2);
+        assert_eq!(matcher.feed(b"b"), Some(1));
+    }
+
+    #[test]
+    fn match_at_a_read_boundary() {
+        let mut matcher = Matcher::new(b"\r\n");
+        assert_eq!(matcher.feed(b"line\r\n"), Some(6));
+        assert_eq!(matcher.matched(), 0);
+        assert_eq!(matcher.feed(b"\r\nnext"), Some(2));
+    }
+
+    #[test]
+    fn mismatch_then_match_across_reads() {
+        let mut matcher = Matcher::new(b"abc");
+        assert_eq!(matcher.feed(b"ab"), None);
+        assert_eq!(matcher.feed(b"d"), None);
+        assert_eq!(matcher.matched(), 0);
+        assert_eq!(matcher.feed(b"xab"), None);
+        assert_eq!(matcher.feed(b"c"), Some(1));
+    }
+
+    #[test]
+    fn searched_afresh_after_a_match() {
+        let mut matcher = Matcher::new(b"aa");
+        let stream = b"aaa";
+        assert_eq!(matcher.feed(stream), Some(2));
+        assert_eq!(matcher.matched(), 0);
+        // What followed the match is fed again by the caller
+        assert_eq!(matcher.feed(&stream[2..]), None);
+        assert_eq!(matcher.feed(b"a"), Some(1));
+    }
+
+    #[test]
+    fn empty_needle() {
+        let mut matcher = Matcher::new(b"");
+        assert_eq!(matcher.feed(b""), Some(0));
+        assert_eq!(matcher.feed(b"abc"), Some(0));
+        assert_eq!(matcher.matched(), 0);
+    }
+}
@@ -0,0 +1,356 @@
+//! Measuring the device's heap around a script, to find leaks and fragmentation
+use anyhow::{bail, Result};
+use std::fmt;
+use std::time::Duration;
+
+use crate::device::Device;
+use crate::progress::format_bytes;
+use crate::script::quote;
+use crate::serial::{eval, execute, ExecOptions};
+
+const MEASURE: &str = "import gc
+gc.collect()
+print(gc.mem_free(), gc.mem_alloc())
+";
+
+const INFO: &str = "import micropython
+micropython.mem_info()
+";
+
+/// The function a profiled script calls to print a snapshot of the heap between markers. Its
+/// `gc.collect()` is left out when garbage is to be kept.
+const SNAPSHOT: &str = r#"def _serpico_snapshot(label):
+    import gc, micropython
+    gc.collect()
+    print("\x01heap", label)
+    micropython.mem_info(1)
+    print("\x01end")
+"#;
+
+/// The lines the markers of a snapshot start with
+const SNAPSHOT_START: &str = "\x01heap ";
+const SNAPSHOT_END: &str = "\x01end";
+
+/// Keywords that carry on the statement before them, which no snapshot can be taken ahead of
+const CONTINUATIONS: &[&str] = &["elif", "else", "except", "finally"];
+
+/// The heap after a garbage collection, in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Heap {
+    pub free: usize,
+    pub used: usize,
+}
+
+/// The heap before and after a script ran, along with the device's own description of the heap
+/// afterwards
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub before: Heap,
+    pub after: Heap,
+    /// The output of `micropython.mem_info()`, with the largest free block as a sign of
+    /// fragmentation
+    pub info: String,
+}
+
+/// Collect garbage and measure the heap, soft rebooting the device first if `soft_reset` is set
+pub fn measure(device: &mut Device, soft_reset: bool, timeout: Option<Duration>) -> Result<Heap> {
+    let options = ExecOptions {
+        timeout,
+        soft_reset,
+        echo: false,
+        log: false,
+        ..ExecOptions::default()
+    };
+    let result = execute(device, MEASURE, &options)?;
+    if let Some(exception) = result.exception() {
+        bail!("Device raised {}", exception);
+    }
+    let output = String::from_utf8_lossy(&result.stdout);
+    let numbers: Vec<usize> = match output.split_whitespace().map(str::parse).collect() {
+        Ok(numbers) => numbers,
+        Err(_) => bail!("Unexpected heap measurement from device: {:?}", output),
+    };
+    match numbers[..] {
+        [free, used] => Ok(Heap { free, used }),
+        _ => bail!("Unexpected heap measurement from device: {:?}", output),
+    }
+}
+
+/// Measure the heap after the script that ran since `before` was measured, without resetting
+pub fn report(device: &mut Device, before: Heap, timeout: Option<Duration>) -> Result<Report> {
+    let after = measure(device, false, timeout)?;
+    let info = eval(device, INFO, timeout)?;
+    Ok(Report {
+        before,
+        after,
+        info: String::from_utf8_lossy(&info).trim_end().to_string(),
+    })
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let change = |before: usize, after: usize| {
+            let sign = if after >= before { "+" } else { "-" };
+            format!("{}{}", sign, format_bytes(after.abs_diff(before) as f64))
+        };
+        writeln!(
+            f,
+            "{:<6}{:>12}{:>12}{:>12}",
+            "Heap", "Before", "After", "Change"
+        )?;
+        for (name, before, after) in [
+            ("Free", self.before.free, self.after.free),
+            ("Used", self.before.used, self.after.used),
+        ] {
+            writeln!(
+                f,
+                "{:<6}{:>12}{:>12}{:>12}",
+                name,
+                format_bytes(before as f64),
+                format_bytes(after as f64),
+                change(before, after)
+            )?;
+        }
+        write!(f, "{}", self.info)
+    }
+}
+
+/// A script rewritten to snapshot the heap at its start, ahead of chosen lines and at its end
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profiled {
+    pub script: String,
+    /// The lines of the helper ahead of the script's first line
+    pub offset: usize,
+    /// The local line of each line of the script after the helper
+    pub lines: Vec<usize>,
+}
+
+/// Rewrite `source` to snapshot the heap at its start, just before each of the local lines
+/// `points` run, and at its end. Each point has to be a line that starts a statement, which the
+/// snapshot is taken at the indentation of, so that one inside a function is taken each time it
+/// runs. Unless `collect` is set, garbage is left uncollected, to show what the script leaves.
+pub fn instrument(source: &str, points: &[usize], collect: bool) -> Result<Profiled> {
+    let local: Vec<&str> = source.lines().collect();
+    for &point in points {
+        let line = match point.checked_sub(1).and_then(|index| local.get(index)) {
+            Some(line) => line.trim(),
+            None => bail!("The script has no line {}", point),
+        };
+        let keyword = line.split(|c: char| !c.is_alphanumeric()).next();
+        let continued = point > 1 && local[point - 2].trim_end().ends_with('\\');
+        if line.is_empty()
+            || line.starts_with('#')
+            || continued
+            || keyword.is_some_and(|keyword| CONTINUATIONS.contains(&keyword))
+        {
+            bail!(
+                "Line {} doesn't start a statement to snapshot the heap ahead of",
+                point
+            );
+        }
+    }
+
+    let helper = match collect {
+        true => SNAPSHOT.to_string(),
+        false => SNAPSHOT.replace("    gc.collect()\n", ""),
+    };
+    let snapshot =
+        |indent: &str, label: &str| format!("{}_serpico_snapshot({})\n", indent, quote(label));
+    let mut script = helper.clone();
+    script.push_str(&snapshot("", "start"));
+    let mut lines = vec![1];
+    for (index, line) in local.iter().enumerate() {
+        if points.contains(&(index + 1)) {
+            let indent = &line[..line.len() - line.trim_start().len()];
+            script.push_str(&snapshot(indent, &format!("line {}", index + 1)));
+            lines.push(index + 1);
+        }
+        script.push_str(line);
+        script.push('\n');
+        lines.push(index + 1);
+    }
+    script.push_str(&snapshot("", "end"));
+    lines.push(local.len().max(1));
+    Ok(Profiled {
+        script,
+        offset: helper.lines().count(),
+        lines,
+    })
+}
+
+/// The heap as `micropython.mem_info(1)` described it at a point of a profiled script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub label: String,
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+    /// The longest run of free blocks, the largest object that can be allocated
+    pub max_free_blocks: usize,
+    /// A character for each block of the heap: `.` for a free block, `=` for the rest of an
+    /// object and a letter for the first block of one
+    pub blocks: String,
+    /// How many blocks the device printed on each row of the map
+    pub width: usize,
+    /// The bytes of heap each block stands for
+    pub block_size: usize,
+}
+
+impl Snapshot {
+    fn parse(label: &str, info: &[&str]) -> Snapshot {
+        // Statistics are printed as lists of `name: value`, such as `GC: total: 8192, used: 96`
+        let field = |name: &str| {
+            info.iter()
+                .flat_map(|line| line.split(','))
+                .filter_map(|part| part.rsplit_once(':'))
+                .find(|(key, _)| key.trim().ends_with(name))
+                .and_then(|(_, value)| value.trim().parse().ok())
+                .unwrap_or(0)
+        };
+        let mut blocks = String::new();
+        let mut width = 0;
+        let mut first_offset = None;
+        let mut block_size = 0;
+        for line in info {
+            let line = line.trim();
+            let free_rows = line
+                .strip_prefix('(')
+                .and_then(|line| line.strip_suffix(" lines all free)"))
+                .and_then(|rows| rows.parse::<usize>().ok());
+            if let Some(rows) = free_rows {
+                blocks.push_str(&".".repeat(rows * width));
+                continue;
+            }
+            let (offset, row) = match line.split_once(": ") {
+                Some((offset, row)) => match usize::from_str_radix(offset, 16) {
+                    Ok(offset) => (offset, row),
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+            // The rows' offsets tell the size of a block
+            match first_offset {
+                None => first_offset = Some(offset),
+                Some(first) if block_size == 0 && !blocks.is_empty() => {
+                    block_size = offset.saturating_sub(first) / blocks.len()
+                }
+                Some(_) => {}
+            }
+            width = width.max(row.len());
+            blocks.push_str(row);
+        }
+        let total = field("total");
+        if block_size == 0 && !blocks.is_empty() {
+            block_size = (total + blocks.len() / 2) / blocks.len();
+        }
+        Snapshot {
+            label: label.to_string(),
+            total,
+            used: field("used"),
+            free: field("free"),
+            max_free_blocks: field("max free sz"),
+            blocks,
+            width,
+            block_size,
+        }
+    }
+
+    /// The largest object that can be allocated, in bytes
+    pub fn max_free(&self) -> usize {
+        self.max_free_blocks * self.block_size
+    }
+}
+
+/// Split what a profiled script printed into its own output and the snapshots it took
+pub fn parse_snapshots(output: &str) -> (String, Vec<Snapshot>) {
+    let mut own = String::new();
+    let mut snapshots = Vec::new();
+    let mut snapshot: Option<(String, Vec<&str>)> = None;
+    for line in output.split_inclusive('\n') {
+        let text = line.trim_end();
+        match &mut snapshot {
+            Some((label, info)) if text == SNAPSHOT_END => {
+                snapshots.push(Snapshot::parse(label, info));
+                snapshot = None;
+            }
+            Some((_, info)) => info.push(text),
+            None => match text.strip_prefix(SNAPSHOT_START) {
+                Some(label) => snapshot = Some((label.to_string(), Vec::new())),
+                None => own.push_str(line),
+            },
+        }
+    }
+    (own, snapshots)
+}
+
+/// A table of how much of the heap was used and free at each snapshot, and how fragmented the
+/// free heap was, as how much of it isn't in the largest free block
+pub fn summary(snapshots: &[Snapshot]) -> String {
+    let label_width = snapshots
+        .iter()
+        .map(|snapshot| snapshot.label.len())
+        .chain([8])
+        .max()
+        .unwrap_or_default();
+    let mut table = format!(
+        "{:<label_width$}{:>12}{:>12}{:>15}{:>12}\n",
+        "Snapshot", "Used", "Free", "Largest free", "Fragmented"
+    );
+    for snapshot in snapshots {
+        let fragmented = match snapshot.free {
+            0 => 0.0,
+            free => 100.0 * (1.0 - snapshot.max_free().min(free) as f64 / free as f64),
+        };
+        table.push_str(&format!(
+            "{:<label_width$}{:>12}{:>12}{:>15}{:>11.0}%\n",
+            snapshot.label,
+            format_bytes(snapshot.used as f64),
+            format_bytes(snapshot.free as f64),
+            format_bytes(snapshot.max_free() as f64),
+            fragmented
+        ));
+    }
+    table.trim_end().to_string()
+}
+
+/// The heap maps of `before` and `after` side by side, a row of blocks at a time from the offset
+/// each row starts at in the heap, with runs of rows free in both folded into one line
+pub fn compare(before: &Snapshot, after: &Snapshot) -> String {
+    let width = before.width.max(after.width).max(1);
+    let block_size = before.block_size.max(after.block_size);
+    let row = |snapshot: &Snapshot, index: usize| -> String {
+        let start = (index * width).min(snapshot.blocks.len());
+        let end = ((index + 1) * width).min(snapshot.blocks.len());
+        format!("{:<width$}", &snapshot.blocks[start..end])
+    };
+    let rows = before.blocks.len().max(after.blocks.len()).div_ceil(width);
+
+    let mut map = format!(
+        "Heap map, a character for each block of {}: . free, = the rest of an object, a letter \
+         the start of one\n{:<10}{:<width$}  {}\n",
+        format_bytes(block_size as f64),
+        "Offset",
+        before.label,
+        after.label,
+    );
+    let free = |row: &str| row.trim_end().chars().all(|c| c == '.');
+    let mut folded = 0;
+    for index in 0..rows {
+        let (old, new) = (row(before, index), row(after, index));
+        if free(&old) && free(&new) && index + 1 < rows {
+            folded += 1;
+            continue;
+        }
+        if folded > 0 {
+            map.push_str(&format!("{:10}({} rows free in both)\n", "", folded));
+            folded = 0;
+        }
+        map.push_str(&format!(
+            "{:08x}  {}  {}\n",
+            index * width * block_size,
+            old,
+            new
+        ));
+    }
+    map.trim_end().to_string()
+}
@@ -0,0 +1,274 @@
+//! Shrinking Python scripts before upload by removing comments, docstrings and blank lines
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// A minified script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Minified {
+    pub source: String,
+    /// The line of the original script each line of the minified source came from
+    pub lines: Vec<usize>,
+}
+
+/// A logical line of the script, which may span several physical lines
+#[derive(Debug, Default)]
+struct Statement {
+    /// Physical lines with their original line numbers
+    lines: Vec<(usize, String)>,
+    /// Whether the statement is nothing but string literals and so has no effect
+    only_strings: bool,
+    has_string: bool,
+}
+
+impl Statement {
+    fn new() -> Self {
+        Statement {
+            only_strings: true,
+            ..Statement::default()
+        }
+    }
+
+    fn is_docstring(&self) -> bool {
+        self.only_strings && self.has_string
+    }
+
+    fn indent(&self) -> &str {
+        let first = &self.lines[0].1;
+        &first[..first.len() - first.trim_start().len()]
+    }
+
+    fn opens_block(&self) -> bool {
+        self.lines
+            .last()
+            .is_some_and(|(_, line)| line.ends_with(':'))
+    }
+}
+
+struct Tokenizer<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+    current: String,
+    statement: Statement,
+    statements: Vec<Statement>,
+}
+
+impl<'a> Tokenizer<'a> {
+    /// End the physical line being built. Lines that end inside a string are kept as they are,
+    /// others lose trailing whitespace and are dropped if empty.
+    fn end_line(&mut self, in_string: bool) {
+        let line = std::mem::take(&mut self.current);
+        if in_string {
+            self.statement.lines.push((self.line, line));
+        } else if !line.trim().is_empty() {
+            self.statement
+                .lines
+                .push((self.line, line.trim_end().to_string()));
+        }
+    }
+
+    fn end_statement(&mut self) {
+        let statement = std::mem::replace(&mut self.statement, Statement::new());
+        if !statement.lines.is_empty() {
+            self.statements.push(statement);
+        }
+    }
+
+    /// Copy a string literal, starting at its opening quote. A backslash protects the next
+    /// character from ending the string in raw strings too, so they need no special handling.
+    fn string(&mut self, quote: char) {
+        self.statement.has_string = true;
+        self.current.push(quote);
+        self.chars.next();
+
+        let mut triple = false;
+        if self.chars.peek() == Some(&quote) {
+            self.current.push(quote);
+            self.chars.next();
+            if self.chars.peek() != Some(&quote) {
+                // An empty string
+                return;
+            }
+            self.current.push(quote);
+            self.chars.next();
+            triple = true;
+        }
+
+        let mut closing = 0;
+        while let Some(c) = self.chars.next() {
+            if c == '\n' {
+                self.end_line(true);
+                self.line += 1;
+                closing = 0;
+                continue;
+            }
+            self.current.push(c);
+            if c == '\\' {
+                match self.chars.next() {
+                    Some('\n') => {
+                        self.end_line(true);
+                        self.line += 1;
+                    }
+                    Some(escaped) => self.current.push(escaped),
+                    None => {}
+                }
+                closing = 0;
+                continue;
+            }
+            if c == quote {
+                closing += 1;
+                if !triple || closing == 3 {
+                    return;
+                }
+            } else {
+                closing = 0;
+            }
+        }
+    }
+
+    fn run(mut self) -> Vec<Statement> {
+        let mut depth = 0usize;
+        while let Some(&c) = self.chars.peek() {
+            match c {
+                '#' => {
+                    while self.chars.peek().is_some_and(|&c| c != '\n') {
+                        self.chars.next();
+                    }
+                }
+                '\\' => {
+                    self.chars.next();
+                    self.current.push('\\');
+                    if self.chars.peek() == Some(&'\n') {
+                        self.chars.next();
+                        self.end_line(false);
+                        self.line += 1;
+                    }
+                }
+                '\n' => {
+                    self.chars.next();
+                    self.end_line(false);
+                    self.line += 1;
+                    if depth == 0 {
+                        self.end_statement();
+                    }
+                }
+                '\'' | '"' => self.string(c),
+                c if c.is_alphabetic() || c == '_' => {
+                    let mut word = String::new();
+                    while let Some(&c) = self.chars.peek() {
+                        if !c.is_alphanumeric() && c != '_' {
+                            break;
+                        }
+                        word.push(c);
+                        self.chars.next();
+                    }
+                    self.current.push_str(&word);
+
+                    let lower = word.to_ascii_lowercase();
+                    let is_prefix = matches!(
+                        lower.as_str(),
+                        "r" | "b" | "u" | "f" | "rb" | "br" | "fr" | "rf"
+                    );
+                    match self.chars.peek() {
+                        Some(&quote) if is_prefix && (quote == '\'' || quote == '"') => {
+                            if lower.contains('f') {
+                                // f-strings may call functions, so they are kept
+                                self.statement.only_strings = false;
+                            }
+                            self.string(quote);
+                        }
+                        _ => self.statement.only_strings = false,
+                    }
+                }
+                c => {
+                    self.chars.next();
+                    self.current.push(c);
+                    match c {
+                        '(' | '[' | '{' => depth += 1,
+                        ')' | ']' | '}' => depth = depth.saturating_sub(1),
+                        _ => {}
+                    }
+                    if !c.is_whitespace() {
+                        self.statement.only_strings = false;
+                    }
+                }
+            }
+        }
+        self.end_line(false);
+        self.end_statement();
+        self.statements
+    }
+}
+
+fn statements(source: &str) -> Vec<Statement> {
+    Tokenizer {
+        chars: source.chars().peekable(),
+        line: 1,
+        current: String::new(),
+        statement: Statement::new(),
+        statements: Vec::new(),
+    }
+    .run()
+}
+
+/// The lines of `source` that start a top-level statement other than the first, where the script
+/// can be split without breaking up a block. The clauses that continue a compound statement, such
+/// as `else:`, and what follows a decorator don't count.
+pub fn split_points(source: &str) -> Vec<usize> {
+    let mut points = Vec::new();
+    let mut first = true;
+    let mut after_decorator = false;
+    for statement in statements(source) {
+        if !statement.indent().is_empty() {
+            continue;
+        }
+        let (line, text) = &statement.lines[0];
+        let keyword = text
+            .split(|c: char| !c.is_alphanumeric() && c != '_')
+            .next()
+            .unwrap_or_default();
+        let continues = matches!(keyword, "else" | "elif" | "except" | "finally");
+        if !continues && !after_decorator && !first {
+            points.push(*line);
+        }
+        first = false;
+        after_decorator = text.starts_with('@');
+    }
+    points
+}
+
+/// Remove comments, docstrings and blank lines from `source` without changing what it does.
+/// Indentation is kept as it is, and a docstring that is the only statement of a block is
+/// replaced with `pass`.
+pub fn minify(source: &str) -> Minified {
+    let statements = statements(source);
+
+    let mut minified = Minified {
+        source: String::new(),
+        lines: Vec::new(),
+    };
+    let mut previous_opens_block = false;
+    for (index, statement) in statements.iter().enumerate() {
+        if statement.is_docstring() {
+            let indent = statement.indent();
+            let block_continues = statements[index + 1..]
+                .iter()
+                .find(|next| !next.is_docstring())
+                .is_some_and(|next| next.indent().len() >= indent.len());
+            if previous_opens_block && !block_continues {
+                minified.source.push_str(indent);
+                minified.source.push_str("pass\n");
+                minified.lines.push(statement.lines[0].0);
+                previous_opens_block = false;
+            }
+            continue;
+        }
+
+        for (line, text) in statement.lines.iter() {
+            minified.source.push_str(text);
+            minified.source.push('\n');
+            minified.lines.push(*line);
+        }
+        previous_opens_block = statement.opens_block();
+    }
+    minified
+}
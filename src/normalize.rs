@@ -0,0 +1,135 @@
+//! Cleaning up scripts as they are uploaded. Line endings are made LF, and tabs in indentation can
+//! be expanded to spaces. Scripts that aren't valid UTF-8 or that have NUL bytes in them are
+//! rejected, as the device would fail on them with confusing errors, or take a NUL for the end of
+//! the upload.
+use std::io::{self, ErrorKind, Read};
+
+/// How much of the script is read at a time
+const CHUNK_SIZE: usize = 4096;
+
+/// Normalizes a script while it's read from `reader`, a chunk at a time
+pub struct Normalizer<R> {
+    reader: R,
+    /// Expand tabs in indentation to stops this many columns apart
+    tab_width: Option<usize>,
+    /// Normalized bytes waiting to be read
+    output: Vec<u8>,
+    /// The start of a UTF-8 sequence that continues in the next chunk
+    partial: Vec<u8>,
+    /// A CR has been read, and it's yet to be seen if an LF follows
+    cr: bool,
+    /// The line being read, for errors
+    line: usize,
+    /// The column in the line, while still in its indentation
+    indent: Option<usize>,
+    ended: bool,
+}
+
+impl<R: Read> Normalizer<R> {
+    pub fn new(reader: R, tab_width: Option<usize>) -> Self {
+        Normalizer {
+            reader,
+            tab_width: tab_width.filter(|width| *width > 0),
+            output: Vec::new(),
+            partial: Vec::new(),
+            cr: false,
+            line: 1,
+            indent: Some(0),
+            ended: false,
+        }
+    }
+
+    /// Read and normalize the next chunk of the script
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let count = loop {
+            match self.reader.read(&mut chunk) {
+                Ok(count) => break count,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        };
+        if count == 0 {
+            self.ended = true;
+            if !self.partial.is_empty() {
+                return Err(self.invalid("isn't valid UTF-8"));
+            }
+            if self.cr {
+                self.cr = false;
+                self.output.push(b'\r');
+            }
+            return Ok(());
+        }
+
+        let mut input = std::mem::take(&mut self.partial);
+        input.extend_from_slice(&chunk[..count]);
+        let (valid, invalid) = match std::str::from_utf8(&input) {
+            Ok(_) => (input.len(), false),
+            // A sequence cut off by the end of the chunk may be completed by the next one
+            Err(e) if e.error_len().is_none() => (e.valid_up_to(), false),
+            Err(e) => (e.valid_up_to(), true),
+        };
+        for &byte in &input[..valid] {
+            self.push(byte)?;
+        }
+        if invalid {
+            return Err(self.invalid("isn't valid UTF-8"));
+        }
+        self.partial = input[valid..].to_vec();
+        Ok(())
+    }
+
+    fn push(&mut self, byte: u8) -> io::Result<()> {
+        if byte == 0 {
+            return Err(self.invalid("has a NUL byte in it, is it a binary file?"));
+        }
+        // A CR is left as it is unless it ends a line
+        if self.cr && byte != b'\n' {
+            self.output.push(b'\r');
+            self.indent = None;
+        }
+        self.cr = byte == b'\r';
+        match byte {
+            b'\r' => {}
+            b'\n' => {
+                self.output.push(b'\n');
+                self.line += 1;
+                self.indent = Some(0);
+            }
+            b'\t' if self.indent.is_some() && self.tab_width.is_some() => {
+                let (column, width) = (self.indent.unwrap(), self.tab_width.unwrap());
+                let spaces = width - column % width;
+                self.output.extend(std::iter::repeat_n(b' ', spaces));
+                self.indent = Some(column + spaces);
+            }
+            b' ' | b'\t' => {
+                self.output.push(byte);
+                self.indent = self.indent.map(|column| column + 1);
+            }
+            _ => {
+                self.output.push(byte);
+                self.indent = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn invalid(&self, problem: &str) -> io::Error {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("line {} {}", self.line, problem),
+        )
+    }
+}
+
+impl<R: Read> Read for Normalizer<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.output.is_empty() && !self.ended {
+            self.fill()?;
+        }
+        let count = buf.len().min(self.output.len());
+        buf[..count].copy_from_slice(&self.output[..count]);
+        self.output.drain(..count);
+        Ok(count)
+    }
+}
@@ -0,0 +1,445 @@
+//! Echoing the device's output on the host, a line at a time
+use anyhow::{bail, Result};
+use std::io::{self, IsTerminal, Write};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::encoding::{Decoder, Decoding};
+use crate::regex::Regex;
+use crate::sink::SharedSink;
+use crate::traceback::SourceMap;
+
+/// Which kind of timestamp to prefix output lines with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timestamps {
+    /// The host's local wall clock time
+    Absolute,
+    /// Time since the script started
+    Relative,
+}
+
+impl Timestamps {
+    pub fn parse(value: &str) -> Result<Timestamps> {
+        match value {
+            "absolute" => Ok(Timestamps::Absolute),
+            "relative" => Ok(Timestamps::Relative),
+            _ => bail!("Unknown timestamps {:?}, use absolute or relative", value),
+        }
+    }
+}
+
+/// The two output channels of a script running in the raw REPL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    /// Where uncaught exceptions and their tracebacks are printed
+    Stderr,
+}
+
+const RED: &str = "\x1b[31m";
+const BOLD_RED: &str = "\x1b[1;31m";
+const CYAN: &str = "\x1b[36m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+/// Whether output should be colored by default: stderr, where tracebacks are echoed, is a terminal
+/// and `NO_COLOR` isn't set
+pub fn color_by_default() -> bool {
+    std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()) && io::stderr().is_terminal()
+}
+
+/// Which lines of stdout are shown. Tracebacks on stderr are always shown.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LineFilter {
+    /// Show only lines matching one of these, all lines if empty
+    pub include: Vec<Regex>,
+    /// Hide lines matching one of these, even if included
+    pub exclude: Vec<Regex>,
+}
+
+impl LineFilter {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether the line, without its line ending, is shown
+    pub fn shows(&self, line: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|regex| regex.is_match(line)))
+            && !self.exclude.iter().any(|regex| regex.is_match(line))
+    }
+}
+
+/// How many bytes are shown on each row of a hexdump
+const HEX_BYTES_PER_ROW: usize = 16;
+
+/// Renders output as a canonical hexdump, rows of an offset, 16 bytes in hex and the same bytes as
+/// printable characters, like `hexdump -C`
+#[derive(Debug, Clone, Default)]
+pub struct Hexdump {
+    offset: usize,
+    row: Vec<u8>,
+}
+
+impl Hexdump {
+    /// Add bytes, returning the rows they complete
+    pub fn write(&mut self, bytes: &[u8]) -> String {
+        let mut rows = String::new();
+        for &byte in bytes {
+            self.row.push(byte);
+            if self.row.len() == HEX_BYTES_PER_ROW {
+                rows.push_str(&self.take_row());
+            }
+        }
+        rows
+    }
+
+    /// The partial row that's left over, if any
+    pub fn finish(&mut self) -> String {
+        if self.row.is_empty() {
+            String::new()
+        } else {
+            self.take_row()
+        }
+    }
+
+    fn take_row(&mut self) -> String {
+        let mut hex = String::new();
+        for index in 0..HEX_BYTES_PER_ROW {
+            if index == HEX_BYTES_PER_ROW / 2 {
+                hex.push(' ');
+            }
+            match self.row.get(index) {
+                Some(byte) => hex.push_str(&format!(" {:02x}", byte)),
+                None => hex.push_str("   "),
+            }
+        }
+        let printable: String = self
+            .row
+            .iter()
+            .map(|&byte| match byte {
+                0x20..=0x7e => char::from(byte),
+                _ => '.',
+            })
+            .collect();
+        let row = format!("{:08x} {}  |{}|\n", self.offset, hex, printable);
+        self.offset += self.row.len();
+        self.row.clear();
+        row
+    }
+}
+
+/// Writes the device's output and its stderr to a sink, the terminal unless told otherwise,
+/// complete lines at a time
+pub struct Echo {
+    sink: SharedSink,
+    timestamps: Option<Timestamps>,
+    color: bool,
+    source_map: Option<SourceMap>,
+    filter: LineFilter,
+    hexdump: Option<Hexdump>,
+    decoder: Decoder,
+    stream: Stream,
+    start: Instant,
+    line: Vec<u8>,
+    line_start: Option<(Instant, SystemTime)>,
+}
+
+impl Echo {
+    pub fn new(timestamps: Option<Timestamps>, color: bool, source_map: Option<SourceMap>) -> Echo {
+        Echo {
+            sink: SharedSink::default(),
+            timestamps,
+            color,
+            source_map,
+            filter: LineFilter::default(),
+            hexdump: None,
+            decoder: Decoder::default(),
+            stream: Stream::Stdout,
+            start: Instant::now(),
+            line: Vec::new(),
+            line_start: None,
+        }
+    }
+
+    /// Write to `sink` rather than to the terminal
+    pub fn sink(mut self, sink: SharedSink) -> Echo {
+        self.sink = sink;
+        self
+    }
+
+    /// Only show the lines of stdout that pass `filter`
+    pub fn filter(mut self, filter: LineFilter) -> Echo {
+        self.filter = filter;
+        self
+    }
+
+    /// Show stdout as a hexdump instead of as text
+    pub fn hexdump(mut self, hexdump: bool) -> Echo {
+        self.hexdump = hexdump.then(Hexdump::default);
+        self
+    }
+
+    /// Decode the device's output with `decoding` rather than as UTF-8 with replacements
+    pub fn decoding(mut self, decoding: Decoding) -> Echo {
+        self.decoder = decoding.decoder();
+        self
+    }
+
+    /// Switch to echoing another stream, writing out any partial line of the current one first
+    pub fn set_stream(&mut self, stream: Stream) -> Result<()> {
+        self.finish()?;
+        self.stream = stream;
+        Ok(())
+    }
+
+    /// Add bytes read from the device, writing out any lines they complete
+    pub fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.write_received(bytes, (Instant::now(), SystemTime::now()))
+    }
+
+    /// Like [`Echo::write`], for bytes that were read at `received`, which lines starting in
+    /// them are timestamped with
+    pub fn write_received(&mut self, bytes: &[u8], received: (Instant, SystemTime)) -> Result<()> {
+        if let (Stream::Stdout, Some(hexdump)) = (self.stream, self.hexdump.as_mut()) {
+            let rows = hexdump.write(bytes);
+            return self.sink.0.write(Stream::Stdout, rows.as_bytes());
+        }
+        let bytes = &self.decoder.feed(bytes)?;
+        if self.timestamps.is_none() && !self.rewrite_stderr() && !self.filters_stdout() {
+            // Nothing to add to the lines, so there's no need to hold back partial lines
+            return self.sink.0.write(self.stream, bytes);
+        }
+
+        for &byte in bytes {
+            if self.line_start.is_none() {
+                self.line_start = Some(received);
+            }
+            self.line.push(byte);
+            if byte == b'\n' {
+                self.write_line()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write out a partial line that hasn't been terminated yet, such as a prompt
+    pub fn finish(&mut self) -> Result<()> {
+        if let Some(hexdump) = self.hexdump.as_mut() {
+            let rows = hexdump.finish();
+            self.sink.0.write(Stream::Stdout, rows.as_bytes())?;
+        }
+        let rest = self.decoder.finish()?;
+        if !rest.is_empty() {
+            self.write_received(&rest, (Instant::now(), SystemTime::now()))?;
+        }
+        if !self.line.is_empty() {
+            self.write_line()?;
+        }
+        self.sink.0.finish()
+    }
+
+    fn write_line(&mut self) -> Result<()> {
+        if self.filters_stdout() {
+            let line = String::from_utf8_lossy(&self.line);
+            if !self.filter.shows(line.trim_end_matches(['\r', '\n'])) {
+                self.line.clear();
+                self.line_start = None;
+                return Ok(());
+            }
+        }
+        // The whole line is written at once, so that it isn't broken up by other echoes writing to
+        // the same sink
+        let mut output = Vec::with_capacity(self.line.len() + 16);
+        if let (Some(timestamps), Some((instant, time))) = (self.timestamps, self.line_start) {
+            let prefix = match timestamps {
+                Timestamps::Absolute => format_time(time),
+                Timestamps::Relative => {
+                    format!("+{:>9.3}", instant.duration_since(self.start).as_secs_f64())
+                }
+            };
+            write!(output, "[{}] ", prefix)?;
+        }
+        if self.rewrite_stderr() {
+            let line = String::from_utf8_lossy(&self.line);
+            let content = line.trim_end_matches(['\r', '\n']);
+            let ending = &line[content.len()..];
+            let content = match &self.source_map {
+                Some(source_map) => source_map.rewrite_line(content),
+                None => content.to_string(),
+            };
+            if self.color {
+                write!(output, "{}{}", highlight_traceback(&content), ending)?;
+            } else {
+                write!(output, "{}{}", content, ending)?;
+            }
+        } else {
+            output.extend_from_slice(&self.line);
+        }
+        self.sink.0.write(self.stream, &output)?;
+
+        self.line.clear();
+        self.line_start = None;
+        Ok(())
+    }
+}
+
+/// What an [`EchoThread`] is asked to do
+enum EchoMessage {
+    Write(Vec<u8>, (Instant, SystemTime)),
+    SetStream(Stream),
+}
+
+/// An [`Echo`] on a thread of its own. Output is queued for it without limit, so a slow terminal
+/// or a pipe that isn't read doesn't hold up reading from the device, where the bytes the device
+/// keeps sending would otherwise be lost once the OS buffers fill up.
+pub struct EchoThread {
+    sender: Option<Sender<EchoMessage>>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl EchoThread {
+    pub fn spawn(mut echo: Echo) -> EchoThread {
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            for message in receiver {
+                match message {
+                    EchoMessage::Write(bytes, received) => echo.write_received(&bytes, received)?,
+                    EchoMessage::SetStream(stream) => echo.set_stream(stream)?,
+                }
+            }
+            echo.finish()
+        });
+        EchoThread {
+            sender: Some(sender),
+            thread: Some(thread),
+        }
+    }
+
+    /// Queue bytes read from the device to be echoed
+    pub fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        let received = (Instant::now(), SystemTime::now());
+        self.send(EchoMessage::Write(bytes.to_vec(), received))
+    }
+
+    /// Switch to echoing another stream once what's queued has been echoed
+    pub fn set_stream(&mut self, stream: Stream) -> Result<()> {
+        self.send(EchoMessage::SetStream(stream))
+    }
+
+    /// Wait for everything queued to be echoed, writing out a partial line at the end
+    pub fn finish(&mut self) -> Result<()> {
+        self.sender = None;
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => bail!("Echoing the output failed"),
+            None => Ok(()),
+        }
+    }
+
+    fn send(&mut self, message: EchoMessage) -> Result<()> {
+        let sent = match &self.sender {
+            Some(sender) => sender.send(message).is_ok(),
+            None => false,
+        };
+        // The thread only stops early when echoing fails, and its error tells why
+        if !sent {
+            self.finish()?;
+            bail!("Echoing the output has stopped");
+        }
+        Ok(())
+    }
+}
+
+impl Drop for EchoThread {
+    /// What has been queued is still echoed, so it comes out ahead of whatever ended the session
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+impl Echo {
+    /// Whether stderr lines are rewritten, which needs them to be complete
+    fn rewrite_stderr(&self) -> bool {
+        self.stream == Stream::Stderr && (self.color || self.source_map.is_some())
+    }
+
+    /// Whether stdout lines are filtered, which also needs them to be complete
+    fn filters_stdout(&self) -> bool {
+        self.stream == Stream::Stdout && !self.filter.is_empty()
+    }
+}
+
+/// Color a line of a MicroPython traceback: frames get their file and line number picked out and
+/// the final exception line has its type in bold
+fn highlight_traceback(line: &str) -> String {
+    if let Some(frame) = line.trim_start().strip_prefix("File \"") {
+        if let Some((file, rest)) = frame.split_once('"') {
+            let indent = &line[..line.len() - line.trim_start().len()];
+            let rest = match rest.strip_prefix(", line ") {
+                Some(rest) => {
+                    let digits = rest
+                        .find(|c: char| !c.is_ascii_digit())
+                        .unwrap_or(rest.len());
+                    format!(
+                        "{}, line {}{}{}{}{}",
+                        RED,
+                        YELLOW,
+                        &rest[..digits],
+                        RESET,
+                        RED,
+                        &rest[digits..]
+                    )
+                }
+                None => format!("{}{}", RED, rest),
+            };
+            return format!(
+                "{}{}File \"{}{}{}\"{}{}",
+                indent, RED, CYAN, file, RED, rest, RESET
+            );
+        }
+    }
+
+    if !line.starts_with(' ') && !line.starts_with("Traceback") {
+        let split = line.find(':').unwrap_or(line.len());
+        let (kind, message) = line.split_at(split);
+        if !kind.is_empty()
+            && kind
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+        {
+            return format!("{}{}{}{}{}{}", BOLD_RED, kind, RESET, RED, message, RESET);
+        }
+    }
+
+    format!("{}{}{}", RED, line, RESET)
+}
+
+/// Format a time as `HH:MM:SS.mmm` in the local timezone, or UTC where that isn't known
+pub fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs() as i64 + utc_offset(since_epoch.as_secs() as i64);
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        seconds.rem_euclid(86_400) / 3600,
+        seconds.rem_euclid(3600) / 60,
+        seconds.rem_euclid(60),
+        since_epoch.subsec_millis()
+    )
+}
+
+/// The host's offset from UTC in seconds, at `seconds` since the epoch
+#[cfg(unix)]
+pub fn utc_offset(seconds: i64) -> i64 {
+    let time = seconds as libc::time_t;
+    let mut local: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut local) }.is_null() {
+        return 0;
+    }
+    local.tm_gmtoff
+}
+
+#[cfg(not(unix))]
+pub fn utc_offset(_seconds: i64) -> i64 {
+    0
+}
@@ -0,0 +1,83 @@
+//! Reaching an RP2040 without a working REPL. Opening its USB serial port at 1200 baud reboots it
+//! into BOOTSEL, which MicroPython's USB stack does on its own however stuck the interpreter is.
+//! Everything else goes through picotool, which talks to the bootloader over its vendor USB
+//! interface and is run as a subprocess.
+use anyhow::{bail, Result};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// The baud rate that the USB serial port of MicroPython's rp2 port reboots into BOOTSEL at
+const TOUCH_BAUD: u32 = 1200;
+
+/// Reboot the board at the serial port `device` into BOOTSEL by opening it at 1200 baud
+pub fn touch(device: &Path) -> Result<()> {
+    match serialport::new(device.to_string_lossy(), TOUCH_BAUD)
+        .timeout(Duration::from_millis(100))
+        .open()
+    {
+        // The board reboots once the port is closed again
+        Ok(port) => {
+            sleep(Duration::from_millis(100));
+            drop(port);
+            Ok(())
+        }
+        Err(e) => bail!(
+            "Couldn't open {} at {} baud: {}",
+            device.display(),
+            TOUCH_BAUD,
+            e
+        ),
+    }
+}
+
+/// The picotool to run, `PICOTOOL` if it's set and otherwise the one on the PATH
+pub fn find() -> Result<PathBuf> {
+    if let Some(program) = env::var_os("PICOTOOL") {
+        return Ok(PathBuf::from(program));
+    }
+    let path = env::var_os("PATH").unwrap_or_default();
+    match env::split_paths(&path)
+        .map(|dir| dir.join("picotool"))
+        .find(|candidate| candidate.is_file())
+    {
+        Some(program) => Ok(program),
+        None => bail!("Couldn't find picotool on the PATH, install it or set PICOTOOL"),
+    }
+}
+
+/// Reboot the board into its application, or into BOOTSEL with `bootsel`. `force` makes picotool
+/// reach a board that isn't in BOOTSEL, through the reset interface of the running firmware.
+pub fn reboot(program: &Path, bootsel: bool, force: bool) -> Result<()> {
+    let mut args = vec!["reboot"];
+    if bootsel {
+        args.push("-u");
+    }
+    if force {
+        args.push("-f");
+    }
+    run(program, &args)
+}
+
+/// Print what picotool reads from the board: its program, the device and the build
+pub fn info(program: &Path, force: bool) -> Result<()> {
+    let args = if force {
+        vec!["info", "-a", "-f"]
+    } else {
+        vec!["info", "-a"]
+    };
+    run(program, &args)
+}
+
+fn run(program: &Path, args: &[&str]) -> Result<()> {
+    let status = match Command::new(program).args(args).status() {
+        Ok(status) => status,
+        Err(e) => bail!("Couldn't run {}: {}", program.display(), e),
+    };
+    if !status.success() {
+        bail!("picotool {} failed with {}", args[0], status);
+    }
+    Ok(())
+}
@@ -0,0 +1,388 @@
+//! The pinouts of common boards, so that the helper commands take the names printed on a board,
+//! such as `GP15` on a Pico or `ADC0`, and know its LED and I2C pins where the firmware doesn't.
+//!
+//! The board is told by the machine its firmware reports, such as `Raspberry Pi Pico W with
+//! RP2040`.
+use anyhow::{bail, Result};
+use std::fmt::{self, Write};
+
+/// A board whose pinout serpico knows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Board {
+    Pico,
+    PicoW,
+    /// The ESP32-DevKitC and the DevKit boards modelled on it, with an ESP32-WROOM-32
+    Esp32DevKit,
+    Esp32S3DevKit,
+    Esp32C3DevKit,
+    /// The pyboard v1.0 and v1.1
+    Pyboard,
+}
+
+/// The boards, in the order their names are listed
+pub const BOARDS: &[Board] = &[
+    Board::Pico,
+    Board::PicoW,
+    Board::Esp32DevKit,
+    Board::Esp32S3DevKit,
+    Board::Esp32C3DevKit,
+    Board::Pyboard,
+];
+
+impl Board {
+    /// A board by the name [`Board::id`] gives it
+    pub fn parse(value: &str) -> Result<Board> {
+        match BOARDS
+            .iter()
+            .find(|board| board.id().eq_ignore_ascii_case(value))
+        {
+            Some(board) => Ok(*board),
+            None => bail!(
+                "Unknown board {:?}, expected one of {}",
+                value,
+                BOARDS
+                    .iter()
+                    .map(|board| board.id())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    /// The board whose firmware reports `machine` as `sys.implementation._machine`
+    pub fn from_machine(machine: &str) -> Option<Board> {
+        let (board, chip) = machine.rsplit_once(" with ")?;
+        match chip {
+            "RP2040" if board.starts_with("Raspberry Pi Pico W") => Some(Board::PicoW),
+            "RP2040" if board.starts_with("Raspberry Pi Pico") => Some(Board::Pico),
+            "ESP32" => Some(Board::Esp32DevKit),
+            "ESP32S3" => Some(Board::Esp32S3DevKit),
+            "ESP32C3" => Some(Board::Esp32C3DevKit),
+            chip if board.starts_with("PYBv1.") && chip.starts_with("STM32F405") => {
+                Some(Board::Pyboard)
+            }
+            _ => None,
+        }
+    }
+
+    /// The board's name on the command line
+    pub fn id(&self) -> &'static str {
+        match self {
+            Board::Pico => "pico",
+            Board::PicoW => "pico-w",
+            Board::Esp32DevKit => "esp32-devkit",
+            Board::Esp32S3DevKit => "esp32-s3-devkit",
+            Board::Esp32C3DevKit => "esp32-c3-devkit",
+            Board::Pyboard => "pyboard",
+        }
+    }
+
+    pub fn pinout(&self) -> &'static Pinout {
+        match self {
+            Board::Pico => &PICO,
+            Board::PicoW => &PICO_W,
+            Board::Esp32DevKit => &ESP32_DEVKIT,
+            Board::Esp32S3DevKit => &ESP32_S3_DEVKIT,
+            Board::Esp32C3DevKit => &ESP32_C3_DEVKIT,
+            Board::Pyboard => &PYBOARD,
+        }
+    }
+}
+
+impl fmt::Display for Board {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Board::Pico => write!(f, "Raspberry Pi Pico"),
+            Board::PicoW => write!(f, "Raspberry Pi Pico W"),
+            Board::Esp32DevKit => write!(f, "ESP32 DevKit"),
+            Board::Esp32S3DevKit => write!(f, "ESP32-S3-DevKitC"),
+            Board::Esp32C3DevKit => write!(f, "ESP32-C3-DevKitM"),
+            Board::Pyboard => write!(f, "pyboard"),
+        }
+    }
+}
+
+/// A pin of a board
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pin {
+    /// What the board's pinout calls it
+    pub name: &'static str,
+    /// What `machine.Pin` takes for it
+    pub id: &'static str,
+    /// What else it's for, such as `ADC0` or `I2C0 SDA`. The ones without spaces name the pin
+    /// as well.
+    pub functions: &'static [&'static str],
+}
+
+const fn pin(name: &'static str, id: &'static str, functions: &'static [&'static str]) -> Pin {
+    Pin {
+        name,
+        id,
+        functions,
+    }
+}
+
+/// The pins of a board
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pinout {
+    pub pins: &'static [Pin],
+    /// The SDA and SCL pins the board is labelled with, where the firmware's default for the bus
+    /// is elsewhere
+    pub i2c: Option<(u32, u32)>,
+}
+
+impl Pinout {
+    /// What `machine.Pin` takes for the pin named `name` or one of its functions, ignoring case
+    pub fn resolve(&self, name: &str) -> Option<&'static str> {
+        self.pins
+            .iter()
+            .find(|pin| {
+                pin.name.eq_ignore_ascii_case(name)
+                    || pin
+                        .functions
+                        .iter()
+                        .any(|function| function.eq_ignore_ascii_case(name))
+            })
+            .map(|pin| pin.id)
+    }
+
+    /// The pins as a table of their names, ids and functions
+    pub fn table(&self) -> String {
+        let width = self
+            .pins
+            .iter()
+            .map(|pin| pin.name.len())
+            .chain(["Pin".len()])
+            .max()
+            .unwrap_or(0);
+        let id_width = self
+            .pins
+            .iter()
+            .map(|pin| pin.id.len())
+            .chain(["machine.Pin".len()])
+            .max()
+            .unwrap_or(0);
+        let mut table = format!("{:width$}  {:id_width$}  Functions\n", "Pin", "machine.Pin");
+        for pin in self.pins {
+            let line = format!(
+                "{:width$}  {:id_width$}  {}",
+                pin.name,
+                pin.id,
+                pin.functions.join(", ")
+            );
+            writeln!(table, "{}", line.trim_end()).unwrap();
+        }
+        table
+    }
+}
+
+/// The RP2040's GPIOs that both Picos break out, with the firmware's default UART and I2C pins
+macro_rules! pico_pins {
+    ($($extra:expr),* $(,)?) => {
+        &[
+            pin("GP0", "0", &["UART0 TX"]),
+            pin("GP1", "1", &["UART0 RX"]),
+            pin("GP2", "2", &[]),
+            pin("GP3", "3", &[]),
+            pin("GP4", "4", &["UART1 TX"]),
+            pin("GP5", "5", &["UART1 RX"]),
+            pin("GP6", "6", &["I2C1 SDA"]),
+            pin("GP7", "7", &["I2C1 SCL"]),
+            pin("GP8", "8", &["I2C0 SDA"]),
+            pin("GP9", "9", &["I2C0 SCL"]),
+            pin("GP10", "10", &[]),
+            pin("GP11", "11", &[]),
+            pin("GP12", "12", &[]),
+            pin("GP13", "13", &[]),
+            pin("GP14", "14", &[]),
+            pin("GP15", "15", &[]),
+            pin("GP16", "16", &[]),
+            pin("GP17", "17", &[]),
+            pin("GP18", "18", &[]),
+            pin("GP19", "19", &[]),
+            pin("GP20", "20", &[]),
+            pin("GP21", "21", &[]),
+            pin("GP22", "22", &[]),
+            pin("GP26", "26", &["ADC0"]),
+            pin("GP27", "27", &["ADC1"]),
+            pin("GP28", "28", &["ADC2"]),
+            $($extra),*
+        ]
+    };
+}
+
+const PICO: Pinout = Pinout {
+    pins: pico_pins![pin("GP25", "25", &["LED"])],
+    i2c: None,
+};
+
+/// The Pico W's LED is on the wireless chip, which the firmware names `LED`. GP23 to GP25 and
+/// GP29 drive the wireless chip.
+const PICO_W: Pinout = Pinout {
+    pins: pico_pins![pin("LED", "LED", &[])],
+    i2c: None,
+};
+
+/// The GPIOs DevKits break out, leaving out those of the flash and GPIO1 and GPIO3, the REPL's
+/// UART. The LED is that of the DevKit V1 clones, which the DevKitC lacks.
+const ESP32_DEVKIT: Pinout = Pinout {
+    pins: &[
+        pin("IO0", "0", &["ADC2_1", "BOOT"]),
+        pin("IO2", "2", &["ADC2_2", "LED"]),
+        pin("IO4", "4", &["ADC2_0"]),
+        pin("IO5", "5", &[]),
+        pin("IO12", "12", &["ADC2_5"]),
+        pin("IO13", "13", &["ADC2_4"]),
+        pin("IO14", "14", &["ADC2_6"]),
+        pin("IO15", "15", &["ADC2_3"]),
+        pin("IO16", "16", &[]),
+        pin("IO17", "17", &[]),
+        pin("IO18", "18", &[]),
+        pin("IO19", "19", &[]),
+        pin("IO21", "21", &["I2C SDA"]),
+        pin("IO22", "22", &["I2C SCL"]),
+        pin("IO23", "23", &[]),
+        pin("IO25", "25", &["ADC2_8", "DAC1"]),
+        pin("IO26", "26", &["ADC2_9", "DAC2"]),
+        pin("IO27", "27", &["ADC2_7"]),
+        pin("IO32", "32", &["ADC1_4"]),
+        pin("IO33", "33", &["ADC1_5"]),
+        pin("IO34", "34", &["ADC1_6", "input only"]),
+        pin("IO35", "35", &["ADC1_7", "input only"]),
+        pin("VP", "36", &["ADC1_0", "input only"]),
+        pin("VN", "39", &["ADC1_3", "input only"]),
+    ],
+    i2c: Some((21, 22)),
+};
+
+/// The GPIOs the DevKitC-1 breaks out but for USB's. Its LED is an addressable RGB one, which
+/// can't be driven as a pin.
+const ESP32_S3_DEVKIT: Pinout = Pinout {
+    pins: &[
+        pin("IO0", "0", &["BOOT"]),
+        pin("IO1", "1", &["ADC1_0"]),
+        pin("IO2", "2", &["ADC1_1"]),
+        pin("IO3", "3", &["ADC1_2"]),
+        pin("IO4", "4", &["ADC1_3"]),
+        pin("IO5", "5", &["ADC1_4"]),
+        pin("IO6", "6", &["ADC1_5"]),
+        pin("IO7", "7", &["ADC1_6"]),
+        pin("IO8", "8", &["ADC1_7"]),
+        pin("IO9", "9", &["ADC1_8"]),
+        pin("IO10", "10", &["ADC1_9"]),
+        pin("IO11", "11", &["ADC2_0"]),
+        pin("IO12", "12", &["ADC2_1"]),
+        pin("IO13", "13", &["ADC2_2"]),
+        pin("IO14", "14", &["ADC2_3"]),
+        pin("IO15", "15", &["ADC2_4"]),
+        pin("IO16", "16", &["ADC2_5"]),
+        pin("IO17", "17", &["ADC2_6"]),
+        pin("IO18", "18", &["ADC2_7"]),
+        pin("IO21", "21", &[]),
+        pin("IO35", "35", &[]),
+        pin("IO36", "36", &[]),
+        pin("IO37", "37", &[]),
+        pin("IO38", "38", &[]),
+        pin("IO39", "39", &[]),
+        pin("IO40", "40", &[]),
+        pin("IO41", "41", &[]),
+        pin("IO42", "42", &[]),
+        pin("IO45", "45", &[]),
+        pin("IO46", "46", &[]),
+        pin("IO47", "47", &[]),
+        pin("IO48", "48", &["RGB LED"]),
+    ],
+    i2c: None,
+};
+
+/// The GPIOs the DevKitM-1 breaks out but for USB's and the REPL's UART. Its LED is an
+/// addressable RGB one.
+const ESP32_C3_DEVKIT: Pinout = Pinout {
+    pins: &[
+        pin("IO0", "0", &["ADC1_0"]),
+        pin("IO1", "1", &["ADC1_1"]),
+        pin("IO2", "2", &["ADC1_2"]),
+        pin("IO3", "3", &["ADC1_3"]),
+        pin("IO4", "4", &["ADC1_4"]),
+        pin("IO5", "5", &[]),
+        pin("IO6", "6", &[]),
+        pin("IO7", "7", &[]),
+        pin("IO8", "8", &["RGB LED"]),
+        pin("IO9", "9", &["BOOT"]),
+        pin("IO10", "10", &[]),
+    ],
+    i2c: None,
+};
+
+/// The pyboard's firmware takes the names of its pins as they are
+const PYBOARD: Pinout = Pinout {
+    pins: &[
+        pin("X1", "X1", &["analog in"]),
+        pin("X2", "X2", &["analog in"]),
+        pin("X3", "X3", &["analog in"]),
+        pin("X4", "X4", &["analog in"]),
+        pin("X5", "X5", &["analog in", "DAC1"]),
+        pin("X6", "X6", &["analog in", "DAC2"]),
+        pin("X7", "X7", &["analog in"]),
+        pin("X8", "X8", &["analog in"]),
+        pin("X9", "X9", &["I2C1 SCL"]),
+        pin("X10", "X10", &["I2C1 SDA"]),
+        pin("X11", "X11", &["analog in"]),
+        pin("X12", "X12", &["analog in"]),
+        pin("X17", "X17", &["USR"]),
+        pin("X18", "X18", &[]),
+        pin("X19", "X19", &["analog in"]),
+        pin("X20", "X20", &["analog in"]),
+        pin("X21", "X21", &["analog in"]),
+        pin("X22", "X22", &["analog in"]),
+        pin("Y1", "Y1", &[]),
+        pin("Y2", "Y2", &[]),
+        pin("Y3", "Y3", &[]),
+        pin("Y4", "Y4", &[]),
+        pin("Y5", "Y5", &[]),
+        pin("Y6", "Y6", &[]),
+        pin("Y7", "Y7", &[]),
+        pin("Y8", "Y8", &[]),
+        pin("Y9", "Y9", &["I2C2 SCL"]),
+        pin("Y10", "Y10", &["I2C2 SDA"]),
+        pin("Y11", "Y11", &[]),
+        pin("Y12", "Y12", &[]),
+        pin("LED_RED", "LED_RED", &["LED"]),
+        pin("LED_GREEN", "LED_GREEN", &[]),
+        pin("LED_YELLOW", "LED_YELLOW", &[]),
+        pin("LED_BLUE", "LED_BLUE", &[]),
+    ],
+    i2c: None,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boards_from_machine() {
+        let machines = [
+            ("Raspberry Pi Pico W with RP2040", Some(Board::PicoW)),
+            ("Raspberry Pi Pico with RP2040", Some(Board::Pico)),
+            ("Generic ESP32 module with ESP32", Some(Board::Esp32DevKit)),
+            ("ESP32S3 module with ESP32S3", Some(Board::Esp32S3DevKit)),
+            ("ESP32C3 module with ESP32C3", Some(Board::Esp32C3DevKit)),
+            ("PYBv1.1 with STM32F405RG", Some(Board::Pyboard)),
+            // Not a board with a pinout yet
+            ("Raspberry Pi Pico2 with RP2350", None),
+            ("linux [GCC 12.2.0] version", None),
+        ];
+        for (machine, board) in machines {
+            assert_eq!(Board::from_machine(machine), board, "{}", machine);
+        }
+    }
+
+    #[test]
+    fn boards_by_id() {
+        for board in BOARDS {
+            assert_eq!(Board::parse(board.id()).unwrap(), *board);
+        }
+        assert_eq!(Board::parse("Pico-W").unwrap(), Board::PicoW);
+        assert!(Board::parse("pico2").is_err());
+    }
+}
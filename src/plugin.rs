@@ -0,0 +1,132 @@
+//! Commands provided by other programs: `serpico NAME` runs a `serpico-NAME` executable found on
+//! the PATH, with the rest of the command line as its arguments. What serpico has worked out is
+//! passed on in the environment, each variable only set when known:
+//!
+//! - `SERPICO`: this serpico executable, for plugins that run serpico commands themselves
+//! - `SERPICO_DEVICE`: the device given or discovered
+//! - `SERPICO_BAUD`: the baud rate
+//! - `SERPICO_CONFIG`: the serpico.toml in effect
+//! - `SERPICO_PROFILE`: the profile selected from it
+//! - `SERPICO_QUIET`, `SERPICO_VERBOSE`: `1` when given, the verbosity as a count
+use anyhow::{bail, Result};
+use std::env;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// What the executable of a plugin starts with
+pub const PREFIX: &str = "serpico-";
+
+/// What a plugin is told about the invocation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Context {
+    pub device: Option<PathBuf>,
+    pub baud: Option<u32>,
+    pub config: Option<PathBuf>,
+    pub profile: Option<String>,
+    pub quiet: bool,
+    pub verbose: u8,
+}
+
+/// The executable of the plugin `name`, if there is one on the PATH
+pub fn find(name: &str) -> Option<PathBuf> {
+    find_in(name, &env::var_os("PATH")?)
+}
+
+/// The executable of the plugin `name` in the directories of `path`, a list such as the PATH
+fn find_in(name: &str, path: &OsStr) -> Option<PathBuf> {
+    let file = format!("{}{}{}", PREFIX, name, env::consts::EXE_SUFFIX);
+    env::split_paths(path)
+        .map(|dir| dir.join(&file))
+        .find(|candidate| executable(candidate))
+}
+
+#[cfg(unix)]
+fn executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Run the plugin `name` with `args`, returning its exit code
+pub fn run(name: &str, args: &[String], context: &Context) -> Result<i32> {
+    let program = match find(name) {
+        Some(program) => program,
+        None => bail!(
+            "No command {:?}, and no {}{} on the PATH to run instead",
+            name,
+            PREFIX,
+            name
+        ),
+    };
+    let mut command = Command::new(&program);
+    command.args(args);
+    if let Ok(serpico) = env::current_exe() {
+        command.env("SERPICO", serpico);
+    }
+    if let Some(device) = &context.device {
+        command.env("SERPICO_DEVICE", device);
+    }
+    if let Some(baud) = context.baud {
+        command.env("SERPICO_BAUD", baud.to_string());
+    }
+    if let Some(config) = &context.config {
+        command.env("SERPICO_CONFIG", config);
+    }
+    if let Some(profile) = &context.profile {
+        command.env("SERPICO_PROFILE", profile);
+    }
+    if context.quiet {
+        command.env("SERPICO_QUIET", "1");
+    }
+    if context.verbose > 0 {
+        command.env("SERPICO_VERBOSE", context.verbose.to_string());
+    }
+    let status = match command.status() {
+        Ok(status) => status,
+        Err(e) => bail!("Couldn't run {}: {}", program.display(), e),
+    };
+    // A plugin killed by a signal exits like a shell reports it
+    Ok(status.code().unwrap_or(128 + signal(&status)))
+}
+
+#[cfg(unix)]
+fn signal(status: &std::process::ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal().unwrap_or(0)
+}
+
+#[cfg(not(unix))]
+fn signal(_status: &std::process::ExitStatus) -> i32 {
+    0
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn found_on_the_path() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+        let dir = env::temp_dir().join(format!("serpico-test-{}-plugins", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let plugin = dir.join("serpico-flash");
+        fs::write(&plugin, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&plugin, fs::Permissions::from_mode(0o755)).unwrap();
+        // Not executable, so not a plugin
+        fs::write(dir.join("serpico-notes"), "").unwrap();
+
+        let path = env::join_paths([Path::new("/nonexistent"), &dir]).unwrap();
+        assert_eq!(find_in("flash", &path), Some(plugin));
+        assert_eq!(find_in("notes", &path), None);
+        assert_eq!(find_in("missing", &path), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
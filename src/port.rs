@@ -0,0 +1,434 @@
+use anyhow::{bail, Context, Result};
+use serialport::{FlowControl, SerialPort};
+use std::fmt;
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::device::{Device, DEFAULT_BUFFER_SIZE};
+use crate::lock;
+use crate::reset::ResetStrategy;
+use crate::subprocess;
+use crate::tap::TapPort;
+use crate::trace::TraceLog;
+
+/// The baud rate used unless another one is configured
+pub const DEFAULT_BAUD_RATE: u32 = 115_200;
+
+/// How many more times opening the port is tried when it fails in a way that tends to pass, such
+/// as when udev hasn't set the permissions of a port that just appeared yet
+pub const DEFAULT_OPEN_RETRIES: u32 = 4;
+
+/// How long to wait before trying to open the port again, doubling with every retry
+const OPEN_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How long to wait for processes to let go of the port after being asked to terminate
+const FORCE_WAIT: Duration = Duration::from_secs(2);
+
+/// A process that has a serial port open
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortHolder {
+    pub pid: u32,
+    pub name: String,
+}
+
+/// Builder for opening the serial port of a MicroPython device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortBuilder {
+    path: PathBuf,
+    baud_rate: u32,
+    flow_control: FlowControl,
+    reset: ResetStrategy,
+    buffer_size: usize,
+    force: bool,
+    open_retries: u32,
+    queue: bool,
+    shared: bool,
+    trace: Option<PathBuf>,
+    password: Option<String>,
+}
+
+/// Start building a port for the device at `path`, see [`PortBuilder`]
+pub fn new(path: impl AsRef<Path>) -> PortBuilder {
+    PortBuilder {
+        path: path.as_ref().to_path_buf(),
+        baud_rate: DEFAULT_BAUD_RATE,
+        flow_control: FlowControl::None,
+        reset: ResetStrategy::default(),
+        buffer_size: DEFAULT_BUFFER_SIZE,
+        force: false,
+        open_retries: DEFAULT_OPEN_RETRIES,
+        queue: false,
+        shared: false,
+        trace: None,
+        password: None,
+    }
+}
+
+impl PortBuilder {
+    /// Set the path of the device
+    pub fn path(mut self, path: impl AsRef<Path>) -> Self {
+        self.path = path.as_ref().to_path_buf();
+        self
+    }
+
+    /// Set the baud rate, defaults to [`DEFAULT_BAUD_RATE`]
+    pub fn baud_rate(mut self, baud_rate: u32) -> Self {
+        self.baud_rate = baud_rate;
+        self
+    }
+
+    /// Set the flow control, defaults to none
+    pub fn flow_control(mut self, flow_control: FlowControl) -> Self {
+        self.flow_control = flow_control;
+        self
+    }
+
+    /// Set the control line sequence used to reset the board right after opening the port
+    pub fn reset(mut self, reset: ResetStrategy) -> Self {
+        self.reset = reset;
+        self
+    }
+
+    /// Set the size of the buffers used for reading from the device, defaults to
+    /// [`DEFAULT_BUFFER_SIZE`]
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size.max(1);
+        self
+    }
+
+    /// If other processes are holding the port, ask them to terminate instead of failing
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Set how many more times to try opening the port when it's busy or not accessible yet,
+    /// waiting longer each time, defaults to [`DEFAULT_OPEN_RETRIES`]
+    pub fn open_retries(mut self, open_retries: u32) -> Self {
+        self.open_retries = open_retries;
+        self
+    }
+
+    /// If another process has the board locked, wait for it to be done instead of failing, see
+    /// [`lock`]
+    pub fn queue(mut self, queue: bool) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    /// Open the port without claiming it, so that other processes can have it open too, such as
+    /// a logger, and without locking the board against other serpico processes. By default the
+    /// port is claimed, so that nothing else corrupts the session by writing to it.
+    pub fn shared(mut self, shared: bool) -> Self {
+        self.shared = shared;
+        self
+    }
+
+    /// Log every byte exchanged with the device to a file at `path`, see [`TraceLog`]
+    pub fn trace(mut self, path: Option<impl AsRef<Path>>) -> Self {
+        self.trace = path.map(|path| path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set the password for logging in to a WebREPL, see [`crate::webrepl`]
+    pub fn password(mut self, password: Option<impl Into<String>>) -> Self {
+        self.password = password.map(Into::into);
+        self
+    }
+
+    /// Open the port. A `ws://` URL connects to the board's WebREPL instead, a `tcp://host:port`
+    /// URL to a REPL served over TCP, a `ble://` address to a REPL served over Bluetooth Low
+    /// Energy and a `micropython://` URL spawns the unix port.
+    ///
+    /// If the port can't be opened because other processes are holding it, the error lists those
+    /// processes, unless they are asked to terminate with [`PortBuilder::force`]. Serial ports are
+    /// locked against other serpico processes for as long as the device is open.
+    pub fn open(&self) -> Result<Device> {
+        self.connect().context(ConnectFailed {
+            device: self.path.clone(),
+        })
+    }
+
+    fn connect(&self) -> Result<Device> {
+        let path = self.path.as_path();
+        let device_path = match path.to_str() {
+            Some(path) => path,
+            None => bail!("Unable to convert path to string: {:?}", path),
+        };
+        let mut lock = None;
+        let mut port: Box<dyn SerialPort> = if device_path.starts_with("ws://") {
+            let password = match &self.password {
+                Some(password) => password,
+                None => bail!("{} needs the WebREPL password", device_path),
+            };
+            connect_webrepl(device_path, password)?
+        } else if device_path.starts_with("tcp://") {
+            connect_tcp(device_path)?
+        } else if subprocess::is_url(device_path) {
+            Box::new(subprocess::spawn(device_path)?)
+        } else if device_path.starts_with("ble://") {
+            connect_ble(device_path)?
+        } else {
+            if !self.shared {
+                lock = Some(lock::acquire(path, self.queue)?);
+            }
+            let builder = serialport::new(device_path, self.baud_rate)
+                .flow_control(self.flow_control)
+                .timeout(Duration::from_millis(10));
+            open(path, builder, !self.shared, self.force, self.open_retries)?
+        };
+        if let Some(trace) = &self.trace {
+            let name = port.name().unwrap_or_default();
+            port = Box::new(TapPort::new(port, TraceLog::create(trace, &name)?));
+        }
+        self.reset.apply(&mut *port)?;
+        let device = Device::new(port, self.buffer_size);
+        Ok(match lock {
+            Some(lock) => device.with_lock(lock),
+            None => device,
+        })
+    }
+}
+
+/// Connect to a board's WebREPL, see [`crate::webrepl`]
+#[cfg(unix)]
+fn connect_webrepl(url: &str, password: &str) -> Result<Box<dyn SerialPort>> {
+    Ok(Box::new(crate::webrepl::connect(url, password)?))
+}
+
+#[cfg(not(unix))]
+fn connect_webrepl(url: &str, _password: &str) -> Result<Box<dyn SerialPort>> {
+    bail!(
+        "{} is a WebREPL device, which serpico only supports on unix",
+        url
+    )
+}
+
+/// Connect to a REPL served over TCP, see [`crate::socket`]
+#[cfg(unix)]
+fn connect_tcp(url: &str) -> Result<Box<dyn SerialPort>> {
+    Ok(Box::new(crate::socket::connect_tcp(url)?))
+}
+
+#[cfg(not(unix))]
+fn connect_tcp(url: &str) -> Result<Box<dyn SerialPort>> {
+    bail!(
+        "{} is a TCP device, which serpico only supports on unix",
+        url
+    )
+}
+
+/// Connect to a board over Bluetooth Low Energy, see [`crate::ble`]
+#[cfg(all(feature = "ble", target_os = "linux"))]
+fn connect_ble(url: &str) -> Result<Box<dyn SerialPort>> {
+    Ok(Box::new(crate::ble::connect(url)?))
+}
+
+#[cfg(not(all(feature = "ble", target_os = "linux")))]
+fn connect_ble(url: &str) -> Result<Box<dyn SerialPort>> {
+    bail!(
+        "{} is a Bluetooth device, which needs serpico built with --features ble on Linux",
+        url
+    )
+}
+
+/// The device couldn't be opened, the error it's the context of tells why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectFailed {
+    pub device: PathBuf,
+}
+
+impl fmt::Display for ConnectFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Couldn't connect to {}", self.device.display())
+    }
+}
+
+impl std::error::Error for ConnectFailed {}
+
+/// Parse a flow control setting: `none`, `rtscts` (hardware) or `xonxoff` (software)
+pub fn parse_flow_control(value: &str) -> Result<FlowControl> {
+    match value {
+        "none" => Ok(FlowControl::None),
+        "rtscts" => Ok(FlowControl::Hardware),
+        "xonxoff" => Ok(FlowControl::Software),
+        _ => bail!(
+            "Unknown flow control {:?}, use none, rtscts or xonxoff",
+            value
+        ),
+    }
+}
+
+fn open(
+    path: &Path,
+    builder: serialport::SerialPortBuilder,
+    exclusive: bool,
+    force: bool,
+    retries: u32,
+) -> Result<Box<dyn SerialPort>> {
+    // Ports are claimed with TIOCEXCL while they're opened either way, which fails if another
+    // process has claimed it, and released after unless `exclusive` is set
+    let open = |builder: serialport::SerialPortBuilder| -> serialport::Result<Box<dyn SerialPort>> {
+        let mut port = builder.open_native()?;
+        port.set_exclusive(exclusive)?;
+        Ok(Box::new(port))
+    };
+    let mut backoff = OPEN_BACKOFF;
+    let mut retries_left = retries;
+    let holders = loop {
+        let err = match open(builder.clone()) {
+            Ok(port) => return Ok(port),
+            Err(e) => e,
+        };
+        let holders = find_port_holders(path);
+        if !holders.is_empty() {
+            break holders;
+        }
+        if retries_left == 0 || !is_transient(&err) {
+            return Err(err.into());
+        }
+        retries_left -= 1;
+        sleep(backoff);
+        backoff *= 2;
+    };
+
+    if !force {
+        bail!(
+            "{} is in use by {}, close it or use --force to terminate it",
+            path.display(),
+            describe_holders(&holders)
+        );
+    }
+
+    for holder in holders.iter() {
+        terminate(holder.pid)?;
+    }
+
+    let start = Instant::now();
+    while !find_port_holders(path).is_empty() {
+        if start.elapsed() > FORCE_WAIT {
+            bail!(
+                "{} is still in use by {} after asking it to terminate",
+                path.display(),
+                describe_holders(&holders)
+            );
+        }
+        sleep(Duration::from_millis(50));
+    }
+
+    Ok(open(builder)?)
+}
+
+/// Whether opening the port failed in a way that tends to pass by itself. Right after a port
+/// appears udev may still be setting its permissions, or ModemManager may have it open to probe
+/// it.
+fn is_transient(err: &serialport::Error) -> bool {
+    is_permission_denied(err) || is_busy(err)
+}
+
+/// Whether the port couldn't be opened for lack of permission
+pub fn is_permission_denied(err: &serialport::Error) -> bool {
+    err.kind() == serialport::ErrorKind::Io(io::ErrorKind::PermissionDenied)
+}
+
+/// Whether the port couldn't be opened because something else has it open exclusively
+pub fn is_busy(err: &serialport::Error) -> bool {
+    // EBUSY has no kind of its own
+    err.kind() == serialport::ErrorKind::Unknown && err.description.contains("busy")
+}
+
+/// Find the processes that currently have the port at `path` open. Best effort, any process that
+/// can't be inspected is skipped.
+#[cfg(target_os = "linux")]
+pub fn find_port_holders(path: &Path) -> Vec<PortHolder> {
+    let target = match fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(_) => return Vec::new(),
+    };
+    let own_pid = std::process::id();
+    let mut holders = Vec::new();
+
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return holders,
+    };
+    for entry in entries.flatten() {
+        let pid: u32 = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if pid == own_pid {
+            continue;
+        }
+        let fds = match fs::read_dir(entry.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        let holds_port = fds
+            .flatten()
+            .any(|fd| fs::read_link(fd.path()).is_ok_and(|link| link == target));
+        if holds_port {
+            let name = fs::read_to_string(entry.path().join("comm"))
+                .map(|name| name.trim().to_string())
+                .unwrap_or_else(|_| String::from("?"));
+            holders.push(PortHolder { pid, name });
+        }
+    }
+
+    holders
+}
+
+/// Find the processes that currently have the port at `path` open, using `lsof`. Best effort, an
+/// empty list is returned if `lsof` isn't available.
+#[cfg(not(target_os = "linux"))]
+pub fn find_port_holders(path: &Path) -> Vec<PortHolder> {
+    let output = match Command::new("lsof").arg("-Fpc").arg(path).output() {
+        Ok(output) => output,
+        Err(_) => return Vec::new(),
+    };
+    let own_pid = std::process::id();
+    let mut holders: Vec<PortHolder> = Vec::new();
+
+    // lsof field output is one field per line, "p<pid>" followed by "c<command>"
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Some(pid) = line.strip_prefix('p') {
+            if let Ok(pid) = pid.parse() {
+                holders.push(PortHolder {
+                    pid,
+                    name: String::from("?"),
+                });
+            }
+        } else if let Some(name) = line.strip_prefix('c') {
+            if let Some(holder) = holders.last_mut() {
+                holder.name = name.to_string();
+            }
+        }
+    }
+    holders.retain(|holder| holder.pid != own_pid);
+
+    holders
+}
+
+fn terminate(pid: u32) -> Result<()> {
+    let status = Command::new("kill")
+        .arg("-TERM")
+        .arg(pid.to_string())
+        .status()?;
+    if !status.success() {
+        bail!("Unable to terminate process {}", pid);
+    }
+    Ok(())
+}
+
+fn describe_holders(holders: &[PortHolder]) -> String {
+    holders
+        .iter()
+        .map(|holder| format!("{} (pid {})", holder.name, holder.pid))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,211 @@
+//! Transforming scripts on the host before they're sent to the device, with steps of the user's
+//! own, such as templating, backporting f-strings for old firmware or a minifier of their choice.
+//! `run` applies them to the script it runs, and the commands that copy files to the `.py` files
+//! they copy.
+use anyhow::{bail, Result};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{self, Stdio};
+use std::thread;
+
+/// A step that transforms a script before it's sent
+pub trait Preprocessor {
+    /// The script from `path`, as the steps before this one left it in `source`, transformed
+    fn process(&self, path: &Path, source: Vec<u8>) -> Result<Vec<u8>>;
+}
+
+/// A command run in the shell, given the script on stdin and its path in `$SERPICO_FILE`, that
+/// prints the transformed script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Command {
+    pub command: String,
+}
+
+impl Command {
+    pub fn parse(value: &str) -> Result<Command> {
+        if value.trim().is_empty() {
+            bail!("Expected a command to preprocess scripts with");
+        }
+        Ok(Command {
+            command: value.to_string(),
+        })
+    }
+}
+
+impl Preprocessor for Command {
+    fn process(&self, path: &Path, source: Vec<u8>) -> Result<Vec<u8>> {
+        let mut shell = if cfg!(windows) {
+            let mut shell = process::Command::new("cmd");
+            shell.arg("/C");
+            shell
+        } else {
+            let mut shell = process::Command::new("sh");
+            shell.arg("-c");
+            shell
+        };
+        let mut child = match shell
+            .arg(&self.command)
+            .env("SERPICO_FILE", path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => bail!("Couldn't run preprocessor `{}`: {}", self.command, e),
+        };
+
+        // The script is written from a thread of its own, as the command may print as it reads
+        let mut stdin = child.stdin.take();
+        let writer = thread::spawn(move || match stdin.as_mut() {
+            Some(stdin) => stdin.write_all(&source),
+            None => Ok(()),
+        });
+        let mut processed = Vec::new();
+        if let Some(mut stdout) = child.stdout.take() {
+            stdout.read_to_end(&mut processed)?;
+        }
+        let status = child.wait()?;
+        let written = writer.join();
+        if !status.success() {
+            bail!(
+                "Preprocessor `{}` failed on {} with {}",
+                self.command,
+                path.display(),
+                status
+            );
+        }
+        match written {
+            Ok(Ok(())) => Ok(processed),
+            // A command that finished without reading all of the script has ignored the rest
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(processed),
+            Ok(Err(e)) => bail!(
+                "Couldn't pass {} to `{}`: {}",
+                path.display(),
+                self.command,
+                e
+            ),
+            Err(_) => bail!("Couldn't pass {} to `{}`", path.display(), self.command),
+        }
+    }
+}
+
+/// Preprocessors applied one after the other, in the order they were added
+#[derive(Default)]
+pub struct Pipeline {
+    steps: Vec<Box<dyn Preprocessor>>,
+}
+
+impl Pipeline {
+    /// A pipeline of the commands, as given with `--preprocess`
+    pub fn commands(commands: &[Command]) -> Pipeline {
+        commands
+            .iter()
+            .cloned()
+            .fold(Pipeline::default(), Pipeline::then)
+    }
+
+    /// Apply `step` after the steps so far
+    pub fn then(mut self, step: impl Preprocessor + 'static) -> Pipeline {
+        self.steps.push(Box::new(step));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// The script from `path` as text, transformed, for the steps on the host that come after
+    pub fn process_text(&self, path: &Path, source: String) -> Result<String> {
+        if self.is_empty() {
+            return Ok(source);
+        }
+        match String::from_utf8(self.process(path, source.into_bytes())?) {
+            Ok(processed) => Ok(processed),
+            Err(_) => bail!("Preprocessing {} left it not valid UTF-8", path.display()),
+        }
+    }
+}
+
+impl Preprocessor for Pipeline {
+    fn process(&self, path: &Path, source: Vec<u8>) -> Result<Vec<u8>> {
+        self.steps
+            .iter()
+            .try_fold(source, |source, step| step.process(path, source))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Appends its mark, to tell the order steps ran in
+    struct Mark(&'static str);
+
+    impl Preprocessor for Mark {
+        fn process(&self, _path: &Path, mut source: Vec<u8>) -> Result<Vec<u8>> {
+            source.extend_from_slice(self.0.as_bytes());
+            Ok(source)
+        }
+    }
+
+    #[cfg(unix)]
+    fn commands(commands: &[&str]) -> Pipeline {
+        let commands: Vec<Command> = commands
+            .iter()
+            .map(|command| Command::parse(command).unwrap())
+            .collect();
+        Pipeline::commands(&commands)
+    }
+
+    #[test]
+    fn steps_run_in_order() {
+        let pipeline = Pipeline::default().then(Mark("1")).then(Mark("2"));
+        let processed = pipeline
+            .process(Path::new("main.py"), b"0".to_vec())
+            .unwrap();
+        assert_eq!(processed, b"012");
+        assert!(Pipeline::default().is_empty());
+        assert!(Command::parse("  ").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commands_run_in_order() {
+        let path = Path::new("main.py");
+        let source = "x = 'a'\n".to_string();
+        let pipeline = commands(&["sed s/a/b/", "sed s/b/c/"]);
+        assert_eq!(
+            pipeline.process_text(path, source.clone()).unwrap(),
+            "x = 'c'\n"
+        );
+        let pipeline = commands(&["sed s/b/c/", "sed s/a/b/"]);
+        assert_eq!(pipeline.process_text(path, source).unwrap(), "x = 'b'\n");
+        let pipeline = commands(&["printf %s \"$SERPICO_FILE\""]);
+        assert_eq!(
+            pipeline.process_text(path, String::new()).unwrap(),
+            "main.py"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failing_command() {
+        let error = commands(&["cat", "exit 3"])
+            .process(Path::new("main.py"), b"print(1)\n".to_vec())
+            .unwrap_err();
+        let error = error.to_string();
+        assert!(error.contains("`exit 3` failed on main.py"), "{}", error);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_that_ignores_stdin() {
+        // More than a pipe holds, so that writing it fails once the command has exited
+        let source = vec![b'#'; 1 << 20];
+        let processed = commands(&["echo replaced"])
+            .process(Path::new("main.py"), source)
+            .unwrap();
+        assert_eq!(processed, b"replaced\n");
+    }
+}
@@ -0,0 +1,53 @@
+//! Telling MicroPython boards apart from other USB serial devices by whether a REPL answers, for
+//! boards whose USB descriptors don't say they run MicroPython, such as ESP boards behind a
+//! bridge chip
+use anyhow::Result;
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
+use crate::device::Device;
+
+/// How long a port is given to answer unless told otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// What's in the banners of MicroPython and its forks
+const BANNERS: &[&str] = &["MicroPython", "CircuitPython"];
+
+/// The prompts of the friendly and the raw REPL, which a board whose banner is customized still
+/// prints
+const PROMPTS: &[&str] = &[">>> ", "raw REPL; CTRL-B to exit"];
+
+/// Ask the device for its REPL's banner with Ctrl-B, which leaves the raw REPL and prints the
+/// banner at the friendly prompt, without interrupting a running program. Returns the banner, or
+/// the prompt where the banner is customized, or `None` if nothing like a REPL answered within
+/// `timeout`.
+pub fn probe(device: &mut Device, timeout: Duration) -> Result<Option<String>> {
+    let port = device.port();
+    port.write_all(b"\x02")?;
+    port.flush()?;
+
+    let start = Instant::now();
+    let mut received = Vec::new();
+    let mut buffer = [0; 256];
+    while start.elapsed() < timeout {
+        match port.read(&mut buffer) {
+            Ok(count) => received.extend_from_slice(&buffer[..count]),
+            Err(e) if e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => return Err(e.into()),
+        }
+        let text = String::from_utf8_lossy(&received);
+        let complete = &text[..text.rfind('\n').map_or(0, |end| end + 1)];
+        let banner = complete
+            .lines()
+            .map(str::trim)
+            .find(|line| BANNERS.iter().any(|banner| line.contains(banner)));
+        if let Some(banner) = banner {
+            return Ok(Some(banner.to_string()));
+        }
+        // The banner comes just ahead of the prompt
+        if let Some(prompt) = PROMPTS.iter().find(|prompt| text.contains(*prompt)) {
+            return Ok(Some(prompt.trim().to_string()));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,109 @@
+//! A progress bar for uploads, drawn on stderr
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+const WIDTH: usize = 30;
+
+/// Don't draw anything for uploads that finish quicker than this
+const SHOW_AFTER: Duration = Duration::from_millis(250);
+const REDRAW_EVERY: Duration = Duration::from_millis(100);
+
+/// Whether a progress bar can be shown: both stdout and stderr are terminals
+pub fn available() -> bool {
+    io::stdout().is_terminal() && io::stderr().is_terminal()
+}
+
+/// Progress of sending `total` bytes
+pub struct Progress {
+    label: String,
+    total: usize,
+    start: Instant,
+    last_draw: Option<Instant>,
+}
+
+impl Progress {
+    pub fn new(label: &str, total: usize) -> Progress {
+        Progress {
+            label: label.to_string(),
+            total,
+            start: Instant::now(),
+            last_draw: None,
+        }
+    }
+
+    /// Update the progress bar with the number of bytes sent so far
+    pub fn update(&mut self, sent: usize) {
+        let now = Instant::now();
+        if now.duration_since(self.start) < SHOW_AFTER {
+            return;
+        }
+        if let Some(last_draw) = self.last_draw {
+            if now.duration_since(last_draw) < REDRAW_EVERY && sent < self.total {
+                return;
+            }
+        }
+        self.last_draw = Some(now);
+        self.draw(sent);
+    }
+
+    /// Remove the progress bar, if it was drawn
+    pub fn finish(&mut self) {
+        if self.last_draw.take().is_some() {
+            let mut stderr = io::stderr();
+            let _ = write!(stderr, "\r\x1b[2K");
+            let _ = stderr.flush();
+        }
+    }
+
+    fn draw(&self, sent: usize) {
+        let fraction = if self.total == 0 {
+            1.0
+        } else {
+            sent as f64 / self.total as f64
+        };
+        let filled = ((fraction * WIDTH as f64) as usize).min(WIDTH);
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            sent as f64 / elapsed
+        } else {
+            0.0
+        };
+        let eta = if rate > 0.0 {
+            format!("{:.0}s", (self.total - sent.min(self.total)) as f64 / rate)
+        } else {
+            String::from("?")
+        };
+
+        let mut stderr = io::stderr();
+        let _ = write!(
+            stderr,
+            "\r\x1b[2K{} [{}{}] {:3.0}% {}/{} {}/s ETA {}",
+            self.label,
+            "=".repeat(filled),
+            " ".repeat(WIDTH - filled),
+            fraction * 100.0,
+            format_bytes(sent as f64),
+            format_bytes(self.total as f64),
+            format_bytes(rate),
+            eta
+        );
+        let _ = stderr.flush();
+    }
+}
+
+/// Format a number of bytes as B, KiB or MiB
+pub fn format_bytes(bytes: f64) -> String {
+    if bytes < 1024.0 {
+        format!("{:.0} B", bytes)
+    } else if bytes < 1024.0 * 1024.0 {
+        format!("{:.1} KiB", bytes / 1024.0)
+    } else {
+        format!("{:.1} MiB", bytes / (1024.0 * 1024.0))
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
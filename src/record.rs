@@ -0,0 +1,297 @@
+//! Turning the lines a device prints into structured records with host timestamps, written as
+//! NDJSON or CSV, for analysing sensor runs afterwards
+use anyhow::{bail, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::encoding::Decoding;
+use crate::json::{self, Value};
+use crate::window::{self, Capture, Window};
+
+/// How records are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A JSON object per line
+    Ndjson,
+    /// A header row of column names, then a row per record
+    Csv,
+}
+
+impl Format {
+    pub fn parse(value: &str) -> Result<Format> {
+        match value {
+            "ndjson" => Ok(Format::Ndjson),
+            "csv" => Ok(Format::Csv),
+            _ => bail!("Expected ndjson or csv, got {:?}", value),
+        }
+    }
+}
+
+/// How the fields of a record are found in a line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fields {
+    /// The whole line is the one field, `line`
+    Line,
+    /// `key=value` pairs separated by spaces or commas, such as `temp=21.5 humidity=40`
+    KeyValue,
+    /// A JSON object, such as `{"temp": 21.5}`
+    Json,
+}
+
+impl Fields {
+    pub fn parse(value: &str) -> Result<Fields> {
+        match value {
+            "line" => Ok(Fields::Line),
+            "kv" => Ok(Fields::KeyValue),
+            "json" => Ok(Fields::Json),
+            _ => bail!("Expected line, kv or json, got {:?}", value),
+        }
+    }
+
+    /// The fields of `line`, or `None` if it isn't a record, such as a line without any `key=value`
+    /// pairs when those are expected
+    pub fn of(&self, line: &str) -> Option<Vec<(String, Value)>> {
+        match self {
+            Fields::Line => Some(vec![("line".to_string(), Value::from(line))]),
+            Fields::KeyValue => Some(pairs(line)).filter(|fields| !fields.is_empty()),
+            Fields::Json => match json::parse(line.trim()) {
+                Ok(Value::Object(entries)) => Some(entries),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// The `key=value` pairs in `line`, where a value in quotes can have spaces and commas in it.
+/// Words that aren't pairs are skipped.
+fn pairs(line: &str) -> Vec<(String, Value)> {
+    let is_separator = |c: char| c.is_whitespace() || c == ',';
+    let mut pairs = Vec::new();
+    let mut rest = line;
+    loop {
+        rest = rest.trim_start_matches(is_separator);
+        if rest.is_empty() {
+            return pairs;
+        }
+        let word_end = rest.find(is_separator).unwrap_or(rest.len());
+        let (key, after) = match rest[..word_end].split_once('=') {
+            Some((key, _)) => (key, &rest[key.len() + 1..]),
+            None => {
+                rest = &rest[word_end..];
+                continue;
+            }
+        };
+        let (value, next) = match after.chars().next() {
+            Some(quote @ ('"' | '\'')) => match after[1..].find(quote) {
+                Some(end) => (Value::from(&after[1..end + 1]), &after[end + 2..]),
+                None => (Value::from(&after[1..]), ""),
+            },
+            _ => {
+                let end = after.find(is_separator).unwrap_or(after.len());
+                (scalar(&after[..end]), &after[end..])
+            }
+        };
+        if !key.is_empty() {
+            pairs.push((key.to_string(), value));
+        }
+        rest = next;
+    }
+}
+
+/// A `key=value` value as a number or boolean if it is one, otherwise as a string
+fn scalar(value: &str) -> Value {
+    match value {
+        "true" | "True" => return Value::Bool(true),
+        "false" | "False" => return Value::Bool(false),
+        _ => {}
+    }
+    match value.parse::<f64>() {
+        Ok(number) if number.is_finite() => Value::Number(number),
+        _ => Value::from(value),
+    }
+}
+
+/// Writes a record for each line of output that is one, ahead of its fields the host's time the
+/// line started arriving, `host_time`, and the seconds since the first, `host_elapsed`
+pub struct RecordWriter {
+    format: Format,
+    fields: Fields,
+    out: Box<dyn Write>,
+    /// The CSV columns after the host's, from the first record unless they were given
+    columns: Option<Vec<String>>,
+    /// Whether the CSV header row has been written
+    header: bool,
+    /// Only lines in the window are recorded
+    window: Option<Window>,
+    /// Each window is written to a file of its own, numbered from this path
+    rotate: Option<PathBuf>,
+    /// How many windows have had files of their own
+    windows: usize,
+    decoding: Decoding,
+    start: Option<Instant>,
+    line: Vec<u8>,
+    line_start: Option<(Instant, SystemTime)>,
+}
+
+impl RecordWriter {
+    /// Records from lines parsed with `fields`, written to `out`. For CSV, `columns` are the
+    /// fields to write, the fields of the first record if it's empty.
+    pub fn new(
+        format: Format,
+        fields: Fields,
+        columns: Vec<String>,
+        out: Box<dyn Write>,
+    ) -> RecordWriter {
+        RecordWriter {
+            format,
+            fields,
+            out,
+            columns: Some(columns).filter(|columns| !columns.is_empty()),
+            header: false,
+            window: None,
+            rotate: None,
+            windows: 0,
+            decoding: Decoding::default(),
+            start: None,
+            line: Vec::new(),
+            line_start: None,
+        }
+    }
+
+    /// Only record the lines in `window`
+    pub fn window(mut self, window: Window) -> RecordWriter {
+        self.window = Some(window);
+        self
+    }
+
+    /// Write each window to a new file, `path` numbered with [`window::numbered`], instead of the
+    /// output given
+    pub fn rotate(mut self, path: PathBuf) -> RecordWriter {
+        self.rotate = Some(path);
+        self
+    }
+
+    /// Decode lines with `decoding` rather than as UTF-8 with replacements
+    pub fn decoding(mut self, decoding: Decoding) -> RecordWriter {
+        self.decoding = decoding;
+        self
+    }
+
+    /// Add bytes read from the device, writing records for the lines they complete
+    pub fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        let received = (Instant::now(), SystemTime::now());
+        for &byte in bytes {
+            if self.line_start.is_none() {
+                self.line_start = Some(received);
+            }
+            if byte == b'\n' {
+                let line = self.decoding.text(&self.line)?.trim_end().to_string();
+                let started = self.line_start.take().unwrap_or(received);
+                self.line.clear();
+                self.record(&line, started)?;
+            } else {
+                self.line.push(byte);
+            }
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn record(&mut self, line: &str, (instant, time): (Instant, SystemTime)) -> Result<()> {
+        let capture = match &mut self.window {
+            Some(window) => window.line(line),
+            None => Capture::Inside,
+        };
+        if capture == Capture::Outside {
+            return Ok(());
+        }
+        if let Some(path) = &self.rotate {
+            if capture == Capture::Opened || self.windows == 0 {
+                self.windows += 1;
+                let path = window::numbered(path, self.windows);
+                self.out = match File::create(&path) {
+                    Ok(file) => Box::new(BufWriter::new(file)),
+                    Err(e) => bail!("Couldn't create {}: {}", path.display(), e),
+                };
+                self.header = false;
+                self.start = None;
+            }
+        }
+
+        let fields = match self.fields.of(line) {
+            Some(fields) => fields,
+            None => return Ok(()),
+        };
+        let start = *self.start.get_or_insert(instant);
+        let elapsed = (instant.duration_since(start).as_secs_f64() * 1000.0).round() / 1000.0;
+        let mut record = vec![
+            ("host_time".to_string(), Value::from(format_utc(time))),
+            ("host_elapsed".to_string(), Value::from(elapsed)),
+        ];
+        record.extend(fields);
+
+        match self.format {
+            Format::Ndjson => writeln!(self.out, "{}", Value::Object(record))?,
+            Format::Csv => {
+                let columns = self.columns.get_or_insert_with(|| {
+                    record[2..].iter().map(|(key, _)| key.clone()).collect()
+                });
+                let names: Vec<&str> = ["host_time", "host_elapsed"]
+                    .into_iter()
+                    .chain(columns.iter().map(String::as_str))
+                    .collect();
+                if !self.header {
+                    self.header = true;
+                    let header: Vec<String> = names.iter().map(|name| csv_field(name)).collect();
+                    writeln!(self.out, "{}", header.join(","))?;
+                }
+                let row: Vec<String> = names
+                    .iter()
+                    .map(|name| match record.iter().find(|(key, _)| key == name) {
+                        Some((_, Value::String(value))) => csv_field(value),
+                        Some((_, Value::Null)) | None => String::new(),
+                        Some((_, value)) => csv_field(&value.to_string()),
+                    })
+                    .collect();
+                writeln!(self.out, "{}", row.join(","))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A CSV field, quoted if it has to be
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// `time` in UTC as ISO 8601, to the millisecond
+#[cfg(unix)]
+pub fn format_utc(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::gmtime_r(&seconds, &mut tm) };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(not(unix))]
+pub fn format_utc(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{:.3}", since_epoch.as_secs_f64())
+}
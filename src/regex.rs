@@ -0,0 +1,505 @@
+//! A small regular expression matcher, for filtering the device's output by line. Expressions
+//! are compiled to instructions run by a Pike VM, which steps every way of matching along the line
+//! at once, so that matching takes time linear in the length of the line whatever the expression,
+//! as the device's output can be anything.
+//!
+//! Supports literals, `.`, character classes such as `[a-z_]` and `[^0-9]`, the escapes `\d`,
+//! `\w`, `\s` and their negations, the anchors `^` and `$`, groups, alternation with `|` and the
+//! quantifiers `*`, `+`, `?` and `{n,m}`.
+use anyhow::{bail, Result};
+use std::fmt;
+
+/// A compiled regular expression
+#[derive(Clone)]
+pub struct Regex {
+    pattern: String,
+    program: Vec<Inst>,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Literal(char),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    Group(Vec<Vec<Node>>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+struct Class {
+    negated: bool,
+    ranges: Vec<(char, char)>,
+}
+
+const DIGIT: &[(char, char)] = &[('0', '9')];
+const WORD: &[(char, char)] = &[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+const SPACE: &[(char, char)] = &[(' ', ' '), ('\t', '\r')];
+
+impl Class {
+    fn matches(&self, c: char) -> bool {
+        let found = self
+            .ranges
+            .iter()
+            .any(|&(start, end)| start <= c && c <= end);
+        found != self.negated
+    }
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex> {
+        let mut parser = Parser {
+            chars: pattern.chars().collect(),
+            position: 0,
+        };
+        let alternatives = parser.alternatives()?;
+        if parser.position < parser.chars.len() {
+            bail!("Unmatched ')' in {:?}", pattern);
+        }
+        let mut compiler = Compiler::default();
+        compiler.alternatives(&alternatives)?;
+        compiler.push(Inst::Match)?;
+        Ok(Regex {
+            pattern: pattern.to_string(),
+            program: compiler.program,
+        })
+    }
+
+    /// Whether the expression matches anywhere in `text`
+    pub fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        let program = &self.program;
+        let mut current = Threads::new(program.len());
+        let mut next = Threads::new(program.len());
+        for position in 0..=text.len() {
+            // A match may start at any position
+            if current.add(program, &text, 0, position) {
+                return true;
+            }
+            let c = match text.get(position) {
+                Some(&c) => c,
+                None => break,
+            };
+            for &pc in &current.pcs {
+                let step = match &program[pc] {
+                    Inst::Literal(literal) => *literal == c,
+                    Inst::Any => true,
+                    Inst::Class(class) => class.matches(c),
+                    _ => false,
+                };
+                if step && next.add(program, &text, pc + 1, position + 1) {
+                    return true;
+                }
+            }
+            current.pcs.clear();
+            std::mem::swap(&mut current, &mut next);
+        }
+        false
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+}
+
+/// Expressions are equal if they were compiled from the same pattern
+impl PartialEq for Regex {
+    fn eq(&self, other: &Regex) -> bool {
+        self.pattern == other.pattern
+    }
+}
+
+impl Eq for Regex {}
+
+impl fmt::Debug for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Regex({:?})", self.pattern)
+    }
+}
+
+/// An instruction of a compiled expression
+#[derive(Debug, Clone)]
+enum Inst {
+    Literal(char),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    /// Carry on at both
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+/// The most instructions an expression may compile to, as bounded repetitions are compiled to a
+/// copy of what they repeat for each repetition
+const MAX_PROGRAM: usize = 10_000;
+
+/// Compiles expressions to instructions
+#[derive(Default)]
+struct Compiler {
+    program: Vec<Inst>,
+}
+
+impl Compiler {
+    fn push(&mut self, inst: Inst) -> Result<usize> {
+        if self.program.len() == MAX_PROGRAM {
+            bail!("Pattern is too large");
+        }
+        self.program.push(inst);
+        Ok(self.program.len() - 1)
+    }
+
+    fn alternatives(&mut self, alternatives: &[Vec<Node>]) -> Result<()> {
+        let (last, rest) = match alternatives.split_last() {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        let mut jumps = Vec::new();
+        for sequence in rest {
+            let split = self.push(Inst::Split(0, 0))?;
+            self.sequence(sequence)?;
+            jumps.push(self.push(Inst::Jump(0))?);
+            self.program[split] = Inst::Split(split + 1, self.program.len());
+        }
+        self.sequence(last)?;
+        let end = self.program.len();
+        for jump in jumps {
+            self.program[jump] = Inst::Jump(end);
+        }
+        Ok(())
+    }
+
+    fn sequence(&mut self, nodes: &[Node]) -> Result<()> {
+        nodes.iter().try_for_each(|node| self.node(node))
+    }
+
+    fn node(&mut self, node: &Node) -> Result<()> {
+        match node {
+            Node::Literal(c) => self.push(Inst::Literal(*c)).map(drop),
+            Node::Any => self.push(Inst::Any).map(drop),
+            Node::Class(class) => self.push(Inst::Class(class.clone())).map(drop),
+            Node::Start => self.push(Inst::Start).map(drop),
+            Node::End => self.push(Inst::End).map(drop),
+            Node::Group(alternatives) => self.alternatives(alternatives),
+            Node::Repeat { node, min, max } => {
+                for _ in 0..*min {
+                    self.node(node)?;
+                }
+                match max {
+                    None => {
+                        let split = self.push(Inst::Split(0, 0))?;
+                        self.node(node)?;
+                        self.push(Inst::Jump(split))?;
+                        self.program[split] = Inst::Split(split + 1, self.program.len());
+                    }
+                    Some(max) => {
+                        // Once one of the optional repetitions is skipped, so are the rest
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            splits.push(self.push(Inst::Split(0, 0))?);
+                            self.node(node)?;
+                        }
+                        let end = self.program.len();
+                        for split in splits {
+                            self.program[split] = Inst::Split(split + 1, end);
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The threads of the machine at one position of the text: the instructions they're at, each
+/// once, as a thread that reaches an instruction another has reached would do the same from there
+struct Threads {
+    pcs: Vec<usize>,
+    /// The position each instruction was last reached at, plus one
+    seen: Vec<usize>,
+}
+
+impl Threads {
+    fn new(size: usize) -> Threads {
+        Threads {
+            pcs: Vec::new(),
+            seen: vec![0; size],
+        }
+    }
+
+    /// Add a thread at `pc` for `position` of `text`, following its jumps, splits and anchors.
+    /// Returns whether it reaches the end of the expression.
+    fn add(&mut self, program: &[Inst], text: &[char], pc: usize, position: usize) -> bool {
+        let mut stack = vec![pc];
+        while let Some(pc) = stack.pop() {
+            if self.seen[pc] == position + 1 {
+                continue;
+            }
+            self.seen[pc] = position + 1;
+            match &program[pc] {
+                Inst::Jump(to) => stack.push(*to),
+                Inst::Split(first, second) => {
+                    stack.push(*second);
+                    stack.push(*first);
+                }
+                Inst::Start if position == 0 => stack.push(pc + 1),
+                Inst::End if position == text.len() => stack.push(pc + 1),
+                Inst::Start | Inst::End => {}
+                Inst::Match => return true,
+                Inst::Literal(_) | Inst::Any | Inst::Class(_) => self.pcs.push(pc),
+            }
+        }
+        false
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += 1;
+        Some(c)
+    }
+
+    /// Sequences separated by `|`, up to the end or a closing `)`
+    fn alternatives(&mut self) -> Result<Vec<Vec<Node>>> {
+        let mut alternatives = vec![self.sequence()?];
+        while self.peek() == Some('|') {
+            self.position += 1;
+            alternatives.push(self.sequence()?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let node = self.atom()?;
+            nodes.push(self.quantified(node)?);
+        }
+        Ok(nodes)
+    }
+
+    fn atom(&mut self) -> Result<Node> {
+        let c = match self.next() {
+            Some(c) => c,
+            None => bail!("Unexpected end of pattern"),
+        };
+        Ok(match c {
+            '(' => {
+                // Groups don't capture, so `(?:` is the same as `(`
+                if self.chars[self.position..].starts_with(&['?', ':']) {
+                    self.position += 2;
+                }
+                let alternatives = self.alternatives()?;
+                if self.next() != Some(')') {
+                    bail!("Missing ')'");
+                }
+                Node::Group(alternatives)
+            }
+            '[' => Node::Class(self.class()?),
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '*' | '+' | '?' => bail!("Nothing to repeat before {:?}", c),
+            '\\' => match self.next() {
+                Some(escaped) => match escape_class(escaped) {
+                    Some(class) => Node::Class(class),
+                    None => Node::Literal(escape_char(escaped)),
+                },
+                None => bail!("Pattern ends with '\\'"),
+            },
+            c => Node::Literal(c),
+        })
+    }
+
+    fn quantified(&mut self, node: Node) -> Result<Node> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => match self.bounds() {
+                Some(bounds) => bounds,
+                // A `{` that doesn't start a quantifier stands for itself
+                None => return Ok(node),
+            },
+            _ => return Ok(node),
+        };
+        // Past the quantifier, or the closing `}` of its bounds
+        self.position += 1;
+        if max.is_some_and(|max| max < min) {
+            bail!("Invalid repetition {{{},{}}}", min, max.unwrap());
+        }
+        Ok(Node::Repeat {
+            node: Box::new(node),
+            min,
+            max,
+        })
+    }
+
+    /// Parse `{n}`, `{n,}` or `{n,m}`, leaving the position at its closing `}`
+    fn bounds(&mut self) -> Option<(usize, Option<usize>)> {
+        let rest: String = self.chars[self.position + 1..].iter().collect();
+        let end = rest.find('}')?;
+        let (min, max) = match rest[..end].split_once(',') {
+            Some((min, "")) => (min.parse().ok()?, None),
+            Some((min, max)) => (min.parse().ok()?, Some(max.parse().ok()?)),
+            None => {
+                let count = rest[..end].parse().ok()?;
+                (count, Some(count))
+            }
+        };
+        self.position += end + 1;
+        Some((min, max))
+    }
+
+    fn class(&mut self) -> Result<Class> {
+        let mut class = Class::default();
+        if self.peek() == Some('^') {
+            class.negated = true;
+            self.position += 1;
+        }
+        let mut first = true;
+        loop {
+            let c = match self.next() {
+                Some(c) => c,
+                None => bail!("Missing ']'"),
+            };
+            let start = match c {
+                ']' if !first => return Ok(class),
+                '\\' => match self.next() {
+                    Some(escaped) => match escape_class(escaped) {
+                        Some(escaped) if !escaped.negated => {
+                            class.ranges.extend(escaped.ranges);
+                            first = false;
+                            continue;
+                        }
+                        Some(_) => bail!("\\{} isn't supported in a character class", escaped),
+                        None => escape_char(escaped),
+                    },
+                    None => bail!("Missing ']'"),
+                },
+                c => c,
+            };
+            first = false;
+
+            let is_range = self.peek() == Some('-')
+                && self.chars.get(self.position + 1).is_some_and(|&c| c != ']');
+            if is_range {
+                self.position += 1;
+                let end = match self.next() {
+                    Some('\\') => match self.next() {
+                        Some(escaped) => escape_char(escaped),
+                        None => bail!("Missing ']'"),
+                    },
+                    Some(end) => end,
+                    None => bail!("Missing ']'"),
+                };
+                if end < start {
+                    bail!("Invalid range {}-{}", start, end);
+                }
+                class.ranges.push((start, end));
+            } else {
+                class.ranges.push((start, start));
+            }
+        }
+    }
+}
+
+/// The class an escape such as `\d` stands for, if it stands for one
+fn escape_class(c: char) -> Option<Class> {
+    let (ranges, negated) = match c {
+        'd' => (DIGIT, false),
+        'D' => (DIGIT, true),
+        'w' => (WORD, false),
+        'W' => (WORD, true),
+        's' => (SPACE, false),
+        'S' => (SPACE, true),
+        _ => return None,
+    };
+    Some(Class {
+        negated,
+        ranges: ranges.to_vec(),
+    })
+}
+
+fn escape_char(c: char) -> char {
+    match c {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        c => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Regex::new(pattern).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn alternation() {
+        assert!(matches("cat|dog", "hotdog"));
+        assert!(matches("^(error|warn)ing:", "warning: low"));
+        assert!(!matches("^(error|warn)ing:", "a warning:"));
+        assert!(matches("a(|b)c", "ac"));
+        assert!(!matches("x(a|b)y", "xcy"));
+    }
+
+    #[test]
+    fn repetition() {
+        assert!(matches("^a*$", ""));
+        assert!(matches("^ab+c$", "abbbc"));
+        assert!(!matches("^ab+c$", "ac"));
+        assert!(matches("^colou?r$", "color"));
+        assert!(matches("^\\d{2,3}$", "123"));
+        assert!(!matches("^\\d{2,3}$", "1234"));
+        assert!(matches("^x{2,}$", "xxxxx"));
+        assert!(!matches("^x{2,}$", "x"));
+        assert!(matches("^(a*)*b$", "aab"));
+        assert!(matches("^(a?){3}$", "aa"));
+    }
+
+    #[test]
+    fn classes_and_anchors() {
+        assert!(matches("[0-9]+ms$", "took 12ms"));
+        assert!(!matches("^[^#]", "# comment"));
+        assert!(matches("\\w+\\s=", "x_1 = 2"));
+        assert!(matches("a.c", "abc"));
+        assert!(!matches("a.c", "ac"));
+    }
+
+    #[test]
+    fn long_line() {
+        let line = format!("{}b", "a".repeat(100_000));
+        assert!(matches("a.*b", &line));
+        assert!(!matches("a.*c", &line));
+        assert!(!matches("(a|a)*c", &"a".repeat(10_000)));
+        assert!(!matches("(a|a)*b", &"a".repeat(25)));
+    }
+
+    #[test]
+    fn too_large() {
+        assert!(Regex::new("((a{100}){100}){100}").is_err());
+    }
+}
@@ -0,0 +1,194 @@
+//! An interactive bridge between the host terminal and the device's friendly REPL
+use anyhow::{bail, Result};
+use std::io::{self, ErrorKind, Write};
+use std::time::Duration;
+
+use crate::device::Device;
+use crate::lineedit::LineEditor;
+use crate::logfile;
+use crate::magic::Magic;
+use crate::serial::eval_at_prompt;
+use crate::terminal::{read_stdin, BracketedPaste, RawTerminal};
+
+/// Ctrl-]: The key that exits the REPL bridge, everything else is sent to the device
+pub const EXIT_KEY: u8 = 0x1d;
+
+/// How long the device gets to list names for Tab
+const COMPLETION_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Key presses come in a few at a time, so there's no need for a large buffer
+const INPUT_BUFFER_SIZE: usize = 256;
+
+/// What the terminal sends around pasted text once bracketed paste is on
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+/// Input from the terminal, with pasted text told apart from keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Input {
+    Keys(Vec<u8>),
+    Pasted(Vec<u8>),
+}
+
+/// Finds pasted text in what's read from the terminal, where a marker or the text can be split
+/// across reads
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct PasteInput {
+    /// The end of the last read, which could be the start of a marker
+    pending: Vec<u8>,
+    /// The text pasted so far, while in a paste
+    pasted: Option<Vec<u8>>,
+}
+
+impl PasteInput {
+    fn read(&mut self, bytes: &[u8]) -> Vec<Input> {
+        let mut bytes = [std::mem::take(&mut self.pending).as_slice(), bytes].concat();
+        let mut inputs = Vec::new();
+        loop {
+            let marker = match self.pasted {
+                Some(_) => PASTE_END,
+                None => PASTE_START,
+            };
+            let (text, found) = match bytes.windows(marker.len()).position(|w| w == marker) {
+                Some(start) => (bytes[..start].to_vec(), Some(start + marker.len())),
+                None => {
+                    let kept = (1..marker.len())
+                        .rev()
+                        .find(|&length| bytes.ends_with(&marker[..length]))
+                        .unwrap_or(0);
+                    self.pending = bytes.split_off(bytes.len() - kept);
+                    (bytes.clone(), None)
+                }
+            };
+            match &mut self.pasted {
+                Some(pasted) => pasted.extend_from_slice(&text),
+                None if !text.is_empty() => inputs.push(Input::Keys(text)),
+                None => {}
+            }
+            match found {
+                Some(end) => {
+                    match self.pasted.take() {
+                        Some(pasted) => inputs.push(Input::Pasted(pasted)),
+                        None => self.pasted = Some(Vec::new()),
+                    }
+                    bytes.drain(..end);
+                }
+                None => return inputs,
+            }
+        }
+    }
+}
+
+/// Whether pasted text is a block of more than one line, ignoring the line break at its end
+pub fn is_block(text: &[u8]) -> bool {
+    let end = text
+        .iter()
+        .rposition(|&byte| byte != b'\r' && byte != b'\n')
+        .map_or(0, |i| i + 1);
+    text[..end]
+        .iter()
+        .any(|&byte| byte == b'\r' || byte == b'\n')
+}
+
+/// A block sent through the device's paste mode, which takes it as it is instead of indenting each
+/// line after the one before, and runs it once it's all there
+pub fn paste_mode(block: &[u8]) -> Vec<u8> {
+    let text = String::from_utf8_lossy(block).replace("\r\n", "\n");
+    let mut input = vec![0x05];
+    input.extend(
+        text.trim_end_matches(['\r', '\n'])
+            .bytes()
+            .map(|byte| match byte {
+                b'\n' => b'\r',
+                byte => byte,
+            }),
+    );
+    input.extend_from_slice(b"\r\x04");
+    input
+}
+
+/// Bridge the terminal to the device's REPL until the exit key is pressed. The device is expected
+/// to already be in the friendly REPL. With an editor, lines are edited on the host and sent when
+/// Enter is pressed, otherwise every key is sent as it's typed.
+pub fn repl(device: &mut Device, mut editor: Option<LineEditor>) -> Result<()> {
+    let _terminal = RawTerminal::enable()?;
+    let _paste = BracketedPaste::enable()?;
+    let mut stdout = io::stdout();
+    let mut input = [0; INPUT_BUFFER_SIZE];
+    let mut output = vec![0; device.buffer_size()];
+    let mut paste = PasteInput::default();
+
+    // What the device printed right after the last script is shown ahead of the rest
+    show(&device.take_unread(), editor.as_mut(), &mut stdout)?;
+
+    'bridge: loop {
+        let n = read_stdin(&mut input, 10)?;
+        if n == 0 {
+            if let Some(editor) = &mut editor {
+                device.port().write_all(&editor.idle(&mut stdout)?)?;
+            }
+        }
+        for input in paste.read(&input[..n]) {
+            match (input, &mut editor) {
+                (Input::Keys(keys), editor) => {
+                    let exit = keys.iter().position(|&key| key == EXIT_KEY);
+                    let typed = &keys[..exit.unwrap_or(keys.len())];
+                    match editor {
+                        Some(editor) => {
+                            let mut eval =
+                                |code: &str| eval_at_prompt(device, code, Some(COMPLETION_TIMEOUT));
+                            let send = editor.keys(typed, &mut stdout, &mut eval)?;
+                            device.port().write_all(&send)?;
+                            while let Some(command) = editor.take_command() {
+                                stdout.write_all(b"\r\n")?;
+                                if let Err(e) = Magic::parse(&command)
+                                    .and_then(|magic| magic.run(device, &mut stdout))
+                                {
+                                    writeln!(stdout, "Error: {:#}", e)?;
+                                }
+                                editor.command_done(&mut stdout)?;
+                            }
+                        }
+                        None => device.port().write_all(typed)?,
+                    }
+                    if exit.is_some() {
+                        break 'bridge;
+                    }
+                }
+                (Input::Pasted(text), Some(editor)) => device
+                    .port()
+                    .write_all(&editor.paste(&text, &mut stdout)?)?,
+                (Input::Pasted(text), None) if is_block(&text) => {
+                    device.port().write_all(&paste_mode(&text))?
+                }
+                (Input::Pasted(text), None) => device.port().write_all(&text)?,
+            }
+        }
+
+        match device.port().read(&mut output) {
+            Ok(0) => bail!("Unable to read"),
+            Ok(n) => show(&output[..n], editor.as_mut(), &mut stdout)?,
+            Err(ref e) if e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => bail!(e),
+        }
+    }
+
+    println!();
+    Ok(())
+}
+
+/// Show what the device printed, through the editor if there is one
+fn show(output: &[u8], editor: Option<&mut LineEditor>, stdout: &mut io::Stdout) -> Result<()> {
+    if output.is_empty() {
+        return Ok(());
+    }
+    logfile::write(output);
+    match editor {
+        Some(editor) => editor.received(output, stdout)?,
+        None => {
+            stdout.write_all(output)?;
+            stdout.flush()?;
+        }
+    }
+    Ok(())
+}
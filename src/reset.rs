@@ -0,0 +1,90 @@
+//! Resetting boards by toggling the DTR and RTS control lines, as used by ESP32 dev boards where
+//! the lines drive the EN (reset) and IO0 (boot mode) pins
+use anyhow::{bail, Result};
+use serialport::SerialPort;
+use std::fmt;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// A single step of a reset sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetStep {
+    /// Set the DTR line, `true` asserts it
+    Dtr(bool),
+    /// Set the RTS line, `true` asserts it
+    Rts(bool),
+    /// Wait before the next step
+    Wait(Duration),
+}
+
+/// A sequence of control line changes applied right after opening the port
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResetStrategy {
+    pub steps: Vec<ResetStep>,
+}
+
+impl ResetStrategy {
+    /// Parse a named strategy or a custom sequence.
+    ///
+    /// The named strategies are `none`, `esp32` (reset into the application) and
+    /// `esp32-bootloader` (reset into download mode). A custom sequence uses the same format as
+    /// esptool's custom reset sequences, steps separated by `|`: `D1`/`D0` and `R1`/`R0` set DTR
+    /// and RTS, `W0.1` waits for a number of seconds. For example `R1|W0.1|R0`.
+    pub fn parse(value: &str) -> Result<ResetStrategy> {
+        let sequence = match value {
+            "none" => "",
+            "esp32" => "D0|R1|W0.1|R0",
+            "esp32-bootloader" => "D0|R1|W0.1|D1|R0|W0.05|D0",
+            sequence => sequence,
+        };
+
+        let mut steps = Vec::new();
+        for step in sequence.split('|').filter(|step| !step.is_empty()) {
+            let mut chars = step.chars();
+            let step = match (chars.next(), chars.as_str()) {
+                (Some('D'), "1") => ResetStep::Dtr(true),
+                (Some('D'), "0") => ResetStep::Dtr(false),
+                (Some('R'), "1") => ResetStep::Rts(true),
+                (Some('R'), "0") => ResetStep::Rts(false),
+                (Some('W'), secs) => match secs.parse::<f64>().map(Duration::try_from_secs_f64) {
+                    Ok(Ok(duration)) => ResetStep::Wait(duration),
+                    _ => bail!("Invalid wait {:?} in reset sequence", step),
+                },
+                _ => bail!("Invalid step {:?} in reset sequence {:?}", step, value),
+            };
+            steps.push(step);
+        }
+
+        Ok(ResetStrategy { steps })
+    }
+
+    /// Apply the sequence to an open port
+    pub fn apply(&self, port: &mut dyn SerialPort) -> Result<()> {
+        for step in self.steps.iter() {
+            match *step {
+                ResetStep::Dtr(level) => port.write_data_terminal_ready(level)?,
+                ResetStep::Rts(level) => port.write_request_to_send(level)?,
+                ResetStep::Wait(duration) => sleep(duration),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ResetStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.steps.is_empty() {
+            return write!(f, "none");
+        }
+        let steps: Vec<String> = self
+            .steps
+            .iter()
+            .map(|step| match step {
+                ResetStep::Dtr(level) => format!("D{}", *level as u8),
+                ResetStep::Rts(level) => format!("R{}", *level as u8),
+                ResetStep::Wait(duration) => format!("W{}", duration.as_secs_f64()),
+            })
+            .collect();
+        write!(f, "{}", steps.join("|"))
+    }
+}
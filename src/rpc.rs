@@ -0,0 +1,314 @@
+//! A JSON-RPC 2.0 server for embedding serpico in editors and IDEs. Messages are exchanged one
+//! per line. While a script executes, its output is sent as `output` notifications carrying the
+//! id of the `exec` request, ahead of the response.
+//!
+//! Methods:
+//! - `discover`: the MicroPython devices found, with their `path`, `serial_number` and `product`
+//! - `exec`: run `script` on `device`, with an optional `timeout` in seconds and `soft_reset`
+//! - `call`: call `function` on `device` with `args`, a JSON array or object, and an optional
+//!   `timeout`, returning its JSON result as `value` and what it printed as `output`
+//! - `put`: copy the local file `local` to `remote` on `device`
+//! - `mkdir`, `remove`: create a directory or remove a file at `path` on `device`
+//! - `shutdown`: stop the server
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::call;
+use crate::device::Device;
+use crate::fs::{self, Transfer};
+use crate::json::{self, Value};
+use crate::output::Stream;
+use crate::serial::{discover_micropython_devices, execute_streaming, Disconnected, ExecOptions};
+
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const SERVER_ERROR: i32 = -32000;
+
+/// An error to respond to a request with
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        RpcError {
+            code: SERVER_ERROR,
+            message: e.to_string(),
+        }
+    }
+}
+
+struct Server<W: Write, F: FnMut(&Path) -> Result<Device>> {
+    output: W,
+    open: F,
+    /// Devices are opened on first use and kept open
+    devices: HashMap<PathBuf, Device>,
+}
+
+/// Serve requests read from `input` until it ends or `shutdown` is requested, opening devices
+/// with `open`
+pub fn serve(
+    input: impl BufRead,
+    output: impl Write,
+    open: impl FnMut(&Path) -> Result<Device>,
+) -> Result<()> {
+    let mut server = Server {
+        output,
+        open,
+        devices: HashMap::new(),
+    };
+
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = match json::parse(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                server.respond(Value::Null, Err(rpc_error(PARSE_ERROR, e)))?;
+                continue;
+            }
+        };
+        let id = request.get("id").cloned();
+        let method = match request.get("method").and_then(Value::as_str) {
+            Some(method) => method.to_string(),
+            None => {
+                let error = rpc_error(INVALID_REQUEST, "Missing method");
+                server.respond(id.unwrap_or(Value::Null), Err(error))?;
+                continue;
+            }
+        };
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+        let result = server.call(&method, &params, id.clone().unwrap_or(Value::Null));
+        // Requests without an id are notifications, which get no response
+        if let Some(id) = id {
+            server.respond(id, result)?;
+        }
+        if method == "shutdown" {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn rpc_error(code: i32, message: impl ToString) -> RpcError {
+    RpcError {
+        code,
+        message: message.to_string(),
+    }
+}
+
+impl<W: Write, F: FnMut(&Path) -> Result<Device>> Server<W, F> {
+    fn send(&mut self, message: Value) -> Result<()> {
+        writeln!(self.output, "{}", message)?;
+        self.output.flush()?;
+        Ok(())
+    }
+
+    fn respond(&mut self, id: Value, result: Result<Value, RpcError>) -> Result<()> {
+        let outcome = match result {
+            Ok(result) => ("result", result),
+            Err(error) => (
+                "error",
+                Value::object([
+                    ("code", Value::Number(f64::from(error.code))),
+                    ("message", error.message.into()),
+                ]),
+            ),
+        };
+        self.send(Value::object([
+            ("jsonrpc", "2.0".into()),
+            ("id", id),
+            outcome,
+        ]))
+    }
+
+    fn call(&mut self, method: &str, params: &Value, id: Value) -> Result<Value, RpcError> {
+        match method {
+            "discover" => Ok(discover()?),
+            "exec" => self.exec(params, id),
+            "call" => {
+                let function = string_param(params, "function")?;
+                let args = params.get("args").cloned().unwrap_or(Value::Null);
+                let timeout = match params.get("timeout").and_then(Value::as_f64) {
+                    Some(timeout) => match Duration::try_from_secs_f64(timeout) {
+                        Ok(timeout) => Some(timeout),
+                        Err(_) => return Err(rpc_error(SERVER_ERROR, "Invalid timeout")),
+                    },
+                    None => None,
+                };
+                let returned = self.with_device(params, |device| {
+                    call::call(device, &function, &args, timeout)
+                })?;
+                Ok(Value::object([
+                    ("value", returned.value),
+                    (
+                        "output",
+                        String::from_utf8_lossy(&returned.printed)
+                            .to_string()
+                            .into(),
+                    ),
+                ]))
+            }
+            "put" => {
+                let local = PathBuf::from(string_param(params, "local")?);
+                let remote = string_param(params, "remote")?;
+                let data = self.with_device(params, |device| {
+                    let transfer = Transfer::negotiate(device, true, None)?;
+                    fs::put(device, &local, &remote, &transfer, None)
+                })?;
+                Ok(Value::object([("size", Value::Number(data.len() as f64))]))
+            }
+            "mkdir" => {
+                let path = string_param(params, "path")?;
+                self.with_device(params, |device| fs::make_dirs(device, &path, None))?;
+                Ok(Value::Null)
+            }
+            "remove" => {
+                let path = string_param(params, "path")?;
+                self.with_device(params, |device| fs::remove(device, &path, None))?;
+                Ok(Value::Null)
+            }
+            "shutdown" => Ok(Value::Null),
+            _ => Err(rpc_error(
+                METHOD_NOT_FOUND,
+                format!("Unknown method {:?}", method),
+            )),
+        }
+    }
+
+    fn exec(&mut self, params: &Value, id: Value) -> Result<Value, RpcError> {
+        let script = string_param(params, "script")?;
+        let mut options = ExecOptions {
+            echo: false,
+            ..ExecOptions::default()
+        };
+        if let Some(timeout) = params.get("timeout").and_then(Value::as_f64) {
+            match Duration::try_from_secs_f64(timeout) {
+                Ok(timeout) => options.timeout = Some(timeout),
+                Err(_) => return Err(rpc_error(SERVER_ERROR, "Invalid timeout")),
+            }
+        }
+        if let Some(soft_reset) = params.get("soft_reset").and_then(Value::as_bool) {
+            options.soft_reset = soft_reset;
+        }
+
+        let path = PathBuf::from(string_param(params, "device")?);
+        let mut device = self.take_device(&path)?;
+        let mut pending = Vec::new();
+        let output = &mut self.output;
+        let result = execute_streaming(&mut device, script, &options, &mut |stream, bytes| {
+            pending.extend_from_slice(bytes);
+            let text = take_utf8(&mut pending);
+            if text.is_empty() {
+                return Ok(());
+            }
+            let stream = match stream {
+                Stream::Stdout => "stdout",
+                Stream::Stderr => "stderr",
+            };
+            let notification = Value::object([
+                ("jsonrpc", "2.0".into()),
+                ("method", "output".into()),
+                (
+                    "params",
+                    Value::object([
+                        ("id", id.clone()),
+                        ("stream", stream.into()),
+                        ("data", text.into()),
+                    ]),
+                ),
+            ]);
+            writeln!(output, "{}", notification)?;
+            output.flush()?;
+            Ok(())
+        });
+        let result = self.return_device(path, device, result)?;
+
+        Ok(Value::object([
+            (
+                "stdout",
+                String::from_utf8_lossy(&result.stdout).to_string().into(),
+            ),
+            (
+                "stderr",
+                String::from_utf8_lossy(&result.stderr).to_string().into(),
+            ),
+            ("exception", result.exception().into()),
+            ("exit_code", Value::Number(f64::from(result.exit_code()))),
+        ]))
+    }
+
+    fn with_device<T>(
+        &mut self,
+        params: &Value,
+        f: impl FnOnce(&mut Device) -> Result<T>,
+    ) -> Result<T, RpcError> {
+        let path = PathBuf::from(string_param(params, "device")?);
+        let mut device = self.take_device(&path)?;
+        let result = f(&mut device);
+        Ok(self.return_device(path, device, result)?)
+    }
+
+    fn take_device(&mut self, path: &Path) -> Result<Device> {
+        match self.devices.remove(path) {
+            Some(device) => Ok(device),
+            None => (self.open)(path),
+        }
+    }
+
+    /// Keep the device for the next request, unless it disconnected
+    fn return_device<T>(&mut self, path: PathBuf, device: Device, result: Result<T>) -> Result<T> {
+        match &result {
+            Err(e) if e.is::<Disconnected>() => {}
+            _ => {
+                self.devices.insert(path, device);
+            }
+        }
+        result
+    }
+}
+
+fn string_param(params: &Value, name: &str) -> Result<String, RpcError> {
+    match params.get(name).and_then(Value::as_str) {
+        Some(value) => Ok(value.to_string()),
+        None => Err(rpc_error(
+            INVALID_REQUEST,
+            format!("Missing string parameter {:?}", name),
+        )),
+    }
+}
+
+fn discover() -> Result<Value> {
+    let devices = discover_micropython_devices()?
+        .into_iter()
+        .map(|info| {
+            Value::object([
+                ("path", info.path.display().to_string().into()),
+                ("serial_number", info.serial_number.into()),
+                ("product", info.product.into()),
+            ])
+        })
+        .collect();
+    Ok(Value::Array(devices))
+}
+
+/// Take the valid UTF-8 from the start of `pending`, leaving behind an incomplete character at
+/// the end for the next chunk of output. Invalid bytes are replaced.
+fn take_utf8(pending: &mut Vec<u8>) -> String {
+    let complete = match std::str::from_utf8(pending) {
+        Ok(_) => pending.len(),
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => pending.len(),
+    };
+    let text = String::from_utf8_lossy(&pending[..complete]).to_string();
+    pending.drain(..complete);
+    text
+}
@@ -0,0 +1,104 @@
+//! Setting the device's clock, from NTP where the board is on a network and otherwise from the
+//! host's clock over the connection
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::output::utc_offset;
+use crate::script::quote;
+
+/// The NTP server used unless another is given
+pub const DEFAULT_SERVER: &str = "pool.ntp.org";
+
+/// Prints the device's time as `YYYY-MM-DD HH:MM:SS`
+pub const READ: &str = "import time
+print('%04d-%02d-%02d %02d:%02d:%02d' % time.localtime()[:6])
+";
+
+/// Code that sets the clock to UTC from the NTP server at `server`, printing `ok` if it did and
+/// why not otherwise
+pub fn ntp_script(server: &str) -> String {
+    format!(
+        "try:
+    import network, ntptime
+    if not network.WLAN(network.STA_IF).isconnected():
+        raise OSError('the board is not on a network')
+    ntptime.host = {}
+    ntptime.settime()
+    print('ok')
+except Exception as e:
+    print(repr(e))
+",
+        quote(server)
+    )
+}
+
+/// Code that sets the clock to `time`, in the host's timezone unless `utc` is set
+pub fn set_script(time: SystemTime, utc: bool) -> String {
+    let mut seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    if !utc {
+        seconds += utc_offset(seconds);
+    }
+    let days = seconds.div_euclid(86_400);
+    let (year, month, day) = civil_from_days(days);
+    let of_day = seconds.rem_euclid(86_400);
+    // The RTC counts weekdays from Monday, and the epoch was a Thursday. The subseconds are left
+    // at zero as their unit differs between ports.
+    format!(
+        "import machine
+machine.RTC().datetime(({}, {}, {}, {}, {}, {}, {}, 0))
+",
+        year,
+        month,
+        day,
+        (days + 3).rem_euclid(7),
+        of_day / 3600,
+        of_day % 3600 / 60,
+        of_day % 60
+    )
+}
+
+/// The year, month and day of the civil date `days` after 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    // Counted in eras of 400 years from 0000-03-01, so that the leap day ends each year
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let of_era = days.rem_euclid(146_097);
+    let year_of_era = (of_era - of_era / 1460 + of_era / 36_524 - of_era / 146_096) / 365;
+    let of_year = of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * of_year + 2) / 153;
+    let day = of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn datetime(seconds: u64) -> String {
+        let script = set_script(UNIX_EPOCH + Duration::from_secs(seconds), true);
+        let start = script.find("((").unwrap() + 2;
+        let end = script.find("))").unwrap();
+        script[start..end].to_string()
+    }
+
+    #[test]
+    fn utc() {
+        assert_eq!(datetime(0), "1970, 1, 1, 3, 0, 0, 0, 0");
+        assert_eq!(datetime(1_700_000_000), "2023, 11, 14, 1, 22, 13, 20, 0");
+    }
+
+    #[test]
+    fn leap_days() {
+        assert_eq!(datetime(951_782_400), "2000, 2, 29, 1, 0, 0, 0, 0");
+        assert_eq!(datetime(4_107_542_399), "2100, 2, 28, 6, 23, 59, 59, 0");
+    }
+}
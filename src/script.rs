@@ -0,0 +1,123 @@
+//! Generating Python code that is sent to the device alongside the user's script
+use std::fmt;
+
+use crate::checksum::Algorithm;
+
+/// Quote `value` as a Python string literal
+pub fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('\'');
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '\'' => quoted.push_str("\\'"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 || c as u32 == 0x7f => {
+                quoted.push_str(&format!("\\x{:02x}", c as u32))
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// A Python literal for a value given on the command line: integers and floats are kept as
+/// numbers, anything else becomes a string
+pub fn literal(value: &str) -> String {
+    if value.parse::<i64>().is_ok() || value.parse::<f64>().is_ok_and(f64::is_finite) {
+        value.to_string()
+    } else {
+        quote(value)
+    }
+}
+
+/// Quote `data` as a Python bytes literal
+pub fn quote_bytes(data: &[u8]) -> String {
+    let mut quoted = String::with_capacity(data.len() + 3);
+    quoted.push_str("b'");
+    for &byte in data {
+        match byte {
+            b'\\' => quoted.push_str("\\\\"),
+            b'\'' => quoted.push_str("\\'"),
+            0x20..=0x7e => quoted.push(char::from(byte)),
+            _ => quoted.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// A Python list literal of the given strings
+pub fn quote_list(values: &[String]) -> String {
+    let items: Vec<String> = values.iter().map(|value| quote(value)).collect();
+    format!("[{}]", items.join(", "))
+}
+
+/// A single line of Python that sets `sys.argv` to `argv`, without leaving `sys` imported
+pub fn argv_prelude(argv: &[String]) -> String {
+    format!(
+        "import sys as _serpico_sys; _serpico_sys.argv.clear(); \
+         _serpico_sys.argv.extend({}); del _serpico_sys\n",
+        quote_list(argv)
+    )
+}
+
+/// A single line of Python that defines a global `config` dict holding the given variables
+pub fn config_prelude(vars: &[(String, String)]) -> String {
+    let items: Vec<String> = vars
+        .iter()
+        .map(|(key, value)| format!("{}: {}", quote(key), literal(value)))
+        .collect();
+    format!("config = {{{}}}\n", items.join(", "))
+}
+
+/// What the script wrapped by [`verified`] raises when it didn't arrive intact
+pub const CORRUPTED: &str = "serpico: the script arrived corrupted";
+
+/// `source` as a literal, checked against its checksum on the device before it's run with
+/// `exec`, so that bytes lost or mangled on the way fail with [`CORRUPTED`] rather than with a
+/// mystery syntax error. SHA-256 is used where the device has `hashlib`, CRC-32 elsewhere. The
+/// device holds the source in memory as well as compiling it, and its tracebacks are in
+/// `<string>`.
+pub fn verified(source: &str) -> String {
+    format!(
+        "\
+_serpico_source = {}
+def _serpico_intact(source):
+    try:
+        from hashlib import sha256
+        from binascii import hexlify
+        return hexlify(sha256(source).digest()) == b'{}'
+    except ImportError:
+        from binascii import crc32
+        return crc32(source) & 0xffffffff == 0x{}
+if not _serpico_intact(_serpico_source):
+    raise RuntimeError({})
+del _serpico_intact
+exec(globals().pop('_serpico_source'), globals())
+",
+        quote_bytes(source.as_bytes()),
+        Algorithm::Sha256.digest(source.as_bytes()),
+        Algorithm::Crc32.digest(source.as_bytes()),
+        quote(CORRUPTED)
+    )
+}
+
+/// The script wrapped by [`verified`] didn't arrive on the device as it was sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadCorrupted;
+
+impl fmt::Display for UploadCorrupted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The script arrived on the device corrupted, so it wasn't run. The link may be noisy, \
+             or the baud rate too high for it."
+        )
+    }
+}
+
+impl std::error::Error for UploadCorrupted {}
@@ -0,0 +1,63 @@
+//! Mounting an SD card wired to an SPI bus, so files can be copied onto it
+use anyhow::{bail, Result};
+use std::time::Duration;
+
+use crate::device::Device;
+use crate::script::quote;
+use crate::serial::eval;
+
+/// Where the card is mounted unless configured otherwise
+pub const DEFAULT_MOUNT_POINT: &str = "/sd";
+
+/// An SD card on SPI bus `spi`, with its chip select on pin `cs`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SdCard {
+    pub spi: u32,
+    pub cs: u32,
+    pub mount_point: String,
+}
+
+impl SdCard {
+    /// Code that mounts the card unless it's mounted already, printing whether it mounted it. The
+    /// sdcard driver from micropython-lib is used, or the ESP32's built-in one without it.
+    fn mount_script(&self) -> String {
+        format!(
+            "import machine, os
+_mount = {}
+if _mount[1:] in os.listdir('/') and os.statvfs(_mount) != os.statvfs('/'):
+    print(0)
+else:
+    try:
+        import sdcard
+        _sd = sdcard.SDCard(machine.SPI({}), machine.Pin({}))
+    except ImportError:
+        if not hasattr(machine, 'SDCard'):
+            raise OSError('no sdcard module, install it with mip')
+        # The slots of the ESP32's driver are numbered from SPI(1) as 2
+        _sd = machine.SDCard(slot={}, cs=machine.Pin({}))
+    os.mount(_sd, _mount)
+    print(1)
+",
+            quote(&self.mount_point),
+            self.spi,
+            self.cs,
+            self.spi + 1,
+            self.cs
+        )
+    }
+
+    /// Mount the card unless it's mounted already, returning whether it was mounted now. It stays
+    /// mounted until the device resets.
+    pub fn mount(&self, device: &mut Device, timeout: Option<Duration>) -> Result<bool> {
+        let name = self.mount_point.strip_prefix('/').unwrap_or_default();
+        if name.is_empty() || name.contains('/') {
+            bail!(
+                "The SD card can only be mounted in the root, such as at {}, not at {}",
+                DEFAULT_MOUNT_POINT,
+                self.mount_point
+            );
+        }
+        let output = eval(device, &self.mount_script(), timeout)?;
+        Ok(String::from_utf8_lossy(&output).trim() == "1")
+    }
+}
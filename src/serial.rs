@@ -1,176 +1,2638 @@
 use anyhow::{bail, Result};
 use serialport::{SerialPort, SerialPortType};
-use std::char;
-use std::cmp::min;
-use std::collections::VecDeque;
-use std::io::ErrorKind;
-use std::path::PathBuf;
-use std::thread::sleep;
-use std::time::Duration;
+use std::cmp::{max, min};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Cursor, ErrorKind, Read};
+use std::ops::{AddAssign, Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
 
-const BUFFER_SIZE: usize = 16;
+use crate::alert::Alerts;
+use crate::chatter::Chatter;
+use crate::circuitpython::{self, StatusBar};
+use crate::device::Device;
+use crate::encoding::Decoding;
+use crate::exit;
+use crate::feed::{self, Feed};
+use crate::interact::Interaction;
+use crate::interrupt;
+use crate::logfile;
+use crate::matcher::Matcher;
+use crate::normalize::Normalizer;
+use crate::output::{Echo, EchoThread, LineFilter, Stream, Timestamps};
+use crate::progress::Progress;
+use crate::repl::EXIT_KEY;
+use crate::sink::{OutputSink, SharedSink, Terminal};
+use crate::terminal::{read_stdin, RawTerminal};
+use crate::traceback::{parse_frames, Frame, SourceMap};
+use crate::window::Window;
 
-pub fn find_micropython_devices() -> Result<Vec<PathBuf>> {
+/// The longest a read blocks before checking for Ctrl-C and deadlines
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often a port that has been silent is checked for still being there. A pulled cable doesn't
+/// always fail the reads, which would then wait for output that never comes.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the device has to be quiet after being interrupted for its output to be drained
+const DRAIN_QUIET_TIME: Duration = Duration::from_millis(10);
+
+/// How long a device gets to go quiet after being interrupted before it's interrupted again, and
+/// how long it's kept at before carrying on regardless
+const INTERRUPT_PATIENCE: Duration = Duration::from_millis(200);
+const INTERRUPT_LIMIT: Duration = Duration::from_secs(3);
+
+/// The longest the device's output is drained for after leaving a failed session
+const LEAVE_DRAIN_LIMIT: Duration = Duration::from_millis(500);
+
+/// A stage of getting the device ready to run a script, and how patient to be with it. Boards
+/// differ a lot in how long they take, an ESP32 with a large filesystem can take many seconds to
+/// soft reboot where a Pico takes a fraction of one.
+struct Stage {
+    /// What is being waited for, for the error if it never comes
+    name: &'static str,
+    /// How long to wait before nudging the device, doubling every time it runs out
+    patience: Duration,
+    /// How long to wait in total before giving up, unless the execution's timeout is longer
+    limit: Duration,
+    /// Sent to the device again when patience runs out, if it's safe to repeat
+    nudge: Option<&'static [u8]>,
+}
+
+const RAW_REPL_BANNER: Stage = Stage {
+    name: "raw REPL banner",
+    patience: Duration::from_millis(500),
+    limit: Duration::from_secs(5),
+    nudge: Some(b"\r\x01"),
+};
+
+/// The banner comes as soon as the reboot starts, so a board that hasn't printed it after a few
+/// seconds is taken not to print it, and the raw REPL banner is waited for instead
+const SOFT_REBOOT_BANNER: Stage = Stage {
+    name: "soft reboot banner",
+    patience: Duration::from_secs(1),
+    limit: Duration::from_secs(3),
+    nudge: None,
+};
+
+const RAW_REPL_BANNER_AFTER_REBOOT: Stage = Stage {
+    name: "raw REPL banner after the soft reboot",
+    patience: Duration::from_secs(1),
+    limit: Duration::from_secs(30),
+    nudge: None,
+};
+
+const FRIENDLY_PROMPT_AFTER_REBOOT: Stage = Stage {
+    name: "friendly REPL prompt after the soft reboot",
+    patience: Duration::from_secs(1),
+    limit: Duration::from_secs(30),
+    nudge: None,
+};
+
+/// `stage` of waiting for a soft reboot, given up on after `limit` where the board is known to
+/// reboot quicker than most
+fn reboot_stage(stage: &Stage, limit: Option<Duration>) -> Stage {
+    Stage {
+        limit: limit.unwrap_or(stage.limit),
+        ..*stage
+    }
+}
+
+const FRIENDLY_PROMPT: Stage = Stage {
+    name: "friendly REPL prompt",
+    patience: Duration::from_millis(500),
+    limit: Duration::from_secs(5),
+    nudge: None,
+};
+
+const PASTE_MODE_PROMPT: Stage = Stage {
+    name: "paste mode prompt",
+    patience: Duration::from_millis(500),
+    limit: Duration::from_secs(5),
+    nudge: None,
+};
+
+const PASTE_ECHO: Stage = Stage {
+    name: "device to echo the script",
+    patience: Duration::from_millis(500),
+    limit: Duration::from_secs(5),
+    nudge: None,
+};
+
+const RAW_REPL_PROMPT: Stage = Stage {
+    name: "raw REPL prompt",
+    patience: Duration::from_millis(500),
+    limit: Duration::from_secs(5),
+    nudge: Some(b"\r\x01"),
+};
+
+const RAW_PASTE_RESPONSE: Stage = Stage {
+    name: "raw-paste response",
+    patience: Duration::from_millis(500),
+    limit: Duration::from_secs(5),
+    nudge: None,
+};
+
+const RAW_REPL_OK: Stage = Stage {
+    name: "raw REPL to accept the script",
+    patience: Duration::from_millis(500),
+    limit: Duration::from_secs(5),
+    nudge: None,
+};
+
+/// What serpico runs in the raw REPL itself to learn about the device or set it up
+const HELPER_OUTPUT: Stage = Stage {
+    name: "helper script to finish",
+    patience: Duration::from_millis(500),
+    limit: Duration::from_secs(5),
+    nudge: None,
+};
+
+/// The device compiles the whole script before starting it, which takes a while for large ones
+const SCRIPT_START: Stage = Stage {
+    name: "script to start",
+    patience: Duration::from_secs(1),
+    limit: Duration::from_secs(60),
+    nudge: None,
+};
+
+impl Stage {
+    fn limit(&self, timeout: Option<Duration>) -> Duration {
+        timeout.map_or(self.limit, |timeout| max(timeout, self.limit))
+    }
+
+    fn timed_out(&self, limit: Duration) -> anyhow::Error {
+        StageTimeout {
+            stage: self.name,
+            limit,
+        }
+        .into()
+    }
+}
+
+/// The device didn't get through a stage of running a script in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StageTimeout {
+    /// What was being waited for
+    pub stage: &'static str,
+    pub limit: Duration,
+}
+
+impl StageTimeout {
+    /// Whether the device never got into the raw REPL, so it didn't respond to the REPL at all
+    pub fn unresponsive(&self) -> bool {
+        [
+            RAW_REPL_BANNER.name,
+            RAW_REPL_PROMPT.name,
+            PASTE_MODE_PROMPT.name,
+        ]
+        .contains(&self.stage)
+    }
+
+    /// Whether the device didn't enter the raw REPL, which firmware without one doesn't
+    pub fn raw_repl(&self) -> bool {
+        self.stage == RAW_REPL_BANNER.name
+    }
+
+    /// Whether the device didn't answer the request for raw-paste mode
+    pub fn raw_paste(&self) -> bool {
+        self.stage == RAW_PASTE_RESPONSE.name
+    }
+}
+
+impl fmt::Display for StageTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Timed out waiting for {} after {}s",
+            self.stage,
+            self.limit.as_secs_f64()
+        )
+    }
+}
+
+impl std::error::Error for StageTimeout {}
+
+/// The device went wrong in raw-paste mode, which some firmware doesn't get right
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawPasteFailed {
+    pub reason: String,
+}
+
+impl fmt::Display for RawPasteFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl std::error::Error for RawPasteFailed {}
+
+/// The device didn't send anything for longer than the timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadTimeout;
+
+impl fmt::Display for ReadTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Timed out waiting for the device to send anything")
+    }
+}
+
+impl std::error::Error for ReadTimeout {}
+
+/// The script was interrupted for running longer than it was allowed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxRuntimeExceeded {
+    pub max_runtime: Duration,
+}
+
+impl fmt::Display for MaxRuntimeExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Script was interrupted after exceeding the maximum runtime of {}s",
+            self.max_runtime.as_secs_f64()
+        )
+    }
+}
+
+impl std::error::Error for MaxRuntimeExceeded {}
+
+/// Ctrl-C was pressed while the device was being talked to, without the script being told of it.
+/// The device has been returned to the friendly REPL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// The device printed a boot banner while the script was running, so it crashed or was reset by
+/// a watchdog, and isn't in the raw REPL anymore
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rebooted;
+
+impl fmt::Display for Rebooted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Device rebooted during execution")
+    }
+}
+
+impl std::error::Error for Rebooted {}
+
+/// Output that only shows up when a device boots: the friendly REPL's banner, the reset reason
+/// the ROM of Espressif chips prints, such as `rst:0x1 (POWERON_RESET),boot:0x13`, and what
+/// CircuitPython prints before running `code.py`
+const REBOOT_MARKERS: &[&[u8]] = &[
+    b"Type \"help()\" for more information.",
+    b"),boot:0x",
+    b"Auto-reload is ",
+];
+
+/// The port of a device that has been put in the raw REPL or paste mode. Unless the session is
+/// finished, the device is taken out of it again when this is dropped, aborting whatever it's
+/// doing in the raw REPL, raw-paste mode or paste mode, so that a session that fails along the
+/// way doesn't leave it where a terminal seems dead.
+struct RawSession<'a> {
+    port: &'a mut dyn SerialPort,
+    /// Set instead of draining the port here, when the reader reads it on a thread that drains it
+    /// once it's stopped
+    drain: Option<Arc<AtomicBool>>,
+    finished: bool,
+}
+
+impl<'a> RawSession<'a> {
+    fn new(port: &'a mut dyn SerialPort, reader: &Reader) -> Self {
+        RawSession {
+            port,
+            drain: reader.drain_flag(),
+            finished: false,
+        }
+    }
+
+    /// The session went as it should, leave the device in the raw REPL
+    fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl<'a> Deref for RawSession<'a> {
+    type Target = dyn SerialPort + 'a;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.port
+    }
+}
+
+impl<'a> DerefMut for RawSession<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut *self.port
+    }
+}
+
+impl Drop for RawSession<'_> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        // Ctrl-C ends raw-paste mode and any script that's running, Ctrl-B leaves the raw REPL.
+        // The session has failed already, so the device is left be if it doesn't take this.
+        let port = &mut *self.port;
+        if port.write_all("\x03\x03\x02".as_bytes()).is_err() || port.flush().is_err() {
+            return;
+        }
+        match &self.drain {
+            Some(flag) => flag.store(true, Ordering::SeqCst),
+            None => {
+                let _ = drain(port, &mut [0; 256], LEAVE_DRAIN_LIMIT);
+            }
+        }
+    }
+}
+
+/// How much of the script is sent at a time in the standard raw REPL, and how long to pause in
+/// between for the device to keep up
+const RAW_CHUNK_SIZE: usize = 256;
+const RAW_CHUNK_PAUSE: Duration = Duration::from_millis(10);
+
+/// A MicroPython device discovered on one of the USB serial ports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// The port of the board's REPL
+    pub path: PathBuf,
+    pub serial_number: Option<String>,
+    pub product: Option<String>,
+    /// The board's second port, for boards built with a second USB-CDC interface for data, which
+    /// enumerates with the same descriptors as the REPL's
+    pub data: Option<PathBuf>,
+}
+
+/// Which of a board's ports to connect to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Channel {
+    #[default]
+    Repl,
+    /// The second USB-CDC interface of a board that has one
+    Data,
+}
+
+impl Channel {
+    pub fn parse(value: &str) -> Result<Channel> {
+        match value {
+            "repl" => Ok(Channel::Repl),
+            "data" => Ok(Channel::Data),
+            _ => bail!("Unknown channel {:?}, use repl or data", value),
+        }
+    }
+}
+
+/// No device was given and no MicroPython device was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoDevice {
+    /// Whether only devices matching the configuration were looked for
+    pub configured: bool,
+}
+
+impl fmt::Display for NoDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.configured {
+            write!(f, "No MicroPython device matching serpico.toml found")
+        } else {
+            write!(f, "No MicroPython devices found")
+        }
+    }
+}
+
+impl std::error::Error for NoDevice {}
+
+/// No device was given and more than one MicroPython device was found, so it's unclear which
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MultipleDevices {
+    /// Whether only devices matching the configuration were looked for
+    pub configured: bool,
+}
+
+impl fmt::Display for MultipleDevices {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.configured {
+            write!(
+                f,
+                "Multiple MicroPython devices match serpico.toml, please specify with the device \
+                 option"
+            )
+        } else {
+            write!(
+                f,
+                "Multiple MicroPython devices found, please specify with the device option"
+            )
+        }
+    }
+}
+
+impl std::error::Error for MultipleDevices {}
+
+/// A change in the set of connected MicroPython devices
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    Connected(DeviceInfo),
+    Disconnected(DeviceInfo),
+}
+
+pub fn discover_micropython_devices() -> Result<Vec<DeviceInfo>> {
+    usb_serial_ports(true)
+}
+
+/// Every USB serial port, whatever its manufacturer, as candidates for boards whose USB
+/// descriptors don't tell they run MicroPython
+pub fn discover_usb_serial_ports() -> Result<Vec<DeviceInfo>> {
+    usb_serial_ports(false)
+}
+
+fn usb_serial_ports(only_micropython: bool) -> Result<Vec<DeviceInfo>> {
     let ports = serialport::available_ports()?;
-    let mut micropython_ports: Vec<PathBuf> = Vec::new();
+    let mut devices: Vec<DeviceInfo> = Vec::new();
     for p in ports {
         if p.port_name.starts_with("/dev/cu.") {
             // Skip /dev/cu.X devices in MacOS
             continue;
         }
-        if let SerialPortType::UsbPort(info) = p.port_type {
-            if let Some(manufacturer) = info.manufacturer {
-                if manufacturer == "MicroPython" {
-                    micropython_ports.push(PathBuf::from(p.port_name));
+        if let SerialPortType::UsbPort(info) = p.port_type {
+            if only_micropython && info.manufacturer.as_deref() != Some("MicroPython") {
+                continue;
+            }
+            let path = PathBuf::from(p.port_name);
+            // A port with the serial number and product of another is a second interface of the
+            // same board, and the REPL is on the first
+            let board = devices.iter_mut().find(|board| {
+                board.data.is_none()
+                    && board.serial_number.is_some()
+                    && board.serial_number == info.serial_number
+                    && board.product == info.product
+            });
+            if let Some(board) = board {
+                if (interface_number(&path), &path) < (interface_number(&board.path), &board.path) {
+                    board.data = Some(std::mem::replace(&mut board.path, path));
+                } else {
+                    board.data = Some(path);
+                }
+                continue;
+            }
+            devices.push(DeviceInfo {
+                path,
+                serial_number: info.serial_number,
+                product: info.product,
+                data: None,
+            });
+        }
+    }
+
+    Ok(devices)
+}
+
+/// The number of the USB interface the serial port at `path` belongs to, where the platform tells
+#[cfg(target_os = "linux")]
+fn interface_number(path: &Path) -> Option<u8> {
+    let path = path.canonicalize().ok()?;
+    let name = path.file_name()?.to_str()?;
+    let number =
+        std::fs::read_to_string(format!("/sys/class/tty/{}/device/bInterfaceNumber", name));
+    u8::from_str_radix(number.ok()?.trim(), 16).ok()
+}
+
+/// Elsewhere the ports' names are in the order of their interfaces
+#[cfg(not(target_os = "linux"))]
+fn interface_number(_path: &Path) -> Option<u8> {
+    None
+}
+
+pub fn find_micropython_devices() -> Result<Vec<PathBuf>> {
+    Ok(discover_micropython_devices()?
+        .into_iter()
+        .map(|device| device.path)
+        .collect())
+}
+
+/// Poll for MicroPython devices every `interval`, calling `on_event` whenever a device is connected
+/// or disconnected. Devices already connected when watching starts are reported as connected.
+///
+/// Runs until `on_event` returns an error, which is then returned.
+pub fn watch_devices<F>(interval: Duration, mut on_event: F) -> Result<()>
+where
+    F: FnMut(DeviceEvent) -> Result<()>,
+{
+    let mut known: Vec<DeviceInfo> = Vec::new();
+
+    loop {
+        let current = discover_micropython_devices()?;
+
+        for device in known.iter() {
+            if !current.contains(device) {
+                on_event(DeviceEvent::Disconnected(device.clone()))?;
+            }
+        }
+        for device in current.iter() {
+            if !known.contains(device) {
+                on_event(DeviceEvent::Connected(device.clone()))?;
+            }
+        }
+
+        known = current;
+        sleep(interval);
+    }
+}
+
+/// A script to execute, read a chunk at a time while uploading it so that large scripts don't
+/// have to be held in memory. Scripts are bytes, they don't have to be valid UTF-8.
+pub struct Script<'a> {
+    reader: Box<dyn Read + 'a>,
+    /// The size of the script in bytes, for the progress bar
+    size: usize,
+    /// Whether any of the script has been read, after which it can't be sent again
+    read: bool,
+}
+
+impl<'a> Script<'a> {
+    /// A script of `size` bytes read from `reader`
+    pub fn from_reader(reader: impl Read + 'a, size: usize) -> Script<'a> {
+        Script {
+            reader: Box::new(reader),
+            size,
+            read: false,
+        }
+    }
+
+    /// The script in the file at `path`, streamed from the file
+    pub fn open(path: &Path) -> Result<Script<'static>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => bail!("Couldn't open file {}: {}", path.display(), e),
+        };
+        let size = match file.metadata() {
+            Ok(metadata) => metadata.len() as usize,
+            Err(e) => bail!("Couldn't read file {}: {}", path.display(), e),
+        };
+        Ok(Script::from_reader(file, size))
+    }
+
+    /// The script with `prelude` ahead of it
+    pub fn with_prelude(self, prelude: String) -> Script<'a> {
+        let size = prelude.len() + self.size;
+        Script::from_reader(Cursor::new(prelude.into_bytes()).chain(self.reader), size)
+    }
+
+    /// The script with its line endings normalized and tabs in its indentation expanded to stops
+    /// `tab_width` apart, if given, as it's read. A script that isn't valid UTF-8 or that has NUL
+    /// bytes fails to upload.
+    pub fn normalize(self, tab_width: Option<usize>) -> Script<'a> {
+        let size = self.size;
+        Script::from_reader(Normalizer::new(self.reader, tab_width), size)
+    }
+
+    /// Read the next chunk of the script into `buf`, returning how much was read. Nothing is
+    /// read once the script has ended.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.read = true;
+        loop {
+            match self.reader.read(buf) {
+                Ok(count) => return Ok(count),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => bail!("Couldn't read the script: {}", e),
+            }
+        }
+    }
+}
+
+impl From<Vec<u8>> for Script<'static> {
+    fn from(script: Vec<u8>) -> Self {
+        let size = script.len();
+        Script::from_reader(Cursor::new(script), size)
+    }
+}
+
+impl From<String> for Script<'static> {
+    fn from(script: String) -> Self {
+        Script::from(script.into_bytes())
+    }
+}
+
+impl<'a> From<&'a [u8]> for Script<'a> {
+    fn from(script: &'a [u8]) -> Self {
+        Script::from_reader(script, script.len())
+    }
+}
+
+impl<'a> From<&'a str> for Script<'a> {
+    fn from(script: &'a str) -> Self {
+        Script::from(script.as_bytes())
+    }
+}
+
+/// How scripts are sent to the device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExecMode {
+    /// In the raw REPL, in raw-paste mode when the device supports it
+    #[default]
+    Raw,
+    /// In the friendly REPL's paste mode, for firmware without a raw REPL. Each line is checked
+    /// against what the device echoes. The output isn't framed, so stdout and stderr are told
+    /// apart by where a traceback starts.
+    Paste,
+    /// In the raw REPL, falling back to paste mode from then on if the device doesn't enter it
+    Auto,
+}
+
+impl ExecMode {
+    /// Parse `raw`, `paste` or `auto`
+    pub fn parse(value: &str) -> Result<ExecMode> {
+        match value {
+            "raw" => Ok(ExecMode::Raw),
+            "paste" => Ok(ExecMode::Paste),
+            "auto" => Ok(ExecMode::Auto),
+            _ => bail!("Unknown exec mode {:?}, use raw, paste or auto", value),
+        }
+    }
+}
+
+/// The firmware a device runs, which changes how scripts are run on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Firmware {
+    MicroPython,
+    /// See [`circuitpython`] for how it differs
+    CircuitPython,
+}
+
+impl Firmware {
+    /// Parse `micropython` or `circuitpython`
+    pub fn parse(value: &str) -> Result<Firmware> {
+        match value {
+            "micropython" => Ok(Firmware::MicroPython),
+            "circuitpython" => Ok(Firmware::CircuitPython),
+            _ => bail!(
+                "Unknown firmware {:?}, use micropython or circuitpython",
+                value
+            ),
+        }
+    }
+}
+
+/// Options controlling how a script is executed on the device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecOptions {
+    /// How long to wait for the script to send anything, waits forever if not set. The handshake
+    /// stages have limits of their own, which a longer timeout extends.
+    pub timeout: Option<Duration>,
+    /// Soft reboot the device before executing. Without it the script runs against the state left
+    /// behind by whatever was running on the device.
+    pub soft_reset: bool,
+    /// Return as soon as the device has started the script, without waiting for its output
+    pub detach: bool,
+    /// Forward Ctrl-C on the host to the device while the script runs. Otherwise Ctrl-C ends the
+    /// session with an [`Interrupted`] error, after returning the device to the friendly REPL.
+    pub forward_interrupt: bool,
+    /// Interrupt the script if it's still running after this long
+    pub max_runtime: Option<Duration>,
+    /// Echo the script's output as it runs, it is collected in the result either way. Only what
+    /// the script prints is echoed, none of the handshake before it nor the framing of its output.
+    pub echo: bool,
+    /// Add the script's output to the log file, if one was installed with [`logfile::install`]
+    pub log: bool,
+    /// Prefix each line of output with a timestamp
+    pub timestamps: Option<Timestamps>,
+    /// Color the stderr output, highlighting tracebacks
+    pub color: bool,
+    /// Rewrite traceback frames in the stderr output to point at local files
+    pub source_map: Option<SourceMap>,
+    /// Which lines of stdout to echo
+    pub filter: LineFilter,
+    /// Echo stdout as a hexdump
+    pub hexdump: bool,
+    /// Put the host terminal into raw mode while the script runs, sending key presses to the
+    /// script. The REPL's exit key interrupts it.
+    pub terminal: bool,
+    /// Respond to the script's output with input, as described by the interaction
+    pub interaction: Option<Interaction>,
+    /// Stream a binary payload to the script, which reads it with the helper of
+    /// [`feed::HELPER`]
+    pub feed: Option<feed::Source>,
+    /// Show a progress bar on stderr while uploading the script
+    pub progress: bool,
+    /// Where the echoed output is written
+    pub sink: SharedSink,
+    /// How the echoed output is decoded
+    pub decoding: Decoding,
+}
+
+impl Default for ExecOptions {
+    fn default() -> Self {
+        ExecOptions {
+            timeout: None,
+            soft_reset: true,
+            detach: false,
+            forward_interrupt: false,
+            max_runtime: None,
+            echo: true,
+            log: true,
+            timestamps: None,
+            color: false,
+            source_map: None,
+            filter: LineFilter::default(),
+            hexdump: false,
+            terminal: false,
+            interaction: None,
+            feed: None,
+            progress: false,
+            sink: SharedSink::default(),
+            decoding: Decoding::default(),
+        }
+    }
+}
+
+/// How long each stage of executing a script took
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings {
+    /// Interrupting whatever was running and draining what it printed
+    pub interrupt: Duration,
+    /// Entering the raw REPL
+    pub raw_repl: Duration,
+    /// Soft rebooting the device, zero if it wasn't rebooted
+    pub soft_reboot: Duration,
+    /// Asking for raw-paste mode and its window size
+    pub negotiation: Duration,
+    /// Sending the script, until the device acknowledged it
+    pub upload: Duration,
+    /// Running the script, until it had printed all of its stdout
+    pub execution: Duration,
+    /// Reading the rest of the output, the traceback if there was one
+    pub output: Duration,
+}
+
+impl Timings {
+    /// Everything before the script is sent: interrupting, entering the raw REPL and rebooting
+    pub fn handshake(&self) -> Duration {
+        self.interrupt + self.raw_repl + self.soft_reboot
+    }
+
+    pub fn total(&self) -> Duration {
+        self.handshake() + self.negotiation + self.upload + self.execution + self.output
+    }
+}
+
+impl AddAssign for Timings {
+    fn add_assign(&mut self, other: Timings) {
+        self.interrupt += other.interrupt;
+        self.raw_repl += other.raw_repl;
+        self.soft_reboot += other.soft_reboot;
+        self.negotiation += other.negotiation;
+        self.upload += other.upload;
+        self.execution += other.execution;
+        self.output += other.output;
+    }
+}
+
+impl fmt::Display for Timings {
+    /// A row for each stage
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stages = [
+            ("Interrupt and drain", self.interrupt),
+            ("Raw REPL", self.raw_repl),
+            ("Soft reboot", self.soft_reboot),
+            ("Raw-paste negotiation", self.negotiation),
+            ("Upload", self.upload),
+            ("Execution", self.execution),
+            ("Output read", self.output),
+        ];
+        for (name, duration) in stages {
+            writeln!(f, "{:<22}{:>9.3}s", name, duration.as_secs_f64())?;
+        }
+        write!(f, "{:<22}{:>9.3}s", "Total", self.total().as_secs_f64())
+    }
+}
+
+/// Flow control statistics of the raw-paste upload, for debugging slow or stalling uploads
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlowStats {
+    /// How many bytes the device grants with each window refill
+    pub window_size: usize,
+    /// How many times the device refilled the window
+    pub refills: usize,
+    /// How many times the upload ran out of window and had to wait for a refill
+    pub stalls: usize,
+    /// Total time spent waiting for refills
+    pub stall_time: Duration,
+    /// The largest chunk written in one go
+    pub largest_chunk: usize,
+}
+
+impl fmt::Display for FlowStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "window {} bytes, {} refills, {} stalls waiting {:.3}s, largest chunk {} bytes",
+            self.window_size,
+            self.refills,
+            self.stalls,
+            self.stall_time.as_secs_f64(),
+            self.largest_chunk
+        )
+    }
+}
+
+/// The outcome of executing a script on the device
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecResult {
+    /// Everything the script printed, including any 0x04 bytes
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub timings: Timings,
+    pub flow: FlowStats,
+}
+
+impl ExecResult {
+    /// The uncaught exception the script ended with, such as `ValueError: bad value`, taken from
+    /// the last line of the traceback on stderr
+    pub fn exception(&self) -> Option<String> {
+        String::from_utf8_lossy(&self.stderr)
+            .lines()
+            .rev()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .map(String::from)
+    }
+
+    /// Whether the script was stopped by a `KeyboardInterrupt`
+    pub fn interrupted(&self) -> bool {
+        self.exception()
+            .is_some_and(|exception| exception.starts_with("KeyboardInterrupt"))
+    }
+
+    /// How the script failed, if it ended with an uncaught exception other than `SystemExit`
+    pub fn error(&self) -> Option<ExecError> {
+        let exception = self.exception()?;
+        if exception.starts_with("SystemExit") {
+            return None;
+        }
+        let traceback = String::from_utf8_lossy(&self.stderr).to_string();
+        let (name, msg) = match exception.split_once(':') {
+            Some((name, msg)) => (name, msg.trim()),
+            None => (exception.as_str(), ""),
+        };
+        // A script that doesn't compile has a traceback of just the line the compiler stopped at,
+        // which unlike the frames of a running script isn't in any function
+        if matches!(name, "SyntaxError" | "IndentationError" | "TabError") {
+            if let Some(Frame {
+                file,
+                line,
+                function: None,
+            }) = parse_frames(&traceback).pop()
+            {
+                return Some(ExecError::Syntax {
+                    file,
+                    line,
+                    msg: msg.to_string(),
+                });
+            }
+        }
+        Some(ExecError::Runtime { traceback })
+    }
+
+    /// The exit code the script would have in CPython: 0 on success, the code of a `SystemExit`,
+    /// 130 when interrupted, or 1 for any other uncaught exception. A script that doesn't compile
+    /// exits with 2 instead, to tell it from one that failed while running.
+    pub fn exit_code(&self) -> i32 {
+        if self.interrupted() {
+            return exit::CANCELLED;
+        }
+        if let Some(ExecError::Syntax { .. }) = self.error() {
+            return exit::SYNTAX_ERROR;
+        }
+        match self.exception() {
+            None => 0,
+            Some(exception) => match exception.strip_prefix("SystemExit") {
+                Some("") => 0,
+                Some(code) => code
+                    .trim_start_matches(':')
+                    .trim()
+                    .parse()
+                    .unwrap_or(exit::SCRIPT_FAILED),
+                None => exit::SCRIPT_FAILED,
+            },
+        }
+    }
+}
+
+/// How a script failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecError {
+    /// The script didn't compile, so none of it ran
+    Syntax {
+        /// The file as named by the device, `<stdin>` for the script itself
+        file: String,
+        line: usize,
+        msg: String,
+    },
+    /// The script raised an exception while running
+    Runtime { traceback: String },
+}
+
+/// Receives a running script's output as it arrives, along with the stream it was printed to
+pub type OutputCallback<'a> = &'a mut dyn FnMut(Stream, &[u8]) -> Result<()>;
+
+/// How long the device has to pause in output held after a 0x04 for it to be taken for the
+/// script's own. The stderr frame and the end of it are sent all at once after the script ends.
+const HOLD_QUIET_TIME: Duration = Duration::from_millis(250);
+
+/// Output after a 0x04 that may be the stderr frame, or the script's own output if it printed the
+/// 0x04 itself
+struct Held {
+    bytes: Vec<u8>,
+    last_arrived: Instant,
+    /// Whether the device paused in it, after which it was passed on as the script's output
+    released: bool,
+}
+
+impl Held {
+    fn new() -> Held {
+        Held {
+            bytes: Vec::new(),
+            last_arrived: Instant::now(),
+            released: false,
+        }
+    }
+}
+
+/// The state of reading a running script's output
+struct OutputStage<'a> {
+    echo: Option<EchoThread>,
+    log: bool,
+    stream: Stream,
+    on_output: Option<OutputCallback<'a>>,
+    /// When to interrupt the script, cleared once it has been interrupted
+    deadline: Option<Instant>,
+    interaction: Option<Interaction>,
+    feed: Option<Feed>,
+    /// Output collected instead of handled while it's unknown which stream it belongs to
+    held: Option<Held>,
+    /// Raw mode for the terminal, while key presses are sent to the script
+    terminal: Option<RawTerminal>,
+    /// Whether Ctrl-C interrupts the script, rather than ending the session
+    forward_interrupt: bool,
+    /// Look for a boot banner, which may be split across reads
+    reboot_markers: Vec<Matcher>,
+}
+
+impl<'a> OutputStage<'a> {
+    fn new(options: &ExecOptions, on_output: Option<OutputCallback<'a>>) -> Result<Self> {
+        Ok(OutputStage {
+            log: options.log,
+            echo: options.echo.then(|| {
+                let echo = Echo::new(
+                    options.timestamps,
+                    options.color,
+                    options.source_map.clone(),
+                )
+                .filter(options.filter.clone())
+                .hexdump(options.hexdump)
+                .sink(options.sink.clone())
+                .decoding(options.decoding);
+                EchoThread::spawn(echo)
+            }),
+            stream: Stream::Stdout,
+            on_output,
+            deadline: options
+                .max_runtime
+                .map(|max_runtime| Instant::now() + max_runtime),
+            interaction: options.interaction.clone(),
+            feed: options.feed.as_ref().map(Feed::open).transpose()?,
+            held: None,
+            terminal: if options.terminal {
+                Some(RawTerminal::enable_passthrough()?)
+            } else {
+                None
+            },
+            forward_interrupt: options.forward_interrupt,
+            reboot_markers: REBOOT_MARKERS
+                .iter()
+                .map(|marker| Matcher::new(marker))
+                .collect(),
+        })
+    }
+
+    /// Fail a script that finished having run out of time, or before the interaction was done
+    fn check(&self, options: &ExecOptions) -> Result<()> {
+        if let (Some(max_runtime), None) = (options.max_runtime, self.deadline) {
+            bail!(MaxRuntimeExceeded { max_runtime });
+        }
+        if let Some(expected) = self.interaction.as_ref().and_then(Interaction::expecting) {
+            bail!("Script finished while still expecting {:?}", expected);
+        }
+        Ok(())
+    }
+
+    /// Handle output from the script, sending any input the interaction responds with. Held output
+    /// is passed on along with the 0x04 ahead of it once the device pauses in it, or reboots.
+    fn output(&mut self, port: &mut dyn SerialPort, bytes: &[u8]) -> Result<()> {
+        let mut rebooted = false;
+        for marker in self.reboot_markers.iter_mut() {
+            rebooted |= marker.feed(bytes).is_some();
+        }
+        let released;
+        let bytes = match self.held.as_mut() {
+            Some(held) if !held.released => {
+                if !bytes.is_empty() {
+                    held.bytes.extend_from_slice(bytes);
+                    held.last_arrived = Instant::now();
+                }
+                let paused = held.last_arrived.elapsed() >= HOLD_QUIET_TIME;
+                if held.bytes.is_empty() || !(paused || rebooted) {
+                    return Ok(());
+                }
+                released = [&[0x04], &held.bytes[..]].concat();
+                held.bytes.clear();
+                held.released = true;
+                &released[..]
+            }
+            _ => bytes,
+        };
+        let filtered;
+        let bytes = match self.feed.as_mut() {
+            Some(feed) => {
+                filtered = feed.filter(bytes);
+                feed.send(port)?;
+                &filtered[..]
+            }
+            None => bytes,
+        };
+        if self.log {
+            logfile::write(bytes);
+        }
+        if let Some(echo) = self.echo.as_mut() {
+            echo.write(bytes)?;
+        }
+        // What the device printed as it went down is echoed, as it tells why
+        if rebooted {
+            bail!(Rebooted);
+        }
+        if let Some(on_output) = self.on_output.as_mut() {
+            if !bytes.is_empty() {
+                on_output(self.stream, bytes)?;
+            }
+        }
+        if let Some(interaction) = self.interaction.as_mut() {
+            let input = interaction.feed(bytes);
+            if !input.is_empty() {
+                port.write_all(&input)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the keys pressed since the last check to the script, interrupting it on the exit key
+    fn forward_keys(&mut self, port: &mut dyn SerialPort) -> Result<()> {
+        if self.terminal.is_none() {
+            return Ok(());
+        }
+        let mut keys = [0; 256];
+        let n = read_stdin(&mut keys, 0)?;
+        for &key in &keys[..n] {
+            port.write_all(&[if key == EXIT_KEY { 0x03 } else { key }])?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads from the port in bulk, holding on to anything read past what was asked for until the
+/// next read. The port is either read in between writes, or by a thread of its own that the reader
+/// takes what it read from.
+struct Reader {
+    pending: Vec<u8>,
+    /// Strips CircuitPython's status bar from everything read
+    status_bar: Option<StatusBar>,
+    /// For reading the port in between writes
+    buf: Vec<u8>,
+    thread: Option<ReadThread>,
+}
+
+/// How many chunks the reader thread reads ahead of the protocol before it waits for them to be
+/// taken, leaving the rest of what the device sends to the port's buffers and flow control
+const READ_AHEAD: usize = 32;
+
+/// How long the reader thread's reads block before it checks whether it has been stopped
+const READ_THREAD_POLL: Duration = Duration::from_millis(10);
+
+/// A thread that reads the port for a [`Reader`] until it's dropped
+struct ReadThread {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    stop: Arc<AtomicBool>,
+    /// Set when a [`RawSession`] has been left, for the thread to drain what the device answers
+    /// once it's stopped
+    drain: Arc<AtomicBool>,
+}
+
+impl Drop for ReadThread {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Reader {
+    fn new(buffer_size: usize) -> Reader {
+        Reader {
+            pending: Vec::new(),
+            status_bar: None,
+            buf: vec![0; buffer_size],
+            thread: None,
+        }
+    }
+
+    /// A reader of `port` on a thread of `scope`, so that the device's output is taken in while
+    /// the protocol is busy writing, such as an abort the device sends in the middle of a chunk of
+    /// the script. Everything else goes through another handle of the port.
+    fn spawn<'scope>(
+        scope: &'scope thread::Scope<'scope, '_>,
+        port: &'scope mut dyn SerialPort,
+        buffer_size: usize,
+    ) -> Reader {
+        let (sender, chunks) = mpsc::sync_channel(READ_AHEAD);
+        let stop = Arc::new(AtomicBool::new(false));
+        let leave = Arc::new(AtomicBool::new(false));
+        let (stopped, left) = (stop.clone(), leave.clone());
+        scope.spawn(move || {
+            let port_timeout = port.timeout();
+            if let Err(e) = port.set_timeout(READ_THREAD_POLL) {
+                let _ = sender.send(Err(e.into()));
+                return;
+            }
+            let mut buf = vec![0; buffer_size];
+            while !stopped.load(Ordering::SeqCst) {
+                let chunk = match port.read(&mut buf) {
+                    Ok(n) => Ok(buf[..n].to_vec()),
+                    Err(ref e)
+                        if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) =>
+                    {
+                        continue
+                    }
+                    Err(e) => Err(e),
+                };
+                // Once the port has failed or been closed, there's nothing more to read
+                let ended = !matches!(&chunk, Ok(bytes) if !bytes.is_empty());
+                if sender.send(chunk).is_err() || ended {
+                    break;
+                }
+            }
+            if left.load(Ordering::SeqCst) {
+                let _ = drain(port, &mut buf, LEAVE_DRAIN_LIMIT);
+            }
+            let _ = port.set_timeout(port_timeout);
+        });
+        Reader {
+            thread: Some(ReadThread {
+                chunks,
+                stop,
+                drain: leave,
+            }),
+            ..Reader::new(buffer_size)
+        }
+    }
+
+    /// Add what was read from the port to `read`
+    fn received(&mut self, bytes: &[u8], read: &mut Vec<u8>) {
+        match self.status_bar.as_mut() {
+            Some(status_bar) => status_bar.strip(bytes, read),
+            None => read.extend_from_slice(bytes),
+        }
+    }
+
+    /// Wait up to `wait` for more of what the device sends, adding it to the pending bytes.
+    /// Returns whether anything arrived.
+    fn fill(&mut self, port: &mut dyn SerialPort, wait: Duration) -> Result<bool> {
+        let wait = max(wait, Duration::from_millis(1));
+        let chunk = match &self.thread {
+            Some(thread) => match thread.chunks.recv_timeout(wait) {
+                Ok(chunk) => chunk,
+                Err(RecvTimeoutError::Timeout) => return Ok(false),
+                Err(RecvTimeoutError::Disconnected) => bail!("Unable to read"),
+            },
+            None => {
+                let port_timeout = port.timeout();
+                port.set_timeout(wait)?;
+                let read = port.read(&mut self.buf);
+                port.set_timeout(port_timeout)?;
+                read.map(|n| self.buf[..n].to_vec())
+            }
+        };
+        self.take(chunk)
+    }
+
+    /// Add a chunk read from the port to the pending bytes, returning whether there was any of it
+    fn take(&mut self, chunk: io::Result<Vec<u8>>) -> Result<bool> {
+        match chunk {
+            Ok(bytes) if bytes.is_empty() => bail!("Unable to read"),
+            Ok(bytes) => {
+                let mut pending = std::mem::take(&mut self.pending);
+                self.received(&bytes, &mut pending);
+                self.pending = pending;
+                Ok(true)
+            }
+            Err(ref e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {
+                Ok(false)
+            }
+            Err(e) => bail!(e),
+        }
+    }
+
+    /// Read from the port until `bytes` are seen, returning everything read before them.
+    ///
+    /// While reading the script's output, the `stage` is given everything read. If its deadline
+    /// passes, the running script is interrupted with Ctrl-C and the deadline is cleared.
+    fn read_until(
+        &mut self,
+        port: &mut dyn SerialPort,
+        bytes: &[u8],
+        mut stage: Option<&mut OutputStage<'_>>,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        let mut read = std::mem::take(&mut self.pending);
+        let mut matcher = Matcher::new(bytes);
+        // Everything before `searched` is known not to start a match, so it can be passed on
+        let mut searched: usize = 0;
+        let mut passed_on: usize = 0;
+
+        let mut last_read = Instant::now();
+        let mut last_check = Instant::now();
+
+        loop {
+            // The matcher has seen what was read before, and how much of its end may start a match
+            let fed = searched + matcher.matched();
+            if let Some(position) = matcher.feed(&read[fed..]) {
+                let end = fed + position - bytes.len();
+                if let Some(stage) = stage.as_mut() {
+                    stage.output(port, &read[passed_on..end])?;
+                }
+                self.pending = read.split_off(end + bytes.len());
+                read.truncate(end);
+                return Ok(read);
+            }
+            searched = read.len() - matcher.matched();
+            if let Some(stage) = stage.as_mut() {
+                stage.output(port, &read[passed_on..searched])?;
+            }
+            passed_on = searched;
+
+            if interrupt::take() {
+                if !stage.as_ref().is_some_and(|stage| stage.forward_interrupt) {
+                    bail!(Interrupted);
+                }
+                // Ctrl-C: Interrupt the running script, which then reports a KeyboardInterrupt
+                port.write_all("\x03".as_bytes())?;
+            }
+            if let Some(stage) = stage.as_mut() {
+                stage.forward_keys(port)?;
+                if stage
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline)
+                {
+                    port.write_all("\x03".as_bytes())?;
+                    stage.deadline = None;
+                }
+            }
+
+            // Wait until something arrives or there's something else to check on
+            let now = Instant::now();
+            let mut wait = POLL_INTERVAL;
+            if let Some(timeout) = timeout {
+                wait = min(wait, (last_read + timeout).saturating_duration_since(now));
+            }
+            if let Some(deadline) = stage.as_ref().and_then(|stage| stage.deadline) {
+                wait = min(wait, deadline.saturating_duration_since(now));
+            }
+
+            if self.fill(port, wait)? {
+                read.append(&mut self.pending);
+                last_read = Instant::now();
+            } else {
+                if timeout.is_some_and(|timeout| last_read.elapsed() >= timeout) {
+                    // What was read is kept for whoever tries again
+                    self.pending = read;
+                    bail!(ReadTimeout);
+                }
+                if last_check.elapsed() >= KEEPALIVE_INTERVAL {
+                    check_alive(port)?;
+                    last_check = Instant::now();
+                }
+            }
+        }
+    }
+
+    /// Read until `bytes` are seen like [`Reader::read_until`], as a handshake `stage`. The device
+    /// is nudged every time patience runs out, until the stage's limit.
+    fn wait_for(
+        &mut self,
+        port: &mut dyn SerialPort,
+        bytes: &[u8],
+        stage: &Stage,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        let limit = stage.limit(timeout);
+        let start = Instant::now();
+        let mut patience = stage.patience;
+        loop {
+            let remaining = limit.saturating_sub(start.elapsed());
+            match self.read_until(port, bytes, None, Some(min(patience, remaining))) {
+                Err(e) if e.is::<ReadTimeout>() => {
+                    if start.elapsed() >= limit {
+                        return Err(stage.timed_out(limit));
+                    }
+                    if let Some(nudge) = stage.nudge {
+                        port.write_all(nudge)?;
+                    }
+                    patience *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Read exactly enough bytes to fill `buf`, waiting up to `timeout` for the device to send
+    /// more each time
+    fn read_exact(
+        &mut self,
+        port: &mut dyn SerialPort,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<()> {
+        let mut filled = 0;
+        let mut last_read = Instant::now();
+        loop {
+            let from_pending = min(buf.len() - filled, self.pending.len());
+            buf[filled..filled + from_pending].copy_from_slice(&self.pending[..from_pending]);
+            self.pending.drain(..from_pending);
+            filled += from_pending;
+            if filled == buf.len() {
+                return Ok(());
+            }
+
+            if interrupt::take() {
+                bail!(Interrupted);
+            }
+            let wait = min(POLL_INTERVAL, timeout.saturating_sub(last_read.elapsed()));
+            if self.fill(port, wait)? {
+                last_read = Instant::now();
+            } else if last_read.elapsed() >= timeout {
+                bail!(ReadTimeout);
+            }
+        }
+    }
+
+    /// The next byte from the port, left to be read again
+    fn peek(&mut self, port: &mut dyn SerialPort, timeout: Option<Duration>) -> Result<u8> {
+        let start = Instant::now();
+        while self.pending.is_empty() {
+            let wait = match timeout {
+                Some(timeout) => min(POLL_INTERVAL, timeout.saturating_sub(start.elapsed())),
+                None => POLL_INTERVAL,
+            };
+            if !self.fill(port, wait)? && timeout.is_some_and(|timeout| start.elapsed() >= timeout)
+            {
+                bail!(ReadTimeout);
+            }
+        }
+        Ok(self.pending[0])
+    }
+
+    /// The next byte from the port, however long it takes to arrive, unless Ctrl-C is caught
+    fn next_byte(&mut self, port: &mut dyn SerialPort) -> Result<u8> {
+        while self.pending.is_empty() {
+            if interrupt::take() {
+                bail!(Interrupted);
+            }
+            self.fill(port, POLL_INTERVAL)?;
+        }
+        Ok(self.pending.remove(0))
+    }
+
+    /// How many bytes can be read without waiting
+    fn available(&mut self, port: &mut dyn SerialPort) -> Result<usize> {
+        let Some(thread) = &self.thread else {
+            return Ok(self.pending.len() + port.bytes_to_read()? as usize);
+        };
+        let ready: Vec<_> = thread.chunks.try_iter().collect();
+        for chunk in ready {
+            self.take(chunk)?;
+        }
+        Ok(self.pending.len())
+    }
+
+    /// Read and discard what the device prints until it has been quiet for [`DRAIN_QUIET_TIME`],
+    /// returning whether it went quiet within `patience`
+    fn drain(&mut self, port: &mut dyn SerialPort, patience: Duration) -> Result<bool> {
+        let start = Instant::now();
+        loop {
+            if start.elapsed() >= patience {
+                return Ok(false);
+            }
+            let arrived = self.fill(port, DRAIN_QUIET_TIME)?;
+            self.pending.clear();
+            if !arrived {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Stop reading the port, returning what was read and not taken. A reader thread is waited on
+    /// until it has stopped, so that what it read in the meantime isn't lost either.
+    fn finish(mut self) -> Vec<u8> {
+        if let Some(thread) = self.thread.take() {
+            thread.stop.store(true, Ordering::SeqCst);
+            let chunks: Vec<_> = thread.chunks.iter().collect();
+            for chunk in chunks {
+                if self.take(chunk).is_err() {
+                    break;
                 }
             }
         }
+        self.pending
     }
 
-    Ok(micropython_ports)
+    /// Where a [`RawSession`] that's left is to ask for what the device answers to be drained, if
+    /// the port is read on a thread
+    fn drain_flag(&self) -> Option<Arc<AtomicBool>> {
+        self.thread.as_ref().map(|thread| thread.drain.clone())
+    }
 }
 
-fn read_until(
-    port: &mut dyn SerialPort,
-    bytes: &[u8],
-    echo: bool,
-    timeout: Option<usize>,
-) -> Result<()> {
-    let mut deque: VecDeque<u8> = VecDeque::from(vec![0; bytes.len()]);
-    let mut buf: Vec<u8> = vec![0; 1];
+/// The device disappeared from under an open connection, usually because it re-enumerated on USB
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disconnected {
+    /// Whether the script had already been started on the device when it disconnected
+    pub started: bool,
+}
+
+impl fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.started {
+            write!(f, "Device disconnected while the script was running")
+        } else {
+            write!(f, "Device disconnected before the script was started")
+        }
+    }
+}
 
-    let sleep_time = Duration::from_millis(10);
-    let mut timeout_count: usize = 0;
+impl std::error::Error for Disconnected {}
 
+/// Wait until a MicroPython device with the given serial number is connected
+pub fn wait_for_device(serial_number: &str, timeout: Duration) -> Result<DeviceInfo> {
+    let start = Instant::now();
     loop {
-        match port.read(&mut buf) {
-            Ok(0) => bail!("Unable to read"),
-            Ok(1) => {
-                let byte = buf[0];
-                deque.pop_front();
-                deque.push_back(byte);
-                if echo {
-                    print!("{}", char::from(byte));
-                }
+        let found = discover_micropython_devices()?
+            .into_iter()
+            .find(|device| device.serial_number.as_deref() == Some(serial_number));
+        if let Some(device) = found {
+            return Ok(device);
+        }
+        if start.elapsed() > timeout {
+            bail!(
+                "Timed out waiting for device with serial number {} to reappear",
+                serial_number
+            );
+        }
+        sleep(Duration::from_millis(100));
+    }
+}
 
-                if deque.iter().copied().collect::<Vec<u8>>() == bytes {
-                    break;
-                }
+/// Execute `script` on the device. If the device disconnects along the way, the error is a
+/// [`Disconnected`] error.
+pub fn execute<'a>(
+    device: &mut Device,
+    script: impl Into<Script<'a>>,
+    options: &ExecOptions,
+) -> Result<ExecResult> {
+    execute_with(device, script.into(), options, None)
+}
+
+/// Like [`execute`], also passing the script's output to `on_output` as it arrives
+pub fn execute_streaming<'a>(
+    device: &mut Device,
+    script: impl Into<Script<'a>>,
+    options: &ExecOptions,
+    on_output: OutputCallback<'_>,
+) -> Result<ExecResult> {
+    execute_with(device, script.into(), options, Some(on_output))
+}
+
+fn execute_with(
+    device: &mut Device,
+    script: Script<'_>,
+    options: &ExecOptions,
+    on_output: Option<OutputCallback<'_>>,
+) -> Result<ExecResult> {
+    let mut started = false;
+    let mut script = script;
+    let mut on_output = on_output;
+    let mut quirks = Quirks {
+        exec_mode: device.exec_mode(),
+        firmware: device.firmware(),
+        raw_paste: device.raw_paste(),
+        reboot_banner: device.reboot_banner().map(str::to_string),
+        reboot_limit: device.reboot_limit(),
+    };
+    let buffer_size = device.buffer_size();
+    let device_retries = device.handshake_retries();
+    // Whatever was left unread is drained ahead of the script anyway
+    device.take_unread();
+    let port = device.port();
+    let (mut result, mut unread) = execute_script(
+        port,
+        buffer_size,
+        &mut script,
+        options,
+        on_output
+            .as_mut()
+            .map(|on_output| &mut **on_output as OutputCallback<'_>),
+        &mut started,
+        &mut quirks,
+    );
+    let no_raw_repl = |e: &anyhow::Error| {
+        e.downcast_ref::<StageTimeout>()
+            .is_some_and(StageTimeout::raw_repl)
+    };
+    let mut retries = device_retries;
+    loop {
+        let retry = match &result {
+            Err(e) if quirks.exec_mode == ExecMode::Auto && no_raw_repl(e) => {
+                // Nothing of the script has been read yet, so it's sent again in paste mode
+                quirks.exec_mode = ExecMode::Paste;
+                true
             }
-            Err(ref e) if e.kind() == ErrorKind::TimedOut => {
-                if let Some(timeout) = timeout {
-                    timeout_count += 1;
-                    if timeout_count > timeout * 100 {
-                        bail!("Timed out in read_until");
-                    }
-                }
-                sleep(sleep_time);
+            // Garbage or boot output in the way of the handshake usually clears up by itself
+            Err(e) if retries > 0 && !script.read && transient(e, port) => {
+                retries -= 1;
+                true
             }
-            Err(e) => bail!(e),
-            _ => bail!("Unhandled state"),
+            _ => false,
+        };
+        if !retry {
+            break;
         }
+        (result, unread) = execute_script(
+            port,
+            buffer_size,
+            &mut script,
+            options,
+            on_output
+                .as_mut()
+                .map(|on_output| &mut **on_output as OutputCallback<'_>),
+            &mut started,
+            &mut quirks,
+        );
     }
-    Ok(())
+    let result = match result {
+        // A port that can't even report how much there is to read has gone away
+        Err(_) if port.bytes_to_read().is_err() => Err(Disconnected { started }.into()),
+        result => result,
+    };
+    device.set_exec_mode(quirks.exec_mode);
+    device.set_firmware(quirks.firmware);
+    device.set_raw_paste(quirks.raw_paste);
+    device.set_unread(unread);
+    result
+}
+
+/// Whether an execution that failed in its handshake may get through it if tried again: it wasn't
+/// interrupted on the host, and the port is still there
+fn transient(error: &anyhow::Error, port: &mut dyn SerialPort) -> bool {
+    !error.is::<Interrupted>() && port.bytes_to_read().is_ok()
 }
 
-pub fn execute(device: PathBuf, script: String, timeout: Option<usize>) -> Result<()> {
-    let device_path = match device.into_os_string().into_string() {
-        Ok(path) => path,
-        Err(e) => bail!("Unable to convert path to string: {:?}", e),
+/// How the device differs from what's expected of MicroPython, which executions learn more of
+struct Quirks {
+    exec_mode: ExecMode,
+    /// Which firmware the device runs, until that has been probed
+    firmware: Option<Firmware>,
+    /// Whether the device supports raw-paste mode, until it turns out not to
+    raw_paste: bool,
+    reboot_banner: Option<String>,
+    reboot_limit: Option<Duration>,
+}
+
+/// Run the script, with the port read on a thread of its own while the protocol writes through
+/// another handle of it. A port that can't be cloned, such as a replayed one, is read in between
+/// writes. Also returns what was read past the end of the script's output.
+fn execute_script(
+    port: &mut dyn SerialPort,
+    buffer_size: usize,
+    script: &mut Script<'_>,
+    options: &ExecOptions,
+    on_output: Option<OutputCallback<'_>>,
+    started: &mut bool,
+    quirks: &mut Quirks,
+) -> (Result<ExecResult>, Vec<u8>) {
+    let mut writer = match port.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => {
+            let mut reader = Reader::new(buffer_size);
+            let result = run_script(
+                port,
+                &mut reader,
+                script,
+                options,
+                on_output,
+                started,
+                quirks,
+            );
+            return (result, reader.finish());
+        }
     };
-    let mut port = serialport::new(device_path, 115_200)
-        .timeout(Duration::from_millis(10))
-        .open()?;
+    thread::scope(|scope| {
+        let mut reader = Reader::spawn(scope, port, buffer_size);
+        let result = run_script(
+            &mut *writer,
+            &mut reader,
+            script,
+            options,
+            on_output,
+            started,
+            quirks,
+        );
+        (result, reader.finish())
+    })
+}
 
-    let mut buf: Vec<u8> = vec![0; BUFFER_SIZE];
-    let mut byte_buf = [0; 1];
-    let mut double_buf = [0; 2];
+fn run_script(
+    port: &mut dyn SerialPort,
+    reader: &mut Reader,
+    script: &mut Script<'_>,
+    options: &ExecOptions,
+    on_output: Option<OutputCallback<'_>>,
+    started: &mut bool,
+    quirks: &mut Quirks,
+) -> Result<ExecResult> {
+    let timeout = options.timeout;
+    let mut timings = Timings::default();
+    let mut stage_start = Instant::now();
+    // Ctrl-C on the host is handled from here on, to leave the device as it was found
+    let _catch = interrupt::catch()?;
 
-    // Ctrl-C twice: Interrupt any running program
-    port.write_all("\r\x03\x03".as_bytes())?;
+    interrupt_program(port, reader)?;
+    timings.interrupt = stage_start.elapsed();
+    stage_start = Instant::now();
 
-    loop {
-        match port.read(&mut buf) {
-            Ok(_) => continue,
-            Err(ref e) if e.kind() == ErrorKind::TimedOut => break,
-            Err(e) => return Err(e.into()),
-        }
+    if quirks.exec_mode == ExecMode::Paste {
+        let mut result = execute_pasted(port, reader, script, options, on_output, started, quirks)?;
+        result.timings.interrupt = timings.interrupt;
+        return Ok(result);
     }
 
+    // From here on, failing leaves the raw REPL again
+    let mut session = RawSession::new(port, reader);
+    let port: &mut dyn SerialPort = &mut *session;
     port.write_all("\r\x01".as_bytes())?;
 
-    read_until(
-        &mut *port,
+    reader.wait_for(
+        port,
         "raw REPL; CTRL-B to exit\r\n".as_bytes(),
-        false,
+        &RAW_REPL_BANNER,
         timeout,
     )?;
+    // Whether the prompt that follows the banner has been read already
+    let mut prompted = false;
+    if quirks.firmware.is_none() {
+        reader.wait_for(port, ">".as_bytes(), &RAW_REPL_PROMPT, timeout)?;
+        let (stdout, _) = raw_eval(port, reader, circuitpython::PROBE, timeout)?;
+        quirks.firmware = Some(if circuitpython::probed(&stdout) {
+            Firmware::CircuitPython
+        } else {
+            Firmware::MicroPython
+        });
+        prompted = true;
+    }
+    let circuitpython = quirks.firmware == Some(Firmware::CircuitPython);
+    if circuitpython {
+        reader.status_bar = Some(StatusBar::new());
+    }
+    timings.raw_repl = stage_start.elapsed();
+    stage_start = Instant::now();
+
+    if options.soft_reset && circuitpython {
+        // CircuitPython runs code.py after the reboot rather than returning to the raw REPL, so
+        // that's interrupted and the raw REPL entered again
+        port.write_all("\x04".as_bytes())?;
+        if let Some(banner) = &quirks.reboot_banner {
+            match reader.wait_for(port, banner.as_bytes(), &SOFT_REBOOT_BANNER, None) {
+                Err(e) if e.is::<StageTimeout>() => {}
+                result => {
+                    result?;
+                }
+            }
+        }
+        interrupt_program(port, reader)?;
+        port.write_all("\r\x01".as_bytes())?;
+        reader.wait_for(
+            port,
+            "raw REPL; CTRL-B to exit\r\n".as_bytes(),
+            &reboot_stage(&RAW_REPL_BANNER_AFTER_REBOOT, quirks.reboot_limit),
+            timeout,
+        )?;
+        prompted = false;
+        timings.soft_reboot = stage_start.elapsed();
+        stage_start = Instant::now();
+    } else if options.soft_reset {
+        port.write_all("\x04".as_bytes())?;
+
+        // Whatever was read while waiting for a banner that never came is kept for the raw REPL
+        // banner to be found in. The execution's timeout doesn't extend this wait.
+        if let Some(banner) = &quirks.reboot_banner {
+            match reader.wait_for(port, banner.as_bytes(), &SOFT_REBOOT_BANNER, None) {
+                Err(e) if e.is::<StageTimeout>() => {}
+                result => {
+                    result?;
+                }
+            }
+        }
+        reader.wait_for(
+            port,
+            "raw REPL; CTRL-B to exit\r\n".as_bytes(),
+            &reboot_stage(&RAW_REPL_BANNER_AFTER_REBOOT, quirks.reboot_limit),
+            timeout,
+        )?;
+        prompted = false;
+        timings.soft_reboot = stage_start.elapsed();
+        stage_start = Instant::now();
+    }
+
+    if !prompted {
+        reader.wait_for(port, ">".as_bytes(), &RAW_REPL_PROMPT, timeout)?;
+    }
+    if circuitpython {
+        raw_eval(port, reader, circuitpython::DISABLE_AUTORELOAD, timeout)?;
+    }
+    timings.raw_repl += stage_start.elapsed();
+    stage_start = Instant::now();
+
+    // CircuitPython doesn't have raw-paste mode
+    let window_size = if quirks.raw_paste && !circuitpython {
+        let window_size = raw_paste_negotiate(port, reader, timeout)?;
+        // Devices that don't support raw-paste get the script the old way, now and from then on
+        quirks.raw_paste = window_size.is_some();
+        window_size
+    } else {
+        None
+    };
+    timings.negotiation = stage_start.elapsed();
+    stage_start = Instant::now();
 
+    let mut progress = options
+        .progress
+        .then(|| Progress::new("Uploading", script.size));
+    let flow = match window_size {
+        Some(window_size) => raw_paste_upload(
+            port,
+            reader,
+            script,
+            window_size,
+            progress.as_mut(),
+            timeout,
+        )?,
+        None => {
+            raw_upload(port, reader, script, progress.as_mut(), timeout)?;
+            FlowStats::default()
+        }
+    };
+    if let Some(progress) = progress.as_mut() {
+        progress.finish();
+    }
+    *started = true;
+    timings.upload = stage_start.elapsed();
+    stage_start = Instant::now();
+
+    if options.detach {
+        session.finish();
+        return Ok(ExecResult {
+            timings,
+            flow,
+            ..ExecResult::default()
+        });
+    }
+
+    let mut stage = OutputStage::new(options, on_output)?;
+    // Input the interaction starts with is sent right away, without waiting for output
+    stage.output(port, &[])?;
+    let mut stdout = match reader.read_until(port, "\x04".as_bytes(), Some(&mut stage), timeout) {
+        // The device has left the raw REPL by itself, whatever it booted into is left to run
+        Err(e) if e.is::<Rebooted>() => {
+            session.finish();
+            return Err(e);
+        }
+        result => result?,
+    };
+    timings.execution = stage_start.elapsed();
+    stage_start = Instant::now();
+
+    // The output is framed as `stdout \x04 stderr \x04 >`, but a script can print 0x04 itself.
+    // Until the `>` shows that the stderr frame has ended, what follows a 0x04 is held back in
+    // case it's still stdout, unless the device pauses in it. The frame's end decides which stream
+    // it was, even if it has been passed on already.
+    let (stderr, shown) = loop {
+        stage.held = Some(Held::new());
+        let result = reader.read_until(port, "\x04".as_bytes(), Some(&mut stage), timeout);
+        let released = stage.held.take().is_some_and(|held| held.released);
+        let candidate = match result {
+            Err(e) if e.is::<Rebooted>() => {
+                session.finish();
+                return Err(e);
+            }
+            result => result?,
+        };
+        if reader.peek(port, timeout)? == b'>' {
+            break (candidate, released);
+        }
+
+        let mut printed = vec![0x04];
+        printed.extend_from_slice(&candidate);
+        if !released {
+            stage.output(port, &printed)?;
+        }
+        stdout.extend_from_slice(&printed);
+    };
+    // The prompt ends the output, whatever follows it is left to be read on
+    reader.next_byte(port)?;
+
+    stage.terminal = None;
+    stage.stream = Stream::Stderr;
+    if let Some(echo) = stage.echo.as_mut() {
+        echo.set_stream(Stream::Stderr)?;
+    }
+    if !shown {
+        stage.output(port, &stderr)?;
+    }
+    if let Some(echo) = stage.echo.as_mut() {
+        echo.finish()?;
+    }
+
+    stage.check(options)?;
+
+    timings.output = stage_start.elapsed();
+    session.finish();
+
+    // The requests for the payload's frames were printed by the helper, not the script
+    if options.feed.is_some() {
+        stdout.retain(|&byte| byte != feed::REQUEST);
+    }
+    Ok(ExecResult {
+        stdout,
+        stderr,
+        timings,
+        flow,
+    })
+}
+
+/// Ctrl-C twice: Interrupt any running program, and drain whatever it printed until the device
+/// goes quiet. A board that keeps printing gets interrupted again, as it may have been booting
+/// when the first one arrived, and it's given up on once it has had long enough.
+fn interrupt_program(port: &mut dyn SerialPort, reader: &mut Reader) -> Result<()> {
+    let interrupt_start = Instant::now();
+    loop {
+        port.write_all("\r\x03\x03".as_bytes())?;
+        if reader.drain(port, INTERRUPT_PATIENCE)? || interrupt_start.elapsed() >= INTERRUPT_LIMIT {
+            return Ok(());
+        }
+        if interrupt::take() {
+            bail!(Interrupted);
+        }
+    }
+}
+
+/// Execute a script in the friendly REPL's paste mode, sending it a line at a time and checking
+/// each line against what the device echoes, as there's no flow control. The output ends with the
+/// REPL's prompt, and a traceback in it is taken for stderr.
+fn execute_pasted(
+    port: &mut dyn SerialPort,
+    reader: &mut Reader,
+    script: &mut Script<'_>,
+    options: &ExecOptions,
+    on_output: Option<OutputCallback<'_>>,
+    started: &mut bool,
+    quirks: &Quirks,
+) -> Result<ExecResult> {
+    let timeout = options.timeout;
+    let mut timings = Timings::default();
+    let mut stage_start = Instant::now();
+
+    // Ctrl-C cancels paste mode as well as a script it started
+    let mut session = RawSession::new(port, reader);
+    let port: &mut dyn SerialPort = &mut *session;
+    if options.soft_reset {
+        port.write_all("\x04".as_bytes())?;
+        if let Some(banner) = &quirks.reboot_banner {
+            match reader.wait_for(port, banner.as_bytes(), &SOFT_REBOOT_BANNER, None) {
+                Err(e) if e.is::<StageTimeout>() => {}
+                result => {
+                    result?;
+                }
+            }
+        }
+        reader.wait_for(
+            port,
+            ">>> ".as_bytes(),
+            &reboot_stage(&FRIENDLY_PROMPT_AFTER_REBOOT, quirks.reboot_limit),
+            timeout,
+        )?;
+        timings.soft_reboot = stage_start.elapsed();
+        stage_start = Instant::now();
+    }
+
+    port.write_all("\x05".as_bytes())?;
+    reader.wait_for(port, "\r\n=== ".as_bytes(), &PASTE_MODE_PROMPT, timeout)?;
+    timings.raw_repl = stage_start.elapsed();
+    stage_start = Instant::now();
+
+    let mut progress = options
+        .progress
+        .then(|| Progress::new("Uploading", script.size));
+    paste_upload(port, reader, script, progress.as_mut(), timeout)?;
+    if let Some(progress) = progress.as_mut() {
+        progress.finish();
+    }
     port.write_all("\x04".as_bytes())?;
+    reader.wait_for(port, "\r\n".as_bytes(), &PASTE_ECHO, timeout)?;
+    *started = true;
+    timings.upload = stage_start.elapsed();
+    stage_start = Instant::now();
 
-    read_until(&mut *port, "soft reboot\r\n".as_bytes(), false, timeout)?;
-    read_until(
-        &mut *port,
-        "raw REPL; CTRL-B to exit\r\n".as_bytes(),
-        false,
-        timeout,
-    )?;
+    if options.detach {
+        session.finish();
+        return Ok(ExecResult {
+            timings,
+            ..ExecResult::default()
+        });
+    }
+
+    let mut stage = OutputStage::new(options, on_output)?;
+    stage.output(port, &[])?;
+    let mut output = match reader.read_until(port, ">>> ".as_bytes(), Some(&mut stage), timeout) {
+        Err(e) if e.is::<Rebooted>() => {
+            session.finish();
+            return Err(e);
+        }
+        result => result?,
+    };
+    stage.terminal = None;
+    if let Some(echo) = stage.echo.as_mut() {
+        echo.finish()?;
+    }
+    stage.check(options)?;
+    timings.execution = stage_start.elapsed();
+    session.finish();
+
+    if options.feed.is_some() {
+        output.retain(|&byte| byte != feed::REQUEST);
+    }
+    let traceback = output
+        .windows(TRACEBACK_HEADER.len())
+        .rposition(|window| window == TRACEBACK_HEADER)
+        .filter(|&start| start == 0 || output[start - 1] == b'\n');
+    let stderr = match traceback {
+        Some(start) => output.split_off(start),
+        None => Vec::new(),
+    };
+    Ok(ExecResult {
+        stdout: output,
+        stderr,
+        timings,
+        flow: FlowStats::default(),
+    })
+}
+
+/// How MicroPython starts the traceback of an uncaught exception
+const TRACEBACK_HEADER: &[u8] = b"Traceback (most recent call last):";
+
+/// Send the script in paste mode a line at a time, waiting for each to be echoed before sending
+/// the next. A line the device echoes differently lost bytes on the way, and fails the upload.
+fn paste_upload(
+    port: &mut dyn SerialPort,
+    reader: &mut Reader,
+    script: &mut Script<'_>,
+    mut progress: Option<&mut Progress>,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let mut chunk = [0; RAW_CHUNK_SIZE];
+    let mut line: Vec<u8> = Vec::new();
+    let mut number = 0;
+    let mut sent = 0;
+    let mut ended = false;
+    while !ended {
+        let count = script.read(&mut chunk)?;
+        ended = count == 0;
+        line.extend_from_slice(&chunk[..count]);
+        let mut lines: Vec<Vec<u8>> = Vec::new();
+        while let Some(end) = line.iter().position(|&byte| byte == b'\n') {
+            let rest = line.split_off(end + 1);
+            lines.push(std::mem::replace(&mut line, rest));
+        }
+        if ended && !line.is_empty() {
+            lines.push(std::mem::take(&mut line));
+        }
+
+        for mut line in lines {
+            number += 1;
+            sent += line.len();
+            while matches!(line.last(), Some(b'\n' | b'\r')) {
+                line.pop();
+            }
+            // Paste mode ends a line on CR, and echoes it as CRLF and the next prompt
+            line.push(b'\r');
+            port.write_all(&line)?;
+            line.pop();
+            let echoed = reader.wait_for(port, "\r\n=== ".as_bytes(), &PASTE_ECHO, timeout)?;
+            if echoed != line {
+                bail!(
+                    "The device echoed line {} of the script as {:?} rather than {:?}, so some \
+                     of it was lost on the way",
+                    number,
+                    String::from_utf8_lossy(&echoed),
+                    String::from_utf8_lossy(&line)
+                );
+            }
+            if let Some(progress) = progress.as_mut() {
+                progress.update(sent);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read and discard what the device prints until it has been quiet for [`DRAIN_QUIET_TIME`],
+/// returning whether it went quiet within `patience`
+fn drain(port: &mut dyn SerialPort, buf: &mut [u8], patience: Duration) -> Result<bool> {
+    let start = Instant::now();
+    let port_timeout = port.timeout();
+    port.set_timeout(DRAIN_QUIET_TIME)?;
+    let quiet = loop {
+        if start.elapsed() >= patience {
+            break false;
+        }
+        match port.read(buf) {
+            Ok(_) => continue,
+            Err(ref e) if e.kind() == ErrorKind::TimedOut => break true,
+            Err(e) => return Err(e.into()),
+        }
+    };
+    port.set_timeout(port_timeout)?;
+    Ok(quiet)
+}
+
+/// Ask for raw-paste mode, returning the window size the device grants with each refill, or
+/// `None` if the device doesn't support raw-paste and is back in the raw REPL
+fn raw_paste_negotiate(
+    port: &mut dyn SerialPort,
+    reader: &mut Reader,
+    timeout: Option<Duration>,
+) -> Result<Option<usize>> {
+    // Everything the device answered with, for the error if it can't be made sense of
+    let mut handshake: Vec<u8> = Vec::new();
+    let context =
+        |handshake: &[u8]| format!("Raw-paste handshake: b\"{}\"", handshake.escape_ascii());
+    for _ in 0..RAW_PASTE_ATTEMPTS {
+        match raw_paste_request(port, reader, timeout, &mut handshake) {
+            Ok(Negotiation::Window(window_size)) => return Ok(Some(window_size)),
+            Ok(Negotiation::Unsupported) => return Ok(None),
+            Ok(Negotiation::Anomalous) => {}
+            Err(e) => return Err(e.context(context(&handshake))),
+        }
+        // Whatever state the answer left the device in, it's put back at the raw REPL's prompt
+        if let Err(e) = raw_repl_resync(port, reader, timeout) {
+            return Err(e.context(context(&handshake)));
+        }
+    }
+    // A device that keeps answering nonsense gets the script the old way
+    Ok(None)
+}
+
+/// How many times raw-paste mode is asked for before giving up on it, when the device's answers
+/// make no sense
+const RAW_PASTE_ATTEMPTS: usize = 2;
+
+/// The device's answer to asking for raw-paste mode
+enum Negotiation {
+    Window(usize),
+    /// The device knows about raw-paste but doesn't support it, or predates it. It's at the raw
+    /// REPL's prompt.
+    Unsupported,
+    /// The device answered with something else, or with an empty window
+    Anomalous,
+}
 
-    read_until(&mut *port, ">".as_bytes(), false, timeout)?;
+/// Ask for raw-paste mode once, adding what the device answers to `handshake`
+fn raw_paste_request(
+    port: &mut dyn SerialPort,
+    reader: &mut Reader,
+    timeout: Option<Duration>,
+    handshake: &mut Vec<u8>,
+) -> Result<Negotiation> {
+    let mut double_buf = [0; 2];
 
     port.write_all("\x05A\x01".as_bytes())?;
 
-    port.read_exact(&mut double_buf)?;
+    let limit = RAW_PASTE_RESPONSE.limit(timeout);
+    let mut read_response = |reader: &mut Reader, port: &mut dyn SerialPort, buf: &mut [u8]| {
+        match reader.read_exact(port, buf, limit) {
+            Err(e) if e.is::<ReadTimeout>() => return Err(RAW_PASTE_RESPONSE.timed_out(limit)),
+            result => result?,
+        }
+        handshake.extend_from_slice(buf);
+        Ok(())
+    };
+    read_response(reader, port, &mut double_buf)?;
     match double_buf {
-        [82, 0] => bail!("Device doesn't support raw-paste"),
-        [82, 1] => {}
-        _ => bail!("Unknown response"),
+        [b'R', 1] => {}
+        // The device knows about raw-paste, but doesn't support it
+        [b'R', 0] => return Ok(Negotiation::Unsupported),
+        // Firmware from before raw-paste treats the request as entering the raw REPL again
+        [b'r', b'a'] => {
+            let banner = reader.wait_for(
+                port,
+                "w REPL; CTRL-B to exit\r\n>".as_bytes(),
+                &RAW_REPL_BANNER,
+                timeout,
+            )?;
+            handshake.extend_from_slice(&banner);
+            return Ok(Negotiation::Unsupported);
+        }
+        _ => return Ok(Negotiation::Anomalous),
     }
 
-    port.read_exact(&mut double_buf)?;
+    read_response(reader, port, &mut double_buf)?;
     let window_size: usize = (double_buf[0] as usize) | (double_buf[1] as usize) << 8;
-    let mut window_remain = 0;
+    if window_size == 0 {
+        return Ok(Negotiation::Anomalous);
+    }
+    Ok(Negotiation::Window(window_size))
+}
+
+/// Get the device back to the raw REPL's prompt from wherever it is in it, interrupting anything
+/// it's doing, even raw-paste mode
+fn raw_repl_resync(
+    port: &mut dyn SerialPort,
+    reader: &mut Reader,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    port.write_all("\x03\x03\x02".as_bytes())?;
+    interrupt_program(port, reader)?;
+    port.write_all("\r\x01".as_bytes())?;
+    reader.wait_for(
+        port,
+        "raw REPL; CTRL-B to exit\r\n".as_bytes(),
+        &RAW_REPL_BANNER,
+        timeout,
+    )?;
+    reader.wait_for(port, ">".as_bytes(), &RAW_REPL_PROMPT, timeout)?;
+    Ok(())
+}
 
-    let script_bytes = script.as_bytes();
+/// Upload the script in raw-paste mode, once negotiated, where the device tells how much it's able
+/// to take
+fn raw_paste_upload(
+    port: &mut dyn SerialPort,
+    reader: &mut Reader,
+    script: &mut Script<'_>,
+    window_size: usize,
+    mut progress: Option<&mut Progress>,
+    timeout: Option<Duration>,
+) -> Result<FlowStats> {
+    let mut window_remain = 0;
+    let mut flow = FlowStats {
+        window_size,
+        ..FlowStats::default()
+    };
 
-    let mut i: usize = 0;
-    while i < script.len() {
-        while window_remain == 0 || port.bytes_to_read()? > 0 {
-            match port.read_exact(&mut byte_buf) {
-                Ok(_) => (),
-                Err(ref e) if e.kind() == ErrorKind::TimedOut => (),
-                Err(e) => bail!("Unable to read from port: {:?}", e),
+    // At most a window of the script is read ahead of what has been sent
+    let mut chunk = vec![0; window_size];
+    let mut unsent = 0..0;
+    let mut sent: usize = 0;
+    loop {
+        if unsent.is_empty() {
+            unsent = 0..script.read(&mut chunk)?;
+            if unsent.is_empty() {
+                break;
             }
+        }
+
+        // Whatever the device has sent is dealt with before each chunk, which is how an abort it
+        // sent while the last chunk was being written is noticed. Without a window left to send
+        // in, the next refill is waited for.
+        let stall_start = (window_remain == 0 && sent > 0).then(Instant::now);
+        while window_remain == 0 || reader.available(port)? > 0 {
+            let byte = match reader.next_byte(port) {
+                Ok(byte) => byte,
+                Err(e) => return Err(e.context("Unable to read from port")),
+            };
 
-            match byte_buf {
-                [1] => window_remain += window_size,
-                [4] => {
+            match byte {
+                1 => {
+                    window_remain += window_size;
+                    flow.refills += 1;
+                }
+                4 => {
                     port.write_all("\x04".as_bytes())?;
-                    bail!("Device indicated abrupt end.");
+                    bail!(RawPasteFailed {
+                        reason: String::from("Device indicated abrupt end."),
+                    });
                 }
-                [byte] => bail!("Unexpected error during raw paste: {:?}", byte),
+                byte => bail!(RawPasteFailed {
+                    reason: format!(
+                        "Device sent {:#04x} during the raw-paste upload, after {} bytes of the \
+                         script, where only flow control was expected",
+                        byte, sent
+                    ),
+                }),
             }
         }
 
-        let chunk_size = min(window_remain, script_bytes.len() - i);
+        if let Some(stall_start) = stall_start {
+            flow.stalls += 1;
+            flow.stall_time += stall_start.elapsed();
+        }
+
+        let chunk_size = min(window_remain, unsent.len());
+        flow.largest_chunk = max(flow.largest_chunk, chunk_size);
 
-        let mut chunk = vec![0; chunk_size];
-        chunk.copy_from_slice(&script_bytes[i..i + chunk_size]);
+        port.write_all(&chunk[unsent.start..unsent.start + chunk_size])?;
+        window_remain -= chunk_size;
+        unsent.start += chunk_size;
+        sent += chunk_size;
 
-        port.write_all(&chunk)?;
-        window_remain -= chunk.len();
-        i += chunk.len();
+        if let Some(progress) = progress.as_mut() {
+            progress.update(sent);
+        }
     }
 
     port.write_all("\x04".as_bytes())?;
 
-    read_until(&mut *port, "\x04".as_bytes(), false, timeout)?;
+    reader.wait_for(port, "\x04".as_bytes(), &SCRIPT_START, timeout)?;
+    Ok(flow)
+}
+
+/// Upload the script in the standard raw REPL, for devices without raw-paste. As there is no flow
+/// control, the script is sent in small chunks with pauses for the device to keep up.
+fn raw_upload(
+    port: &mut dyn SerialPort,
+    reader: &mut Reader,
+    script: &mut Script<'_>,
+    mut progress: Option<&mut Progress>,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let mut chunk = [0; RAW_CHUNK_SIZE];
+    let mut sent = 0;
+    loop {
+        let count = script.read(&mut chunk)?;
+        if count == 0 {
+            break;
+        }
+        port.write_all(&chunk[..count])?;
+        sent += count;
+        if let Some(progress) = progress.as_mut() {
+            progress.update(sent);
+        }
+        sleep(RAW_CHUNK_PAUSE);
+    }
+    port.write_all("\x04".as_bytes())?;
+
+    let mut response = [0; 2];
+    let limit = RAW_REPL_OK.limit(timeout);
+    match reader.read_exact(port, &mut response, limit) {
+        Err(e) if e.is::<ReadTimeout>() => return Err(RAW_REPL_OK.timed_out(limit)),
+        result => result?,
+    }
+    if &response != b"OK" {
+        bail!("Unexpected response to the script: {:?}", response);
+    }
+    Ok(())
+}
+
+/// Run `code` from the raw REPL's prompt, returning its stdout and stderr once the device is at
+/// the prompt again
+fn raw_eval(
+    port: &mut dyn SerialPort,
+    reader: &mut Reader,
+    code: &str,
+    timeout: Option<Duration>,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    raw_upload(port, reader, &mut Script::from(code), None, timeout)?;
+    let stdout = reader.wait_for(port, "\x04".as_bytes(), &HELPER_OUTPUT, timeout)?;
+    let stderr = reader.wait_for(port, "\x04".as_bytes(), &HELPER_OUTPUT, timeout)?;
+    reader.wait_for(port, ">".as_bytes(), &RAW_REPL_PROMPT, timeout)?;
+    Ok((stdout, stderr))
+}
+
+/// Execute a helper script without echoing its output or soft rebooting, returning what it
+/// printed. Fails if the script raises an exception.
+pub fn eval(device: &mut Device, script: &str, timeout: Option<Duration>) -> Result<Vec<u8>> {
+    let options = ExecOptions {
+        timeout,
+        soft_reset: false,
+        echo: false,
+        log: false,
+        ..ExecOptions::default()
+    };
+    let result = execute(device, script, &options)?;
+    if let Some(exception) = result.exception() {
+        bail!("Device raised {}", exception);
+    }
+    Ok(result.stdout)
+}
+
+/// Execute a helper script from the friendly REPL's prompt, going through the raw REPL and back
+/// without soft rebooting or interrupting anything, and reading everything the device prints on
+/// the way so that none of it shows in the REPL. Returns what the script printed, failing if it
+/// raised an exception.
+pub fn eval_at_prompt(
+    device: &mut Device,
+    code: &str,
+    timeout: Option<Duration>,
+) -> Result<Vec<u8>> {
+    let mut reader = Reader::new(device.buffer_size());
+    let mut session = RawSession::new(device.port(), &reader);
+    let port: &mut dyn SerialPort = &mut *session;
+    port.write_all("\x01".as_bytes())?;
+    reader.wait_for(
+        port,
+        "raw REPL; CTRL-B to exit\r\n>".as_bytes(),
+        &RAW_REPL_BANNER,
+        timeout,
+    )?;
+    let (stdout, stderr) = raw_eval(port, &mut reader, code, timeout)?;
+    port.write_all("\x02".as_bytes())?;
+    reader.wait_for(port, ">>> ".as_bytes(), &FRIENDLY_PROMPT, timeout)?;
+    session.finish();
+
+    if !stderr.is_empty() {
+        bail!("Device raised {}", String::from_utf8_lossy(&stderr).trim());
+    }
+    Ok(stdout)
+}
+
+/// Leave the raw REPL for the friendly REPL's prompt, reading the banner and the prompt so that
+/// they don't show in the REPL
+pub fn return_to_prompt(device: &mut Device, timeout: Option<Duration>) -> Result<()> {
+    let mut reader = Reader::new(device.buffer_size());
+    let port = device.port();
+    port.write_all("\r\x02".as_bytes())?;
+    reader.wait_for(port, ">>> ".as_bytes(), &FRIENDLY_PROMPT, timeout)?;
+    Ok(())
+}
+
+/// Leave the raw REPL, returning the device to the friendly REPL
+pub fn exit_raw_repl(device: &mut Device) -> Result<()> {
+    device.port().write_all("\r\x02".as_bytes())?;
+    Ok(())
+}
+
+/// Soft reboot the device from the friendly REPL, interrupting whatever is running, so that it
+/// runs `boot.py` and `main.py` again
+pub fn soft_reset(device: &mut Device) -> Result<()> {
+    device.port().write_all("\r\x03\x03\x02\x04".as_bytes())?;
+    Ok(())
+}
+
+/// Echo everything the device prints until the port is closed or fails, or Ctrl-C is caught by
+/// the handler from [`interrupt::install`]
+pub fn follow(device: &mut Device) -> Result<()> {
+    follow_until(device, || false)?;
+    Ok(())
+}
+
+/// Like [`follow`], also stopping once `stop` returns true, which is checked between reads.
+/// Returns whether it was `stop` that ended it.
+pub fn follow_until(device: &mut Device, stop: impl FnMut() -> bool) -> Result<bool> {
+    let mut decoder = device.decoding().decoder();
+    let stopped = follow_with(device, stop, |bytes| {
+        Terminal.write(Stream::Stdout, &decoder.feed(bytes)?)
+    })?;
+    Terminal.write(Stream::Stdout, &decoder.finish()?)?;
+    Ok(stopped)
+}
+
+/// Show everything the device prints through `echo`, or only the lines in `window`, without
+/// sending it anything, so that an application that's running carries on undisturbed. Every line
+/// is matched against `alerts`, whatever is shown. Ends when the port is closed or fails, or
+/// Ctrl-C is caught by the handler from [`interrupt::install`].
+pub fn monitor(
+    device: &mut Device,
+    mut echo: Echo,
+    window: Option<Window>,
+    alerts: &mut Alerts,
+) -> Result<()> {
+    let result = match window {
+        Some(mut window) => listen(device, |bytes| {
+            alerts.feed(bytes);
+            echo.write(&window.filter(bytes))
+        }),
+        None => listen(device, |bytes| {
+            alerts.feed(bytes);
+            echo.write(bytes)
+        }),
+    };
+    alerts.finish();
+    result?;
+    echo.finish()
+}
+
+/// Like [`monitor`], passing what the device prints to `on_output` instead of echoing it
+pub fn listen(device: &mut Device, on_output: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+    follow_with(device, || false, on_output)?;
+    Ok(())
+}
+
+/// Pass everything the device prints to `on_output` until `stop` returns true or Ctrl-C is caught,
+/// returning whether it was `stop` that ended it
+fn follow_with(
+    device: &mut Device,
+    mut stop: impl FnMut() -> bool,
+    mut on_output: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<bool> {
+    let mut buf: Vec<u8> = vec![0; device.buffer_size()];
+    let mut chatter = Chatter::new(device.echo_policy());
+    let unread = device.take_unread();
+    if !unread.is_empty() {
+        let output = chatter.filter(&unread);
+        logfile::write(&output);
+        on_output(&output)?;
+    }
+    let port = device.port();
 
-    // stdout
-    read_until(&mut *port, "\x04".as_bytes(), true, timeout)?;
+    let port_timeout = port.timeout();
+    port.set_timeout(POLL_INTERVAL)?;
+    let mut last_check = Instant::now();
+    let stopped = loop {
+        if interrupt::take() {
+            break false;
+        }
+        if stop() {
+            break true;
+        }
 
-    // stderr
-    read_until(&mut *port, "\x04".as_bytes(), true, timeout)?;
+        match port.read(&mut buf) {
+            Ok(0) => bail!("Unable to read"),
+            Ok(n) => {
+                let output = chatter.filter(&buf[..n]);
+                logfile::write(&output);
+                on_output(&output)?;
+            }
+            Err(ref e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {
+                if last_check.elapsed() >= KEEPALIVE_INTERVAL {
+                    check_alive(port)?;
+                    last_check = Instant::now();
+                }
+            }
+            Err(e) => bail!(e),
+        }
+    };
+    let rest = chatter.finish();
+    if !rest.is_empty() {
+        logfile::write(&rest);
+        on_output(&rest)?;
+    }
+    port.set_timeout(port_timeout)?;
+    Ok(stopped)
+}
 
+/// Fail if the port has gone away, which asking how much there is to read tells without waiting
+fn check_alive(port: &dyn SerialPort) -> Result<()> {
+    if let Err(e) = port.bytes_to_read() {
+        bail!("Device disconnected: {}", e);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interact::Step;
+    use crate::session::ReplayPort;
+
+    /// What uploading `script` in raw-paste mode looks like, up to the device starting it
+    fn uploaded(script: &'static str) -> Vec<(&'static [u8], &'static [u8])> {
+        vec![
+            (b"\r\x03\x03", b"\r\n>>> "),
+            (b"\r\x01", b"raw REPL; CTRL-B to exit\r\n>"),
+            (b"\x05A\x01", b"R\x01\x80\x00\x01"),
+            (script.as_bytes(), b""),
+            (b"\x04", b"\x04"),
+        ]
+    }
+
+    fn device(port: ReplayPort) -> Device {
+        let mut device = Device::new(Box::new(port), 256);
+        device.set_firmware(Some(Firmware::MicroPython));
+        device
+    }
+
+    fn options() -> ExecOptions {
+        ExecOptions {
+            // A device that's never answered fails the test rather than waiting forever
+            timeout: Some(Duration::from_secs(5)),
+            soft_reset: false,
+            echo: false,
+            log: false,
+            ..ExecOptions::default()
+        }
+    }
+
+    #[test]
+    fn output_after_the_prompt_is_left_unread() {
+        let mut exchange = uploaded("print(\"hello\")\n");
+        exchange.push((b"", b"hello\r\n\x04\x04>"));
+        exchange.push((b"", b"late\r\n"));
+        let mut device = device(ReplayPort::recorded("unread", &exchange));
+        let result = execute(&mut device, "print(\"hello\")\n", &options()).unwrap();
+        assert_eq!(result.stdout, b"hello\r\n");
+        assert!(result.stderr.is_empty());
+
+        let mut followed = Vec::new();
+        let ended = listen(&mut device, |bytes| {
+            followed.extend_from_slice(bytes);
+            Ok(())
+        });
+        // The replay ends after what was recorded, like a port that's closed
+        assert!(ended.is_err());
+        assert_eq!(followed, b"late\r\n");
+    }
+
+    #[test]
+    fn reader_thread_hands_back_what_it_read() {
+        let mut port = ReplayPort::recorded("thread", &[(b"", b"ab\x04cd"), (b"", b"ef")]);
+        let unread = thread::scope(|scope| {
+            let mut reader = Reader::spawn(scope, &mut port, 256);
+            let mut writer = ReplayPort::recorded("thread-writer", &[]);
+            let read = reader
+                .read_until(&mut writer, b"\x04", None, Some(Duration::from_secs(1)))
+                .unwrap();
+            assert_eq!(read, b"ab");
+            reader.finish()
+        });
+        // The thread may have been stopped before it got to the rest
+        let mut rest = vec![0; 16];
+        let count = port.read(&mut rest).unwrap_or(0);
+        assert_eq!([unread, rest[..count].to_vec()].concat(), b"cdef");
+    }
+
+    #[test]
+    fn printed_end_of_frame_stays_stdout() {
+        let script = "print('a\\x04b')\n";
+        let mut exchange = uploaded(script);
+        exchange.push((b"", b"a\x04b\r\n\x04\x04>"));
+        let mut device = device(ReplayPort::recorded("printed-eof", &exchange));
+        let result = execute(&mut device, script, &options()).unwrap();
+        assert_eq!(result.stdout, b"a\x04b\r\n");
+        assert!(result.stderr.is_empty());
+    }
+
+    #[test]
+    fn held_output_is_passed_on_once_the_device_pauses() {
+        let script = "print('\\x04', end='')\nprint('hi', input('Name? '))\n";
+        let mut exchange = uploaded(script);
+        exchange.push((b"", b"\x04Name? "));
+        exchange.push((b"bob\r", b"bob\r\nhi bob\r\n\x04\x04>"));
+        let mut device = device(ReplayPort::recorded("held-input", &exchange));
+        let options = ExecOptions {
+            interaction: Some(Interaction::new(vec![
+                Step::Expect("Name? ".to_string()),
+                Step::Send("bob\r".to_string()),
+            ])),
+            ..options()
+        };
+        let mut streamed = Vec::new();
+        let mut on_output = |stream, bytes: &[u8]| {
+            assert_eq!(stream, Stream::Stdout);
+            streamed.extend_from_slice(bytes);
+            Ok(())
+        };
+        let result = execute_streaming(&mut device, script, &options, &mut on_output).unwrap();
+        assert_eq!(result.stdout, b"\x04Name? bob\r\nhi bob\r\n");
+        assert_eq!(streamed, result.stdout);
+        assert!(result.stderr.is_empty());
+    }
+
+    #[test]
+    fn reboot_is_noticed_in_held_output() {
+        let script = "print('\\x04', end='')\nmachine.reset()\n";
+        let mut exchange = uploaded(script);
+        exchange.push((
+            b"",
+            b"\x04MicroPython v1.22.0 on 2024-01-01; Raspberry Pi Pico with RP2040\r\n\
+              Type \"help()\" for more information.\r\n>>> ",
+        ));
+        // The port is still there afterwards, for whatever comes next
+        exchange.push((b"\r\x03\x03", b""));
+        let mut device = device(ReplayPort::recorded("held-reboot", &exchange));
+        let error = execute(&mut device, script, &options()).unwrap_err();
+        assert!(error.is::<Rebooted>(), "{:#}", error);
+    }
+}
@@ -0,0 +1,441 @@
+//! Recording a serial session to a file and replaying it without the hardware, so bugs seen on
+//! unusual boards can be reproduced.
+//!
+//! A session file starts with [`MAGIC`], followed by events of a tag byte, a little endian `u32`
+//! length and that many bytes of data.
+use anyhow::{bail, Result};
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::tap::Tap;
+
+/// The start of every session file
+pub const MAGIC: &[u8] = b"SERPICO-SESSION 1\n";
+
+/// The command line arguments of the recorded command, separated by NUL bytes
+const TAG_ARGS: u8 = b'A';
+/// The path of the recorded device
+const TAG_DEVICE: u8 = b'D';
+const TAG_READ: u8 = b'R';
+const TAG_WRITE: u8 = b'W';
+/// One or more reads in a row timed out
+const TAG_TIMEOUT: u8 = b'T';
+
+/// Something that happened on the port during the session
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Event {
+    Read(Vec<u8>),
+    Write(Vec<u8>),
+    Timeout,
+}
+
+/// Records the traffic of a port to a session file, for use with a [`crate::tap::TapPort`]
+pub struct Recorder {
+    out: BufWriter<File>,
+    timed_out: bool,
+}
+
+impl Recorder {
+    /// Start recording to a new file at `path`, noting the command's arguments and device so
+    /// that the session can be replayed on its own
+    pub fn create(path: &Path, args: &[String], device: &Path) -> Result<Recorder> {
+        let mut recorder = Recorder {
+            out: BufWriter::new(File::create(path)?),
+            timed_out: false,
+        };
+        recorder.out.write_all(MAGIC)?;
+        recorder.record(TAG_ARGS, args.join("\0").as_bytes());
+        recorder.record(TAG_DEVICE, device.display().to_string().as_bytes());
+        Ok(recorder)
+    }
+
+    fn record(&mut self, tag: u8, data: &[u8]) {
+        let _ = self.out.write_all(&[tag]);
+        let _ = self.out.write_all(&(data.len() as u32).to_le_bytes());
+        let _ = self.out.write_all(data);
+        let _ = self.out.flush();
+    }
+}
+
+impl Tap for Recorder {
+    fn read(&mut self, bytes: &[u8]) {
+        self.timed_out = false;
+        self.record(TAG_READ, bytes);
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.timed_out = false;
+        self.record(TAG_WRITE, bytes);
+    }
+
+    fn timed_out(&mut self) {
+        // Polling can time out many times in a row, which replays the same as once
+        if !self.timed_out {
+            self.timed_out = true;
+            self.record(TAG_TIMEOUT, &[]);
+        }
+    }
+}
+
+/// A recorded session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    /// The command line arguments of the recorded command
+    pub args: Vec<String>,
+    /// The path of the recorded device
+    pub device: String,
+    events: VecDeque<Event>,
+}
+
+impl Session {
+    pub fn load(path: &Path) -> Result<Session> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) => bail!("Couldn't read session {}: {}", path.display(), e),
+        };
+        let mut rest = match data.strip_prefix(MAGIC) {
+            Some(rest) => rest,
+            None => bail!("{} isn't a serpico session", path.display()),
+        };
+
+        let mut session = Session {
+            args: Vec::new(),
+            device: String::new(),
+            events: VecDeque::new(),
+        };
+        while !rest.is_empty() {
+            if rest.len() < 5 {
+                bail!("Session {} is truncated", path.display());
+            }
+            let tag = rest[0];
+            let length = u32::from_le_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+            let data = match rest.get(5..5 + length) {
+                Some(data) => data.to_vec(),
+                None => bail!("Session {} is truncated", path.display()),
+            };
+            rest = &rest[5 + length..];
+
+            match tag {
+                TAG_ARGS => {
+                    session.args = String::from_utf8_lossy(&data)
+                        .split('\0')
+                        .map(String::from)
+                        .collect()
+                }
+                TAG_DEVICE => session.device = String::from_utf8_lossy(&data).to_string(),
+                TAG_READ => session.events.push_back(Event::Read(data)),
+                TAG_WRITE => session.events.push_back(Event::Write(data)),
+                TAG_TIMEOUT => session.events.push_back(Event::Timeout),
+                _ => bail!("Unknown event {:?} in session {}", tag, path.display()),
+            }
+        }
+        Ok(session)
+    }
+}
+
+/// A port that plays back what the device sent during a session, failing as soon as the host
+/// writes something other than what was recorded
+pub struct ReplayPort {
+    events: VecDeque<Event>,
+    name: String,
+    timeout: Duration,
+    baud_rate: u32,
+}
+
+impl ReplayPort {
+    pub fn new(session: Session) -> ReplayPort {
+        ReplayPort {
+            name: format!("{} (replay)", session.device),
+            events: session.events,
+            timeout: Duration::from_millis(10),
+            baud_rate: 0,
+        }
+    }
+
+    fn ended(&self) -> io::Error {
+        io::Error::new(
+            ErrorKind::UnexpectedEof,
+            format!("{} reached the end of the session", self.name),
+        )
+    }
+}
+
+#[cfg(test)]
+impl ReplayPort {
+    /// A port replaying a session recorded of `exchange`, of what the host writes and what the
+    /// device answers to it in turn. Either can be left empty.
+    pub fn recorded(name: &str, exchange: &[(&[u8], &[u8])]) -> ReplayPort {
+        let path = std::env::temp_dir().join(format!(
+            "serpico-test-{}-{}.session",
+            std::process::id(),
+            name
+        ));
+        let mut recorder = Recorder::create(&path, &[], Path::new("/dev/test")).unwrap();
+        for (written, answer) in exchange {
+            if !written.is_empty() {
+                recorder.write(written);
+            }
+            if !answer.is_empty() {
+                recorder.read(answer);
+            }
+        }
+        drop(recorder);
+        let session = Session::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        ReplayPort::new(session)
+    }
+}
+
+impl Read for ReplayPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.events.front_mut() {
+            Some(Event::Read(data)) => {
+                let count = data.len().min(buf.len());
+                buf[..count].copy_from_slice(&data[..count]);
+                data.drain(..count);
+                if data.is_empty() {
+                    self.events.pop_front();
+                }
+                Ok(count)
+            }
+            Some(Event::Timeout) => {
+                self.events.pop_front();
+                Err(io::Error::new(ErrorKind::TimedOut, "Operation timed out"))
+            }
+            // The device stayed quiet until the host wrote something
+            Some(Event::Write(_)) => {
+                Err(io::Error::new(ErrorKind::TimedOut, "Operation timed out"))
+            }
+            None => Err(self.ended()),
+        }
+    }
+}
+
+impl Write for ReplayPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Output recorded ahead of the write stays to be read afterwards
+        let index = match self
+            .events
+            .iter()
+            .position(|event| matches!(event, Event::Write(_)))
+        {
+            Some(index) => index,
+            None => return Err(self.ended()),
+        };
+        let expected = match &mut self.events[index] {
+            Event::Write(expected) => expected,
+            _ => unreachable!(),
+        };
+
+        let count = expected.len().min(buf.len());
+        if buf[..count] != expected[..count] {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Replay diverged from the session: host wrote {:?}, session has {:?}",
+                    String::from_utf8_lossy(&buf[..count]),
+                    String::from_utf8_lossy(&expected[..count])
+                ),
+            ));
+        }
+        expected.drain(..count);
+        if expected.is_empty() {
+            self.events.remove(index);
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for ReplayPort {
+    fn name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.baud_rate)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _data_bits: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _flow_control: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _parity: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _stop_bits: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        match self.events.front() {
+            Some(Event::Read(data)) => Ok(data.len() as u32),
+            Some(_) => Ok(0),
+            None => Err(self.ended().into()),
+        }
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, _buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Err(serialport::Error::new(
+            serialport::ErrorKind::Unknown,
+            "A replayed port can't be cloned",
+        ))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Device;
+    use crate::serial::{execute, ExecOptions};
+
+    /// The handshake up to the raw REPL's prompt after a soft reboot, as MicroPython answers it
+    const HANDSHAKE: &[(&[u8], &[u8])] = &[
+        (b"\r\x03\x03", b"\r\n>>> "),
+        (b"\r\x01", b"raw REPL; CTRL-B to exit\r\n>"),
+        (b"import sys\nprint(sys.implementation.name)\n", b""),
+        (b"\x04", b"OKmicropython\r\n\x04\x04>"),
+        (b"\x04", b"soft reboot\r\nraw REPL; CTRL-B to exit\r\n>"),
+    ];
+
+    fn options() -> ExecOptions {
+        ExecOptions {
+            echo: false,
+            log: false,
+            ..ExecOptions::default()
+        }
+    }
+
+    #[test]
+    fn replay_raw_paste() {
+        let mut exchange = HANDSHAKE.to_vec();
+        exchange.extend_from_slice(&[
+            (b"\x05A\x01", b"R\x01\x80\x00\x01"),
+            (b"print(1)\nraise ValueError(\"bad\")\n", b""),
+            (b"\x04", b"\x04"),
+            (
+                b"",
+                b"1\r\n\x04Traceback (most recent call last):\r\n  File \"<stdin>\", line 2, in \
+                  <module>\r\nValueError: bad\r\n\x04>",
+            ),
+        ]);
+        let port = ReplayPort::recorded("raw-paste", &exchange);
+        let mut device = Device::new(Box::new(port), 256);
+        let script = "print(1)\nraise ValueError(\"bad\")\n";
+        let result = execute(&mut device, script, &options()).unwrap();
+        assert_eq!(result.stdout, b"1\r\n");
+        assert_eq!(result.exception().as_deref(), Some("ValueError: bad"));
+        assert_eq!(result.flow.window_size, 128);
+        assert!(device.raw_paste());
+    }
+
+    #[test]
+    fn replay_raw_repl_fallback() {
+        let mut exchange = HANDSHAKE.to_vec();
+        exchange.extend_from_slice(&[
+            // The device knows about raw-paste mode, but doesn't support it
+            (b"\x05A\x01", b"R\x00"),
+            (b"print(\"hello\")\n", b""),
+            (b"\x04", b"OKhello\r\n\x04\x04>"),
+        ]);
+        let port = ReplayPort::recorded("raw-repl", &exchange);
+        let mut device = Device::new(Box::new(port), 256);
+        let result = execute(&mut device, "print(\"hello\")\n", &options()).unwrap();
+        assert_eq!(result.stdout, b"hello\r\n");
+        assert!(result.stderr.is_empty());
+        assert!(!device.raw_paste());
+    }
+
+    #[test]
+    fn replay_diverges() {
+        let mut exchange = HANDSHAKE.to_vec();
+        exchange.push((b"\x05A\x01", b"R\x00"));
+        exchange.push((b"print(\"hello\")\n", b""));
+        let port = ReplayPort::recorded("diverges", &exchange);
+        let mut device = Device::new(Box::new(port), 256);
+        let error = execute(&mut device, "print(\"bye\")\n", &options()).unwrap_err();
+        assert!(format!("{:#}", error).contains("Replay diverged"));
+    }
+}
@@ -0,0 +1,205 @@
+//! Where the device's output ends up once it has been echoed: the terminal, a file, a stream of
+//! JSON objects or memory. An [`Echo`](crate::output::Echo) adds timestamps, filters and colors
+//! and hands what it shows to a sink, so that each of those works with any of the sinks.
+//!
+//! Sinks are shared between threads, such as the echo threads of several devices writing to one
+//! file, and write each call as a whole, so that the lines of one don't break up those of another.
+use anyhow::Result;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::encoding::escape_invalid;
+use crate::json::Value;
+use crate::output::{format_time, Stream};
+
+/// Where echoed output is written
+pub trait OutputSink: Send + Sync {
+    /// Write `bytes` of `stream`, which are mostly complete lines
+    fn write(&self, stream: Stream, bytes: &[u8]) -> Result<()>;
+
+    /// Write out anything held back, such as a partial line
+    fn finish(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A sink that can be handed to several echoes, and to options that have to be compared
+#[derive(Clone)]
+pub struct SharedSink(pub Arc<dyn OutputSink>);
+
+impl SharedSink {
+    pub fn new(sink: impl OutputSink + 'static) -> SharedSink {
+        SharedSink(Arc::new(sink))
+    }
+}
+
+impl Default for SharedSink {
+    fn default() -> SharedSink {
+        SharedSink::new(Terminal)
+    }
+}
+
+impl fmt::Debug for SharedSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedSink")
+    }
+}
+
+impl PartialEq for SharedSink {
+    fn eq(&self, other: &SharedSink) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SharedSink {}
+
+/// The host's stdout for the device's stdout and its stderr for the device's stderr
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Terminal;
+
+impl OutputSink for Terminal {
+    fn write(&self, stream: Stream, bytes: &[u8]) -> Result<()> {
+        match stream {
+            Stream::Stdout => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(bytes)?;
+                stdout.flush()?;
+            }
+            Stream::Stderr => io::stderr().lock().write_all(bytes)?,
+        }
+        Ok(())
+    }
+}
+
+/// Both streams to one file, or anything else that can be written to
+pub struct FileSink<W: Write + Send> {
+    out: Mutex<W>,
+}
+
+impl<W: Write + Send> FileSink<W> {
+    pub fn new(out: W) -> FileSink<W> {
+        FileSink {
+            out: Mutex::new(out),
+        }
+    }
+}
+
+impl<W: Write + Send> OutputSink for FileSink<W> {
+    fn write(&self, _stream: Stream, bytes: &[u8]) -> Result<()> {
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        out.write_all(bytes)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// A JSON object for each line, with the host's time it was written at and its stream, such as
+/// `{"time": "12:00:01.250", "stream": "stdout", "line": "ready"}`, one object to a line
+pub struct JsonLines<W: Write + Send> {
+    state: Mutex<JsonState<W>>,
+}
+
+struct JsonState<W> {
+    out: W,
+    /// The partial line of each stream
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+impl<W: Write + Send> JsonLines<W> {
+    pub fn new(out: W) -> JsonLines<W> {
+        JsonLines {
+            state: Mutex::new(JsonState {
+                out,
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            }),
+        }
+    }
+}
+
+impl<W: Write> JsonState<W> {
+    fn emit(&mut self, stream: Stream, line: &[u8]) -> Result<()> {
+        // Output decoded as raw may have bytes in it that aren't UTF-8
+        let line = escape_invalid(line);
+        let object = Value::object([
+            ("time", Value::from(format_time(SystemTime::now()).as_str())),
+            ("stream", Value::from(stream_name(stream))),
+            ("line", Value::from(line.trim_end_matches(['\r', '\n']))),
+        ]);
+        writeln!(self.out, "{}", object)?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> OutputSink for JsonLines<W> {
+    fn write(&self, stream: Stream, bytes: &[u8]) -> Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let state = &mut *state;
+        let partial = match stream {
+            Stream::Stdout => &mut state.stdout,
+            Stream::Stderr => &mut state.stderr,
+        };
+        partial.extend_from_slice(bytes);
+        let mut lines = Vec::new();
+        while let Some(end) = partial.iter().position(|&byte| byte == b'\n') {
+            lines.push(partial.drain(..=end).collect::<Vec<u8>>());
+        }
+        for line in lines {
+            state.emit(stream, &line)?;
+        }
+        state.out.flush()?;
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        for stream in [Stream::Stdout, Stream::Stderr] {
+            let line = match stream {
+                Stream::Stdout => std::mem::take(&mut state.stdout),
+                Stream::Stderr => std::mem::take(&mut state.stderr),
+            };
+            if !line.is_empty() {
+                state.emit(stream, &line)?;
+            }
+        }
+        state.out.flush()?;
+        Ok(())
+    }
+}
+
+fn stream_name(stream: Stream) -> &'static str {
+    match stream {
+        Stream::Stdout => "stdout",
+        Stream::Stderr => "stderr",
+    }
+}
+
+/// Keeps what's written, for callers that want the echoed output rather than to show it
+#[derive(Debug, Default)]
+pub struct Memory {
+    written: Mutex<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Memory {
+    /// What has been written to stdout and to stderr so far
+    pub fn contents(&self) -> (Vec<u8>, Vec<u8>) {
+        self.written
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+impl OutputSink for Memory {
+    fn write(&self, stream: Stream, bytes: &[u8]) -> Result<()> {
+        let mut written = self.written.lock().unwrap_or_else(|e| e.into_inner());
+        match stream {
+            Stream::Stdout => written.0.extend_from_slice(bytes),
+            Stream::Stderr => written.1.extend_from_slice(bytes),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+//! Relaying bytes between two serial ports while reporting what goes each way, for watching a
+//! board talk to another UART device by putting serpico between the two
+use anyhow::{bail, Result};
+use serialport::SerialPort;
+use std::fmt::Write;
+use std::io::{self, ErrorKind, Write as _};
+use std::sync::mpsc;
+use std::thread;
+use std::time::SystemTime;
+
+/// Which way bytes went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    AToB,
+    BToA,
+}
+
+/// Pass bytes between `a` and `b` until either fails, calling `on_data` with each chunk relayed
+pub fn relay(
+    a: Box<dyn SerialPort>,
+    b: Box<dyn SerialPort>,
+    mut on_data: impl FnMut(Direction, SystemTime, &[u8]),
+) -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let a_writer = a.try_clone()?;
+    let b_writer = b.try_clone()?;
+    spawn(a, b_writer, Direction::AToB, sender.clone());
+    spawn(b, a_writer, Direction::BToA, sender);
+
+    // Both threads only stop on an error, which ends the channel once both are gone
+    let mut error = None;
+    for message in receiver {
+        match message {
+            Ok((direction, time, data)) => on_data(direction, time, &data),
+            Err(e) => {
+                error = Some(e);
+                break;
+            }
+        }
+    }
+    match error {
+        Some(e) => bail!(e),
+        None => Ok(()),
+    }
+}
+
+type Message = io::Result<(Direction, SystemTime, Vec<u8>)>;
+
+fn spawn(
+    mut from: Box<dyn SerialPort>,
+    mut to: Box<dyn SerialPort>,
+    direction: Direction,
+    sender: mpsc::Sender<Message>,
+) {
+    thread::spawn(move || {
+        let mut buf = [0; 1024];
+        loop {
+            let result = match from.read(&mut buf) {
+                Ok(0) => continue,
+                Ok(n) => to
+                    .write_all(&buf[..n])
+                    .map(|_| (direction, SystemTime::now(), buf[..n].to_vec())),
+                Err(ref e) if e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) => Err(e),
+            };
+            let failed = result.is_err();
+            if sender.send(result).is_err() || failed {
+                return;
+            }
+        }
+    });
+}
+
+/// A chunk of bytes as hex, followed by the printable ones with the rest as dots
+pub fn format_data(data: &[u8]) -> String {
+    let mut formatted = String::new();
+    for byte in data {
+        write!(formatted, "{:02x} ", byte).unwrap();
+    }
+    formatted.push(' ');
+    formatted.extend(data.iter().map(|&byte| {
+        if byte.is_ascii_graphic() || byte == b' ' {
+            byte as char
+        } else {
+            '.'
+        }
+    }));
+    formatted
+}
@@ -0,0 +1,76 @@
+//! Named snippets of Python for common diagnostics, run with `serpico snippet NAME`. Projects add
+//! their own in the `[snippets]` table of serpico.toml, where they replace built-ins of the same
+//! name.
+//!
+//! ```toml
+//! [snippets]
+//! blink = """
+//! import machine, time
+//! led = machine.Pin("LED", machine.Pin.OUT)
+//! for _ in range(6):
+//!     led.toggle()
+//!     time.sleep(0.25)
+//! """
+//! ```
+
+/// A snippet that comes with serpico
+pub struct Builtin {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub source: &'static str,
+}
+
+pub const BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "i2c-scan",
+        description: "List the addresses of devices on I2C bus 0, with its default pins",
+        source: "import machine
+addresses = machine.I2C(0).scan()
+for address in addresses:
+    print(hex(address))
+print(len(addresses), 'devices found')
+",
+    },
+    Builtin {
+        name: "wifi-status",
+        description: "Show whether WiFi is connected, with its address and signal strength",
+        source: "import network
+wlan = network.WLAN(network.STA_IF)
+if not wlan.active():
+    print('WiFi is off')
+elif not wlan.isconnected():
+    print('WiFi is on, not connected')
+else:
+    ip, netmask, gateway, dns = wlan.ifconfig()
+    print('Connected to', wlan.config('essid'))
+    print('Address', ip, 'netmask', netmask, 'gateway', gateway, 'DNS', dns)
+    try:
+        print('Signal', wlan.status('rssi'), 'dBm')
+    except Exception:
+        pass
+",
+    },
+    Builtin {
+        name: "fs-usage",
+        description: "Show how much of the filesystem is used and free",
+        source: "import os
+stat = os.statvfs('/')
+total = stat[0] * stat[2]
+free = stat[0] * stat[3]
+print('Total', total, 'bytes')
+print('Used ', total - free, 'bytes')
+print('Free ', free, 'bytes')
+",
+    },
+];
+
+/// The source of the snippet `name`, from the configured snippets or else the built-ins
+pub fn find<'a>(configured: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    match configured.iter().find(|(snippet, _)| snippet == name) {
+        Some((_, source)) => Some(source),
+        None => BUILTINS
+            .iter()
+            .find(|builtin| builtin.name == name)
+            .map(|builtin| builtin.source),
+    }
+}
@@ -0,0 +1,279 @@
+//! Serial ports on top of sockets, for devices that are reached through something other than a
+//! local tty. The unix and TCP sockets themselves are only supported on unix.
+#[cfg(unix)]
+use anyhow::{bail, Result};
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+use std::io::{self, ErrorKind, Read, Write};
+#[cfg(unix)]
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// A connected socket that a [`SocketPort`] can be built on
+pub trait Socket: Read + Write + Send + Sized + 'static {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn try_clone(&self) -> io::Result<Self>;
+
+    /// Shut the connection down, which also ends reads in progress on its clones
+    fn shutdown(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// How many bytes can be read without waiting
+    fn bytes_available(&self) -> io::Result<usize>;
+}
+
+/// How many bytes can be read from a file descriptor without waiting
+#[cfg(unix)]
+pub fn bytes_queued(fd: &impl AsRawFd) -> io::Result<usize> {
+    let mut count: libc::c_int = 0;
+    if unsafe { libc::ioctl(fd.as_raw_fd(), libc::FIONREAD, &mut count) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(count as usize)
+}
+
+#[cfg(unix)]
+impl Socket for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn bytes_available(&self) -> io::Result<usize> {
+        bytes_queued(self)
+    }
+}
+
+#[cfg(unix)]
+impl Socket for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn bytes_available(&self) -> io::Result<usize> {
+        bytes_queued(self)
+    }
+}
+
+/// How long connecting to a TCP device may take
+#[cfg(unix)]
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether the device is a `tcp://host:port` URL rather than the path of a serial port
+#[cfg(unix)]
+pub fn is_tcp_url(device: &str) -> bool {
+    device.starts_with("tcp://")
+}
+
+/// Connect to a REPL served over plain TCP at `tcp://host:port`, such as a board's own server or
+/// a serial port bridged with socat
+#[cfg(unix)]
+pub fn connect_tcp(url: &str) -> Result<SocketPort<TcpStream>> {
+    let address = match url.strip_prefix("tcp://") {
+        Some(address) => address.trim_end_matches('/'),
+        None => bail!("Expected a tcp:// URL, got {:?}", url),
+    };
+    if !address.contains(':') {
+        bail!("{} is missing the port, such as tcp://{}:23", url, address);
+    }
+    let addresses = match address.to_socket_addrs() {
+        Ok(addresses) => addresses,
+        Err(e) => bail!("Couldn't resolve {}: {}", address, e),
+    };
+    let mut error = None;
+    for resolved in addresses {
+        match TcpStream::connect_timeout(&resolved, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_nodelay(true)?;
+                return Ok(SocketPort::new(
+                    stream,
+                    url.to_string(),
+                    Duration::from_millis(10),
+                )?);
+            }
+            Err(e) => error = Some(e),
+        }
+    }
+    match error {
+        Some(e) => bail!("Couldn't connect to {}: {}", address, e),
+        None => bail!("Couldn't resolve {}", address),
+    }
+}
+
+/// A socket that behaves like a serial port, so the raw REPL protocol can run over it. Settings
+/// that only make sense for a tty are remembered but otherwise ignored.
+pub struct SocketPort<S: Socket> {
+    socket: S,
+    name: String,
+    timeout: Duration,
+    baud_rate: u32,
+}
+
+impl<S: Socket> SocketPort<S> {
+    pub fn new(socket: S, name: impl Into<String>, timeout: Duration) -> io::Result<Self> {
+        socket.set_read_timeout(Some(timeout))?;
+        Ok(SocketPort {
+            socket,
+            name: name.into(),
+            timeout,
+            baud_rate: 0,
+        })
+    }
+}
+
+impl<S: Socket> Read for SocketPort<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.socket.read(buf) {
+            // Sockets report a timeout as WouldBlock, serial ports as TimedOut
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                Err(io::Error::new(ErrorKind::TimedOut, "Operation timed out"))
+            }
+            Ok(0) if !buf.is_empty() => Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("{} closed the connection", self.name),
+            )),
+            result => result,
+        }
+    }
+}
+
+impl<S: Socket> Write for SocketPort<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl<S: Socket> SerialPort for SocketPort<S> {
+    fn name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.baud_rate)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _data_bits: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _flow_control: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _parity: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _stop_bits: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        // A zero timeout would make the socket block forever
+        self.socket
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self.socket.bytes_available()? as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, _buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(SocketPort {
+            socket: self.socket.try_clone()?,
+            name: self.name.clone(),
+            timeout: self.timeout,
+            baud_rate: self.baud_rate,
+        }))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+//! Running scripts too large for the device to compile at once, as parts executed one after the
+//! other in the same session. The script is split between top-level statements, so that each part
+//! compiles by itself and sees the globals the parts before it defined.
+use anyhow::{bail, Result};
+
+use crate::minify::split_points;
+
+/// Scripts smaller than this compile on any board, so the heap isn't measured for them
+pub const THRESHOLD: usize = 8 * 1024;
+
+/// Roughly how many bytes of heap compiling a byte of source takes
+const COMPILE_FACTOR: usize = 4;
+
+/// When to split a script
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Split {
+    /// When it's large enough that the device's free heap may not be enough to compile it
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl Split {
+    /// Parse `auto`, `always` or `never`
+    pub fn parse(value: &str) -> Result<Split> {
+        match value {
+            "auto" => Ok(Split::Auto),
+            "always" => Ok(Split::Always),
+            "never" => Ok(Split::Never),
+            _ => bail!("Unknown split {:?}, use auto, always or never", value),
+        }
+    }
+}
+
+/// A part of a script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    /// The line of the script the part starts on
+    pub first_line: usize,
+    pub source: String,
+}
+
+/// Whether a script of `size` bytes is likely too large to compile with `free` bytes of heap
+pub fn needed(size: usize, free: usize) -> bool {
+    size * COMPILE_FACTOR > free
+}
+
+/// The size of the parts to split a script into for a device with `free` bytes of heap, leaving
+/// room for what the parts before have allocated
+pub fn part_size(free: usize) -> usize {
+    (free / COMPILE_FACTOR / 2).max(1)
+}
+
+/// Split `source` into parts of about `max_size` bytes. A top-level statement is never split, so
+/// a large class or function makes a part of its own that can be larger.
+pub fn split(source: &str, max_size: usize) -> Vec<Part> {
+    let mut points = split_points(source).into_iter().peekable();
+    let mut parts: Vec<Part> = Vec::new();
+    let mut part = Part {
+        first_line: 1,
+        source: String::new(),
+    };
+    let mut unit = String::new();
+    let mut unit_line = 1;
+    for (index, line) in source.split_inclusive('\n').enumerate() {
+        let number = index + 1;
+        if points.peek() == Some(&number) {
+            points.next();
+            add_unit(&mut parts, &mut part, &unit, unit_line, max_size);
+            unit.clear();
+            unit_line = number;
+        }
+        unit.push_str(line);
+    }
+    add_unit(&mut parts, &mut part, &unit, unit_line, max_size);
+    if !part.source.is_empty() {
+        parts.push(part);
+    }
+    parts
+}
+
+/// Add a top-level statement starting on `line` to the part being built, first starting a new
+/// part if it would make the current one too large
+fn add_unit(parts: &mut Vec<Part>, part: &mut Part, unit: &str, line: usize, max_size: usize) {
+    if !part.source.is_empty() && part.source.len() + unit.len() > max_size {
+        let next = Part {
+            first_line: line,
+            source: String::new(),
+        };
+        parts.push(std::mem::replace(part, next));
+    }
+    part.source.push_str(unit);
+}
@@ -0,0 +1,246 @@
+//! Generating `.pyi` type stubs from the modules built into the firmware of the connected board,
+//! so that editors complete exactly what that build has
+use anyhow::{bail, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::script::quote_list;
+
+/// Lists the modules built into the firmware, frozen ones included
+pub const LIST: &str = "help('modules')\n";
+
+/// Modules that are run at boot rather than imported, or that aren't modules at all
+const SKIPPED: &[&str] = &["__main__", "_boot", "_boot_fat", "boot", "main"];
+
+/// Imports each module of `_modules` and prints a line for it, then a line for each of its
+/// members, tab separated: `F` with the name for functions, `C` for classes, followed by their
+/// members indented, and `V` with the type name for anything else. Modules that fail to import
+/// get an `E` line with the error. `_modules` is set ahead of it.
+const INSPECT: &str = "\
+import gc, sys
+print('P', sys.platform, sys.version, sep='\\t')
+def _serpico_members(value, indent):
+    for name in dir(value):
+        if name.startswith('__'):
+            continue
+        try:
+            member = getattr(value, name)
+        except Exception:
+            continue
+        kind = type(member)
+        if kind is type:
+            print(indent + 'C', name, sep='\\t')
+            if not indent:
+                _serpico_members(member, '  ')
+        elif kind.__name__ == 'module':
+            continue
+        elif callable(member):
+            print(indent + 'F', name, sep='\\t')
+        else:
+            print(indent + 'V', name, kind.__name__, sep='\\t')
+for _name in _modules:
+    try:
+        _module = __import__(_name)
+        for _part in _name.split('.')[1:]:
+            _module = getattr(_module, _part)
+    except Exception as e:
+        print('E', _name, repr(e), sep='\\t')
+        continue
+    print('M', _name, sep='\\t')
+    _serpico_members(_module, '')
+    del _module
+    gc.collect()
+del _serpico_members, _modules, _name
+";
+
+/// What a member of a module or class is, as far as can be told on the device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Kind {
+    Function,
+    Class(Vec<Member>),
+    /// Anything else, with the name of its type
+    Value(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub name: String,
+    pub kind: Kind,
+}
+
+/// A module of the firmware and what's in it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Module {
+    pub name: String,
+    pub members: Vec<Member>,
+}
+
+/// What inspecting the firmware's modules found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Firmware {
+    /// The platform and version, such as `esp32 3.4.0; MicroPython v1.22.0 on 2023-12-27`
+    pub description: String,
+    pub modules: Vec<Module>,
+    /// The modules that couldn't be imported, with why not
+    pub failed: Vec<(String, String)>,
+}
+
+/// The module names in the output of [`LIST`], which lists them in columns ahead of a note about
+/// the modules on the filesystem. Modules of frozen packages are listed as paths, such as
+/// `asyncio/core`.
+pub fn module_names(listing: &str) -> Vec<String> {
+    let mut names: Vec<String> = listing
+        .lines()
+        .take_while(|line| !line.trim_start().starts_with("Plus any modules"))
+        .flat_map(str::split_whitespace)
+        .map(|name| name.trim_end_matches("/__init__").replace('/', "."))
+        .filter(|name| !SKIPPED.contains(&name.as_str()))
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Code that inspects `modules`, for [`parse`]
+pub fn inspect_script(modules: &[String]) -> String {
+    format!("_modules = {}\n{}", quote_list(modules), INSPECT)
+}
+
+/// Read the output of [`inspect_script`]
+pub fn parse(output: &str) -> Result<Firmware> {
+    let mut firmware = Firmware {
+        description: String::new(),
+        modules: Vec::new(),
+        failed: Vec::new(),
+    };
+    for line in output.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+        let class_member = line.starts_with("  ");
+        let fields: Vec<&str> = line.trim_start().split('\t').collect();
+        let kind = match fields.as_slice() {
+            ["P", platform, version] => {
+                firmware.description = format!("{} {}", platform, version);
+                continue;
+            }
+            ["M", name] => {
+                firmware.modules.push(Module {
+                    name: name.to_string(),
+                    members: Vec::new(),
+                });
+                continue;
+            }
+            ["E", name, error] => {
+                firmware.failed.push((name.to_string(), error.to_string()));
+                continue;
+            }
+            ["F", name] => (name, Kind::Function),
+            ["C", name] => (name, Kind::Class(Vec::new())),
+            ["V", name, type_name] => (name, Kind::Value(type_name.to_string())),
+            _ => bail!("Unexpected output while inspecting modules: {:?}", line),
+        };
+        let member = Member {
+            name: kind.0.to_string(),
+            kind: kind.1,
+        };
+        let members = match firmware.modules.last_mut() {
+            Some(module) => &mut module.members,
+            None => bail!("Unexpected output while inspecting modules: {:?}", line),
+        };
+        match (class_member, members.last_mut()) {
+            (
+                true,
+                Some(Member {
+                    kind: Kind::Class(class_members),
+                    ..
+                }),
+            ) => class_members.push(member),
+            (true, _) => bail!("Unexpected output while inspecting modules: {:?}", line),
+            (false, _) => members.push(member),
+        }
+    }
+    Ok(firmware)
+}
+
+/// The annotation for a value of the type named `type_name`, `Any` for types that the stubs
+/// don't know
+fn annotation(type_name: &str) -> &str {
+    match type_name {
+        "int" | "float" | "str" | "bytes" | "bytearray" | "bool" | "tuple" | "list" | "dict"
+        | "set" | "frozenset" => type_name,
+        "NoneType" => "None",
+        _ => "Any",
+    }
+}
+
+impl Module {
+    /// The stub of the module. Functions on the device don't tell what arguments they take, so
+    /// all of them take any.
+    pub fn stub(&self, firmware: &str) -> String {
+        let mut stub = format!(
+            "\"\"\"Stubs for {} on {}, generated by serpico\"\"\"\nfrom typing import Any\n",
+            self.name, firmware
+        );
+        for member in &self.members {
+            match &member.kind {
+                Kind::Function => stub.push_str(&format!(
+                    "\ndef {}(*args: Any, **kwargs: Any) -> Any: ...\n",
+                    member.name
+                )),
+                Kind::Class(members) => {
+                    stub.push_str(&format!("\nclass {}:\n", member.name));
+                    stub.push_str(
+                        "    def __init__(self, *args: Any, **kwargs: Any) -> None: ...\n",
+                    );
+                    for member in members {
+                        match &member.kind {
+                            Kind::Value(type_name) => stub.push_str(&format!(
+                                "    {}: {}\n",
+                                member.name,
+                                annotation(type_name)
+                            )),
+                            _ => stub.push_str(&format!(
+                                "    def {}(self, *args: Any, **kwargs: Any) -> Any: ...\n",
+                                member.name
+                            )),
+                        }
+                    }
+                }
+                Kind::Value(type_name) => {
+                    stub.push_str(&format!("{}: {}\n", member.name, annotation(type_name)))
+                }
+            }
+        }
+        stub
+    }
+}
+
+/// Write a stub for each module under `out`, as `name.pyi`, or `name/__init__.pyi` for a package
+/// whose modules are stubbed too. Returns the files written.
+pub fn write(out: &Path, firmware: &Firmware) -> Result<Vec<PathBuf>> {
+    let mut written = Vec::new();
+    for module in &firmware.modules {
+        let is_package = firmware
+            .modules
+            .iter()
+            .any(|other| other.name.starts_with(&format!("{}.", module.name)));
+        let mut path = out.join(module.name.replace('.', "/"));
+        if is_package {
+            path.push("__init__.pyi");
+        } else {
+            path.set_extension("pyi");
+        }
+        if let Some(dir) = path.parent() {
+            if let Err(e) = fs::create_dir_all(dir) {
+                bail!("Couldn't create {}: {}", dir.display(), e);
+            }
+        }
+        if let Err(e) = fs::write(&path, module.stub(&firmware.description)) {
+            bail!("Couldn't write {}: {}", path.display(), e);
+        }
+        written.push(path);
+    }
+    Ok(written)
+}
@@ -0,0 +1,443 @@
+//! Running scripts on the unix port of MicroPython, spawned as a subprocess at a device such as
+//! `micropython://` or `micropython:///opt/micropython/bin/micropython`. This needs no hardware,
+//! so the whole protocol can be exercised locally.
+//!
+//! The unix port's REPL has no raw REPL, so the subprocess runs a small server that speaks the raw
+//! REPL and raw-paste protocols on its stdin and stdout, much like a board does. On unix, Ctrl-C
+//! written to it is also delivered as SIGINT, which interrupts the running script. Elsewhere it
+//! only reaches the server as a byte, so a script that's running can't be interrupted.
+use anyhow::{bail, Result};
+use std::cell::Cell;
+#[cfg(not(unix))]
+use std::collections::VecDeque;
+#[cfg(unix)]
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+#[cfg(not(unix))]
+use std::process::ChildStdin;
+use std::process::{Child, Command, Stdio};
+#[cfg(not(unix))]
+use std::sync::{Arc, Condvar, Mutex};
+#[cfg(not(unix))]
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::socket::{Socket, SocketPort};
+
+const SCHEME: &str = "micropython://";
+
+/// The interpreter run for a device of just `micropython://`, found on the PATH
+const DEFAULT_PROGRAM: &str = "micropython";
+
+/// How long the interpreter may take to start serving
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The raw REPL server run by the interpreter. It sticks to what both MicroPython and CPython
+/// support, so it can be tried with either.
+const SERVER: &str = r#"
+import sys
+try:
+    from sys import print_exception
+except ImportError:
+    import traceback
+    def print_exception(e, file):
+        traceback.print_exception(type(e), e, e.__traceback__, file=file)
+
+WINDOW = 128
+BANNER = b"MicroPython unix port, served for serpico\r\n"
+RAW_BANNER = b"raw REPL; CTRL-B to exit\r\n>"
+
+def write(data):
+    sys.stdout.flush()
+    sys.stdout.buffer.write(data)
+    sys.stdout.buffer.flush()
+
+def getc():
+    c = sys.stdin.buffer.read(1)
+    if not c:
+        raise SystemExit
+    return c[0]
+
+class Server:
+    def __init__(self):
+        self.globals = {"__name__": "__main__"}
+        self.raw = False
+        self.line = bytearray()
+
+    def execute(self, source, mode):
+        try:
+            exec(compile(source.decode(), "<stdin>", mode), self.globals)
+        except BaseException as e:
+            if self.raw:
+                write(b"\x04")
+            print_exception(e, sys.stdout)
+            if self.raw:
+                write(b"\x04>")
+            return
+        if self.raw:
+            write(b"\x04\x04>")
+
+    def paste(self):
+        source = bytearray()
+        remaining = WINDOW
+        while True:
+            c = getc()
+            if c == 4:
+                return source
+            source.append(c)
+            remaining -= 1
+            if remaining == 0:
+                write(b"\x01")
+                remaining = WINDOW
+
+    def friendly(self, c):
+        if c == 1:
+            self.raw = True
+            self.line = bytearray()
+            write(b"\r\n" + RAW_BANNER)
+        elif c == 3:
+            self.line = bytearray()
+            write(b"\r\n>>> ")
+        elif c == 4 and not self.line:
+            self.globals = {"__name__": "__main__"}
+            write(b"\r\nMPY: soft reboot\r\n" + BANNER + b">>> ")
+        elif c == 13:
+            write(b"\r\n")
+            if self.line:
+                self.execute(self.line, "single")
+            self.line = bytearray()
+            write(b">>> ")
+        elif c in (8, 127):
+            if self.line:
+                self.line = self.line[:-1]
+                write(b"\x08 \x08")
+        elif c >= 32:
+            self.line.append(c)
+            write(bytes([c]))
+
+    def step(self):
+        c = getc()
+        if not self.raw:
+            self.friendly(c)
+        elif c == 2:
+            self.raw = False
+            self.line = bytearray()
+            write(b"\r\n" + BANNER + b">>> ")
+        elif c == 1:
+            self.line = bytearray()
+            write(RAW_BANNER)
+        elif c == 3:
+            self.line = bytearray()
+        elif c == 4 and not self.line:
+            self.globals = {"__name__": "__main__"}
+            write(b"soft reboot\r\n" + RAW_BANNER)
+        elif c == 4:
+            write(b"OK")
+            source, self.line = self.line, bytearray()
+            self.execute(source, "exec")
+        elif c == 5 and not self.line:
+            if getc() == 65 and getc() == 1:
+                write(b"R\x01" + bytes([WINDOW & 0xFF, WINDOW >> 8]) + b"\x01")
+                source = self.paste()
+                write(b"\x04")
+                self.execute(source, "exec")
+        else:
+            self.line.append(c)
+
+server = Server()
+greeted = False
+# Ctrl-C raises KeyboardInterrupt wherever the server is, which mustn't end it
+while True:
+    try:
+        if not greeted:
+            greeted = True
+            write(BANNER + b">>> ")
+        server.step()
+    except KeyboardInterrupt:
+        pass
+"#;
+
+/// Whether the device is a `micropython://` URL rather than the path of a serial port
+pub fn is_url(device: &str) -> bool {
+    device.starts_with(SCHEME)
+}
+
+/// Spawn the interpreter of a `micropython://` URL, the one on the PATH if the URL has no path
+pub fn spawn(url: &str) -> Result<SocketPort<Subprocess>> {
+    let program = match url.strip_prefix(SCHEME) {
+        Some("") => DEFAULT_PROGRAM,
+        Some(program) => program,
+        None => bail!("Expected a {} URL, got {:?}", SCHEME, url),
+    };
+    let child = match Command::new(program)
+        .arg("-c")
+        .arg(SERVER)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => bail!("Couldn't start {}: {}", program, e),
+    };
+    let mut process = Subprocess::new(child);
+    process.set_read_timeout(Some(START_TIMEOUT))?;
+
+    // Ctrl-C is only safe to deliver once the server is ready to catch it
+    let start = Instant::now();
+    let mut started = Vec::new();
+    let mut buf = [0; 256];
+    while !started.ends_with(b">>> ") {
+        if start.elapsed() > START_TIMEOUT {
+            bail!("{} didn't start serving the REPL", program);
+        }
+        match process.read(&mut buf) {
+            Ok(0) => bail!("{} exited before serving the REPL", program),
+            Ok(n) => started.extend_from_slice(&buf[..n]),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => bail!(e),
+        }
+    }
+    Ok(SocketPort::new(
+        process,
+        format!("{} (unix port)", program),
+        Duration::from_millis(10),
+    )?)
+}
+
+/// The pipes to a spawned interpreter, which is killed when the one that spawned it is dropped
+#[cfg(unix)]
+pub struct Subprocess {
+    /// Only set for the original, not for clones
+    child: Option<Child>,
+    pid: u32,
+    stdin: File,
+    stdout: File,
+    timeout: Cell<Option<Duration>>,
+}
+
+#[cfg(unix)]
+impl Subprocess {
+    fn new(mut child: Child) -> Subprocess {
+        let stdin = OwnedFd::from(child.stdin.take().unwrap());
+        let stdout = OwnedFd::from(child.stdout.take().unwrap());
+        Subprocess {
+            pid: child.id(),
+            child: Some(child),
+            stdin: File::from(stdin),
+            stdout: File::from(stdout),
+            timeout: Cell::new(None),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Read for Subprocess {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout_ms = match self.timeout.get() {
+            Some(timeout) => timeout.as_millis().min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        let mut fds = libc::pollfd {
+            fd: self.stdout.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut fds, 1, timeout_ms) } {
+            ready if ready < 0 => Err(io::Error::last_os_error()),
+            // Sockets report a timeout like this too, which is what a SocketPort expects
+            0 => Err(io::Error::new(ErrorKind::WouldBlock, "Operation timed out")),
+            _ => self.stdout.read(buf),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Write for Subprocess {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin.write_all(buf)?;
+        if buf.contains(&0x03) {
+            unsafe { libc::kill(self.pid as libc::pid_t, libc::SIGINT) };
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin.flush()
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Subprocess {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stdout.as_raw_fd()
+    }
+}
+
+#[cfg(unix)]
+impl Socket for Subprocess {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeout.set(timeout);
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Subprocess {
+            child: None,
+            pid: self.pid,
+            stdin: self.stdin.try_clone()?,
+            stdout: self.stdout.try_clone()?,
+            timeout: self.timeout.clone(),
+        })
+    }
+
+    fn bytes_available(&self) -> io::Result<usize> {
+        crate::socket::bytes_queued(&self.stdout)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Subprocess {
+    fn drop(&mut self) {
+        if let Some(child) = self.child.as_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// The pipes to a spawned interpreter, which is killed when the one that spawned it is dropped.
+/// Without poll, what the interpreter writes is read into a queue by a thread.
+#[cfg(not(unix))]
+pub struct Subprocess {
+    child: Arc<Mutex<Child>>,
+    /// Only set for the original, not for clones
+    original: bool,
+    stdin: Arc<Mutex<ChildStdin>>,
+    output: Arc<Output>,
+    timeout: Cell<Option<Duration>>,
+}
+
+/// What the interpreter has written that hasn't been read yet
+#[cfg(not(unix))]
+#[derive(Default)]
+struct Output {
+    queue: Mutex<Queue>,
+    arrived: Condvar,
+}
+
+#[cfg(not(unix))]
+#[derive(Default)]
+struct Queue {
+    received: VecDeque<u8>,
+    /// Whether the interpreter closed its stdout, after which nothing more arrives
+    closed: bool,
+}
+
+#[cfg(not(unix))]
+impl Subprocess {
+    fn new(mut child: Child) -> Subprocess {
+        let stdin = child.stdin.take().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        let output = Arc::new(Output::default());
+        let reader = output.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 1024];
+            loop {
+                let n = stdout.read(&mut buf).unwrap_or(0);
+                let mut queue = reader.queue.lock().unwrap();
+                queue.received.extend(&buf[..n]);
+                queue.closed = n == 0;
+                reader.arrived.notify_all();
+                if queue.closed {
+                    break;
+                }
+            }
+        });
+        Subprocess {
+            child: Arc::new(Mutex::new(child)),
+            original: true,
+            stdin: Arc::new(Mutex::new(stdin)),
+            output,
+            timeout: Cell::new(None),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+impl Read for Subprocess {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = self.timeout.get().map(|timeout| Instant::now() + timeout);
+        let mut queue = self.output.queue.lock().unwrap();
+        while queue.received.is_empty() && !queue.closed {
+            queue = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        // Sockets report a timeout like this too, which is what a SocketPort
+                        // expects
+                        return Err(io::Error::new(ErrorKind::WouldBlock, "Operation timed out"));
+                    }
+                    self.output
+                        .arrived
+                        .wait_timeout(queue, deadline - now)
+                        .unwrap()
+                        .0
+                }
+                None => self.output.arrived.wait(queue).unwrap(),
+            };
+        }
+        let n = buf.len().min(queue.received.len());
+        for (slot, byte) in buf.iter_mut().zip(queue.received.drain(..n)) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(not(unix))]
+impl Write for Subprocess {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin.lock().unwrap().write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin.lock().unwrap().flush()
+    }
+}
+
+#[cfg(not(unix))]
+impl Socket for Subprocess {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeout.set(timeout);
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Subprocess {
+            child: self.child.clone(),
+            original: false,
+            stdin: self.stdin.clone(),
+            output: self.output.clone(),
+            timeout: self.timeout.clone(),
+        })
+    }
+
+    fn bytes_available(&self) -> io::Result<usize> {
+        Ok(self.output.queue.lock().unwrap().received.len())
+    }
+}
+
+#[cfg(not(unix))]
+impl Drop for Subprocess {
+    fn drop(&mut self) {
+        if self.original {
+            let mut child = self.child.lock().unwrap();
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Checking scripts for syntax errors on the host before sending them, with the local Python's
+//! compiler, so that a typo fails at once with the line in the local file rather than after a
+//! round trip to the device.
+//!
+//! CPython accepts a little that MicroPython doesn't, such as `match` statements, so a script
+//! that passes may still fail to compile on the device. What CPython rejects, MicroPython does too.
+use anyhow::{bail, Result};
+use std::env;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Compiles the script read from stdin, printing the line, column, message and text of the
+/// syntax error if there is one
+const CHECK: &str = r#"
+import sys
+try:
+    compile(sys.stdin.buffer.read(), "<script>", "exec", dont_inherit=True)
+except SyntaxError as e:
+    sys.stdout.write("%d\n%d\n%s\n%s" % (e.lineno or 0, e.offset or 0, e.msg, (e.text or "").rstrip()))
+    sys.exit(1)
+"#;
+
+/// A syntax error in a local script
+#[derive(Debug)]
+pub struct SyntaxError {
+    pub path: PathBuf,
+    pub line: usize,
+    /// The column the error was found at, counting from 1, if it's known
+    pub column: Option<usize>,
+    pub msg: String,
+    /// The text of the line
+    pub text: String,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.path.display(), self.line, self.msg)?;
+        if !self.text.is_empty() {
+            let indent = self.text.len() - self.text.trim_start().len();
+            write!(f, "\n    {}", self.text.trim())?;
+            if let Some(column) = self.column {
+                let caret = column.saturating_sub(indent + 1);
+                write!(f, "\n    {}^", " ".repeat(caret))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+/// The Python to check with, `$PYTHON` or `python3` from the path
+fn python() -> PathBuf {
+    env::var_os("PYTHON")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("python3"))
+}
+
+/// Check that `source`, read from `path`, compiles, failing with a [`SyntaxError`] if it doesn't
+pub fn check(path: &Path, source: &str) -> Result<()> {
+    let mut child = match Command::new(python())
+        .arg("-c")
+        .arg(CHECK)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => bail!(
+            "Couldn't run {} to check scripts: {}",
+            python().display(),
+            e
+        ),
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(source.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut fields = stdout.splitn(4, '\n');
+    let line = fields.next().and_then(|line| line.parse().ok());
+    let column = fields.next().and_then(|column| column.parse().ok());
+    match (output.status.code(), line, fields.next()) {
+        (Some(1), Some(line), Some(msg)) => Err(SyntaxError {
+            path: path.to_path_buf(),
+            line,
+            column: column.filter(|&column| column > 0),
+            msg: msg.to_string(),
+            text: fields.next().unwrap_or_default().to_string(),
+        }
+        .into()),
+        _ => bail!(
+            "Couldn't check {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+    }
+}
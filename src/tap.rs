@@ -0,0 +1,176 @@
+//! Ports that let something watch the traffic of the port they wrap
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Watches the traffic of a [`TapPort`]
+pub trait Tap: Send {
+    /// Bytes were read from the device
+    fn read(&mut self, bytes: &[u8]);
+    /// Bytes were written to the device
+    fn write(&mut self, bytes: &[u8]);
+    /// A read timed out without the device sending anything
+    fn timed_out(&mut self) {}
+    /// Something other than data happened, such as a control line changing
+    fn event(&mut self, _event: &str) {}
+}
+
+/// A port that passes everything written to and read from the port it wraps to a [`Tap`]. Clones
+/// of the port share the tap.
+pub struct TapPort {
+    port: Box<dyn SerialPort>,
+    tap: Arc<Mutex<dyn Tap>>,
+}
+
+impl TapPort {
+    pub fn new(port: Box<dyn SerialPort>, tap: impl Tap + 'static) -> TapPort {
+        TapPort {
+            port,
+            tap: Arc::new(Mutex::new(tap)),
+        }
+    }
+
+    fn tap(&self) -> MutexGuard<'_, dyn Tap + 'static> {
+        self.tap.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Read for TapPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.port.read(buf);
+        match &result {
+            Ok(count) if *count > 0 => self.tap().read(&buf[..*count]),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => self.tap().timed_out(),
+            Err(e) => self.tap().event(&format!("read error: {}", e)),
+        }
+        result
+    }
+}
+
+impl Write for TapPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.port.write(buf);
+        match &result {
+            Ok(count) => self.tap().write(&buf[..*count]),
+            Err(e) => self.tap().event(&format!("write error: {}", e)),
+        }
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl SerialPort for TapPort {
+    fn name(&self) -> Option<String> {
+        self.port.name()
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        self.port.baud_rate()
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        self.port.data_bits()
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        self.port.flow_control()
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        self.port.parity()
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        self.port.stop_bits()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.port.timeout()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.tap().event(&format!("baud rate {}", baud_rate));
+        self.port.set_baud_rate(baud_rate)
+    }
+
+    fn set_data_bits(&mut self, data_bits: DataBits) -> serialport::Result<()> {
+        self.port.set_data_bits(data_bits)
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        self.port.set_flow_control(flow_control)
+    }
+
+    fn set_parity(&mut self, parity: Parity) -> serialport::Result<()> {
+        self.port.set_parity(parity)
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: StopBits) -> serialport::Result<()> {
+        self.port.set_stop_bits(stop_bits)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.port.set_timeout(timeout)
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.tap().event(&format!("RTS {}", u8::from(level)));
+        self.port.write_request_to_send(level)
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.tap().event(&format!("DTR {}", u8::from(level)));
+        self.port.write_data_terminal_ready(level)
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        self.port.read_clear_to_send()
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        self.port.read_data_set_ready()
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        self.port.read_ring_indicator()
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        self.port.read_carrier_detect()
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        self.port.bytes_to_read()
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        self.port.bytes_to_write()
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        self.tap().event(&format!("clear {:?}", buffer_to_clear));
+        self.port.clear(buffer_to_clear)
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(TapPort {
+            port: self.port.try_clone()?,
+            tap: self.tap.clone(),
+        }))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        self.tap().event("break on");
+        self.port.set_break()
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        self.tap().event("break off");
+        self.port.clear_break()
+    }
+}
@@ -0,0 +1,327 @@
+//! Just enough of the ustar format for archiving the device's files: directories and regular
+//! files, with their paths and sizes. Paths too long for a ustar header are written as GNU tar
+//! does, and read back from either GNU tar's long names or pax extended headers.
+use anyhow::{bail, Result};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const BLOCK_SIZE: usize = 512;
+const NAME_SIZE: usize = 100;
+const PREFIX_SIZE: usize = 155;
+
+/// The name GNU tar gives the members holding the long names of the members after them
+const LONG_NAME: &[u8] = b"././@LongLink";
+
+/// A file or directory in an archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    /// The path relative to the archive's root, with `/` separators
+    pub path: String,
+    /// The contents of a file, `None` for a directory
+    pub data: Option<Vec<u8>>,
+}
+
+/// Writes an archive a member at a time
+pub struct Writer<W: Write> {
+    out: W,
+    /// The modification time given to every member
+    mtime: u64,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(out: W) -> Writer<W> {
+        let mtime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Writer { out, mtime }
+    }
+
+    pub fn dir(&mut self, path: &str) -> Result<()> {
+        self.write_header(&format!("{}/", path.trim_end_matches('/')), 0, b'5')
+    }
+
+    pub fn file(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        self.write_header(path, data.len() as u64, b'0')?;
+        self.write_data(data)
+    }
+
+    /// End the archive with the two empty blocks that mark its end
+    pub fn finish(mut self) -> Result<W> {
+        self.out.write_all(&[0; BLOCK_SIZE * 2])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    /// Write the header of a member, after a GNU long name member if the path doesn't fit in it
+    fn write_header(&mut self, path: &str, size: u64, kind: u8) -> Result<()> {
+        let header = match split_path(path) {
+            Some((prefix, name)) => self.header(prefix.as_bytes(), name.as_bytes(), size, kind),
+            None => {
+                let mut long_name = path.as_bytes().to_vec();
+                long_name.push(0);
+                let header = self.header(b"", LONG_NAME, long_name.len() as u64, b'L');
+                self.out.write_all(&header)?;
+                self.write_data(&long_name)?;
+                let name = &path.as_bytes()[..NAME_SIZE];
+                self.header(b"", name, size, kind)
+            }
+        };
+        self.out.write_all(&header)?;
+        Ok(())
+    }
+
+    /// Write the data of a member, padded to a whole block
+    fn write_data(&mut self, data: &[u8]) -> Result<()> {
+        self.out.write_all(data)?;
+        let padding = (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+        self.out.write_all(&vec![0; padding])?;
+        Ok(())
+    }
+
+    fn header(&self, prefix: &[u8], name: &[u8], size: u64, kind: u8) -> [u8; BLOCK_SIZE] {
+        let mut header = [0; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name);
+        let mode = if kind == b'5' { 0o755 } else { 0o644 };
+        octal(&mut header[100..108], mode);
+        octal(&mut header[108..116], 0);
+        octal(&mut header[116..124], 0);
+        octal(&mut header[124..136], size);
+        octal(&mut header[136..148], self.mtime);
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[345..345 + prefix.len()].copy_from_slice(prefix);
+        // The checksum is summed with its own field as spaces
+        header[148..156].copy_from_slice(b"        ");
+        let checksum: u64 = header.iter().map(|&byte| u64::from(byte)).sum();
+        octal(&mut header[148..155], checksum);
+        header[155] = b' ';
+        header
+    }
+}
+
+/// `path` as the prefix and name fields of a header, split at a `/` where it's too long for the
+/// name alone. `None` if it doesn't fit in them.
+fn split_path(path: &str) -> Option<(&str, &str)> {
+    if path.len() <= NAME_SIZE {
+        return Some(("", path));
+    }
+    for (index, _) in path.match_indices('/') {
+        let (prefix, name) = (&path[..index], &path[index + 1..]);
+        if prefix.len() <= PREFIX_SIZE && !name.is_empty() && name.len() <= NAME_SIZE {
+            return Some((prefix, name));
+        }
+    }
+    None
+}
+
+/// Write `value` into `field` as zero padded octal, ending with a NUL
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[field.len() - 1] = 0;
+}
+
+/// The text of a NUL terminated header field
+fn text(field: &[u8]) -> String {
+    let end = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).to_string()
+}
+
+/// The path in the records of a pax extended header, if it has one
+fn pax_path(records: &[u8], offset: usize) -> Result<Option<String>> {
+    let mut path = None;
+    let mut rest = records;
+    // Each record is `LENGTH KEY=VALUE\n`, its length counting all of it
+    while !rest.is_empty() && rest[0] != 0 {
+        let space = rest.iter().position(|&byte| byte == b' ');
+        let length = space
+            .and_then(|space| std::str::from_utf8(&rest[..space]).ok())
+            .and_then(|length| length.parse::<usize>().ok());
+        let (space, length) = match (space, length) {
+            (Some(space), Some(length)) if space < length && length <= rest.len() => {
+                (space, length)
+            }
+            _ => bail!("Damaged pax header at offset {}", offset),
+        };
+        let record = &rest[space + 1..length];
+        if let Some(value) = record.strip_prefix(b"path=") {
+            let value = value.strip_suffix(b"\n").unwrap_or(value);
+            path = Some(String::from_utf8_lossy(value).to_string());
+        }
+        rest = &rest[length..];
+    }
+    Ok(path)
+}
+
+/// The directories and regular files of the archive `data`. Other members, such as links, are
+/// skipped. Fails on a member outside of the archive's root, whose path goes up with `..`.
+pub fn read(data: &[u8]) -> Result<Vec<Member>> {
+    let mut members = Vec::new();
+    // The path given for the next member by a GNU long name or pax extended header
+    let mut long_path = None;
+    let mut offset = 0;
+    while offset + BLOCK_SIZE <= data.len() {
+        let header = &data[offset..offset + BLOCK_SIZE];
+        if header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        let expected: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(index, &byte)| match index {
+                148..=155 => u64::from(b' '),
+                _ => u64::from(byte),
+            })
+            .sum();
+        let checksum = u64::from_str_radix(text(&header[148..156]).trim(), 8);
+        if checksum != Ok(expected) {
+            bail!("Not a tar archive, or a damaged one, at offset {}", offset);
+        }
+        let size = match u64::from_str_radix(text(&header[124..136]).trim(), 8) {
+            Ok(size) => size as usize,
+            Err(_) => bail!("Damaged tar header at offset {}", offset),
+        };
+        let name = text(&header[..NAME_SIZE]);
+        let prefix = text(&header[345..345 + PREFIX_SIZE]);
+        let start = offset + BLOCK_SIZE;
+        if start + size > data.len() {
+            bail!("The tar archive ends in the middle of {}", name);
+        }
+        let contents = &data[start..start + size];
+        let next = start + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        match header[156] {
+            b'L' => {
+                long_path = Some(text(contents));
+                offset = next;
+                continue;
+            }
+            b'x' => {
+                long_path = pax_path(contents, offset)?.or(long_path);
+                offset = next;
+                continue;
+            }
+            // Global pax headers only hold defaults, such as times, that aren't kept
+            b'g' => {
+                offset = next;
+                continue;
+            }
+            _ => {}
+        }
+        let path = match (long_path.take(), prefix.is_empty()) {
+            (Some(path), _) => path,
+            (None, true) => name,
+            (None, false) => format!("{}/{}", prefix, name),
+        };
+        if path.split('/').any(|component| component == "..") {
+            bail!("{} in the archive is outside of it", path);
+        }
+        match header[156] {
+            b'0' | 0 if !path.ends_with('/') => members.push(Member {
+                path,
+                data: Some(contents.to_vec()),
+            }),
+            b'5' | b'0' | 0 => members.push(Member {
+                path: path.trim_end_matches('/').to_string(),
+                data: None,
+            }),
+            _ => {}
+        }
+        offset = next;
+    }
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(members: &[Member]) -> Vec<u8> {
+        let mut writer = Writer::new(Vec::new());
+        for member in members {
+            match &member.data {
+                Some(data) => writer.file(&member.path, data).unwrap(),
+                None => writer.dir(&member.path).unwrap(),
+            }
+        }
+        writer.finish().unwrap()
+    }
+
+    fn file(path: &str, data: &[u8]) -> Member {
+        Member {
+            path: path.to_string(),
+            data: Some(data.to_vec()),
+        }
+    }
+
+    fn dir(path: &str) -> Member {
+        Member {
+            path: path.to_string(),
+            data: None,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let members = vec![
+            dir("lib"),
+            file("lib/empty.py", b""),
+            file("main.py", b"print('hello')\n"),
+            file("data.bin", &[0xaa; 1500]),
+        ];
+        let data = archive(&members);
+        assert_eq!(data.len() % BLOCK_SIZE, 0);
+        assert_eq!(read(&data).unwrap(), members);
+    }
+
+    #[test]
+    fn long_paths() {
+        // Split into the prefix and name fields
+        let prefixed = format!("{}/{}.py", "a".repeat(120), "b".repeat(60));
+        // Too long for those, so written under a GNU long name
+        let long = format!("{}/{}.py", "c".repeat(200), "d".repeat(120));
+        let long_dir = "e/".repeat(80);
+        let members = vec![
+            file(&prefixed, b"1"),
+            file(&long, b"2"),
+            dir(long_dir.trim_end_matches('/')),
+        ];
+        assert_eq!(read(&archive(&members)).unwrap(), members);
+    }
+
+    #[test]
+    fn pax_extended_header() {
+        let path = format!("{}.py", "f".repeat(150));
+        let record = format!(" path={}\n", path);
+        // The length counts its own digits
+        let record = format!("{}{}", record.len() + 3, record);
+        let writer = Writer::new(Vec::new());
+        let mut data = writer
+            .header(b"", b"PaxHeader", record.len() as u64, b'x')
+            .to_vec();
+        data.extend_from_slice(record.as_bytes());
+        data.resize(BLOCK_SIZE * 2, 0);
+        data.extend_from_slice(&writer.header(b"", &path.as_bytes()[..NAME_SIZE], 1, b'0'));
+        data.extend_from_slice(b"x");
+        data.resize(BLOCK_SIZE * 4, 0);
+        assert_eq!(read(&data).unwrap(), vec![file(&path, b"x")]);
+    }
+
+    #[test]
+    fn outside_of_the_archive() {
+        let data = archive(&[file("lib/../../boot.py", b"")]);
+        let error = read(&data).unwrap_err();
+        assert!(error.to_string().contains("outside of it"));
+    }
+
+    #[test]
+    fn damaged() {
+        let mut data = archive(&[file("main.py", b"")]);
+        data[0] = b'x';
+        assert!(read(&data).is_err());
+    }
+}
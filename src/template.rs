@@ -0,0 +1,33 @@
+//! Substituting `{{NAME}}` placeholders in a script before it's sent to the device
+use std::collections::BTreeMap;
+
+/// Replace each `{{NAME}}` placeholder with the value defined for `NAME`, as it is. Spaces inside
+/// the braces are allowed. Placeholders of names that aren't defined are left alone, as Python
+/// uses `{{` and `}}` for literal braces in format strings.
+pub fn substitute(source: &str, defines: &[(String, String)]) -> String {
+    let defines: BTreeMap<&str, &str> = defines
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+    let mut substituted = String::with_capacity(source.len());
+    let mut rest = source;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        let value = after
+            .find("}}")
+            .and_then(|end| Some((end, *defines.get(after[..end].trim())?)));
+        match value {
+            Some((end, value)) => {
+                substituted.push_str(&rest[..start]);
+                substituted.push_str(value);
+                rest = &after[end + 2..];
+            }
+            None => {
+                substituted.push_str(&rest[..start + 2]);
+                rest = after;
+            }
+        }
+    }
+    substituted.push_str(rest);
+    substituted
+}
@@ -0,0 +1,111 @@
+//! Putting the host terminal into raw mode, so key presses reach the device as they are typed
+use anyhow::{bail, Result};
+use std::io::Write;
+
+/// Raw mode for the terminal on stdin, restoring the original settings when dropped
+pub struct RawTerminal {
+    #[cfg(unix)]
+    original: libc::termios,
+}
+
+impl RawTerminal {
+    /// Raw mode for input, newlines printed on the host still return the cursor
+    pub fn enable() -> Result<RawTerminal> {
+        RawTerminal::enable_with(true)
+    }
+
+    /// Raw mode for output too, so escape sequences and bare newlines from the device reach the
+    /// terminal untouched
+    pub fn enable_passthrough() -> Result<RawTerminal> {
+        RawTerminal::enable_with(false)
+    }
+
+    #[cfg(unix)]
+    fn enable_with(output_processing: bool) -> Result<RawTerminal> {
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            bail!("Unable to read terminal settings, is stdin a terminal?");
+        }
+
+        let mut raw = original;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if output_processing {
+            raw.c_oflag |= libc::OPOST;
+        }
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            bail!("Unable to put terminal into raw mode");
+        }
+
+        Ok(RawTerminal { original })
+    }
+
+    #[cfg(not(unix))]
+    fn enable_with(_output_processing: bool) -> Result<RawTerminal> {
+        bail!("Raw terminal mode is not supported on this platform");
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+/// Bracketed paste for the terminal on stdout, so that pasted text comes marked out from what's
+/// typed, turned off again when dropped
+pub struct BracketedPaste;
+
+impl BracketedPaste {
+    pub fn enable() -> Result<BracketedPaste> {
+        let mut stdout = std::io::stdout();
+        stdout.write_all(b"\x1b[?2004h")?;
+        stdout.flush()?;
+        Ok(BracketedPaste)
+    }
+}
+
+impl Drop for BracketedPaste {
+    fn drop(&mut self) {
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(b"\x1b[?2004l");
+        let _ = stdout.flush();
+    }
+}
+
+/// Wait up to `timeout_ms` for stdin to have bytes available, then read what is there
+#[cfg(unix)]
+pub fn read_stdin(buf: &mut [u8], timeout_ms: i32) -> Result<usize> {
+    let mut fds = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let ready = unsafe { libc::poll(&mut fds, 1, timeout_ms) };
+    if ready < 0 {
+        let e = std::io::Error::last_os_error();
+        if e.kind() == std::io::ErrorKind::Interrupted {
+            return Ok(0);
+        }
+        bail!(e);
+    }
+    if ready == 0 {
+        return Ok(0);
+    }
+
+    let n = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr().cast(), buf.len()) };
+    if n < 0 {
+        bail!(std::io::Error::last_os_error());
+    }
+    if n == 0 {
+        bail!("stdin closed");
+    }
+    Ok(n as usize)
+}
+
+#[cfg(not(unix))]
+pub fn read_stdin(_buf: &mut [u8], _timeout_ms: i32) -> Result<usize> {
+    bail!("Reading the terminal is not supported on this platform");
+}
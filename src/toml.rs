@@ -0,0 +1,527 @@
+//! Just enough TOML for `serpico.toml`: tables, dotted keys, strings, numbers, booleans, arrays
+//! and inline tables. Dates and arrays of tables aren't supported.
+use anyhow::{bail, Result};
+
+/// A parsed TOML value. Tables keep their keys in order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// The value of `key` if this is a table that has it
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Table(entries) => entries
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// What kind of value this is, for errors
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Float(_) => "a float",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+            Value::Table(_) => "a table",
+        }
+    }
+
+    fn entries_mut(&mut self) -> Option<&mut Vec<(String, Value)>> {
+        match self {
+            Value::Table(entries) => Some(entries),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a TOML document into its root table
+pub fn parse(text: &str) -> Result<Value> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        position: 0,
+    };
+    let mut root = Value::Table(Vec::new());
+    // The keys of the table that key/value pairs currently go into
+    let mut current: Vec<String> = Vec::new();
+
+    loop {
+        parser.blank();
+        if parser.peek().is_none() {
+            return Ok(root);
+        }
+        if let Err(e) = parser.statement(&mut root, &mut current) {
+            bail!("Line {}: {}", parser.line(), e);
+        }
+    }
+}
+
+/// The entries of the table at `path` under `root`, creating the tables along the way
+fn table<'a>(root: &'a mut Value, path: &[String]) -> Result<&'a mut Vec<(String, Value)>> {
+    let mut entries = root.entries_mut().unwrap();
+    for key in path {
+        let index = match entries.iter().position(|(name, _)| name == key) {
+            Some(index) => index,
+            None => {
+                entries.push((key.clone(), Value::Table(Vec::new())));
+                entries.len() - 1
+            }
+        };
+        entries = match entries[index].1.entries_mut() {
+            Some(entries) => entries,
+            None => bail!("{:?} isn't a table", key),
+        };
+    }
+    Ok(entries)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Result<char> {
+        match self.peek() {
+            Some(c) => {
+                self.position += 1;
+                Ok(c)
+            }
+            None => bail!("Unexpected end of file"),
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        self.whitespace();
+        match self.next()? {
+            c if c == expected => Ok(()),
+            c => bail!("Expected {:?}, got {:?}", expected, c),
+        }
+    }
+
+    /// The line the parser is on, counting from 1
+    fn line(&self) -> usize {
+        self.chars[..self.position]
+            .iter()
+            .filter(|&&c| c == '\n')
+            .count()
+            + 1
+    }
+
+    fn whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.position += 1;
+        }
+    }
+
+    /// Skip whitespace, newlines and comments
+    fn blank(&mut self) {
+        loop {
+            self.whitespace();
+            match self.peek() {
+                Some('\r' | '\n') => self.position += 1,
+                Some('#') => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.position += 1;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// A table header or a key/value pair, up to the end of its line
+    fn statement(&mut self, root: &mut Value, current: &mut Vec<String>) -> Result<()> {
+        if self.peek() == Some('[') {
+            self.position += 1;
+            if self.peek() == Some('[') {
+                bail!("Arrays of tables aren't supported");
+            }
+            *current = self.keys()?;
+            self.expect(']')?;
+            table(root, current)?;
+        } else {
+            let keys = self.keys()?;
+            self.expect('=')?;
+            let value = self.value()?;
+            let mut path = current.clone();
+            path.extend_from_slice(&keys[..keys.len() - 1]);
+            let entries = table(root, &path)?;
+            let key = &keys[keys.len() - 1];
+            if entries.iter().any(|(name, _)| name == key) {
+                bail!("Duplicate key {:?}", key);
+            }
+            entries.push((key.clone(), value));
+        }
+
+        self.whitespace();
+        match self.peek() {
+            None | Some('#' | '\r' | '\n') => Ok(()),
+            Some(c) => bail!("Unexpected {:?}", c),
+        }
+    }
+
+    /// A key, which may be dotted such as `sync.include`
+    fn keys(&mut self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        loop {
+            self.whitespace();
+            let key = match self.peek() {
+                Some('"') => self.basic_string()?,
+                Some('\'') => self.literal_string()?,
+                _ => {
+                    let start = self.position;
+                    while self
+                        .peek()
+                        .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                    {
+                        self.position += 1;
+                    }
+                    if start == self.position {
+                        bail!("Expected a key");
+                    }
+                    self.chars[start..self.position].iter().collect()
+                }
+            };
+            keys.push(key);
+            self.whitespace();
+            if self.peek() != Some('.') {
+                return Ok(keys);
+            }
+            self.position += 1;
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        self.whitespace();
+        match self.peek() {
+            Some('"') if self.starts_with("\"\"\"") => {
+                Ok(Value::String(self.multi_line_string('"')?))
+            }
+            Some('\'') if self.starts_with("'''") => {
+                Ok(Value::String(self.multi_line_string('\'')?))
+            }
+            Some('"') => Ok(Value::String(self.basic_string()?)),
+            Some('\'') => Ok(Value::String(self.literal_string()?)),
+            Some('[') => {
+                self.position += 1;
+                let mut values = Vec::new();
+                // Arrays can span lines
+                loop {
+                    self.blank();
+                    if self.peek() == Some(']') {
+                        self.position += 1;
+                        return Ok(Value::Array(values));
+                    }
+                    values.push(self.value()?);
+                    self.blank();
+                    match self.next()? {
+                        ',' => continue,
+                        ']' => return Ok(Value::Array(values)),
+                        c => bail!("Expected ',' or ']', got {:?}", c),
+                    }
+                }
+            }
+            Some('{') => {
+                self.position += 1;
+                let mut table = Value::Table(Vec::new());
+                self.whitespace();
+                if self.peek() == Some('}') {
+                    self.position += 1;
+                    return Ok(table);
+                }
+                loop {
+                    let keys = self.keys()?;
+                    self.expect('=')?;
+                    let value = self.value()?;
+                    let entries = self::table(&mut table, &keys[..keys.len() - 1])?;
+                    entries.push((keys[keys.len() - 1].clone(), value));
+                    self.whitespace();
+                    match self.next()? {
+                        ',' => continue,
+                        '}' => return Ok(table),
+                        c => bail!("Expected ',' or '}}', got {:?}", c),
+                    }
+                }
+            }
+            Some(_) => {
+                let start = self.position;
+                while self
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_alphanumeric() || "+-._".contains(c))
+                {
+                    self.position += 1;
+                }
+                let word: String = self.chars[start..self.position].iter().collect();
+                let number = word.replace('_', "");
+                match word.as_str() {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    _ => {
+                        if let Ok(integer) = number.parse() {
+                            Ok(Value::Integer(integer))
+                        } else if let Ok(float) = number.parse() {
+                            Ok(Value::Float(float))
+                        } else {
+                            bail!("Invalid value {:?}", word)
+                        }
+                    }
+                }
+            }
+            None => bail!("Missing value"),
+        }
+    }
+
+    fn starts_with(&self, text: &str) -> bool {
+        self.chars[self.position..]
+            .iter()
+            .copied()
+            .take(text.len())
+            .eq(text.chars())
+    }
+
+    fn basic_string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut value = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(value),
+                '\n' => bail!("Unterminated string"),
+                '\\' => value.push(self.escape()?),
+                c => value.push(c),
+            }
+        }
+    }
+
+    /// The character escaped by a backslash in a basic string
+    fn escape(&mut self) -> Result<char> {
+        Ok(match self.next()? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'b' => '\u{8}',
+            'f' => '\u{c}',
+            '"' => '"',
+            '\\' => '\\',
+            'u' => self.unicode(4)?,
+            'U' => self.unicode(8)?,
+            c => bail!("Invalid escape \\{}", c),
+        })
+    }
+
+    /// A string between `"""` or `'''`, escapes only apply to the former. A newline right after
+    /// the opening quotes isn't part of the string.
+    fn multi_line_string(&mut self, quote: char) -> Result<String> {
+        let delimiter: String = [quote; 3].iter().collect();
+        self.position += 3;
+        if self.starts_with("\r\n") {
+            self.position += 2;
+        } else if self.peek() == Some('\n') {
+            self.position += 1;
+        }
+        let mut value = String::new();
+        loop {
+            if self.starts_with(&delimiter) {
+                self.position += 3;
+                return Ok(value);
+            }
+            match self.peek() {
+                Some('\\') if quote == '"' => {
+                    self.position += 1;
+                    if matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) {
+                        // A backslash at the end of a line joins it with the next one
+                        self.blank_lines();
+                    } else {
+                        value.push(self.escape()?);
+                    }
+                }
+                Some(c) => {
+                    self.position += 1;
+                    value.push(c);
+                }
+                None => bail!("Unterminated string"),
+            }
+        }
+    }
+
+    /// Skip whitespace and newlines, but not comments
+    fn blank_lines(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) {
+            self.position += 1;
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String> {
+        self.expect('\'')?;
+        let mut value = String::new();
+        loop {
+            match self.next()? {
+                '\'' => return Ok(value),
+                '\n' => bail!("Unterminated string"),
+                c => value.push(c),
+            }
+        }
+    }
+
+    fn unicode(&mut self, digits: usize) -> Result<char> {
+        let mut code = 0;
+        for _ in 0..digits {
+            match self.next()?.to_digit(16) {
+                Some(digit) => code = code * 16 + digit,
+                None => bail!("Invalid unicode escape"),
+            }
+        }
+        match char::from_u32(code) {
+            Some(c) => Ok(c),
+            None => bail!("Invalid unicode escape"),
+        }
+    }
+}
+
+/// Quote `value` as a TOML basic string
+pub fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 || c as u32 == 0x7f => {
+                quoted.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> Value {
+        Value::String(value.to_string())
+    }
+
+    fn error(text: &str) -> String {
+        parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn strings() {
+        let root = parse(
+            "basic = \"tab\\tquote\\\" \\u00e9\\U0001F40D\"\n\
+             literal = 'C:\\no\\escapes'\n\
+             multi = \"\"\"\nfirst\n  second \\\n    joined\"\"\"\n\
+             raw = '''\nkept \\n as is'''\n",
+        )
+        .unwrap();
+        assert_eq!(
+            root.get("basic"),
+            Some(&string("tab\tquote\" \u{e9}\u{1F40D}"))
+        );
+        assert_eq!(root.get("literal"), Some(&string("C:\\no\\escapes")));
+        assert_eq!(root.get("multi"), Some(&string("first\n  second joined")));
+        assert_eq!(root.get("raw"), Some(&string("kept \\n as is")));
+        assert!(error("bad = \"\\q\"").contains("Invalid escape"));
+        assert!(error("open = \"never closed\nnext = 1").contains("Unterminated"));
+    }
+
+    #[test]
+    fn numbers_and_arrays() {
+        let root = parse(
+            "baud = 115_200\nratio = -0.5\non = true\n\
+             include = [\n  \"*.py\",  # sources\n  \"lib\",\n]\nmatrix = [[1, 2], []]\n",
+        )
+        .unwrap();
+        assert_eq!(root.get("baud"), Some(&Value::Integer(115200)));
+        assert_eq!(root.get("ratio"), Some(&Value::Float(-0.5)));
+        assert_eq!(root.get("on"), Some(&Value::Boolean(true)));
+        assert_eq!(
+            root.get("include"),
+            Some(&Value::Array(vec![string("*.py"), string("lib")]))
+        );
+        assert_eq!(
+            root.get("matrix"),
+            Some(&Value::Array(vec![
+                Value::Array(vec![Value::Integer(1), Value::Integer(2)]),
+                Value::Array(Vec::new()),
+            ]))
+        );
+    }
+
+    #[test]
+    fn inline_tables() {
+        let root =
+            parse("vars = { ROOM = \"kitchen\", timing.interval = 30 }\nempty = {}\n").unwrap();
+        let vars = root.get("vars").unwrap();
+        assert_eq!(vars.get("ROOM"), Some(&string("kitchen")));
+        assert_eq!(
+            vars.get("timing").and_then(|timing| timing.get("interval")),
+            Some(&Value::Integer(30))
+        );
+        assert_eq!(root.get("empty"), Some(&Value::Table(Vec::new())));
+    }
+
+    #[test]
+    fn profiles() {
+        let root = parse(
+            "device = \"/dev/ttyACM0\"\n\n[sync]\ninclude = [\"*.py\"]\n\n\
+             # Selected with --profile\n[profile.esp32]\nbaud = 460800\n\
+             sync.include = [\"main.py\"]\n\n[profile.pico]\nproduct = \"Pico\"\n",
+        )
+        .unwrap();
+        let Value::Table(entries) = &root else {
+            panic!("Expected a table");
+        };
+        let keys: Vec<&str> = entries.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["device", "sync", "profile"]);
+        let profiles = root.get("profile").unwrap();
+        let esp32 = profiles.get("esp32").unwrap();
+        assert_eq!(esp32.get("baud"), Some(&Value::Integer(460800)));
+        assert_eq!(
+            esp32.get("sync").and_then(|sync| sync.get("include")),
+            Some(&Value::Array(vec![string("main.py")]))
+        );
+        assert_eq!(
+            profiles.get("pico").and_then(|pico| pico.get("product")),
+            Some(&string("Pico"))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            error("[boards]\nname = 1\n\n[[boards.sensor]]\n"),
+            "Line 4: Arrays of tables aren't supported"
+        );
+        assert!(error("baud = 1\nbaud = 2\n").contains("Duplicate key"));
+        assert!(error("baud = 1\n[baud]\n").contains("isn't a table"));
+        assert!(error("when = 2024-01-01\n").contains("Invalid value"));
+    }
+
+    #[test]
+    fn quoted_values_parse_back() {
+        let value = "line\n\"quoted\" \\ tab\t\u{7f}";
+        let root = parse(&format!("value = {}", quote(value))).unwrap();
+        assert_eq!(root.get("value"), Some(&string(value)));
+    }
+}
@@ -0,0 +1,76 @@
+//! Logging every byte exchanged with the device, for debugging the protocol on unusual firmware
+use anyhow::Result;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use crate::tap::Tap;
+
+/// How many bytes are shown on each line of the trace
+const BYTES_PER_LINE: usize = 16;
+
+/// Writes a trace of a port as hex and printable characters, for use with a
+/// [`crate::tap::TapPort`]
+pub struct TraceLog {
+    out: BufWriter<File>,
+    start: Instant,
+}
+
+impl TraceLog {
+    /// Start a trace in a new file at `path`, for the port named `name`
+    pub fn create(path: &Path, name: &str) -> Result<TraceLog> {
+        let mut log = TraceLog {
+            out: BufWriter::new(File::create(path)?),
+            start: Instant::now(),
+        };
+        log.event(&format!("opened {}", name));
+        Ok(log)
+    }
+
+    /// Log `bytes`, where `direction` is `>` for bytes written to the device and `<` for bytes
+    /// read from it
+    fn bytes(&mut self, direction: char, bytes: &[u8]) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        for (index, line) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+            let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let printable: String = line
+                .iter()
+                .map(|&byte| match byte {
+                    0x20..=0x7e => char::from(byte),
+                    _ => '.',
+                })
+                .collect();
+            let prefix = if index == 0 {
+                format!("{:>12.6} {}", elapsed, direction)
+            } else {
+                format!("{:>12} {}", "", direction)
+            };
+            let _ = writeln!(
+                self.out,
+                "{} {:<width$} |{}|",
+                prefix,
+                hex.join(" "),
+                printable,
+                width = BYTES_PER_LINE * 3 - 1
+            );
+        }
+        let _ = self.out.flush();
+    }
+}
+
+impl Tap for TraceLog {
+    fn read(&mut self, bytes: &[u8]) {
+        self.bytes('<', bytes);
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.bytes('>', bytes);
+    }
+
+    fn event(&mut self, event: &str) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let _ = writeln!(self.out, "{:>12.6} * {}", elapsed, event);
+        let _ = self.out.flush();
+    }
+}
@@ -0,0 +1,126 @@
+//! Understanding MicroPython tracebacks and mapping them back to the local source files
+use std::path::PathBuf;
+
+/// A frame of a traceback, `File "<stdin>", line 3, in main`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub file: String,
+    pub line: usize,
+    pub function: Option<String>,
+}
+
+impl Frame {
+    /// Parse a traceback line, returning `None` if it isn't a frame
+    pub fn parse(line: &str) -> Option<Frame> {
+        let rest = line.trim_start().strip_prefix("File \"")?;
+        let (file, rest) = rest.split_once('"')?;
+        let rest = rest.strip_prefix(", line ")?;
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let line = rest[..digits].parse().ok()?;
+        let function = rest[digits..]
+            .strip_prefix(", in ")
+            .map(|function| function.trim_end().to_string());
+
+        Some(Frame {
+            file: file.to_string(),
+            line,
+            function,
+        })
+    }
+}
+
+/// Parse all frames of a traceback, outermost first
+pub fn parse_frames(traceback: &str) -> Vec<Frame> {
+    traceback.lines().filter_map(Frame::parse).collect()
+}
+
+/// Maps file names the device reports in tracebacks to local files
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    files: Vec<MappedFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MappedFile {
+    remote: String,
+    local: PathBuf,
+    /// Lines of generated code sent ahead of the file's own content
+    line_offset: usize,
+    /// The local line of each line the device saw after the offset, empty if they're the same
+    lines: Vec<usize>,
+}
+
+impl SourceMap {
+    /// Map `remote`, as named in tracebacks, to `local`, where the device saw `line_offset` lines
+    /// of generated code before the first line of the local file
+    pub fn add(&mut self, remote: &str, local: impl Into<PathBuf>, line_offset: usize) {
+        self.files.push(MappedFile {
+            remote: remote.to_string(),
+            local: local.into(),
+            line_offset,
+            lines: Vec::new(),
+        });
+    }
+
+    /// Like [`SourceMap::add`], for content that was rewritten before it was sent, where `lines`
+    /// holds the local line of each line the device saw
+    pub fn add_with_lines(
+        &mut self,
+        remote: &str,
+        local: impl Into<PathBuf>,
+        line_offset: usize,
+        lines: Vec<usize>,
+    ) {
+        self.files.push(MappedFile {
+            remote: remote.to_string(),
+            local: local.into(),
+            line_offset,
+            lines,
+        });
+    }
+
+    /// Map a frame to the local file and line, if it's in a mapped file and not in generated code
+    pub fn map(&self, frame: &Frame) -> Option<(PathBuf, usize)> {
+        let file = self.files.iter().find(|file| file.remote == frame.file)?;
+        if frame.line <= file.line_offset {
+            return None;
+        }
+        let line = frame.line - file.line_offset;
+        if file.lines.is_empty() {
+            return Some((file.local.clone(), line));
+        }
+        Some((file.local.clone(), *file.lines.get(line - 1)?))
+    }
+
+    /// Rewrite a traceback line so frames point at the local file and line
+    pub fn rewrite_line(&self, line: &str) -> String {
+        let frame = match Frame::parse(line) {
+            Some(frame) => frame,
+            None => return line.to_string(),
+        };
+        let (path, number) = match self.map(&frame) {
+            Some(mapped) => mapped,
+            None => return line.to_string(),
+        };
+
+        let indent = &line[..line.len() - line.trim_start().len()];
+        let mut rewritten = format!("{}File \"{}\", line {}", indent, path.display(), number);
+        if let Some(function) = frame.function {
+            rewritten.push_str(", in ");
+            rewritten.push_str(&function);
+        }
+        rewritten
+    }
+
+    /// A compiler style diagnostic, `main.py:3: error: ValueError: bad`, for the innermost frame of
+    /// `traceback` that is in a mapped file
+    pub fn diagnostic(&self, traceback: &str, exception: &str) -> Option<String> {
+        let (path, line) = parse_frames(traceback)
+            .iter()
+            .rev()
+            .find_map(|frame| self.map(frame))?;
+        Some(format!("{}:{}: error: {}", path.display(), line, exception))
+    }
+}
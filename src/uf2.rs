@@ -0,0 +1,140 @@
+//! Flashing firmware in the UF2 format, as used by the RP2040's BOOTSEL bootloader. The
+//! bootloader shows up as a USB drive, and copying an image onto it flashes the board.
+use anyhow::{bail, Result};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Reboots a running board into its bootloader
+pub const BOOTLOADER: &str = "import machine
+machine.bootloader()
+";
+
+/// Erases the superblocks of a board's littlefs and resets it, after which there's nothing to
+/// mount and a fresh filesystem is made at boot
+pub const ERASE_FILESYSTEM: &str = "import os, rp2, machine
+flash = rp2.Flash()
+os.umount('/')
+flash.ioctl(6, 0)
+flash.ioctl(6, 1)
+machine.reset()
+";
+
+/// Every block of a UF2 image is this long
+const BLOCK_SIZE: usize = 512;
+
+const MAGIC_START0: u32 = 0x0A32_4655;
+const MAGIC_START1: u32 = 0x9E5D_5157;
+const MAGIC_END: u32 = 0x0AB1_6F30;
+
+/// The file every UF2 bootloader drive has in its root
+const INFO_FILE: &str = "INFO_UF2.TXT";
+
+/// Check that `image` is made of UF2 blocks, returning how many there are
+pub fn check(image: &[u8]) -> Result<usize> {
+    if image.is_empty() || !image.len().is_multiple_of(BLOCK_SIZE) {
+        bail!(
+            "Not a UF2 image, its size isn't a multiple of {} bytes",
+            BLOCK_SIZE
+        );
+    }
+    let word = |block: &[u8], offset: usize| {
+        u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap())
+    };
+    for (index, block) in image.chunks(BLOCK_SIZE).enumerate() {
+        if word(block, 0) != MAGIC_START0
+            || word(block, 4) != MAGIC_START1
+            || word(block, BLOCK_SIZE - 4) != MAGIC_END
+        {
+            bail!("Not a UF2 image, block {} is invalid", index);
+        }
+    }
+    Ok(image.len() / BLOCK_SIZE)
+}
+
+/// Find a mounted UF2 bootloader drive
+pub fn find_volume() -> Option<PathBuf> {
+    mount_points()
+        .into_iter()
+        .find(|mount| mount.join(INFO_FILE).is_file())
+}
+
+/// Wait for a UF2 bootloader drive to be mounted
+pub fn wait_for_volume(timeout: Duration) -> Result<PathBuf> {
+    let start = Instant::now();
+    loop {
+        if let Some(volume) = find_volume() {
+            return Ok(volume);
+        }
+        if start.elapsed() > timeout {
+            bail!(
+                "Timed out waiting for the board's UF2 drive to be mounted, mount it and give it \
+                 with --volume"
+            );
+        }
+        sleep(Duration::from_millis(250));
+    }
+}
+
+/// Wait for the UF2 bootloader drive at `volume` to be unmounted and then mounted again, as it is
+/// when the board reboots back into its bootloader, where it may be mounted somewhere else
+pub fn wait_for_remount(volume: &Path, timeout: Duration) -> Result<PathBuf> {
+    let start = Instant::now();
+    while volume.join(INFO_FILE).is_file() {
+        if start.elapsed() > timeout {
+            bail!("Timed out waiting for {} to be unmounted", volume.display());
+        }
+        sleep(Duration::from_millis(250));
+    }
+    loop {
+        if volume.join(INFO_FILE).is_file() {
+            return Ok(volume.to_path_buf());
+        }
+        if let Some(volume) = find_volume() {
+            return Ok(volume);
+        }
+        if start.elapsed() > timeout {
+            bail!("Timed out waiting for the board's UF2 drive to be mounted again");
+        }
+        sleep(Duration::from_millis(250));
+    }
+}
+
+/// Copy `image` onto the bootloader drive at `volume`, after which the board flashes it and
+/// reboots
+pub fn copy(image: &[u8], volume: &Path) -> Result<()> {
+    if !volume.join(INFO_FILE).is_file() {
+        bail!("{} isn't a UF2 bootloader drive", volume.display());
+    }
+    let path = volume.join("firmware.uf2");
+    let mut file = match File::create(&path) {
+        Ok(file) => file,
+        Err(e) => bail!("Couldn't create {}: {}", path.display(), e),
+    };
+    file.write_all(image)?;
+    // The board reboots as soon as it has the last block, which can take the drive away before
+    // the sync finishes
+    let _ = file.sync_all();
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn mount_points() -> Vec<PathBuf> {
+    let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+    mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        // Spaces and other special characters in mount points are escaped in octal
+        .map(|mount| PathBuf::from(mount.replace("\\040", " ")))
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mount_points() -> Vec<PathBuf> {
+    match fs::read_dir("/Volumes") {
+        Ok(entries) => entries.flatten().map(|entry| entry.path()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
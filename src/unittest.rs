@@ -0,0 +1,125 @@
+//! Running test modules with the device's `unittest` module and reading back their results
+use std::fmt;
+use std::ops::AddAssign;
+use std::path::Path;
+
+use crate::script::quote;
+
+/// The results of running one or more test modules
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub ran: usize,
+    pub failures: usize,
+    pub errors: usize,
+    pub skipped: usize,
+    /// The tests that failed or raised, such as `FAIL: test_add (TestMath)`
+    pub failed: Vec<String>,
+}
+
+impl Summary {
+    pub fn passed(&self) -> usize {
+        self.ran
+            .saturating_sub(self.failures + self.errors + self.skipped)
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.failures == 0 && self.errors == 0
+    }
+}
+
+impl AddAssign for Summary {
+    fn add_assign(&mut self, other: Summary) {
+        self.ran += other.ran;
+        self.failures += other.failures;
+        self.errors += other.errors;
+        self.skipped += other.skipped;
+        self.failed.extend(other.failed);
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let plural = |count: usize, word: &str| {
+            format!("{} {}{}", count, word, if count == 1 { "" } else { "s" })
+        };
+        write!(
+            f,
+            "Ran {}: {} passed, {} failed, {}, {} skipped",
+            plural(self.ran, "test"),
+            self.passed(),
+            self.failures,
+            plural(self.errors, "error"),
+            self.skipped
+        )
+    }
+}
+
+/// Whether the file is a test module, named like `test_*.py` as unittest discovery expects
+pub fn is_test_module(name: &str) -> bool {
+    name.starts_with("test") && name.ends_with(".py")
+}
+
+/// A script running the tests of `module`, which has been copied into `dir` on the device
+pub fn runner(dir: &str, module: &str) -> String {
+    format!(
+        "import sys
+sys.path.insert(0, {})
+import unittest
+try:
+    unittest.main({})
+except SystemExit:
+    pass
+",
+        quote(dir),
+        quote(module)
+    )
+}
+
+/// The module name of a test file, `test_math` for `test_math.py`
+pub fn module_name(path: &Path) -> Option<String> {
+    Some(path.file_stem()?.to_string_lossy().to_string())
+}
+
+/// Read the summary unittest prints at the end of a run, such as `Ran 3 tests` followed by `OK`
+/// or `FAILED (failures=1, errors=0)`. Returns `None` if the run didn't get as far as printing it.
+pub fn parse(output: &str) -> Option<Summary> {
+    let lines: Vec<&str> = output.lines().map(str::trim).collect();
+    let ran_at = lines.iter().rposition(|line| line.starts_with("Ran "))?;
+    let ran = lines[ran_at]
+        .split_whitespace()
+        .nth(1)
+        .and_then(|count| count.parse().ok())?;
+    let status = lines[ran_at + 1..].iter().find(|line| !line.is_empty())?;
+
+    let mut summary = Summary {
+        ran,
+        ..Summary::default()
+    };
+    let counts = match status.strip_prefix("OK") {
+        Some(counts) => counts,
+        None => status.strip_prefix("FAILED")?,
+    };
+    for count in counts
+        .trim()
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .split(',')
+    {
+        let (name, value) = match count.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim().parse().unwrap_or(0)),
+            None => continue,
+        };
+        match name {
+            "failures" => summary.failures = value,
+            "errors" => summary.errors = value,
+            "skipped" => summary.skipped = value,
+            _ => {}
+        }
+    }
+    summary.failed = lines[..ran_at]
+        .iter()
+        .filter(|line| line.starts_with("FAIL: ") || line.starts_with("ERROR: "))
+        .map(|line| line.to_string())
+        .collect();
+    Some(summary)
+}
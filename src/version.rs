@@ -0,0 +1,84 @@
+//! Detecting which MicroPython a device runs, to adapt to the differences between versions
+use anyhow::{bail, Result};
+use std::fmt;
+use std::time::Duration;
+
+use crate::device::Device;
+use crate::serial::eval;
+
+/// The first MicroPython release with raw-paste mode
+const RAW_PASTE_SINCE: (u32, u32, u32) = (1, 14, 0);
+
+const QUERY: &str = "import sys
+i = sys.implementation
+print(i.name)
+print('.'.join(str(n) for n in i.version[:3]))
+print(sys.platform)
+print(getattr(i, '_machine', ''))
+";
+
+/// The firmware running on a device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Firmware {
+    /// The implementation, `micropython` or a fork such as `circuitpython`
+    pub name: String,
+    pub version: (u32, u32, u32),
+    pub platform: String,
+    /// The board and its chip, on versions that report it
+    pub machine: Option<String>,
+}
+
+impl Firmware {
+    /// Whether the firmware is able to receive scripts in raw-paste mode
+    pub fn raw_paste(&self) -> bool {
+        self.name != "micropython" || self.version >= RAW_PASTE_SINCE
+    }
+}
+
+impl fmt::Display for Firmware {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (major, minor, patch) = self.version;
+        write!(
+            f,
+            "{} {}.{}.{} on {}",
+            self.name, major, minor, patch, self.platform
+        )?;
+        if let Some(machine) = &self.machine {
+            write!(f, " ({})", machine)?;
+        }
+        Ok(())
+    }
+}
+
+/// Ask the device which firmware it runs, and set the device up to work around what the version
+/// lacks
+pub fn detect(device: &mut Device, timeout: Option<Duration>) -> Result<Firmware> {
+    let output = eval(device, QUERY, timeout)?;
+    let output = String::from_utf8_lossy(&output);
+    let lines: Vec<&str> = output.lines().map(str::trim).collect();
+    let (name, version, platform, machine) = match lines[..] {
+        [name, version, platform, machine] => (name, version, platform, machine),
+        _ => bail!("Unexpected firmware description from device: {:?}", output),
+    };
+
+    let numbers: Vec<u32> = match version.split('.').map(str::parse).collect() {
+        Ok(numbers) => numbers,
+        Err(_) => bail!("Unexpected version from device: {:?}", version),
+    };
+    let version = match numbers[..] {
+        [major, minor, patch] => (major, minor, patch),
+        [major, minor] => (major, minor, 0),
+        _ => bail!("Unexpected version from device: {:?}", version),
+    };
+
+    let firmware = Firmware {
+        name: name.to_string(),
+        version,
+        platform: platform.to_string(),
+        machine: (!machine.is_empty()).then(|| machine.to_string()),
+    };
+    if !firmware.raw_paste() {
+        device.set_raw_paste(false);
+    }
+    Ok(firmware)
+}
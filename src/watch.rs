@@ -0,0 +1,92 @@
+//! Watching local files for changes, by polling their modification times
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::interrupt;
+
+/// Watches files, and every file under directories, for being changed, created or removed
+pub struct Watcher {
+    roots: Vec<PathBuf>,
+    snapshot: BTreeMap<PathBuf, Option<SystemTime>>,
+    /// Files that changed since changes were last returned, and when the latest change was seen
+    changed: BTreeSet<PathBuf>,
+    last_change: Instant,
+}
+
+impl Watcher {
+    pub fn new(roots: Vec<PathBuf>) -> Watcher {
+        let snapshot = snapshot(&roots);
+        Watcher {
+            roots,
+            snapshot,
+            changed: BTreeSet::new(),
+            last_change: Instant::now(),
+        }
+    }
+
+    /// Check the files for changes, returning the files that changed once nothing has changed for
+    /// `debounce`, so that an editor saving several files counts as one change
+    pub fn poll(&mut self, debounce: Duration) -> Option<Vec<PathBuf>> {
+        let current = snapshot(&self.roots);
+        let removed = self
+            .snapshot
+            .keys()
+            .filter(|path| !current.contains_key(*path));
+        let changed = current
+            .iter()
+            .filter(|(path, modified)| self.snapshot.get(*path) != Some(modified))
+            .map(|(path, _)| path);
+        let changed: Vec<PathBuf> = removed.chain(changed).cloned().collect();
+        if !changed.is_empty() {
+            self.changed.extend(changed);
+            self.last_change = Instant::now();
+        }
+        self.snapshot = current;
+
+        if !self.changed.is_empty() && self.last_change.elapsed() >= debounce {
+            Some(std::mem::take(&mut self.changed).into_iter().collect())
+        } else {
+            None
+        }
+    }
+
+    /// Wait for files to change, checking every `interval`, like [`Watcher::poll`]. Returns
+    /// `None` if Ctrl-C is pressed while a Ctrl-C handler is installed.
+    pub fn wait(&mut self, interval: Duration, debounce: Duration) -> Option<Vec<PathBuf>> {
+        loop {
+            sleep(interval);
+            if interrupt::take() {
+                return None;
+            }
+            if let Some(changed) = self.poll(debounce) {
+                return Some(changed);
+            }
+        }
+    }
+}
+
+/// The modification time of every file under the roots, `None` for roots that don't exist
+fn snapshot(roots: &[PathBuf]) -> BTreeMap<PathBuf, Option<SystemTime>> {
+    let mut snapshot = BTreeMap::new();
+    let mut pending: Vec<PathBuf> = roots.to_vec();
+    while let Some(path) = pending.pop() {
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                snapshot.insert(path, None);
+                continue;
+            }
+        };
+        if metadata.is_dir() {
+            if let Ok(entries) = fs::read_dir(&path) {
+                pending.extend(entries.flatten().map(|entry| entry.path()));
+            }
+        } else {
+            snapshot.insert(path, metadata.modified().ok());
+        }
+    }
+    snapshot
+}
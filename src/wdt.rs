@@ -0,0 +1,112 @@
+//! Keeping the hardware watchdog a script starts fed while it runs, for long test sequences driven
+//! from the host, where the script waits on the host for longer than the watchdog's timeout.
+//!
+//! [`HELPER`] goes ahead of the script and stands in for `machine` in `sys.modules`, so that the
+//! script imports a `machine` whose `WDT` starts a timer feeding the watchdog at a quarter of its
+//! timeout. [`RESTORE`] is run once the script has finished, stopping the timer and putting the
+//! firmware's `machine` back. The watchdog itself can't be stopped once started, so from then on
+//! it resets the board as it would have without serpico unless something feeds it.
+use anyhow::Result;
+use std::time::Duration;
+
+use crate::device::Device;
+use crate::serial::{eval, Disconnected};
+
+/// Has the script's `machine.WDT` feed the watchdogs it starts from a timer, defining
+/// `_serpico_restore_wdt()` to stop it. Firmware without `machine.WDT` is left as it is.
+pub const HELPER: &str = r#"def _serpico_feed_wdt():
+    import sys
+    try:
+        import machine
+        real = machine.WDT
+    except (ImportError, AttributeError):
+        return lambda: None
+    dogs = []
+    timers = []
+    def feed(timer):
+        for dog in dogs:
+            dog.feed()
+    def WDT(*args, **kwargs):
+        dog = real(*args, **kwargs)
+        dogs.append(dog)
+        if not timers:
+            timeout = kwargs.get("timeout", args[1] if len(args) > 1 else 5000)
+            try:
+                timer = machine.Timer(-1)
+            except ValueError:
+                timer = machine.Timer(0)
+            timer.init(mode=machine.Timer.PERIODIC, period=max(timeout // 4, 1), callback=feed)
+            timers.append(timer)
+        return dog
+    class Machine:
+        def __getattr__(self, name):
+            return getattr(machine, name)
+    shim = Machine()
+    shim.WDT = WDT
+    previous = sys.modules.get("machine")
+    sys.modules["machine"] = shim
+    def restore():
+        for timer in timers:
+            timer.deinit()
+        if previous is None:
+            del sys.modules["machine"]
+        else:
+            sys.modules["machine"] = previous
+    return restore
+_serpico_restore_wdt = _serpico_feed_wdt()
+del _serpico_feed_wdt
+"#;
+
+/// Stops feeding the watchdog, if [`HELPER`] ran
+pub const RESTORE: &str = r#"try:
+    _serpico_restore_wdt()
+    del _serpico_restore_wdt
+except NameError:
+    pass
+"#;
+
+/// Run the script with `run`, then run [`RESTORE`] however it ended, unless the device went away
+/// with it. A script that timed out or was interrupted would otherwise leave the timer feeding the
+/// watchdog. The script's own error is the one returned if both fail.
+pub fn restoring<T>(
+    device: &mut Device,
+    timeout: Option<Duration>,
+    run: impl FnOnce(&mut Device) -> Result<T>,
+) -> Result<T> {
+    let result = run(device);
+    if matches!(&result, Err(e) if e.is::<Disconnected>()) {
+        return result;
+    }
+    let restored = eval(device, RESTORE, timeout);
+    let value = result?;
+    restored?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::Firmware;
+    use crate::session::ReplayPort;
+    use anyhow::bail;
+
+    #[test]
+    fn restored_after_a_failed_run() {
+        let exchange: &[(&[u8], &[u8])] = &[
+            (b"\r\x03\x03", b"\r\n>>> "),
+            (b"\r\x01", b"raw REPL; CTRL-B to exit\r\n>"),
+            (b"\x05A\x01", b"R\x01\x80\x00\x01"),
+            (RESTORE.as_bytes(), b""),
+            (b"\x04", b"\x04\x04\x04>"),
+        ];
+        let port = ReplayPort::recorded("wdt-restore", exchange);
+        let mut device = Device::new(Box::new(port), 256);
+        device.set_firmware(Some(Firmware::MicroPython));
+        let result: Result<()> = restoring(&mut device, Some(Duration::from_secs(5)), |_| {
+            bail!("Timed out waiting for the script to finish")
+        });
+        assert!(format!("{}", result.unwrap_err()).contains("Timed out"));
+        // Everything recorded, RESTORE included, has been replayed
+        assert!(device.port().bytes_to_read().is_err());
+    }
+}
@@ -0,0 +1,314 @@
+//! Connecting to a board's WebREPL, the REPL MicroPython serves over a websocket, so that boards
+//! away from a USB cable can be reached at a device such as `ws://192.168.4.1:8266`
+use anyhow::{bail, Result};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+use crate::base64;
+use crate::socket::{Socket, SocketPort};
+
+/// The port WebREPL listens on unless the device says otherwise
+pub const DEFAULT_PORT: u16 = 8266;
+
+/// How long connecting and logging in may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most sent in one frame, well below what needs a 64 bit length
+const MAX_FRAME_SIZE: usize = 4096;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Whether the device is a WebREPL URL rather than the path of a serial port
+pub fn is_url(device: &str) -> bool {
+    device.starts_with("ws://")
+}
+
+/// Connect to the WebREPL at `url` and log in with `password`
+pub fn connect(url: &str, password: &str) -> Result<SocketPort<WebSocket>> {
+    let (host, path) = match url.strip_prefix("ws://") {
+        Some(rest) => match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        },
+        None => bail!("Expected a ws:// URL, got {:?}", url),
+    };
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, DEFAULT_PORT)
+    };
+    let addresses = match address.to_socket_addrs() {
+        Ok(addresses) => addresses,
+        Err(e) => bail!("Couldn't resolve {}: {}", host, e),
+    };
+    let mut connected = None;
+    for address in addresses {
+        if let Ok(stream) = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            connected = Some(stream);
+            break;
+        }
+    }
+    let stream = match connected {
+        Some(stream) => stream,
+        None => bail!("Couldn't connect to the WebREPL at {}", address),
+    };
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+
+    let mut socket = WebSocket::handshake(stream, &address, path)?;
+    login(&mut socket, password)?;
+    Ok(SocketPort::new(
+        socket,
+        format!("WebREPL at {}", address),
+        Duration::from_millis(10),
+    )?)
+}
+
+/// Answer the password prompt WebREPL greets with
+fn login(socket: &mut WebSocket, password: &str) -> Result<()> {
+    read_until(socket, "Password: ")?;
+    socket.write_all(format!("{}\r", password).as_bytes())?;
+    let response = read_until(socket, "\n")?;
+    let response = if response.trim().is_empty() {
+        read_until(socket, "\n")?
+    } else {
+        response
+    };
+    if response.contains("Access denied") {
+        bail!("WebREPL denied access, is the password right?");
+    }
+    if !response.contains("WebREPL connected") {
+        bail!("Unexpected response from WebREPL: {:?}", response.trim());
+    }
+    Ok(())
+}
+
+/// Read until `expected` has been received, returning everything read
+fn read_until(socket: &mut WebSocket, expected: &str) -> Result<String> {
+    let start = Instant::now();
+    let mut received = Vec::new();
+    let mut buf = [0; 256];
+    while !String::from_utf8_lossy(&received).contains(expected) {
+        if start.elapsed() > CONNECT_TIMEOUT {
+            bail!("Timed out waiting for WebREPL to send {:?}", expected);
+        }
+        match socket.read(&mut buf) {
+            Ok(0) => bail!("WebREPL closed the connection"),
+            Ok(n) => received.extend_from_slice(&buf[..n]),
+            Err(ref e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => bail!(e),
+        }
+    }
+    Ok(String::from_utf8_lossy(&received).to_string())
+}
+
+/// A websocket client connection, reading and writing the payload of its frames.
+///
+/// What's written is sent in text frames, as WebREPL takes binary frames to be file transfer
+/// requests. Both text and binary frames are read.
+pub struct WebSocket {
+    stream: TcpStream,
+    /// Bytes read from the connection that don't make up a whole frame yet
+    input: Vec<u8>,
+    /// Payload of frames that hasn't been read yet
+    payload: Vec<u8>,
+    closed: bool,
+}
+
+impl WebSocket {
+    /// Upgrade the HTTP connection to the server at `host` to a websocket
+    fn handshake(mut stream: TcpStream, host: &str, path: &str) -> Result<WebSocket> {
+        let key = base64::encode(&random_bytes(16));
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+             Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            path, host, key
+        )?;
+
+        let mut response = Vec::new();
+        let mut byte = [0];
+        while !response.ends_with(b"\r\n\r\n") {
+            match stream.read(&mut byte) {
+                Ok(0) => bail!(
+                    "{} closed the connection during the websocket handshake",
+                    host
+                ),
+                Ok(_) => response.push(byte[0]),
+                Err(e) => bail!("Websocket handshake with {} failed: {}", host, e),
+            }
+        }
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("101") {
+            bail!("{} refused the websocket: {}", host, status);
+        }
+
+        Ok(WebSocket {
+            stream,
+            input: Vec::new(),
+            payload: Vec::new(),
+            closed: false,
+        })
+    }
+
+    /// Take the first frame out of the input, if it has been read completely
+    fn take_frame(&mut self) -> Option<(u8, Vec<u8>)> {
+        let input = &self.input;
+        if input.len() < 2 {
+            return None;
+        }
+        let opcode = input[0] & 0x0f;
+        let masked = input[1] & 0x80 != 0;
+        let (length, mut offset) = match input[1] & 0x7f {
+            126 => (
+                u16::from_be_bytes(input.get(2..4)?.try_into().ok()?) as usize,
+                4,
+            ),
+            127 => (
+                u64::from_be_bytes(input.get(2..10)?.try_into().ok()?) as usize,
+                10,
+            ),
+            length => (length as usize, 2),
+        };
+        let mask = if masked {
+            let mask: [u8; 4] = input.get(offset..offset + 4)?.try_into().ok()?;
+            offset += 4;
+            Some(mask)
+        } else {
+            None
+        };
+        let mut payload = input.get(offset..offset + length)?.to_vec();
+        if let Some(mask) = mask {
+            for (index, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[index % 4];
+            }
+        }
+        self.input.drain(..offset + length);
+        Some((opcode, payload))
+    }
+
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            length @ 0..=125 => frame.push(0x80 | length as u8),
+            length @ 126..=0xffff => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        // Frames from clients are masked, with a key the payload can't be chosen to predict
+        let mask = random_bytes(4);
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(index, byte)| byte ^ mask[index % 4]),
+        );
+        self.stream.write_all(&frame)
+    }
+}
+
+impl Read for WebSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.payload.is_empty() {
+                let n = buf.len().min(self.payload.len());
+                buf[..n].copy_from_slice(&self.payload[..n]);
+                self.payload.drain(..n);
+                return Ok(n);
+            }
+            if self.closed {
+                return Ok(0);
+            }
+            if let Some((opcode, payload)) = self.take_frame() {
+                match opcode {
+                    OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                        self.payload.extend(payload)
+                    }
+                    OPCODE_CLOSE => {
+                        let _ = self.write_frame(OPCODE_CLOSE, &[]);
+                        self.closed = true;
+                    }
+                    OPCODE_PING => self.write_frame(OPCODE_PONG, &payload)?,
+                    _ => {}
+                }
+                continue;
+            }
+
+            let mut chunk = [0; 1024];
+            match self.stream.read(&mut chunk)? {
+                0 => return Ok(0),
+                n => self.input.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+}
+
+impl Write for WebSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(MAX_FRAME_SIZE);
+        self.write_frame(OPCODE_TEXT, &buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl AsRawFd for WebSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl Socket for WebSocket {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// A clone shares the connection but not what has been read from it, so only one of them
+    /// should read
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(WebSocket {
+            stream: self.stream.try_clone()?,
+            input: Vec::new(),
+            payload: Vec::new(),
+            closed: self.closed,
+        })
+    }
+
+    /// What's waiting on the connection still has frame headers in it, so this can be more than
+    /// what's read
+    fn bytes_available(&self) -> io::Result<usize> {
+        Ok(self.payload.len() + self.stream.bytes_available()?)
+    }
+}
+
+/// Random bytes for websocket keys and masks, from the randomly seeded hasher of the standard
+/// library
+fn random_bytes(count: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(count);
+    while bytes.len() < count {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(bytes.len());
+        bytes.extend_from_slice(&hasher.finish().to_le_bytes());
+    }
+    bytes.truncate(count);
+    bytes
+}
@@ -0,0 +1,81 @@
+//! Connecting boards to WiFi, with the code each port needs, and storing the credentials on the
+//! board so its own code can connect at boot
+use std::time::Duration;
+
+use crate::script::quote;
+
+/// Where credentials are stored unless told otherwise, as JSON with `ssid` and `psk` keys
+pub const DEFAULT_CREDENTIALS: &str = "/wifi.json";
+
+/// Code that connects to `ssid`, waiting up to `timeout` for an address, stores the credentials at
+/// `save` if given and prints the address
+pub fn connect_script(
+    ssid: &str,
+    psk: Option<&str>,
+    timeout: Duration,
+    save: Option<&str>,
+) -> String {
+    let mut script = format!(
+        "import network, sys, time
+ssid = {}
+psk = {}
+if sys.platform == 'esp8266':
+    # The access point the ESP8266 starts with gets in the way of connecting
+    network.WLAN(network.AP_IF).active(False)
+wlan = network.WLAN(network.STA_IF)
+wlan.active(True)
+if wlan.isconnected():
+    wlan.disconnect()
+if psk is None:
+    wlan.connect(ssid)
+else:
+    wlan.connect(ssid, psk)
+start = time.ticks_ms()
+while not wlan.isconnected():
+    if time.ticks_diff(time.ticks_ms(), start) > {}:
+        status = wlan.status()
+        wlan.active(False)
+        raise OSError('no connection to %s, status %s' % (ssid, status))
+    time.sleep_ms(100)
+",
+        quote(ssid),
+        psk.map(quote).unwrap_or_else(|| String::from("None")),
+        timeout.as_millis()
+    );
+    if let Some(path) = save {
+        script.push_str(&format!(
+            "import json
+with open({}, 'w') as f:
+    json.dump({{'ssid': ssid, 'psk': psk}}, f)
+",
+            quote(path)
+        ));
+    }
+    script.push_str("print(wlan.ifconfig()[0])\n");
+    script
+}
+
+/// A `boot.py` that connects to the network stored at `credentials` by `wifi connect`, if there
+/// is one, without waiting for the address so that `main.py` starts right away
+pub fn boot_script(credentials: &str) -> String {
+    format!(
+        "# Generated by serpico install
+import gc
+try:
+    import json, network
+    with open({}) as f:
+        _credentials = json.load(f)
+    _wlan = network.WLAN(network.STA_IF)
+    _wlan.active(True)
+    if _credentials['psk'] is None:
+        _wlan.connect(_credentials['ssid'])
+    else:
+        _wlan.connect(_credentials['ssid'], _credentials['psk'])
+    del _credentials, _wlan
+except Exception as e:
+    print('boot.py: no WiFi:', e)
+gc.collect()
+",
+        quote(credentials)
+    )
+}
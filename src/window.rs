@@ -0,0 +1,91 @@
+//! Capturing only the sections of a long run's output between a line that starts them and a line
+//! that stops them, such as between `TEST BEGIN` and `TEST END` of a soak test
+use std::path::{Path, PathBuf};
+
+use crate::regex::Regex;
+
+/// Whether lines are being captured, opened by a line matching `start_on` and closed after a line
+/// matching `stop_on`. Without `start_on` it's open from the start, without `stop_on` it stays
+/// open once opened.
+#[derive(Debug, Clone)]
+pub struct Window {
+    start_on: Option<Regex>,
+    stop_on: Option<Regex>,
+    open: bool,
+    /// A line that hasn't ended yet, for [`Window::filter`]
+    line: Vec<u8>,
+}
+
+/// Where a line falls against the window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capture {
+    /// The line isn't captured
+    Outside,
+    /// The line matched `start_on`, opening a new window it's the first line of
+    Opened,
+    /// The line is captured
+    Inside,
+}
+
+impl Window {
+    pub fn new(start_on: Option<Regex>, stop_on: Option<Regex>) -> Window {
+        Window {
+            open: start_on.is_none(),
+            start_on,
+            stop_on,
+            line: Vec::new(),
+        }
+    }
+
+    /// Where `line`, without its line ending, falls. The lines that open and close the window are
+    /// both captured.
+    pub fn line(&mut self, line: &str) -> Capture {
+        let capture = if self.open {
+            Capture::Inside
+        } else if self
+            .start_on
+            .as_ref()
+            .is_some_and(|regex| regex.is_match(line))
+        {
+            self.open = true;
+            Capture::Opened
+        } else {
+            return Capture::Outside;
+        };
+        if self
+            .stop_on
+            .as_ref()
+            .is_some_and(|regex| regex.is_match(line))
+        {
+            self.open = false;
+        }
+        capture
+    }
+
+    /// The lines of `bytes` that are captured, holding back a line until it has ended
+    pub fn filter(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut captured = Vec::new();
+        for &byte in bytes {
+            self.line.push(byte);
+            if byte == b'\n' {
+                let line = std::mem::take(&mut self.line);
+                let text = String::from_utf8_lossy(&line);
+                if self.line(text.trim_end_matches(['\r', '\n'])) != Capture::Outside {
+                    captured.extend_from_slice(&line);
+                }
+            }
+        }
+        captured
+    }
+}
+
+/// The file the `number`th window is captured to when each gets a file of its own: `path` with
+/// the number ahead of its extension, such as `run-2.ndjson` for `run.ndjson`
+pub fn numbered(path: &Path, number: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, number, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, number),
+    };
+    path.with_file_name(name)
+}